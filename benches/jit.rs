@@ -0,0 +1,41 @@
+//! `cargo bench --bench jit`
+//!
+//! The benchmarks of the compiled code and of the compilation itself.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use cran_lua::jit::Jit;
+use cran_lua::parse_chunk;
+
+const FIB: &str = include_str!("../src/jit/scripts/fib.lua");
+const MANDELBROT: &str = include_str!("../src/jit/scripts/mandelbrot.lua");
+const NBODY: &str = include_str!("../src/jit/scripts/nbody.lua");
+
+fn run(c: &mut Criterion) {
+    let fib = parse_chunk(FIB).unwrap();
+    let mandelbrot = parse_chunk(MANDELBROT).unwrap();
+    let nbody = parse_chunk(NBODY).unwrap();
+    let mut group = c.benchmark_group("run");
+
+    let mut jit = Jit::new(&fib).unwrap();
+    let f = jit.function::<(i64,), i64>("fib").unwrap();
+    group.bench_function("fib(25)", |b| b.iter(|| f.call(black_box((25,))).unwrap()));
+
+    let mut jit = Jit::new(&mandelbrot).unwrap();
+    let f = jit.function::<(i64,), i64>("mandelbrot").unwrap();
+    group.bench_function("mandelbrot(100)", |b| b.iter(|| f.call(black_box((100,))).unwrap()));
+
+    let mut jit = Jit::new(&nbody).unwrap();
+    let f = jit.function::<(i64, f64), f64>("nbody").unwrap();
+    group.bench_function("nbody(10000)", |b| b.iter(|| f.call(black_box((10000, 0.01))).unwrap()));
+    group.finish();
+}
+
+/// The lowering, the typing and cranelift, every iteration starts with the fresh compiler.
+fn compile(c: &mut Criterion) {
+    let nbody = parse_chunk(NBODY).unwrap();
+    c.bench_function("compile nbody.lua", |b| {
+        b.iter(|| Jit::new(black_box(&nbody)).unwrap().function::<(i64, f64), f64>("nbody").unwrap())
+    });
+}
+
+criterion_group!(benches, run, compile);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+//! `cargo bench --bench parser`
+//!
+//! The parser does not backtrack, so the time grows linearly with the source:
+//! the throughput of `server.lua x n` stays the same whatever `n` is.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cran_lua::parse_chunk;
+use cran_lua::parser::arena::Ast;
+use cran_lua::parser::parse_chunk_arena;
+
+/// Counts the allocations to tell how many boxes the parsing makes.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocations<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+const SERVER: &str = include_str!("../src/parser/scripts/server.lua");
+
+/// The script repeated `n` times, every copy is wrapped into `do ... end`
+/// as the script ends with `return`.
+fn repeated(n: usize) -> String {
+    (0..n).map(|_| format!("do\n{}\nend\n", SERVER)).collect()
+}
+
+fn server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(SERVER.len() as u64));
+    group.bench_function("server.lua", |b| b.iter(|| parse_chunk(black_box(SERVER)).unwrap()));
+    group.finish();
+}
+
+fn scaling(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse server.lua x n");
+    for n in [1, 2, 4, 8, 16] {
+        let src = repeated(n);
+        group.throughput(Throughput::Bytes(src.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &src, |b, src| {
+            b.iter(|| parse_chunk(black_box(src)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// The tree clones a box per node, the arena clones a handful of vectors.
+fn clone(c: &mut Criterion) {
+    let block = parse_chunk(SERVER).unwrap();
+    let ast = Ast::new(&block);
+    let mut group = c.benchmark_group("clone server.lua");
+    group.bench_function("tree", |b| b.iter(|| black_box(&block).clone()));
+    group.bench_function("arena", |b| b.iter(|| black_box(&ast).clone()));
+    group.finish();
+}
+
+/// The parser fills the arena right away, the copy of the tree boxes every node on the way.
+fn arena(c: &mut Criterion) {
+    println!(
+        "allocations of server.lua: tree {}, tree into arena {}, arena {}",
+        allocations(|| parse_chunk(SERVER)),
+        allocations(|| Ast::new(&parse_chunk(SERVER).unwrap())),
+        allocations(|| parse_chunk_arena(SERVER)),
+    );
+    let mut group = c.benchmark_group("arena server.lua");
+    group.throughput(Throughput::Bytes(SERVER.len() as u64));
+    group.bench_function("tree into arena", |b| b.iter(|| Ast::new(&parse_chunk(black_box(SERVER)).unwrap())));
+    group.bench_function("arena", |b| b.iter(|| parse_chunk_arena(black_box(SERVER)).unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, server, scaling, clone, arena);
+criterion_main!(benches);
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use crate::hir::{Block, Expr, FnId, Place, Program, Stmt, TableField, VarId};
+use crate::parser::arena::IdMap;
+use crate::parser::ast::Name;
+
+/// The variable of an enclosing function the closure refers to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Upvalue {
+    pub var: VarId,
+    /// Where the closure takes it from when it is created: `None` is the local of the parent,
+    /// `Some(i)` is the `i`-th upvalue of the parent.
+    pub parent: Option<usize>,
+    /// The variable changes after the closure captures it.
+    pub mutated: bool,
+}
+
+/// What the closures do with the captured local.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Captured {
+    /// The variable is assigned by a closure or after a closure captures it,
+    /// so the function and its closures have to share it.
+    pub mutated: bool,
+    /// The variable is declared in a loop, every iteration captures a new one.
+    pub fresh: bool,
+}
+
+/// The upvalues of every function of the program and the locals they capture.
+///
+/// The function captures the variables it uses and the ones its closures take from the enclosing
+/// functions, the upvalues are listed in the order of the first use. The assignments are ordered
+/// by the position in the function, the loops (and the backward `goto`s) repeat their bodies,
+/// so the capture in one iteration sees the assignment of the next one.
+#[derive(Debug, Clone)]
+pub struct Captures {
+    upvalues: IdMap<FnId, Vec<Upvalue>>,
+    captured: IdMap<VarId, Captured>,
+    in_loop: IdMap<FnId, bool>,
+}
+
+impl Captures {
+    pub fn analyze(program: &Program) -> Self {
+        let mut analyzer = Analyzer {
+            program,
+            pos: 0,
+            closure_assigned: IdMap::new(),
+            captures: Captures { upvalues: IdMap::new(), captured: IdMap::new(), in_loop: IdMap::new() },
+        };
+        analyzer.function(program.main());
+        let mut captures = analyzer.captures;
+        for (id, _) in program.funcs() {
+            let upvalues = captures.upvalues.get(id).cloned().unwrap_or_default();
+            let upvalues = upvalues
+                .into_iter()
+                .map(|u| Upvalue { mutated: captures.captured.get(u.var).is_some_and(|c| c.mutated), ..u })
+                .collect();
+            captures.upvalues.insert(id, upvalues);
+        }
+        captures
+    }
+
+    pub fn upvalues(&self, func: FnId) -> &[Upvalue] {
+        self.upvalues.get(func).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// `None` for the variables no closure captures.
+    pub fn captured(&self, var: VarId) -> Option<&Captured> {
+        self.captured.get(var)
+    }
+
+    /// The variable lives in a box shared by the function and its closures,
+    /// the others are copied into the closures when they are created.
+    pub fn is_boxed(&self, var: VarId) -> bool {
+        self.captured(var).is_some_and(|c| c.mutated)
+    }
+
+    /// The closure of the function is created in a loop of its parent.
+    pub fn in_loop(&self, func: FnId) -> bool {
+        self.in_loop.get(func).copied().unwrap_or(false)
+    }
+}
+
+/// The positions of the declaration, the captures and the assignments of a local.
+#[derive(Default)]
+struct Events {
+    decl: usize,
+    captures: Vec<usize>,
+    assigns: Vec<usize>,
+}
+
+/// The function being analyzed.
+struct Frame<'a> {
+    func: FnId,
+    upvalues: Vec<Upvalue>,
+    locals: HashMap<VarId, Events>,
+    /// The ranges of the positions the loops repeat.
+    loops: Vec<(usize, usize)>,
+    closures: Vec<(FnId, usize)>,
+    /// The last position of the label, the visible one for the checked chunks.
+    labels: HashMap<Name<'a>, usize>,
+}
+
+struct Analyzer<'p, 'a> {
+    program: &'p Program<'a>,
+    /// The counter of the events in the order of the evaluation.
+    pos: usize,
+    /// The variables assigned by a closure.
+    closure_assigned: IdMap<VarId, ()>,
+    captures: Captures,
+}
+
+impl<'p, 'a> Analyzer<'p, 'a> {
+    fn tick(&mut self) -> usize {
+        self.pos += 1;
+        self.pos
+    }
+
+    fn function(&mut self, id: FnId) -> Vec<Upvalue> {
+        let func = &self.program[id];
+        let mut frame =
+            Frame { func: id, upvalues: vec![], locals: HashMap::new(), loops: vec![], closures: vec![], labels: HashMap::new() };
+        for param in &func.params {
+            self.declare(&mut frame, *param);
+        }
+        self.block(&mut frame, &func.body);
+
+        let within = |pos: usize| frame.loops.iter().any(|(start, end)| (*start..=*end).contains(&pos));
+        for (var, events) in &frame.locals {
+            if events.captures.is_empty() {
+                continue;
+            }
+            let first_capture = events.captures.iter().min().copied().unwrap_or_default();
+            let repeated = frame.loops.iter().any(|(start, end)| {
+                let range = *start..=*end;
+                events.decl < *start
+                    && events.assigns.iter().any(|a| range.contains(a))
+                    && events.captures.iter().any(|c| range.contains(c))
+            });
+            let mutated = self.closure_assigned.contains_key(*var)
+                || events.assigns.iter().any(|a| *a > first_capture)
+                || repeated;
+            self.captures.captured.insert(*var, Captured { mutated, fresh: within(events.decl) });
+        }
+        for (closure, pos) in &frame.closures {
+            self.captures.in_loop.insert(*closure, within(*pos));
+        }
+        self.captures.upvalues.insert(id, frame.upvalues.clone());
+        frame.upvalues
+    }
+
+    fn declare(&mut self, frame: &mut Frame<'a>, var: VarId) {
+        let decl = self.tick();
+        frame.locals.insert(var, Events { decl, ..Events::default() });
+    }
+
+    /// The index of the variable in the upvalues of the function, it is added on the first use.
+    fn upvalue(&mut self, frame: &mut Frame<'a>, var: VarId) -> usize {
+        match frame.upvalues.iter().position(|u| u.var == var) {
+            Some(i) => i,
+            None => {
+                frame.upvalues.push(Upvalue { var, parent: None, mutated: false });
+                frame.upvalues.len() - 1
+            }
+        }
+    }
+
+    fn is_local(&self, frame: &Frame<'a>, var: VarId) -> bool {
+        self.program[var].func == frame.func
+    }
+
+    fn assign(&mut self, frame: &mut Frame<'a>, var: VarId) {
+        let pos = self.tick();
+        if self.is_local(frame, var) {
+            frame.locals.entry(var).or_default().assigns.push(pos);
+        } else {
+            self.closure_assigned.insert(var, ());
+            self.upvalue(frame, var);
+        }
+    }
+
+    fn block(&mut self, frame: &mut Frame<'a>, block: &Block<'a>) {
+        for st in block {
+            self.stmt(frame, &st.kind);
+        }
+    }
+
+    fn looped(&mut self, frame: &mut Frame<'a>, rule: impl FnOnce(&mut Self, &mut Frame<'a>)) {
+        let start = self.tick();
+        rule(self, frame);
+        let end = self.tick();
+        frame.loops.push((start, end));
+    }
+
+    fn stmt(&mut self, frame: &mut Frame<'a>, st: &Stmt<'a>) {
+        match st {
+            Stmt::Local(vars, values) => {
+                self.exprs(frame, values);
+                for var in vars {
+                    self.declare(frame, *var);
+                }
+            }
+            Stmt::Assign(place, value) => match place {
+                Place::Var(var) => {
+                    self.expr(frame, value);
+                    self.assign(frame, *var);
+                }
+                Place::Index(obj, key) => {
+                    self.expr(frame, obj);
+                    self.expr(frame, key);
+                    self.expr(frame, value);
+                }
+                Place::Env => self.expr(frame, value),
+            },
+            Stmt::Call(call) => self.expr(frame, call),
+            Stmt::Do(block) => self.block(frame, block),
+            Stmt::Loop(block) => self.looped(frame, |a, frame| a.block(frame, block)),
+            Stmt::If(cond, then, otherwise) => {
+                self.expr(frame, cond);
+                self.block(frame, then);
+                self.block(frame, otherwise);
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => {
+                self.exprs(frame, [start, limit, step]);
+                self.looped(frame, |a, frame| {
+                    a.declare(frame, *var);
+                    a.block(frame, body);
+                });
+            }
+            Stmt::Return(values) => self.exprs(frame, values),
+            Stmt::Break => {}
+            Stmt::Label(name) => {
+                let pos = self.tick();
+                frame.labels.insert(name.clone(), pos);
+            }
+            Stmt::Goto(name) => {
+                let pos = self.tick();
+                if let Some(label) = frame.labels.get(name) {
+                    frame.loops.push((*label, pos));
+                }
+            }
+        }
+    }
+
+    fn exprs<'e>(&mut self, frame: &mut Frame<'a>, exprs: impl IntoIterator<Item = &'e Expr<'a>>)
+    where
+        'a: 'e,
+    {
+        for e in exprs {
+            self.expr(frame, e);
+        }
+    }
+
+    fn expr(&mut self, frame: &mut Frame<'a>, e: &Expr<'a>) {
+        match e {
+            Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) | Expr::VarArgs | Expr::Env => {}
+            Expr::Var(var) => {
+                if !self.is_local(frame, *var) {
+                    self.upvalue(frame, *var);
+                }
+            }
+            Expr::Function(id) => {
+                let mut upvalues = self.function(*id);
+                let pos = self.tick();
+                for u in &mut upvalues {
+                    if self.is_local(frame, u.var) {
+                        frame.locals.entry(u.var).or_default().captures.push(pos);
+                    } else {
+                        u.parent = Some(self.upvalue(frame, u.var));
+                    }
+                }
+                self.captures.upvalues.insert(*id, upvalues);
+                frame.closures.push((*id, pos));
+            }
+            Expr::Index(obj, key) => {
+                self.expr(frame, obj);
+                self.expr(frame, key);
+            }
+            Expr::Call(callee, args) => {
+                self.expr(frame, callee);
+                self.exprs(frame, args);
+            }
+            Expr::Table(fields) => {
+                for field in fields {
+                    match field {
+                        TableField::Keyed(k, v) => {
+                            self.expr(frame, k);
+                            self.expr(frame, v);
+                        }
+                        TableField::Positional(v) => self.expr(frame, v),
+                    }
+                }
+            }
+            Expr::First(e) | Expr::Unary(_, e) => self.expr(frame, e),
+            Expr::Binary(lhs, _, rhs) | Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) | Expr::Let(_, lhs, rhs) => {
+                self.expr(frame, lhs);
+                self.expr(frame, rhs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hir::capture::{Captured, Captures, Upvalue};
+    use crate::hir::{FnId, Program, VarId};
+    use crate::parser::arena::Idx;
+    use crate::parser::parse_chunk;
+
+    fn analyze(src: &str) -> (Program<'_>, Captures) {
+        let program = Program::new(&parse_chunk(src).unwrap());
+        let captures = Captures::analyze(&program);
+        (program, captures)
+    }
+
+    /// The variable by its name.
+    fn var(program: &Program, name: &str) -> VarId {
+        program.vars().find(|(_, v)| v.name.as_str() == name).map(|(id, _)| id).unwrap()
+    }
+
+    fn names(program: &Program, upvalues: &[Upvalue]) -> Vec<(String, Option<usize>, bool)> {
+        upvalues.iter().map(|u| (program[u.var].name.to_string(), u.parent, u.mutated)).collect()
+    }
+
+    #[test]
+    fn upvalues_test() {
+        let (program, captures) = analyze("local a, b = 1, 2 local function f(p) return function() return b + a + p end end");
+        let f = FnId::from_index(1);
+        let inner = FnId::from_index(2);
+        assert_eq!(names(&program, captures.upvalues(f)), [("b".to_string(), None, false), ("a".to_string(), None, false)]);
+        assert_eq!(names(&program, captures.upvalues(inner)), [
+            ("b".to_string(), Some(0), false),
+            ("a".to_string(), Some(1), false),
+            ("p".to_string(), None, false),
+        ]);
+        assert!(captures.upvalues(program.main()).is_empty());
+        assert_eq!(captures.captured(var(&program, "p")), Some(&Captured { mutated: false, fresh: false }));
+        assert_eq!(captures.captured(var(&program, "f")), None);
+    }
+
+    #[test]
+    fn mutated_test() {
+        let mutated = |src: &str| {
+            let (program, captures) = analyze(src);
+            captures.is_boxed(var(&program, "x"))
+        };
+        assert!(!mutated("local x = 1 local f = function() return x end"));
+        assert!(!mutated("local x x = 1 local f = function() return x end"));
+        assert!(mutated("local x = 1 local f = function() x = x + 1 end"));
+        assert!(mutated("local x = 1 local f = function() return function() x = 2 end end"));
+        assert!(mutated("local x = 1 local f = function() return x end x = 2"));
+        assert!(mutated("local x = 0 while true do x = x + 1 local f = function() return x end end"));
+        assert!(mutated("local x = 0 ::top:: x = x + 1 local f = function() return x end goto top"));
+        // every iteration has its own variable
+        assert!(!mutated("while true do local x = 0 x = x + 1 local f = function() return x end end"));
+        // the recursive local function is assigned after its closure captures it
+        assert!(mutated("local function x() return x() end"));
+    }
+
+    #[test]
+    fn loops_test() {
+        let (program, captures) = analyze("for i = 1, 3 do fs[i] = function() return i end end local g = function() end");
+        let i = var(&program, "i");
+        assert_eq!(captures.captured(i), Some(&Captured { mutated: false, fresh: true }));
+        assert!(captures.in_loop(FnId::from_index(1)));
+        assert!(!captures.in_loop(FnId::from_index(2)));
+        let (program, captures) = analyze("for _, v in ipairs(t) do f(function() return v end) end");
+        assert_eq!(captures.captured(var(&program, "v")), Some(&Captured { mutated: false, fresh: true }));
+        assert!(captures.in_loop(FnId::from_index(1)));
+    }
+
+    #[test]
+    fn scripts_test() {
+        for src in [
+            include_str!("../parser/scripts/lazy.lua"),
+            include_str!("../parser/scripts/cassandra.lua"),
+            include_str!("../parser/scripts/treesetter.lua"),
+            include_str!("../parser/scripts/server.lua"),
+        ] {
+            let (program, captures) = analyze(src);
+            for (id, func) in program.funcs() {
+                for u in captures.upvalues(id) {
+                    assert_ne!(program[u.var].func, id);
+                    let parent = func.parent.unwrap();
+                    match u.parent {
+                        None => assert_eq!(program[u.var].func, parent),
+                        Some(i) => assert_eq!(captures.upvalues(parent)[i].var, u.var),
+                    }
+                    assert!(captures.captured(u.var).is_some());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use std::borrow::Cow;
+use std::mem::replace;
+use crate::hir::{BinOp, Block, Expr, FnId, Function, Place, Program, Stmt, TableField, UnOp, Var, VarId};
+use crate::parser::arena::{self, Args, Ast, BlockId, ExprId, Field, FuncId, IdMap, Idx, Node, StmtId};
+use crate::parser::ast::{BinaryType, Id, Name, Number, UnaryType};
+use crate::parser::resolve::{BindingId, Kind, Scopes};
+use crate::parser::span::Span;
+
+/// Lowers the chunk resolved by `scopes`.
+/// The chunk is expected to parse, the error placeholders of the recovery become `nil`.
+pub fn lower<'a>(ast: &Ast<'a>, scopes: &Scopes<'a>) -> Program<'a> {
+    let mut lower = Lower {
+        ast,
+        scopes,
+        program: Program { funcs: vec![], vars: vec![] },
+        vars: IdMap::new(),
+        func: FnId(0),
+    };
+    let root = ast.root();
+    lower.function(&[], true, root, ast[root].span);
+    lower.program
+}
+
+struct Lower<'s, 'a> {
+    ast: &'s Ast<'a>,
+    scopes: &'s Scopes<'a>,
+    program: Program<'a>,
+    /// The variables of the bindings declared so far.
+    vars: IdMap<BindingId, VarId>,
+    /// The function being lowered.
+    func: FnId,
+}
+
+impl<'s, 'a> Lower<'s, 'a> {
+    fn new_var(&mut self, var: Var<'a>) -> VarId {
+        let id = VarId::from_index(self.program.vars.len());
+        self.program.vars.push(var);
+        id
+    }
+
+    fn declare(&mut self, binding: BindingId) -> VarId {
+        let b = &self.scopes[binding];
+        let var = Var { name: b.name.clone(), span: b.span, func: self.func, binding: Some(binding), close: b.is_close() };
+        let id = self.new_var(var);
+        self.vars.insert(binding, id);
+        id
+    }
+
+    fn temp(&mut self, span: Span) -> VarId {
+        let func = self.func;
+        self.new_var(Var { name: Name::Borrowed(""), span, func, binding: None, close: false })
+    }
+
+    fn var(&self, binding: BindingId) -> VarId {
+        *self.vars.get(binding).expect("the binding is declared before its uses")
+    }
+
+    /// The parameters are the bindings of `Scopes::params`.
+    fn function(&mut self, params: &[BindingId], var_args: bool, body: BlockId, span: Span) -> FnId {
+        let id = FnId::from_index(self.program.funcs.len());
+        let parent = (id.index() > 0).then_some(self.func);
+        self.program.funcs.push(Function { params: vec![], var_args, body: vec![], parent, span });
+        let outer = replace(&mut self.func, id);
+        let params = params.iter().map(|p| self.declare(*p)).collect();
+        let body = self.block(body);
+        self.func = outer;
+        let func = &mut self.program.funcs[id.index()];
+        func.params = params;
+        func.body = body;
+        id
+    }
+
+    fn closure(&mut self, func: FuncId) -> Expr<'a> {
+        let (ast, scopes) = (self.ast, self.scopes);
+        let node = &ast[func];
+        Expr::Function(self.function(scopes.params(func), node.kind.var_args, node.kind.body, node.span))
+    }
+
+    fn block(&mut self, id: BlockId) -> Block<'a> {
+        let ast = self.ast;
+        let block = &ast[id];
+        let mut out = vec![];
+        for st in &ast[block.kind.stmts] {
+            self.stmt(*st, &mut out);
+        }
+        if let Some(ret) = block.kind.ret {
+            let span = ast[ret].iter().map(|e| ast[*e].span).reduce(Span::merge).unwrap_or(block.span);
+            out.push(Node { kind: Stmt::Return(self.exprs(&ast[ret])), span });
+        }
+        out
+    }
+
+    fn stmt(&mut self, id: StmtId, out: &mut Block<'a>) {
+        let (ast, scopes) = (self.ast, self.scopes);
+        let span = ast[id].span;
+        let mut push = |kind| out.push(Node { kind, span });
+        match &ast[id].kind {
+            arena::Stmt::Empty | arena::Stmt::Error => {}
+            arena::Stmt::Assign { targets, values } => match (&ast[*targets], &ast[*values]) {
+                ([target], [value]) => {
+                    let place = self.place(*target);
+                    push(Stmt::Assign(place, self.expr(*value)))
+                }
+                (targets, values) => push(Stmt::Do(self.multi_assign(targets, values, span))),
+            },
+            arena::Stmt::Call(call) => push(Stmt::Call(self.expr(*call))),
+            arena::Stmt::Label(name) => push(Stmt::Label(name.v.clone())),
+            arena::Stmt::Break => push(Stmt::Break),
+            arena::Stmt::Goto(name) => push(Stmt::Goto(name.v.clone())),
+            arena::Stmt::Do(body) => push(Stmt::Do(self.block(*body))),
+            arena::Stmt::While { cond, body } => {
+                let cond = self.expr(*cond);
+                let body = self.block(*body);
+                push(Stmt::Loop(vec![Node { kind: Stmt::If(cond, body, vec![Node { kind: Stmt::Break, span }]), span }]))
+            }
+            // the condition is in the scope of the body
+            arena::Stmt::Repeat { body, until } => {
+                let mut body = self.block(*body);
+                let until = Node { kind: Stmt::If(self.expr(*until), vec![Node { kind: Stmt::Break, span }], vec![]), span };
+                body.push(until);
+                push(Stmt::Loop(body))
+            }
+            arena::Stmt::If { branches, otherwise } => {
+                let branches: Vec<_> =
+                    ast[*branches].iter().map(|b| (self.expr(b.cond), self.block(b.body), b.span)).collect();
+                let mut otherwise = otherwise.map(|b| self.block(b)).unwrap_or_default();
+                let mut branches = branches.into_iter().rev().peekable();
+                while let Some((cond, body, branch_span)) = branches.next() {
+                    // the first branch takes the span of the whole statement
+                    let span = if branches.peek().is_some() { branch_span } else { span };
+                    otherwise = vec![Node { kind: Stmt::If(cond, body, otherwise), span }];
+                }
+                out.extend(otherwise)
+            }
+            arena::Stmt::NumericFor { start, limit, step, body, .. } => {
+                let start = self.expr(*start);
+                let limit = self.expr(*limit);
+                let step = step.map(|s| self.expr(s)).unwrap_or(Expr::Int(1));
+                let var = self.declare(scopes.declared(id)[0]);
+                push(Stmt::NumericFor { var, start, limit, step, body: self.block(*body) })
+            }
+            arena::Stmt::GenericFor { exprs, body, .. } => push(Stmt::Do(self.generic_for(id, &ast[*exprs], *body, span))),
+            arena::Stmt::Function { name, func } => {
+                let place = match (&ast[name.path], &name.method) {
+                    ([first], None) => self.name_place(first, scopes.function_name(id).map(|r| (r.binding, r.kind))),
+                    ([first, path @ ..], method) => {
+                        let mut obj = self.name(first, scopes.function_name(id).map(|r| (r.binding, r.kind)));
+                        let mut keys = path.iter().chain(method);
+                        let last = keys.next_back().expect("the path or the method");
+                        for key in keys {
+                            obj = Expr::Index(Box::new(obj), Box::new(text(key)));
+                        }
+                        Place::Index(obj, text(last))
+                    }
+                    ([], _) => unreachable!("the function name has a path"),
+                };
+                push(Stmt::Assign(place, self.closure(*func)))
+            }
+            // the function sees itself
+            arena::Stmt::LocalFunction { func, .. } => {
+                let var = self.declare(scopes.declared(id)[0]);
+                push(Stmt::Local(vec![var], vec![]));
+                push(Stmt::Assign(Place::Var(var), self.closure(*func)))
+            }
+            arena::Stmt::Local { values, .. } => {
+                let values = self.exprs(&ast[*values]);
+                let vars = scopes.declared(id).iter().map(|b| self.declare(*b)).collect();
+                push(Stmt::Local(vars, values))
+            }
+        }
+    }
+
+    /// The objects and the keys of the targets, the values, then the assignments from left to right.
+    fn multi_assign(&mut self, targets: &[ExprId], values: &[ExprId], span: Span) -> Block<'a> {
+        let mut out = vec![];
+        let places: Vec<_> = targets
+            .iter()
+            .map(|t| match self.place(*t) {
+                Place::Index(obj, key) => {
+                    let obj = self.evaluated(obj, &mut out, span);
+                    Place::Index(obj, self.evaluated(key, &mut out, span))
+                }
+                var => var,
+            })
+            .collect();
+        let temps: Vec<_> = targets.iter().map(|t| self.temp(self.ast[*t].span)).collect();
+        let values = self.exprs(values);
+        out.push(Node { kind: Stmt::Local(temps.clone(), values), span });
+        for (place, temp) in places.into_iter().zip(temps) {
+            out.push(Node { kind: Stmt::Assign(place, Expr::Var(temp)), span });
+        }
+        out
+    }
+
+    /// The expression evaluated into a temporary variable unless it is a constant.
+    fn evaluated(&mut self, expr: Expr<'a>, out: &mut Block<'a>, span: Span) -> Expr<'a> {
+        match expr {
+            Expr::Nil | Expr::Bool(_) | Expr::Int(_) | Expr::Float(_) | Expr::Str(_) | Expr::Env => expr,
+            expr => {
+                let temp = self.temp(span);
+                out.push(Node { kind: Stmt::Local(vec![temp], vec![expr]), span });
+                Expr::Var(temp)
+            }
+        }
+    }
+
+    /// ```text
+    /// local f, s, c, close <close> = exprs
+    /// loop
+    ///     local v1, ..., vn = f(s, c)
+    ///     if v1 == nil then break end
+    ///     c = v1
+    ///     body
+    /// end
+    /// ```
+    fn generic_for(&mut self, id: StmtId, exprs: &[ExprId], body: BlockId, span: Span) -> Block<'a> {
+        let values = self.exprs(exprs);
+        let [f, s, c, close] = [(); 4].map(|_| self.temp(span));
+        self.program.vars[close.index()].close = true;
+        let vars: Vec<_> = self.scopes.declared(id).iter().map(|b| self.declare(*b)).collect();
+        let first = vars[0];
+        let node = |kind| Node { kind, span };
+
+        let call = Expr::Call(Box::new(Expr::Var(f)), vec![Expr::Var(s), Expr::Var(c)]);
+        let is_nil = Expr::Binary(Box::new(Expr::Var(first)), BinOp::Eq, Box::new(Expr::Nil));
+        let mut iteration = vec![
+            node(Stmt::Local(vars, vec![call])),
+            node(Stmt::If(is_nil, vec![node(Stmt::Break)], vec![])),
+            node(Stmt::Assign(Place::Var(c), Expr::Var(first))),
+        ];
+        iteration.extend(self.block(body));
+        vec![node(Stmt::Local(vec![f, s, c, close], values)), node(Stmt::Loop(iteration))]
+    }
+
+    fn exprs(&mut self, exprs: &[ExprId]) -> Vec<Expr<'a>> {
+        exprs.iter().map(|e| self.expr(*e)).collect()
+    }
+
+    fn args(&mut self, args: &Args) -> Vec<Expr<'a>> {
+        match args {
+            Args::List(list, _) => self.exprs(&self.ast[*list]),
+            Args::Table(e) | Args::String(e) => vec![self.expr(*e)],
+        }
+    }
+
+    /// The variable or the field of the environment the name resolves to.
+    fn name(&self, name: &Id<'a>, resolved: Option<(BindingId, Kind)>) -> Expr<'a> {
+        let (binding, kind) = resolved.expect("the names are resolved");
+        let env = binding == self.scopes.env();
+        match kind {
+            Kind::Global if env => Expr::Index(Box::new(Expr::Env), Box::new(text(name))),
+            Kind::Global => Expr::Index(Box::new(Expr::Var(self.var(binding))), Box::new(text(name))),
+            _ if env => Expr::Env,
+            _ => Expr::Var(self.var(binding)),
+        }
+    }
+
+    fn name_place(&self, name: &Id<'a>, resolved: Option<(BindingId, Kind)>) -> Place<'a> {
+        match self.name(name, resolved) {
+            Expr::Var(var) => Place::Var(var),
+            Expr::Index(obj, key) => Place::Index(*obj, *key),
+            _ => Place::Env,
+        }
+    }
+
+    fn place(&mut self, target: ExprId) -> Place<'a> {
+        let ast = self.ast;
+        match &ast[target].kind {
+            arena::Expr::Name(name) => self.name_place(name, self.scopes.reference(target).map(|r| (r.binding, r.kind))),
+            arena::Expr::Field { obj, name, .. } => Place::Index(self.expr(*obj), text(name)),
+            arena::Expr::Index { obj, key, .. } => {
+                let obj = self.expr(*obj);
+                Place::Index(obj, self.expr(*key))
+            }
+            _ => unreachable!("the parser accepts only the names and the fields as the targets"),
+        }
+    }
+
+    fn expr(&mut self, id: ExprId) -> Expr<'a> {
+        let ast = self.ast;
+        match &ast[id].kind {
+            arena::Expr::Nil | arena::Expr::Error => Expr::Nil,
+            arena::Expr::False => Expr::Bool(false),
+            arena::Expr::True => Expr::Bool(true),
+            arena::Expr::Number(Number::Int(v) | Number::Hex(v)) => Expr::Int(*v),
+            arena::Expr::Number(Number::Binary(v)) => Expr::Int(*v as i64),
+            arena::Expr::Number(Number::Float(v)) => Expr::Float(*v),
+            arena::Expr::Text(text) => Expr::Str(text.clone()),
+            arena::Expr::VarArgs => Expr::VarArgs,
+            arena::Expr::Function(func) => self.closure(*func),
+            arena::Expr::Table(fields) => Expr::Table(
+                ast[*fields]
+                    .iter()
+                    .map(|f| match f {
+                        Field::Named(name, v) => TableField::Keyed(text(name), self.expr(*v)),
+                        Field::Keyed(k, v) => {
+                            let k = self.expr(*k);
+                            TableField::Keyed(k, self.expr(*v))
+                        }
+                        Field::Positional(v) => TableField::Positional(self.expr(*v)),
+                    })
+                    .collect(),
+            ),
+            arena::Expr::Name(name) => self.name(name, self.scopes.reference(id).map(|r| (r.binding, r.kind))),
+            arena::Expr::Paren(e) => match self.expr(*e) {
+                e if e.is_multi() => Expr::First(Box::new(e)),
+                e => e,
+            },
+            arena::Expr::Field { obj, name, .. } => Expr::Index(Box::new(self.expr(*obj)), Box::new(text(name))),
+            arena::Expr::Index { obj, key, .. } => {
+                let obj = self.expr(*obj);
+                Expr::Index(Box::new(obj), Box::new(self.expr(*key)))
+            }
+            arena::Expr::Call { callee, args, .. } => {
+                let callee = self.expr(*callee);
+                Expr::Call(Box::new(callee), self.args(args))
+            }
+            arena::Expr::Method { obj: obj_id, name, args, .. } => match self.expr(*obj_id) {
+                // nothing runs between the lookup of the method and the first argument
+                obj @ (Expr::Var(_) | Expr::Env) => {
+                    let method = Expr::Index(Box::new(obj.clone()), Box::new(text(name)));
+                    let args = [obj].into_iter().chain(self.args(args)).collect();
+                    Expr::Call(Box::new(method), args)
+                }
+                obj => {
+                    let temp = self.temp(ast[*obj_id].span);
+                    let method = Expr::Index(Box::new(Expr::Var(temp)), Box::new(text(name)));
+                    let args = [Expr::Var(temp)].into_iter().chain(self.args(args)).collect();
+                    Expr::Let(temp, Box::new(obj), Box::new(Expr::Call(Box::new(method), args)))
+                }
+            },
+            arena::Expr::Unary(op, e) => Expr::Unary(un_op(*op), Box::new(self.expr(*e))),
+            arena::Expr::Binary(lhs, op, rhs) => {
+                let lhs = Box::new(self.expr(*lhs));
+                let rhs = Box::new(self.expr(*rhs));
+                match op {
+                    BinaryType::And => Expr::And(lhs, rhs),
+                    BinaryType::Or => Expr::Or(lhs, rhs),
+                    op => Expr::Binary(lhs, bin_op(*op), rhs),
+                }
+            }
+        }
+    }
+}
+
+fn text<'a>(name: &Id<'a>) -> Expr<'a> {
+    Expr::Str(match &name.v {
+        Name::Borrowed(v) => Cow::Borrowed(v.as_bytes()),
+        v => Cow::Owned(v.as_str().as_bytes().to_vec()),
+    })
+}
+
+fn un_op(op: UnaryType) -> UnOp {
+    match op {
+        UnaryType::Minus => UnOp::Neg,
+        UnaryType::Not => UnOp::Not,
+        UnaryType::Hash => UnOp::Len,
+        UnaryType::Tilde => UnOp::BNot,
+    }
+}
+
+fn bin_op(op: BinaryType) -> BinOp {
+    match op {
+        BinaryType::Add => BinOp::Add,
+        BinaryType::Sub => BinOp::Sub,
+        BinaryType::Mult => BinOp::Mul,
+        BinaryType::Div => BinOp::Div,
+        BinaryType::FDiv => BinOp::IDiv,
+        BinaryType::Mod => BinOp::Mod,
+        BinaryType::Pov => BinOp::Pow,
+        BinaryType::Concat => BinOp::Concat,
+        BinaryType::Eq => BinOp::Eq,
+        BinaryType::TEq => BinOp::Ne,
+        BinaryType::Lt => BinOp::Lt,
+        BinaryType::Le => BinOp::Le,
+        BinaryType::Gt => BinOp::Gt,
+        BinaryType::Ge => BinOp::Ge,
+        BinaryType::Amper => BinOp::BAnd,
+        BinaryType::Stick => BinOp::BOr,
+        BinaryType::Tilde => BinOp::BXor,
+        BinaryType::LShift => BinOp::Shl,
+        BinaryType::RShift => BinOp::Shr,
+        BinaryType::And | BinaryType::Or => unreachable!("the short circuit operators"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hir::{Expr, Program, Stmt};
+    use crate::parser::parse_chunk;
+
+    fn lowered(src: &str) -> String {
+        Program::new(&parse_chunk(src).unwrap()).to_string()
+    }
+
+    /// The body of the chunk without the indentation.
+    fn main(src: &str) -> Vec<String> {
+        let printed = lowered(src);
+        let body = printed.lines().skip(1).take_while(|l| *l != "end").map(|l| l[4..].to_string()).collect();
+        body
+    }
+
+    #[test]
+    fn method_test() {
+        assert_eq!(main("local t = {} t:push(1) t.a:b 'x' c:d{}"), [
+            "local t_0 = {}",
+            "t_0.push(t_0, 1)",
+            "let $1 = t_0.a in $1.b($1, \"x\")",
+            "let $2 = _ENV.c in $2.d($2, {})",
+        ]);
+        assert_eq!(lowered("function a.b.c:m(x) return self end"), "fn#0(...)\n    _ENV.a.b.c.m = fn#1\nend\n\n\
+            fn#1(self_0, x_1) in fn#0\n    return self_0\nend\n");
+    }
+
+    #[test]
+    fn assign_test() {
+        assert_eq!(main("local a a = 1 b = 2"), ["local a_0", "a_0 = 1", "_ENV.b = 2"]);
+        assert_eq!(main("local t, i a, t[i], t.x = f()"), [
+            "local t_0, i_1",
+            "do",
+            "    local $2 = t_0",
+            "    local $3 = i_1",
+            "    local $4 = t_0",
+            "    local $5, $6, $7 = _ENV.f()",
+            "    _ENV.a = $5",
+            "    $2[$3] = $6",
+            "    $4.x = $7",
+            "end",
+        ]);
+    }
+
+    #[test]
+    fn local_function_test() {
+        assert_eq!(lowered("local function f() return f() end"), "fn#0(...)\n    local f_0\n    f_0 = fn#1\nend\n\n\
+            fn#1() in fn#0\n    return f_0()\nend\n");
+    }
+
+    #[test]
+    fn loops_test() {
+        assert_eq!(main("while a do break end"), [
+            "loop",
+            "    if _ENV.a then",
+            "        break",
+            "    else",
+            "        break",
+            "    end",
+            "end",
+        ]);
+        assert_eq!(main("repeat local x = 1 until x"), [
+            "loop",
+            "    local x_0 = 1",
+            "    if x_0 then",
+            "        break",
+            "    end",
+            "end",
+        ]);
+        assert_eq!(main("for i = 1, 3 do end"), ["for i_0 = 1, 3, 1 do", "end"]);
+        assert_eq!(main("for k, v in pairs(t) do f(k, v) end"), [
+            "do",
+            "    local $0, $1, $2, $3 <close> = _ENV.pairs(_ENV.t)",
+            "    loop",
+            "        local k_4, v_5 = $0($1, $2)",
+            "        if k_4 == nil then",
+            "            break",
+            "        end",
+            "        $2 = k_4",
+            "        _ENV.f(k_4, v_5)",
+            "    end",
+            "end",
+        ]);
+    }
+
+    #[test]
+    fn if_test() {
+        assert_eq!(main("if a then f() elseif b then g() else h() end"), [
+            "if _ENV.a then",
+            "    _ENV.f()",
+            "else",
+            "    if _ENV.b then",
+            "        _ENV.g()",
+            "    else",
+            "        _ENV.h()",
+            "    end",
+            "end",
+        ]);
+    }
+
+    #[test]
+    fn expr_test() {
+        assert_eq!(main("return (f()), (...), (1), a and b or -c, {x = 1, [2] = 3, 4, ['end'] = 5}"), [
+            "return (_ENV.f()), (...), 1, (_ENV.a and _ENV.b) or (-_ENV.c), {x = 1, [2] = 3, 4, [\"end\"] = 5}",
+        ]);
+        assert_eq!(main("local _ENV = {} x = _ENV"), ["local _ENV_0 = {}", "_ENV_0.x = _ENV_0"]);
+        assert_eq!(main("_ENV = {} print(_ENV)"), ["_ENV = {}", "_ENV.print(_ENV)"]);
+    }
+
+    #[test]
+    fn spans_test() {
+        let src = "local a = 1\nif a then\nreturn a\nend";
+        let program = Program::new(&parse_chunk(src).unwrap());
+        let body = &program[program.main()].body;
+        assert_eq!(body.iter().map(|s| s.span.line).collect::<Vec<_>>(), [1, 2]);
+        match &body[1].kind {
+            Stmt::If(Expr::Var(_), then, _) => assert_eq!(&src[then[0].span.range()], "a"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scripts_test() {
+        for src in [
+            include_str!("../parser/scripts/lazy.lua"),
+            include_str!("../parser/scripts/cassandra.lua"),
+            include_str!("../parser/scripts/treesetter.lua"),
+            include_str!("../parser/scripts/server.lua"),
+        ] {
+            let program = Program::new(&parse_chunk(src).unwrap());
+            assert!(program.vars().all(|(_, v)| v.func.0 < program.funcs().count() as u32));
+            assert!(!program.to_string().is_empty());
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! The high level IR: the chunk lowered into the handful of constructs a backend has to handle.
+//!
+//! The names are resolved into variables (`Var`) or the fields of the environment (`_ENV.x`),
+//! the loops are `Loop` with `Break` (the generic `for` expands as the manual describes it),
+//! `elseif` is the nested `If`, the methods are the functions with the explicit `self`
+//! and the method calls evaluate the object once with `Let`, the string and table arguments
+//! are ordinary arguments and `local function f` is `local f; f = function`.
+//!
+//! The evaluation order is defined: the operands, the arguments, the fields of the tables and
+//! the values of `local` go from left to right, the callee goes before the arguments and the object
+//! and the key of `Assign` go before the value. The multiple assignment `a, t[k] = x, y` evaluates
+//! the objects and the keys of the targets, then the values into the temporary variables,
+//! then assigns them from left to right.
+//!
+//! ```
+//! use cran_lua::hir::Program;
+//! use cran_lua::parse_chunk;
+//!
+//! let program = Program::new(&parse_chunk("local t = {} t:push(1)").unwrap());
+//! println!("{}", program);
+//! ```
+use std::borrow::Cow;
+use std::ops::Index;
+use crate::parser::arena::{ids, Ast, Idx, Node};
+use crate::parser::ast;
+use crate::parser::ast::Name;
+use crate::parser::resolve::{BindingId, Scopes};
+use crate::parser::span::Span;
+
+pub mod capture;
+mod lower;
+mod printer;
+
+pub use lower::lower;
+
+ids!(
+    /// The variable in `Program::vars`.
+    VarId,
+    /// The function in `Program::funcs`, the chunk is the first one.
+    FnId
+);
+
+#[derive(Debug, Clone)]
+pub struct Program<'a> {
+    funcs: Vec<Function<'a>>,
+    vars: Vec<Var<'a>>,
+}
+
+impl<'a> Program<'a> {
+    /// Lowers the chunk, the tree goes through the arena and the name resolution.
+    pub fn new(block: &ast::Block<'a>) -> Self {
+        let ast = Ast::new(block);
+        lower(&ast, &Scopes::resolve(&ast))
+    }
+
+    /// The function of the chunk.
+    pub fn main(&self) -> FnId {
+        FnId(0)
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = (FnId, &Function<'a>)> {
+        self.funcs.iter().enumerate().map(|(i, f)| (FnId::from_index(i), f))
+    }
+
+    pub fn vars(&self) -> impl Iterator<Item = (VarId, &Var<'a>)> {
+        self.vars.iter().enumerate().map(|(i, v)| (VarId::from_index(i), v))
+    }
+}
+
+impl<'a> Index<FnId> for Program<'a> {
+    type Output = Function<'a>;
+
+    fn index(&self, id: FnId) -> &Self::Output {
+        &self.funcs[id.index()]
+    }
+}
+
+impl<'a> Index<VarId> for Program<'a> {
+    type Output = Var<'a>;
+
+    fn index(&self, id: VarId) -> &Self::Output {
+        &self.vars[id.index()]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function<'a> {
+    /// The parameters, `self` goes first for the methods.
+    pub params: Vec<VarId>,
+    pub var_args: bool,
+    pub body: Block<'a>,
+    /// The function the closure is created in, the chunk has none.
+    pub parent: Option<FnId>,
+    pub span: Span,
+}
+
+/// The local variable, the parameter or the temporary variable of the lowering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Var<'a> {
+    /// The temporary variables have no name.
+    pub name: Name<'a>,
+    pub span: Span,
+    /// The function declaring the variable, the other functions use it as an upvalue.
+    pub func: FnId,
+    /// The binding of the source, the temporary variables have none.
+    pub binding: Option<BindingId>,
+    /// The value is closed when the variable goes out of scope (`<close>`, the state of the generic `for`).
+    pub close: bool,
+}
+
+impl<'a> Var<'a> {
+    pub fn is_temp(&self) -> bool {
+        self.binding.is_none()
+    }
+}
+
+/// The statements run in their own scope.
+pub type Block<'a> = Vec<Node<Stmt<'a>>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt<'a> {
+    /// Declares the variables and adjusts the values to them: the missing ones are `nil`,
+    /// the extra ones are evaluated and dropped, the last expression gives all its values.
+    Local(Vec<VarId>, Vec<Expr<'a>>),
+    Assign(Place<'a>, Expr<'a>),
+    /// The call evaluated for its effect.
+    Call(Expr<'a>),
+    Do(Block<'a>),
+    /// Runs the block until `Break`.
+    Loop(Block<'a>),
+    If(Expr<'a>, Block<'a>, Block<'a>),
+    /// `for var = start, limit, step`, the bounds are evaluated once before the loop
+    /// and the variable is a fresh copy of the counter at every iteration.
+    NumericFor { var: VarId, start: Expr<'a>, limit: Expr<'a>, step: Expr<'a>, body: Block<'a> },
+    Return(Vec<Expr<'a>>),
+    Break,
+    Goto(Name<'a>),
+    Label(Name<'a>),
+}
+
+/// The target of `Assign`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Place<'a> {
+    Var(VarId),
+    /// The field of the table, the globals are the fields of `Env`.
+    Index(Expr<'a>, Expr<'a>),
+    /// `_ENV = t` replaces the environment of the chunk.
+    Env,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, [u8]>),
+    VarArgs,
+    /// The environment of the chunk unless a local `_ENV` is in scope.
+    Env,
+    Var(VarId),
+    Index(Box<Expr<'a>>, Box<Expr<'a>>),
+    Call(Box<Expr<'a>>, Vec<Expr<'a>>),
+    /// The closure of the function.
+    Function(FnId),
+    Table(Vec<TableField<'a>>),
+    /// The first value of the call or of `...` (the parens around them).
+    First(Box<Expr<'a>>),
+    Unary(UnOp, Box<Expr<'a>>),
+    Binary(Box<Expr<'a>>, BinOp, Box<Expr<'a>>),
+    /// The short circuit `and`, the right operand is evaluated only when the left one is true.
+    And(Box<Expr<'a>>, Box<Expr<'a>>),
+    Or(Box<Expr<'a>>, Box<Expr<'a>>),
+    /// Evaluates the value into the temporary variable and then the body:
+    /// `o:m(x)` is `let t = o in t.m(t, x)`.
+    Let(VarId, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+
+impl<'a> Expr<'a> {
+    /// The call and `...` give all their values at the end of the lists.
+    pub fn is_multi(&self) -> bool {
+        match self {
+            Expr::Call(..) | Expr::VarArgs => true,
+            Expr::Let(_, _, body) => body.is_multi(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField<'a> {
+    Keyed(Expr<'a>, Expr<'a>),
+    Positional(Expr<'a>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+    BNot,
+}
+
+/// `a > b` stays as it is since `b < a` would evaluate `b` first.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+}
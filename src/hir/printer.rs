@@ -0,0 +1,314 @@
+//! The printer of the ir for the debugging, the output looks like lua but is not parsed back.
+//!
+//! The variables are suffixed with their ids (`x_3`), the temporary ones are `$3`,
+//! the functions are printed one after another and the closures refer to them as `fn#1`,
+//! the operands that are operators themselves are always in parens.
+
+use std::fmt::{Display, Formatter, Result};
+use crate::hir::{BinOp, Block, Expr, FnId, Place, Program, Stmt, TableField, UnOp, VarId};
+use crate::parser::arena::Idx;
+use crate::parser::ast::{Number, Text};
+use crate::parser::span::Span;
+
+impl<'a> Display for Program<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (i, (id, func)) in self.funcs().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let mut w = Writer { program: self, f, indent: 1 };
+            write!(w.f, "{}(", FnRef(id))?;
+            for (i, param) in func.params.iter().enumerate() {
+                if i > 0 {
+                    w.f.write_str(", ")?;
+                }
+                w.var(*param)?;
+            }
+            if func.var_args {
+                w.f.write_str(if func.params.is_empty() { "..." } else { ", ..." })?;
+            }
+            w.f.write_str(")")?;
+            if let Some(parent) = func.parent {
+                write!(w.f, " in {}", FnRef(parent))?;
+            }
+            writeln!(w.f)?;
+            w.block(&func.body)?;
+            writeln!(w.f, "end")?;
+        }
+        Ok(())
+    }
+}
+
+struct FnRef(FnId);
+
+impl Display for FnRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "fn#{}", self.0.index())
+    }
+}
+
+struct Writer<'p, 'a, 'f, 'w> {
+    program: &'p Program<'a>,
+    f: &'f mut Formatter<'w>,
+    indent: usize,
+}
+
+impl<'p, 'a, 'f, 'w> Writer<'p, 'a, 'f, 'w> {
+    fn line(&mut self) -> Result {
+        write!(self.f, "{}", "    ".repeat(self.indent))
+    }
+
+    fn block(&mut self, block: &Block<'a>) -> Result {
+        for st in block {
+            self.line()?;
+            self.stmt(&st.kind)?;
+            writeln!(self.f)?;
+        }
+        Ok(())
+    }
+
+    /// The indented block followed by `end` on its own line.
+    fn body(&mut self, block: &Block<'a>) -> Result {
+        writeln!(self.f)?;
+        self.indent += 1;
+        self.block(block)?;
+        self.indent -= 1;
+        self.line()?;
+        self.f.write_str("end")
+    }
+
+    fn stmt(&mut self, st: &Stmt<'a>) -> Result {
+        match st {
+            Stmt::Local(vars, values) => {
+                self.f.write_str("local ")?;
+                for (i, var) in vars.iter().enumerate() {
+                    if i > 0 {
+                        self.f.write_str(", ")?;
+                    }
+                    self.var(*var)?;
+                    if self.program[*var].close {
+                        self.f.write_str(" <close>")?;
+                    }
+                }
+                if !values.is_empty() {
+                    self.f.write_str(" = ")?;
+                    self.exprs(values)?;
+                }
+                Ok(())
+            }
+            Stmt::Assign(place, value) => {
+                match place {
+                    Place::Var(var) => self.var(*var)?,
+                    Place::Index(obj, key) => self.index(obj, key)?,
+                    Place::Env => self.f.write_str("_ENV")?,
+                }
+                self.f.write_str(" = ")?;
+                self.expr(value)
+            }
+            Stmt::Call(call) => self.expr(call),
+            Stmt::Do(block) => {
+                self.f.write_str("do")?;
+                self.body(block)
+            }
+            Stmt::Loop(block) => {
+                self.f.write_str("loop")?;
+                self.body(block)
+            }
+            Stmt::If(cond, then, otherwise) => {
+                self.f.write_str("if ")?;
+                self.expr(cond)?;
+                self.f.write_str(" then")?;
+                writeln!(self.f)?;
+                self.indent += 1;
+                self.block(then)?;
+                self.indent -= 1;
+                if !otherwise.is_empty() {
+                    self.line()?;
+                    self.f.write_str("else")?;
+                    writeln!(self.f)?;
+                    self.indent += 1;
+                    self.block(otherwise)?;
+                    self.indent -= 1;
+                }
+                self.line()?;
+                self.f.write_str("end")
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => {
+                self.f.write_str("for ")?;
+                self.var(*var)?;
+                self.f.write_str(" = ")?;
+                self.expr(start)?;
+                self.f.write_str(", ")?;
+                self.expr(limit)?;
+                self.f.write_str(", ")?;
+                self.expr(step)?;
+                self.f.write_str(" do")?;
+                self.body(body)
+            }
+            Stmt::Return(values) => {
+                self.f.write_str("return")?;
+                if !values.is_empty() {
+                    self.f.write_str(" ")?;
+                    self.exprs(values)?;
+                }
+                Ok(())
+            }
+            Stmt::Break => self.f.write_str("break"),
+            Stmt::Goto(name) => write!(self.f, "goto {}", name),
+            Stmt::Label(name) => write!(self.f, "::{}::", name),
+        }
+    }
+
+    fn var(&mut self, id: VarId) -> Result {
+        let var = &self.program[id];
+        if var.is_temp() {
+            write!(self.f, "${}", id.index())
+        } else {
+            write!(self.f, "{}_{}", var.name, id.index())
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr<'a>]) -> Result {
+        for (i, e) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.f.write_str(", ")?;
+            }
+            self.expr(e)?;
+        }
+        Ok(())
+    }
+
+    /// `obj.name` when the key is a name, `obj[key]` otherwise.
+    fn index(&mut self, obj: &Expr<'a>, key: &Expr<'a>) -> Result {
+        self.operand(obj)?;
+        match key {
+            Expr::Str(s) if is_name(s) => write!(self.f, ".{}", String::from_utf8_lossy(s)),
+            key => {
+                self.f.write_str("[")?;
+                self.expr(key)?;
+                self.f.write_str("]")
+            }
+        }
+    }
+
+    /// The operators and `let` go in parens.
+    fn operand(&mut self, e: &Expr<'a>) -> Result {
+        match e {
+            Expr::Unary(..) | Expr::Binary(..) | Expr::And(..) | Expr::Or(..) | Expr::Let(..) => {
+                self.f.write_str("(")?;
+                self.expr(e)?;
+                self.f.write_str(")")
+            }
+            e => self.expr(e),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr<'a>) -> Result {
+        match e {
+            Expr::Nil => self.f.write_str("nil"),
+            Expr::Bool(v) => write!(self.f, "{}", v),
+            Expr::Int(v) => write!(self.f, "{}", Number::Int(*v)),
+            Expr::Float(v) => write!(self.f, "{}", Number::Float(*v)),
+            Expr::Str(s) => write!(self.f, "{}", Text { text: s.clone(), span: Span::default() }),
+            Expr::VarArgs => self.f.write_str("..."),
+            Expr::Env => self.f.write_str("_ENV"),
+            Expr::Var(var) => self.var(*var),
+            Expr::Index(obj, key) => self.index(obj, key),
+            Expr::Call(callee, args) => {
+                self.operand(callee)?;
+                self.f.write_str("(")?;
+                self.exprs(args)?;
+                self.f.write_str(")")
+            }
+            Expr::Function(func) => write!(self.f, "{}", FnRef(*func)),
+            Expr::Table(fields) => {
+                self.f.write_str("{")?;
+                for (i, field) in fields.iter().enumerate() {
+                    if i > 0 {
+                        self.f.write_str(", ")?;
+                    }
+                    match field {
+                        TableField::Keyed(Expr::Str(s), v) if is_name(s) => {
+                            write!(self.f, "{} = ", String::from_utf8_lossy(s))?;
+                            self.expr(v)?;
+                        }
+                        TableField::Keyed(k, v) => {
+                            self.f.write_str("[")?;
+                            self.expr(k)?;
+                            self.f.write_str("] = ")?;
+                            self.expr(v)?;
+                        }
+                        TableField::Positional(v) => self.expr(v)?,
+                    }
+                }
+                self.f.write_str("}")
+            }
+            Expr::First(e) => {
+                self.f.write_str("(")?;
+                self.expr(e)?;
+                self.f.write_str(")")
+            }
+            Expr::Unary(op, e) => {
+                self.f.write_str(match op {
+                    UnOp::Neg => "-",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                    UnOp::BNot => "~",
+                })?;
+                self.operand(e)
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                self.operand(lhs)?;
+                write!(self.f, " {} ", bin_op(*op))?;
+                self.operand(rhs)
+            }
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                self.operand(lhs)?;
+                self.f.write_str(if matches!(e, Expr::And(..)) { " and " } else { " or " })?;
+                self.operand(rhs)
+            }
+            Expr::Let(var, value, body) => {
+                self.f.write_str("let ")?;
+                self.var(*var)?;
+                self.f.write_str(" = ")?;
+                self.expr(value)?;
+                self.f.write_str(" in ")?;
+                self.expr(body)
+            }
+        }
+    }
+}
+
+fn bin_op(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::IDiv => "//",
+        BinOp::Mod => "%",
+        BinOp::Pow => "^",
+        BinOp::Concat => "..",
+        BinOp::Eq => "==",
+        BinOp::Ne => "~=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::BAnd => "&",
+        BinOp::BOr => "|",
+        BinOp::BXor => "~",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+    }
+}
+
+fn is_name(s: &[u8]) -> bool {
+    const KEYWORDS: [&[u8]; 22] = [
+        b"and", b"break", b"do", b"else", b"elseif", b"end", b"false", b"for", b"function", b"goto", b"if", b"in",
+        b"local", b"nil", b"not", b"or", b"repeat", b"return", b"then", b"true", b"until", b"while",
+    ];
+    matches!(s.first(), Some(c) if c.is_ascii_alphabetic() || *c == b'_')
+        && s.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        && !KEYWORDS.contains(&s)
+}
@@ -0,0 +1,656 @@
+//! The typed functions lowered into cranelift IR with `cranelift-frontend`.
+//!
+//! The machine function takes the pointer to the `Context` before the arguments of lua.
+//! The runtime error stores its code there and returns zero, the caller checks the error after every call
+//! and returns too, so the error leaves all the frames at once.
+//! Every call takes one of the calls left in the context and gives it back when it returns.
+use std::collections::HashMap;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Signature, TrapCode, Type, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{FuncId, Linkage, Module};
+use crate::hir::{BinOp, Block as HirBlock, Expr, FnId, Place, Program, Stmt, UnOp, VarId};
+use crate::jit::types::{Ret, Specs, Ty};
+use crate::jit::{Callee, Chunk, CompileError, Const, Context, RuntimeError};
+use crate::parser::fold::{float_mod, float_pow};
+
+/// The float operations cranelift has no instructions for.
+pub(crate) struct Runtime {
+    fmod: FuncId,
+    pow: FuncId,
+}
+
+impl Runtime {
+    const FMOD: &'static str = "cran_lua_fmod";
+    const POW: &'static str = "cran_lua_pow";
+
+    pub fn symbols(builder: &mut JITBuilder) {
+        builder.symbol(Runtime::FMOD, fmod as *const u8);
+        builder.symbol(Runtime::POW, pow as *const u8);
+    }
+
+    pub fn declare(module: &mut JITModule) -> Result<Self, CompileError> {
+        let mut signature = module.make_signature();
+        signature.params = vec![AbiParam::new(types::F64), AbiParam::new(types::F64)];
+        signature.returns = vec![AbiParam::new(types::F64)];
+        Ok(Runtime {
+            fmod: module.declare_function(Runtime::FMOD, Linkage::Import, &signature).map_err(CompileError::module)?,
+            pow: module.declare_function(Runtime::POW, Linkage::Import, &signature).map_err(CompileError::module)?,
+        })
+    }
+}
+
+extern "C" fn fmod(a: f64, b: f64) -> f64 {
+    float_mod(a, b)
+}
+
+extern "C" fn pow(a: f64, b: f64) -> f64 {
+    float_pow(a, b)
+}
+
+fn ir(ty: Ty) -> Type {
+    match ty {
+        Ty::Int => types::I64,
+        Ty::Float => types::F64,
+        Ty::Bool => types::I8,
+    }
+}
+
+pub(crate) fn signature(module: &JITModule, params: &[Ty], ret: Ret) -> Signature {
+    let mut signature = module.make_signature();
+    signature.params.push(AbiParam::new(module.target_config().pointer_type()));
+    signature.params.extend(params.iter().map(|ty| AbiParam::new(ir(*ty))));
+    if let Ret::Value(ty) = ret {
+        signature.returns.push(AbiParam::new(ir(ty)));
+    }
+    signature
+}
+
+/// Defines the body of the declared function.
+pub(crate) fn define(
+    module: &mut JITModule,
+    program: &Program,
+    chunk: &Chunk,
+    specs: &Specs,
+    runtime: &Runtime,
+    spec: usize,
+) -> Result<(), CompileError> {
+    let spec = &specs[spec];
+    let mut ctx = module.make_context();
+    ctx.func.signature = signature(module, &spec.params, spec.ret);
+    let mut fctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fctx);
+
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    builder.seal_block(entry);
+    let params = builder.block_params(entry).to_vec();
+    let abort = builder.create_block();
+
+    let func = &program[spec.func];
+    let mut gen = Codegen {
+        builder,
+        module,
+        chunk,
+        specs,
+        runtime,
+        types: &spec.vars,
+        vars: HashMap::new(),
+        context: params[0],
+        abort,
+        exits: vec![],
+    };
+    for (param, value) in func.params.iter().zip(&params[1..]) {
+        gen.assign(*param, *value);
+    }
+    gen.block(&func.body);
+    // the typer proves the end is not reached by the functions giving a value
+    match spec.ret {
+        Ret::Value(_) => gen.builder.ins().trap(TrapCode::unwrap_user(1)),
+        _ => gen.builder.ins().return_(&[]),
+    };
+
+    gen.builder.switch_to_block(abort);
+    gen.builder.seal_block(abort);
+    let zero: Vec<_> = match spec.ret {
+        Ret::Value(ty) => vec![gen.zero(ty)],
+        _ => vec![],
+    };
+    gen.builder.ins().return_(&zero);
+    gen.builder.finalize();
+
+    module
+        .define_function(spec.id.expect("the function is declared"), &mut ctx)
+        .map_err(CompileError::module)?;
+    module.clear_context(&mut ctx);
+    Ok(())
+}
+
+struct Codegen<'b, 'j> {
+    builder: FunctionBuilder<'b>,
+    module: &'j mut JITModule,
+    chunk: &'j Chunk,
+    specs: &'j Specs,
+    runtime: &'j Runtime,
+    types: &'j HashMap<VarId, Ty>,
+    vars: HashMap<VarId, Variable>,
+    /// The pointer to the `Context`.
+    context: Value,
+    /// Returns zero after the runtime error.
+    abort: Block,
+    /// The blocks after the loops, `break` jumps to the last one.
+    exits: Vec<Block>,
+}
+
+impl<'b, 'j> Codegen<'b, 'j> {
+    fn assign(&mut self, var: VarId, value: Value) {
+        let next = self.vars.len();
+        let ty = ir(self.types[&var]);
+        let builder = &mut self.builder;
+        let variable = *self.vars.entry(var).or_insert_with(|| {
+            let variable = Variable::from_u32(next as u32);
+            builder.declare_var(variable, ty);
+            variable
+        });
+        self.builder.def_var(variable, value);
+    }
+
+    /// Continues in the unreachable block after `return` and `break`.
+    fn dead(&mut self) {
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+        self.builder.seal_block(block);
+    }
+
+    fn zero(&mut self, ty: Ty) -> Value {
+        match ty {
+            Ty::Float => self.builder.ins().f64const(0.0),
+            ty => self.builder.ins().iconst(ir(ty), 0),
+        }
+    }
+
+    /// Stops the function with the runtime error when `failed` is true.
+    fn check(&mut self, failed: Value, error: RuntimeError) {
+        let fail = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brif(failed, fail, &[], next, &[]);
+        self.builder.switch_to_block(fail);
+        self.builder.seal_block(fail);
+        let code = self.builder.ins().iconst(types::I32, error.code() as i64);
+        self.builder.ins().store(MemFlags::trusted(), code, self.context, Context::ERROR);
+        self.builder.ins().jump(self.abort, &[]);
+        self.builder.switch_to_block(next);
+        self.builder.seal_block(next);
+    }
+
+    fn block(&mut self, block: &HirBlock) {
+        for st in block {
+            self.stmt(&st.kind);
+        }
+    }
+
+    fn stmt(&mut self, st: &Stmt) {
+        match st {
+            Stmt::Local(vars, values) => {
+                let mut results = vec![];
+                for value in values {
+                    results.push(self.expr(value).0);
+                }
+                for (var, value) in vars.iter().zip(results) {
+                    self.assign(*var, value);
+                }
+            }
+            Stmt::Assign(Place::Var(var), value) => {
+                let (value, _) = self.expr(value);
+                self.assign(*var, value);
+            }
+            Stmt::Call(Expr::Call(callee, args)) => {
+                self.call(callee, args);
+            }
+            Stmt::Do(block) => self.block(block),
+            Stmt::Loop(block) => {
+                let head = self.builder.create_block();
+                let exit = self.builder.create_block();
+                self.builder.ins().jump(head, &[]);
+                self.builder.switch_to_block(head);
+                self.exits.push(exit);
+                self.block(block);
+                self.exits.pop();
+                self.builder.ins().jump(head, &[]);
+                self.builder.seal_block(head);
+                self.builder.switch_to_block(exit);
+                self.builder.seal_block(exit);
+            }
+            Stmt::If(cond, then, otherwise) => {
+                let cond = self.cond(cond);
+                let then_block = self.builder.create_block();
+                let else_block = self.builder.create_block();
+                let join = self.builder.create_block();
+                self.builder.ins().brif(cond, then_block, &[], else_block, &[]);
+                for (block, body) in [(then_block, then), (else_block, otherwise)] {
+                    self.builder.switch_to_block(block);
+                    self.builder.seal_block(block);
+                    self.block(body);
+                    self.builder.ins().jump(join, &[]);
+                }
+                self.builder.switch_to_block(join);
+                self.builder.seal_block(join);
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => {
+                let start = self.expr(start);
+                let limit = self.expr(limit);
+                let step = self.expr(step);
+                match (start, step) {
+                    ((start, Ty::Int), (step, Ty::Int)) => self.int_for(*var, start, limit, step, body),
+                    _ => {
+                        let start = self.float(start);
+                        let limit = self.float(limit);
+                        let step = self.float(step);
+                        self.float_for(*var, start, limit, step, body)
+                    }
+                }
+            }
+            Stmt::Return(values) => {
+                let mut results = vec![];
+                for value in values {
+                    results.push(self.expr(value).0);
+                }
+                self.builder.ins().return_(&results);
+                self.dead();
+            }
+            Stmt::Break => {
+                let exit = *self.exits.last().expect("break is in a loop");
+                self.builder.ins().jump(exit, &[]);
+                self.dead();
+            }
+            st => unreachable!("rejected by the typer: {:?}", st),
+        }
+    }
+
+    /// The loop of lua 5.4: the number of the iterations is computed before the loop, so the counter never overflows.
+    fn int_for(&mut self, var: VarId, start: Value, (limit, ty): (Value, Ty), step: Value, body: &HirBlock) {
+        let zero = self.builder.ins().icmp_imm(IntCC::Equal, step, 0);
+        self.check(zero, RuntimeError::ForStepZero);
+        let up = self.builder.ins().icmp_imm(IntCC::SignedGreaterThan, step, 0);
+        let (limit, clipped) = match ty {
+            Ty::Float => self.int_limit(limit, step, up),
+            _ => (limit, self.builder.ins().iconst(types::I8, 0)),
+        };
+        let ins = self.builder.ins();
+        let above = ins.icmp(IntCC::SignedGreaterThan, start, limit);
+        let below = self.builder.ins().icmp(IntCC::SignedLessThan, start, limit);
+        let beyond = self.builder.ins().select(up, above, below);
+        let skip = self.builder.ins().bor(beyond, clipped);
+        let ascending = self.builder.ins().isub(limit, start);
+        let descending = self.builder.ins().isub(start, limit);
+        let distance = self.builder.ins().select(up, ascending, descending);
+        let down = self.builder.ins().ineg(step);
+        let stride = self.builder.ins().select(up, step, down);
+        let count = self.builder.ins().udiv(distance, stride);
+
+        let body_block = self.builder.create_block();
+        let exit = self.builder.create_block();
+        let index = self.builder.append_block_param(body_block, types::I64);
+        let left = self.builder.append_block_param(body_block, types::I64);
+        self.builder.ins().brif(skip, exit, &[], body_block, &[start, count]);
+
+        self.builder.switch_to_block(body_block);
+        self.assign(var, index);
+        self.exits.push(exit);
+        self.block(body);
+        self.exits.pop();
+        let done = self.builder.ins().icmp_imm(IntCC::Equal, left, 0);
+        let index = self.builder.ins().iadd(index, step);
+        let left = self.builder.ins().iadd_imm(left, -1);
+        self.builder.ins().brif(done, exit, &[], body_block, &[index, left]);
+        self.builder.seal_block(body_block);
+        self.builder.switch_to_block(exit);
+        self.builder.seal_block(exit);
+    }
+
+    /// The float limit of the integer loop: rounded towards the start and clipped to the integers,
+    /// along with whether the loop is skipped as the limit is beyond the integers on the wrong side.
+    fn int_limit(&mut self, limit: Value, step: Value, up: Value) -> (Value, Value) {
+        let down = self.builder.ins().icmp_imm(IntCC::SignedLessThan, step, 0);
+        let ceil = self.builder.ins().ceil(limit);
+        let floor = self.builder.ins().floor(limit);
+        let rounded = self.builder.ins().select(down, ceil, floor);
+        let min = self.builder.ins().f64const(i64::MIN as f64);
+        let max = self.builder.ins().f64const(-(i64::MIN as f64));
+        let above_min = self.builder.ins().fcmp(FloatCC::GreaterThanOrEqual, rounded, min);
+        let below_max = self.builder.ins().fcmp(FloatCC::LessThan, rounded, max);
+        let fits = self.builder.ins().band(above_min, below_max);
+        let int = self.builder.ins().fcvt_to_sint_sat(types::I64, rounded);
+        let zero = self.builder.ins().f64const(0.0);
+        let positive = self.builder.ins().fcmp(FloatCC::GreaterThan, limit, zero);
+        let max = self.builder.ins().iconst(types::I64, i64::MAX);
+        let min = self.builder.ins().iconst(types::I64, i64::MIN);
+        let clip = self.builder.ins().select(positive, max, min);
+        let limit = self.builder.ins().select(fits, int, clip);
+        let away = self.builder.ins().select(positive, down, up);
+        let outside = self.builder.ins().icmp_imm(IntCC::Equal, fits, 0);
+        let skip = self.builder.ins().band(outside, away);
+        (limit, skip)
+    }
+
+    fn float_for(&mut self, var: VarId, start: Value, limit: Value, step: Value, body: &HirBlock) {
+        let zero = self.builder.ins().f64const(0.0);
+        let is_zero = self.builder.ins().fcmp(FloatCC::Equal, step, zero);
+        self.check(is_zero, RuntimeError::ForStepZero);
+        let up = self.builder.ins().fcmp(FloatCC::GreaterThan, step, zero);
+        let above = self.builder.ins().fcmp(FloatCC::LessThan, limit, start);
+        let below = self.builder.ins().fcmp(FloatCC::LessThan, start, limit);
+        let skip = self.builder.ins().select(up, above, below);
+
+        let body_block = self.builder.create_block();
+        let exit = self.builder.create_block();
+        let index = self.builder.append_block_param(body_block, types::F64);
+        self.builder.ins().brif(skip, exit, &[], body_block, &[start]);
+
+        self.builder.switch_to_block(body_block);
+        self.assign(var, index);
+        self.exits.push(exit);
+        self.block(body);
+        self.exits.pop();
+        let index = self.builder.ins().fadd(index, step);
+        let below = self.builder.ins().fcmp(FloatCC::LessThanOrEqual, index, limit);
+        let above = self.builder.ins().fcmp(FloatCC::LessThanOrEqual, limit, index);
+        let more = self.builder.ins().select(up, below, above);
+        self.builder.ins().brif(more, body_block, &[index], exit, &[]);
+        self.builder.seal_block(body_block);
+        self.builder.switch_to_block(exit);
+        self.builder.seal_block(exit);
+    }
+
+    /// The condition of `if`, the numbers are true.
+    fn cond(&mut self, e: &Expr) -> Value {
+        match self.expr(e) {
+            (value, Ty::Bool) => value,
+            _ => self.builder.ins().iconst(types::I8, 1),
+        }
+    }
+
+    fn float(&mut self, (value, ty): (Value, Ty)) -> Value {
+        match ty {
+            Ty::Int => self.builder.ins().fcvt_from_sint(types::F64, value),
+            _ => value,
+        }
+    }
+
+    fn constant(&mut self, value: Const) -> (Value, Ty) {
+        match value {
+            Const::Int(v) => (self.builder.ins().iconst(types::I64, v), Ty::Int),
+            Const::Float(v) => (self.builder.ins().f64const(v), Ty::Float),
+        }
+    }
+
+    fn expr(&mut self, e: &Expr) -> (Value, Ty) {
+        match e {
+            Expr::Bool(v) => (self.builder.ins().iconst(types::I8, *v as i64), Ty::Bool),
+            Expr::Int(v) => self.constant(Const::Int(*v)),
+            Expr::Float(v) => self.constant(Const::Float(*v)),
+            Expr::Var(var) if self.vars.contains_key(var) => {
+                (self.builder.use_var(self.vars[var]), self.types[var])
+            }
+            Expr::Var(_) | Expr::Index(..) => {
+                let value = self.chunk.constant(e).expect("rejected by the typer");
+                self.constant(value)
+            }
+            Expr::Call(callee, args) => self.call(callee, args).expect("rejected by the typer"),
+            Expr::First(e) => self.expr(e),
+            Expr::Unary(op, e) => {
+                let (value, ty) = self.expr(e);
+                match (op, ty) {
+                    (UnOp::Neg, Ty::Int) => (self.builder.ins().ineg(value), ty),
+                    (UnOp::Neg, _) => (self.builder.ins().fneg(value), ty),
+                    (UnOp::Not, Ty::Bool) => (self.builder.ins().icmp_imm(IntCC::Equal, value, 0), ty),
+                    (UnOp::Not, _) => (self.builder.ins().iconst(types::I8, 0), Ty::Bool),
+                    (UnOp::BNot, _) => (self.builder.ins().bnot(value), ty),
+                    (UnOp::Len, _) => unreachable!("rejected by the typer"),
+                }
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = self.expr(lhs);
+                let rhs = self.expr(rhs);
+                self.binary(lhs, *op, rhs)
+            }
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                let (lhs, _) = self.expr(lhs);
+                let rhs_block = self.builder.create_block();
+                let join = self.builder.create_block();
+                let result = self.builder.append_block_param(join, types::I8);
+                if matches!(e, Expr::And(..)) {
+                    self.builder.ins().brif(lhs, rhs_block, &[], join, &[lhs]);
+                } else {
+                    self.builder.ins().brif(lhs, join, &[lhs], rhs_block, &[]);
+                }
+                self.builder.switch_to_block(rhs_block);
+                self.builder.seal_block(rhs_block);
+                let (rhs, _) = self.expr(rhs);
+                self.builder.ins().jump(join, &[rhs]);
+                self.builder.switch_to_block(join);
+                self.builder.seal_block(join);
+                (result, Ty::Bool)
+            }
+            e => unreachable!("rejected by the typer: {:?}", e),
+        }
+    }
+
+    fn binary(&mut self, (a, at): (Value, Ty), op: BinOp, (b, bt): (Value, Ty)) -> (Value, Ty) {
+        match op {
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::IDiv | BinOp::Mod if at == Ty::Int && bt == Ty::Int => {
+                (self.int_arith(a, op, b), Ty::Int)
+            }
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::IDiv | BinOp::Mod | BinOp::Pow => {
+                let a = self.float((a, at));
+                let b = self.float((b, bt));
+                (self.float_arith(a, op, b), Ty::Float)
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge if at != bt => {
+                let value = match at {
+                    Ty::Int => self.compare_mixed(a, op, b),
+                    _ => {
+                        let op = match op {
+                            BinOp::Lt => BinOp::Gt,
+                            BinOp::Le => BinOp::Ge,
+                            BinOp::Gt => BinOp::Lt,
+                            BinOp::Ge => BinOp::Le,
+                            op => op,
+                        };
+                        self.compare_mixed(b, op, a)
+                    }
+                };
+                (value, Ty::Bool)
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge if at == Ty::Float => {
+                let cc = match op {
+                    BinOp::Eq => FloatCC::Equal,
+                    BinOp::Ne => FloatCC::NotEqual,
+                    BinOp::Lt => FloatCC::LessThan,
+                    BinOp::Le => FloatCC::LessThanOrEqual,
+                    BinOp::Gt => FloatCC::GreaterThan,
+                    _ => FloatCC::GreaterThanOrEqual,
+                };
+                (self.builder.ins().fcmp(cc, a, b), Ty::Bool)
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let cc = match op {
+                    BinOp::Eq => IntCC::Equal,
+                    BinOp::Ne => IntCC::NotEqual,
+                    BinOp::Lt => IntCC::SignedLessThan,
+                    BinOp::Le => IntCC::SignedLessThanOrEqual,
+                    BinOp::Gt => IntCC::SignedGreaterThan,
+                    _ => IntCC::SignedGreaterThanOrEqual,
+                };
+                (self.builder.ins().icmp(cc, a, b), Ty::Bool)
+            }
+            BinOp::BAnd => (self.builder.ins().band(a, b), Ty::Int),
+            BinOp::BOr => (self.builder.ins().bor(a, b), Ty::Int),
+            BinOp::BXor => (self.builder.ins().bxor(a, b), Ty::Int),
+            BinOp::Shl => (self.shift_left(a, b), Ty::Int),
+            BinOp::Shr => {
+                let b = self.builder.ins().ineg(b);
+                (self.shift_left(a, b), Ty::Int)
+            }
+            BinOp::Concat => unreachable!("rejected by the typer"),
+        }
+    }
+
+    /// Compares the integer with the float exactly, the integers beyond 2^53 are not rounded:
+    /// `i < f` is `i < ceil(f)` when `ceil(f)` is an integer, otherwise whether `f` is above the integers.
+    fn compare_mixed(&mut self, i: Value, op: BinOp, f: Value) -> Value {
+        let rounded = match op {
+            BinOp::Lt | BinOp::Ge => self.builder.ins().ceil(f),
+            _ => self.builder.ins().floor(f),
+        };
+        let min = self.builder.ins().f64const(i64::MIN as f64);
+        let max = self.builder.ins().f64const(-(i64::MIN as f64));
+        let above_min = self.builder.ins().fcmp(FloatCC::GreaterThanOrEqual, rounded, min);
+        let below_max = self.builder.ins().fcmp(FloatCC::LessThan, rounded, max);
+        let fits = self.builder.ins().band(above_min, below_max);
+        let int = self.builder.ins().fcvt_to_sint_sat(types::I64, rounded);
+        let zero = self.builder.ins().f64const(0.0);
+        let (cc, outside) = match op {
+            BinOp::Lt => (IntCC::SignedLessThan, FloatCC::GreaterThan),
+            BinOp::Le => (IntCC::SignedLessThanOrEqual, FloatCC::GreaterThan),
+            BinOp::Gt => (IntCC::SignedGreaterThan, FloatCC::LessThan),
+            BinOp::Ge => (IntCC::SignedGreaterThanOrEqual, FloatCC::LessThan),
+            _ => {
+                // equal to the float without the fraction only, NaN has no integer
+                let integral = self.builder.ins().fcmp(FloatCC::Equal, rounded, f);
+                let same = self.builder.ins().icmp(IntCC::Equal, i, int);
+                let equal = self.builder.ins().band(integral, fits);
+                let equal = self.builder.ins().band(equal, same);
+                return match op {
+                    BinOp::Eq => equal,
+                    _ => self.builder.ins().icmp_imm(IntCC::Equal, equal, 0),
+                };
+            }
+        };
+        let inside = self.builder.ins().icmp(cc, i, int);
+        let beyond = self.builder.ins().fcmp(outside, f, zero);
+        self.builder.ins().select(fits, inside, beyond)
+    }
+
+    fn int_arith(&mut self, a: Value, op: BinOp, b: Value) -> Value {
+        match op {
+            BinOp::Add => self.builder.ins().iadd(a, b),
+            BinOp::Sub => self.builder.ins().isub(a, b),
+            BinOp::Mul => self.builder.ins().imul(a, b),
+            _ => {
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, b, 0);
+                let error = if op == BinOp::IDiv { RuntimeError::DivisionByZero } else { RuntimeError::ModuloByZero };
+                self.check(zero, error);
+                // `mininteger // -1` overflows the division, the result is the negation
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, b, -1);
+                let one = self.builder.ins().iconst(types::I64, 1);
+                let divisor = self.builder.ins().select(minus_one, one, b);
+                let rem = self.builder.ins().srem(a, divisor);
+                let inexact = self.builder.ins().icmp_imm(IntCC::NotEqual, rem, 0);
+                if op == BinOp::IDiv {
+                    // rounds towards minus infinity when the operands differ in sign
+                    let quot = self.builder.ins().sdiv(a, divisor);
+                    let signs = self.builder.ins().bxor(a, b);
+                    let differ = self.builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+                    let adjust = self.builder.ins().band(differ, inexact);
+                    let adjust = self.builder.ins().uextend(types::I64, adjust);
+                    let quot = self.builder.ins().isub(quot, adjust);
+                    let neg = self.builder.ins().ineg(a);
+                    self.builder.ins().select(minus_one, neg, quot)
+                } else {
+                    // the remainder takes the sign of the divisor
+                    let signs = self.builder.ins().bxor(rem, b);
+                    let differ = self.builder.ins().icmp_imm(IntCC::SignedLessThan, signs, 0);
+                    let adjust = self.builder.ins().band(differ, inexact);
+                    let adjusted = self.builder.ins().iadd(rem, b);
+                    self.builder.ins().select(adjust, adjusted, rem)
+                }
+            }
+        }
+    }
+
+    fn float_arith(&mut self, a: Value, op: BinOp, b: Value) -> Value {
+        match op {
+            BinOp::Add => self.builder.ins().fadd(a, b),
+            BinOp::Sub => self.builder.ins().fsub(a, b),
+            BinOp::Mul => self.builder.ins().fmul(a, b),
+            BinOp::Div => self.builder.ins().fdiv(a, b),
+            BinOp::IDiv => {
+                let quot = self.builder.ins().fdiv(a, b);
+                self.builder.ins().floor(quot)
+            }
+            BinOp::Mod => self.runtime_call(self.runtime.fmod, a, b),
+            _ => self.runtime_call(self.runtime.pow, a, b),
+        }
+    }
+
+    fn runtime_call(&mut self, func: FuncId, a: Value, b: Value) -> Value {
+        let func = self.module.declare_func_in_func(func, self.builder.func);
+        let call = self.builder.ins().call(func, &[a, b]);
+        self.builder.inst_results(call)[0]
+    }
+
+    /// `a << n` of lua: the negative `n` shifts to the right, the shifts by 64 and more give zero.
+    fn shift_left(&mut self, a: Value, n: Value) -> Value {
+        let left = self.builder.ins().ishl(a, n);
+        let back = self.builder.ins().ineg(n);
+        let right = self.builder.ins().ushr(a, back);
+        let forward = self.builder.ins().icmp_imm(IntCC::SignedGreaterThanOrEqual, n, 0);
+        let shifted = self.builder.ins().select(forward, left, right);
+        let far_left = self.builder.ins().icmp_imm(IntCC::SignedGreaterThanOrEqual, n, 64);
+        let far_right = self.builder.ins().icmp_imm(IntCC::SignedLessThanOrEqual, n, -64);
+        let far = self.builder.ins().bor(far_left, far_right);
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.builder.ins().select(far, zero, shifted)
+    }
+
+    /// The value of the call, `None` for the function giving nothing.
+    fn call(&mut self, callee: &Expr, args: &[Expr]) -> Option<(Value, Ty)> {
+        let callee = self.chunk.callee(callee).expect("rejected by the typer");
+        let mut values = vec![self.context];
+        let mut types = vec![];
+        for arg in args {
+            let (value, ty) = self.expr(arg);
+            values.push(value);
+            types.push(ty);
+        }
+        match callee {
+            Callee::Func(func) => self.call_function(func, &values, &types),
+            Callee::Sqrt => {
+                let arg = self.float((values[1], types[0]));
+                Some((self.builder.ins().sqrt(arg), Ty::Float))
+            }
+            Callee::Abs if types[0] == Ty::Int => Some((self.builder.ins().iabs(values[1]), Ty::Int)),
+            Callee::Abs => Some((self.builder.ins().fabs(values[1]), Ty::Float)),
+        }
+    }
+
+    fn call_function(&mut self, func: FnId, values: &[Value], types: &[Ty]) -> Option<(Value, Ty)> {
+        let spec = &self.specs[self.specs.find(func, types)];
+        let callee = self.module.declare_func_in_func(spec.id.expect("the function is declared"), self.builder.func);
+
+        // the recursion fails before it overflows the native stack
+        let depth = self.builder.ins().load(types::I32, MemFlags::trusted(), self.context, Context::DEPTH);
+        let exhausted = self.builder.ins().icmp_imm(IntCC::Equal, depth, 0);
+        self.check(exhausted, RuntimeError::StackOverflow);
+        let left = self.builder.ins().iadd_imm(depth, -1);
+        self.builder.ins().store(MemFlags::trusted(), left, self.context, Context::DEPTH);
+        let call = self.builder.ins().call(callee, values);
+        let result = self.builder.inst_results(call).first().copied();
+        self.builder.ins().store(MemFlags::trusted(), depth, self.context, Context::DEPTH);
+
+        // the error of the callee returns from the caller too
+        let error = self.builder.ins().load(types::I32, MemFlags::trusted(), self.context, Context::ERROR);
+        let next = self.builder.create_block();
+        self.builder.ins().brif(error, self.abort, &[], next, &[]);
+        self.builder.switch_to_block(next);
+        self.builder.seal_block(next);
+
+        match spec.ret {
+            Ret::Value(ty) => result.map(|value| (value, ty)),
+            _ => None,
+        }
+    }
+}
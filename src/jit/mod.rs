@@ -0,0 +1,905 @@
+//! The just in time compiler of the numeric functions: the functions of the chunk
+//! whose values are integers, floats and booleans are lowered into cranelift IR and run as machine code.
+//!
+//! The chunk only declares the functions (`function f`, `local function f`, `local f = function`)
+//! and the numeric constants (`local PI = 3.14159`). `Jit::function` compiles the function for the types
+//! of its arguments along with the functions it calls, every combination of the argument types
+//! is a separate machine function.
+//!
+//! The subset: the arithmetic, the bitwise operators and the comparisons of the numbers,
+//! `not`, `and` and `or` of the booleans, `if`, `while`, `repeat`, the numeric `for`,
+//! the calls of the functions of the chunk, `math.sqrt`, `math.abs` and the numeric constants of `math`.
+//! A variable keeps one type: `local x = 0 x = x + 0.5` is rejected as `x` holds an integer and then a float.
+//! The operations follow lua 5.4, the integers wrap around, `//` and `%` round towards minus infinity
+//! and `n // 0` stops the call with `RuntimeError`, so does the recursion deeper than `MAX_CALL_DEPTH`.
+//!
+//! ```
+//! use cran_lua::jit::Jit;
+//! use cran_lua::parse_chunk;
+//!
+//! let block = parse_chunk("function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end").unwrap();
+//! let mut jit = Jit::new(&block).unwrap();
+//! let fib = jit.function::<(i64,), i64>("fib").unwrap();
+//! assert_eq!(fib.call((20,)), Ok(6765));
+//! ```
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::marker::PhantomData;
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use crate::hir::{BinOp, Expr, FnId, Place, Program, Stmt, UnOp, VarId};
+use crate::parser::ast;
+use crate::parser::ast::{BinaryType, UnaryType};
+use crate::parser::fold;
+use crate::parser::span::Span;
+
+mod codegen;
+mod types;
+
+use codegen::Runtime;
+pub use types::Ty;
+use types::{Ret, Specs};
+
+/// The compiled functions of the chunk.
+///
+/// The machine code is never freed, so the functions returned by `Jit::function` outlive the compiler.
+pub struct Jit<'a> {
+    program: Program<'a>,
+    chunk: Chunk,
+    specs: Specs,
+    module: JITModule,
+    runtime: Runtime,
+}
+
+impl<'a> Jit<'a> {
+    /// Collects the functions and the constants of the chunk, nothing is compiled yet.
+    pub fn new(block: &ast::Block<'a>) -> Result<Self, CompileError> {
+        let program = Program::new(block);
+        let chunk = Chunk::new(&program)?;
+        let mut module = module()?;
+        let runtime = Runtime::declare(&mut module)?;
+        Ok(Jit { program, chunk, specs: Specs::default(), module, runtime })
+    }
+
+    /// Compiles the function for the types of `A` if it is not compiled yet.
+    /// The function has to give back the type of `R`, `()` stands for no value.
+    pub fn function<A: Args, R: Results>(&mut self, name: &str) -> Result<JitFn<A, R>, CompileError> {
+        let func = *self
+            .chunk
+            .functions
+            .get(name)
+            .ok_or_else(|| CompileError::new(None, format!("no function '{}' in the chunk", name)))?;
+        let params = A::types();
+        let expected = self.program[func].params.len();
+        if params.len() != expected {
+            let message = format!("'{}' takes {} arguments, not {}", name, expected, params.len());
+            return Err(CompileError::new(Some(self.program[func].span), message));
+        }
+
+        let first = self.specs.len();
+        let spec = self.specs.request(func, params);
+        if let Err(e) = self.compile(first) {
+            self.specs.truncate(first);
+            return Err(e);
+        }
+
+        let ret = self.specs[spec].ret;
+        let matches = match (ret, R::ty()) {
+            (Ret::Value(ty), Some(expected)) => ty == expected,
+            (Ret::Nothing, None) => true,
+            _ => false,
+        };
+        if !matches {
+            let message = format!("'{}' returns {}", name, ret);
+            return Err(CompileError::new(Some(self.program[func].span), message));
+        }
+
+        let id = self.specs[spec].id.expect("the function is defined");
+        let code = self.module.get_finalized_function(id);
+        Ok(JitFn { code, max_depth: MAX_CALL_DEPTH, signature: PhantomData })
+    }
+
+    /// Types and defines the functions requested since `first`.
+    fn compile(&mut self, first: usize) -> Result<(), CompileError> {
+        types::infer(&self.program, &self.chunk, &mut self.specs, first)?;
+        for i in first..self.specs.len() {
+            let spec = &self.specs[i];
+            let signature = codegen::signature(&self.module, &spec.params, spec.ret);
+            let name = format!("{}#{}", self.chunk.names[&spec.func], i);
+            let id = self
+                .module
+                .declare_function(&name, Linkage::Local, &signature)
+                .map_err(CompileError::module)?;
+            self.specs[i].id = Some(id);
+        }
+        for i in first..self.specs.len() {
+            codegen::define(&mut self.module, &self.program, &self.chunk, &self.specs, &self.runtime, i)?;
+        }
+        self.module.finalize_definitions().map_err(CompileError::module)
+    }
+}
+
+fn module() -> Result<JITModule, CompileError> {
+    let mut flags = settings::builder();
+    for (name, value) in [("use_colocated_libcalls", "false"), ("is_pic", "false"), ("opt_level", "speed")] {
+        flags.set(name, value).map_err(|e| CompileError::new(None, e.to_string()))?;
+    }
+    let isa = cranelift_native::builder()
+        .map_err(|e| CompileError::new(None, e.to_string()))?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| CompileError::new(None, e.to_string()))?;
+    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+    Runtime::symbols(&mut builder);
+    Ok(JITModule::new(builder))
+}
+
+/// The default limit of the nested calls of the compiled functions.
+/// The machine frames take tens of bytes, so the deepest recursion fits into 2MB, the stack of a spawned thread.
+pub const MAX_CALL_DEPTH: u32 = 20_000;
+
+/// The compiled function, `call` runs it.
+pub struct JitFn<A, R> {
+    code: *const u8,
+    max_depth: u32,
+    signature: PhantomData<fn(A) -> R>,
+}
+
+impl<A: Args, R: Results> JitFn<A, R> {
+    pub fn call(&self, args: A) -> Result<R, RuntimeError> {
+        let mut context = Context { error: 0, depth: self.max_depth };
+        // the types of the arguments and of the result are checked by `Jit::function`
+        let result = unsafe { args.call::<R>(self.code, &mut context) };
+        match RuntimeError::from_code(context.error) {
+            None => Ok(result),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Sets the limit of the nested calls (`MAX_CALL_DEPTH` by default),
+    /// the deeper recursion stops with `RuntimeError::StackOverflow`.
+    pub fn with_max_depth(self, max_depth: u32) -> Self {
+        JitFn { max_depth, ..self }
+    }
+}
+
+/// What the machine code shares with `JitFn::call`, the first argument of every compiled function.
+#[repr(C)]
+pub struct Context {
+    /// The code of the runtime error, `0` is no error.
+    error: u32,
+    /// The calls left before the stack overflow.
+    depth: u32,
+}
+
+impl Context {
+    const ERROR: i32 = std::mem::offset_of!(Context, error) as i32;
+    const DEPTH: i32 = std::mem::offset_of!(Context, depth) as i32;
+}
+
+/// The type of the argument or of the result of the compiled function.
+pub trait Value: Copy {
+    const TY: Ty;
+}
+
+impl Value for i64 {
+    const TY: Ty = Ty::Int;
+}
+
+impl Value for f64 {
+    const TY: Ty = Ty::Float;
+}
+
+impl Value for bool {
+    const TY: Ty = Ty::Bool;
+}
+
+/// What the compiled function gives back, `()` is no value.
+pub trait Results {
+    fn ty() -> Option<Ty>;
+}
+
+impl Results for () {
+    fn ty() -> Option<Ty> {
+        None
+    }
+}
+
+impl<T: Value> Results for T {
+    fn ty() -> Option<Ty> {
+        Some(T::TY)
+    }
+}
+
+/// The tuple of the arguments of the compiled function.
+pub trait Args {
+    fn types() -> Vec<Ty>;
+
+    /// Calls the machine code, the runtime error goes into `context`.
+    ///
+    /// # Safety
+    /// `code` is the function compiled for these arguments and `R`.
+    unsafe fn call<R: Results>(self, code: *const u8, context: *mut Context) -> R;
+}
+
+macro_rules! args {
+    ($($ty:ident $arg:ident),*) => {
+        impl<$($ty: Value),*> Args for ($($ty,)*) {
+            fn types() -> Vec<Ty> {
+                vec![$($ty::TY),*]
+            }
+
+            unsafe fn call<R: Results>(self, code: *const u8, context: *mut Context) -> R {
+                let ($($arg,)*) = self;
+                let f = std::mem::transmute::<*const u8, extern "C" fn(*mut Context, $($ty),*) -> R>(code);
+                f(context, $($arg),*)
+            }
+        }
+    };
+}
+
+args!();
+args!(A a);
+args!(A a, B b);
+args!(A a, B b, C c);
+args!(A a, B b, C c, D d);
+args!(A a, B b, C c, D d, E e);
+args!(A a, B b, C c, D d, E e, F f);
+
+/// The source the compiler does not support, or the failure of cranelift.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    /// The statement or the function, the failures of cranelift have none.
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(span: Option<Span>, message: String) -> Self {
+        CompileError { span, message }
+    }
+
+    fn module(e: cranelift_module::ModuleError) -> Self {
+        CompileError::new(None, e.to_string())
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}:{}: {}", span.line, span.col, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// The error of lua that stops the compiled function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// `n // 0` of the integers.
+    DivisionByZero,
+    /// `n % 0` of the integers.
+    ModuloByZero,
+    /// The numeric `for` with the zero step.
+    ForStepZero,
+    /// The calls nested deeper than the limit of `JitFn`.
+    StackOverflow,
+}
+
+impl RuntimeError {
+    const ALL: [RuntimeError; 4] = [
+        RuntimeError::DivisionByZero,
+        RuntimeError::ModuloByZero,
+        RuntimeError::ForStepZero,
+        RuntimeError::StackOverflow,
+    ];
+
+    /// The value the machine code stores into the error slot, `0` is no error.
+    fn code(self) -> u32 {
+        self as u32 + 1
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        RuntimeError::ALL.into_iter().find(|e| e.code() == code)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RuntimeError::DivisionByZero => "attempt to perform 'n//0'",
+            RuntimeError::ModuloByZero => "attempt to perform 'n%0'",
+            RuntimeError::ForStepZero => "'for' step is zero",
+            RuntimeError::StackOverflow => "stack overflow",
+        })
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The number known before the run: the constant of the chunk or of `math`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Const {
+    Int(i64),
+    Float(f64),
+}
+
+impl Const {
+    fn ty(self) -> Ty {
+        match self {
+            Const::Int(_) => Ty::Int,
+            Const::Float(_) => Ty::Float,
+        }
+    }
+}
+
+/// The function being called.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum Callee {
+    Func(FnId),
+    Sqrt,
+    Abs,
+}
+
+/// The declarations of the chunk, the chunk itself never runs.
+#[derive(Debug, Default)]
+pub(crate) struct Chunk {
+    /// The global and the local functions by their names.
+    functions: HashMap<String, FnId>,
+    globals: HashMap<String, FnId>,
+    locals: HashMap<VarId, FnId>,
+    consts: HashMap<VarId, Const>,
+    names: HashMap<FnId, String>,
+}
+
+impl Chunk {
+    fn new(program: &Program) -> Result<Self, CompileError> {
+        let mut chunk = Chunk::default();
+        let mut body = program[program.main()].body.iter().peekable();
+        while let Some(st) = body.next() {
+            let unsupported = || {
+                let message = "only the functions and the numeric constants are supported in the chunk";
+                CompileError::new(Some(st.span), message.to_string())
+            };
+            match &st.kind {
+                // `local function f` is `local f; f = function`
+                Stmt::Local(vars, values) if vars.len() == 1 && values.is_empty() => {
+                    let Some(next) = body.next_if(|next| matches!(&next.kind, Stmt::Assign(Place::Var(v), _) if *v == vars[0])) else {
+                        return Err(unsupported());
+                    };
+                    let Stmt::Assign(_, Expr::Function(func)) = &next.kind else {
+                        return Err(unsupported());
+                    };
+                    chunk.local(program, vars[0], *func);
+                }
+                Stmt::Local(vars, values) if vars.len() == values.len() => {
+                    for (var, value) in vars.iter().zip(values) {
+                        match value {
+                            Expr::Function(func) => chunk.local(program, *var, *func),
+                            value => {
+                                let value = chunk.evaluate(value).ok_or_else(|| {
+                                    let message = format!("'{}' is not a numeric constant", program[*var].name);
+                                    CompileError::new(Some(st.span), message)
+                                })?;
+                                chunk.consts.insert(*var, value);
+                            }
+                        }
+                    }
+                }
+                Stmt::Assign(Place::Index(Expr::Env, Expr::Str(name)), Expr::Function(func)) => {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    if chunk.globals.insert(name.clone(), *func).is_some() {
+                        return Err(CompileError::new(Some(st.span), format!("'{}' is defined twice", name)));
+                    }
+                    chunk.functions.insert(name.clone(), *func);
+                    chunk.names.insert(*func, name);
+                }
+                Stmt::Return(values) if values.is_empty() && body.peek().is_none() => {}
+                _ => return Err(unsupported()),
+            }
+        }
+        Ok(chunk)
+    }
+
+    fn local(&mut self, program: &Program, var: VarId, func: FnId) {
+        let name = program[var].name.to_string();
+        self.locals.insert(var, func);
+        self.functions.insert(name.clone(), func);
+        self.names.insert(func, name);
+    }
+
+    /// The numeric expression of the constant, `fold` evaluates the operators the way lua does.
+    fn evaluate(&self, e: &Expr) -> Option<Const> {
+        let value = match e {
+            Expr::Int(v) => return Some(Const::Int(*v)),
+            Expr::Float(v) => return Some(Const::Float(*v)),
+            Expr::Var(_) | Expr::Index(..) => return self.constant(e),
+            Expr::Unary(op, e) => fold::unary(unary_type(*op), self.evaluate(e)?.into())?,
+            Expr::Binary(lhs, op, rhs) => {
+                fold::binary(self.evaluate(lhs)?.into(), binary_type(*op), self.evaluate(rhs)?.into())?
+            }
+            _ => return None,
+        };
+        match value {
+            fold::Const::Int(v) => Some(Const::Int(v)),
+            fold::Const::Float(v) => Some(Const::Float(v)),
+            _ => None,
+        }
+    }
+
+    /// The constant of the chunk or `math.pi`, `math.huge`, `math.maxinteger` and `math.mininteger`.
+    pub(crate) fn constant(&self, e: &Expr) -> Option<Const> {
+        match e {
+            Expr::Var(var) => self.consts.get(var).copied(),
+            e => match math(e)? {
+                b"pi" => Some(Const::Float(std::f64::consts::PI)),
+                b"huge" => Some(Const::Float(f64::INFINITY)),
+                b"maxinteger" => Some(Const::Int(i64::MAX)),
+                b"mininteger" => Some(Const::Int(i64::MIN)),
+                _ => None,
+            },
+        }
+    }
+
+    pub(crate) fn callee(&self, e: &Expr) -> Option<Callee> {
+        match e {
+            Expr::Var(var) => self.locals.get(var).copied().map(Callee::Func),
+            Expr::Index(obj, key) => match (&**obj, &**key) {
+                (Expr::Env, Expr::Str(name)) => {
+                    self.globals.get(&*String::from_utf8_lossy(name)).copied().map(Callee::Func)
+                }
+                _ => match math(e)? {
+                    b"sqrt" => Some(Callee::Sqrt),
+                    b"abs" => Some(Callee::Abs),
+                    _ => None,
+                },
+            },
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_function(&self, var: VarId) -> bool {
+        self.locals.contains_key(&var)
+    }
+
+    pub(crate) fn name(&self, func: FnId) -> &str {
+        &self.names[&func]
+    }
+}
+
+impl From<Const> for fold::Const<'static> {
+    fn from(value: Const) -> Self {
+        match value {
+            Const::Int(v) => fold::Const::Int(v),
+            Const::Float(v) => fold::Const::Float(v),
+        }
+    }
+}
+
+/// The operator of the ast `fold` takes.
+fn unary_type(op: UnOp) -> UnaryType {
+    match op {
+        UnOp::Neg => UnaryType::Minus,
+        UnOp::Not => UnaryType::Not,
+        UnOp::Len => UnaryType::Hash,
+        UnOp::BNot => UnaryType::Tilde,
+    }
+}
+
+/// The operator of the ast `fold` takes.
+fn binary_type(op: BinOp) -> BinaryType {
+    match op {
+        BinOp::Add => BinaryType::Add,
+        BinOp::Sub => BinaryType::Sub,
+        BinOp::Mul => BinaryType::Mult,
+        BinOp::Div => BinaryType::Div,
+        BinOp::IDiv => BinaryType::FDiv,
+        BinOp::Mod => BinaryType::Mod,
+        BinOp::Pow => BinaryType::Pov,
+        BinOp::Concat => BinaryType::Concat,
+        BinOp::Eq => BinaryType::Eq,
+        BinOp::Ne => BinaryType::TEq,
+        BinOp::Lt => BinaryType::Lt,
+        BinOp::Le => BinaryType::Le,
+        BinOp::Gt => BinaryType::Gt,
+        BinOp::Ge => BinaryType::Ge,
+        BinOp::BAnd => BinaryType::Amper,
+        BinOp::BOr => BinaryType::Stick,
+        BinOp::BXor => BinaryType::Tilde,
+        BinOp::Shl => BinaryType::LShift,
+        BinOp::Shr => BinaryType::RShift,
+    }
+}
+
+/// The field of the global `math`: `math.sqrt` gives `sqrt`.
+fn math<'e>(e: &'e Expr) -> Option<&'e [u8]> {
+    match e {
+        Expr::Index(obj, key) => match (&**obj, &**key) {
+            (Expr::Index(env, math), Expr::Str(field)) if **env == Expr::Env && matches!(&**math, Expr::Str(m) if **m == *b"math") => {
+                Some(field)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jit::{Jit, RuntimeError};
+    use crate::parse_chunk;
+
+    const FIB: &str = include_str!("scripts/fib.lua");
+    const MANDELBROT: &str = include_str!("scripts/mandelbrot.lua");
+    const NBODY: &str = include_str!("scripts/nbody.lua");
+
+    fn error(src: &str, name: &str) -> String {
+        let block = parse_chunk(src).unwrap();
+        let e = match Jit::new(&block) {
+            Ok(mut jit) => jit.function::<(i64,), i64>(name).err().unwrap(),
+            Err(e) => e,
+        };
+        e.to_string()
+    }
+
+    #[test]
+    fn fib() {
+        let block = parse_chunk(FIB).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let fib = jit.function::<(i64,), i64>("fib").unwrap();
+        assert_eq!(fib.call((0,)), Ok(0));
+        assert_eq!(fib.call((25,)), Ok(75025));
+
+        // the same function for the floats is another machine function
+        let fib = jit.function::<(f64,), f64>("fib").unwrap();
+        assert_eq!(fib.call((25.0,)), Ok(75025.0));
+    }
+
+    #[test]
+    fn mandelbrot() {
+        fn escapes(cr: f64, ci: f64) -> bool {
+            let (mut zr, mut zi) = (0.0f64, 0.0f64);
+            for _ in 0..50 {
+                let (zr2, zi2) = (zr * zr, zi * zi);
+                if zr2 + zi2 > 4.0 {
+                    return true;
+                }
+                zi = 2.0 * zr * zi + ci;
+                zr = zr2 - zi2 + cr;
+            }
+            false
+        }
+        let expected = |size: i64| {
+            let mut count = 0;
+            for y in 0..size {
+                let ci = 2.0 * y as f64 / size as f64 - 1.0;
+                for x in 0..size {
+                    let cr = 2.0 * x as f64 / size as f64 - 1.5;
+                    if !escapes(cr, ci) {
+                        count += 1;
+                    }
+                }
+            }
+            count
+        };
+
+        let block = parse_chunk(MANDELBROT).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let mandelbrot = jit.function::<(i64,), i64>("mandelbrot").unwrap();
+        for size in [1, 16, 100] {
+            assert_eq!(mandelbrot.call((size,)), Ok(expected(size)));
+        }
+    }
+
+    #[test]
+    fn nbody() {
+        let block = parse_chunk(NBODY).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let nbody = jit.function::<(i64, f64), f64>("nbody").unwrap();
+        let start = nbody.call((0, 0.01)).unwrap();
+        let end = nbody.call((1000, 0.01)).unwrap();
+        assert_eq!(start, nbody_in_rust(0, 0.01));
+        assert_eq!(end, nbody_in_rust(1000, 0.01));
+        // the energy is conserved
+        assert!(start < 0.0 && ((end - start) / start).abs() < 1e-4, "{} {}", start, end);
+    }
+
+    /// The same operations in the same order as `nbody.lua`, the literals are the ones of the script.
+    #[allow(clippy::excessive_precision)]
+    fn nbody_in_rust(steps: i64, dt: f64) -> f64 {
+        let pi = std::f64::consts::PI;
+        let solar_mass = 4.0 * pi * pi;
+        let days: f64 = 365.24;
+        let mut x: [f64; 3] = [0.0, 4.84143144246472090e+00, 8.34336671824457987e+00];
+        let mut y: [f64; 3] = [0.0, -1.16032004402742839e+00, 4.12479856412430479e+00];
+        let mut z: [f64; 3] = [0.0, -1.03622044471123109e-01, -4.03523417114321381e-01];
+        let mut vx = [0.0, 1.66007664274403694e-03 * days, -2.76742510726862411e-03 * days];
+        let mut vy = [0.0, 7.69901118419740425e-03 * days, 4.99852801234917238e-03 * days];
+        let mut vz = [0.0, -6.90460016972063023e-05 * days, 2.30417297573763929e-05 * days];
+        let m = [solar_mass, 9.54791938424326609e-04 * solar_mass, 2.85885980666130812e-04 * solar_mass];
+        vx[0] = -(vx[1] * m[1] + vx[2] * m[2]) / solar_mass;
+        vy[0] = -(vy[1] * m[1] + vy[2] * m[2]) / solar_mass;
+        vz[0] = -(vz[1] * m[1] + vz[2] * m[2]) / solar_mass;
+        for _ in 0..steps {
+            for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+                let (dx, dy, dz) = (x[i] - x[j], y[i] - y[j], z[i] - z[j]);
+                let d2 = dx * dx + dy * dy + dz * dz;
+                let mag = dt / (d2 * d2.sqrt());
+                vx[i] -= dx * m[j] * mag;
+                vy[i] -= dy * m[j] * mag;
+                vz[i] -= dz * m[j] * mag;
+                vx[j] += dx * m[i] * mag;
+                vy[j] += dy * m[i] * mag;
+                vz[j] += dz * m[i] * mag;
+            }
+            for i in 0..3 {
+                x[i] += dt * vx[i];
+                y[i] += dt * vy[i];
+                z[i] += dt * vz[i];
+            }
+        }
+        let mut e = 0.0;
+        for i in 0..3 {
+            e += 0.5 * m[i] * (vx[i] * vx[i] + vy[i] * vy[i] + vz[i] * vz[i]);
+        }
+        for (i, j) in [(0, 1), (0, 2), (1, 2)] {
+            let (dx, dy, dz) = (x[i] - x[j], y[i] - y[j], z[i] - z[j]);
+            e -= m[i] * m[j] / (dx * dx + dy * dy + dz * dz).sqrt();
+        }
+        e
+    }
+
+    #[test]
+    fn integers() {
+        let src = r#"
+            local function idiv(a, b) return a // b end
+            local function mod(a, b) return a % b end
+            local function shl(a, b) return a << b end
+            local function shr(a, b) return a >> b end
+            local function arith(a, b) return (a + b) * (a - b) ~ -a end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+
+        let idiv = jit.function::<(i64, i64), i64>("idiv").unwrap();
+        assert_eq!(idiv.call((7, 2)), Ok(3));
+        assert_eq!(idiv.call((7, -2)), Ok(-4));
+        assert_eq!(idiv.call((-7, 2)), Ok(-4));
+        assert_eq!(idiv.call((i64::MIN, -1)), Ok(i64::MIN));
+        assert_eq!(idiv.call((1, 0)), Err(RuntimeError::DivisionByZero));
+
+        let modulo = jit.function::<(i64, i64), i64>("mod").unwrap();
+        assert_eq!(modulo.call((7, -2)), Ok(-1));
+        assert_eq!(modulo.call((-7, 2)), Ok(1));
+        assert_eq!(modulo.call((i64::MIN, -1)), Ok(0));
+        assert_eq!(modulo.call((1, 0)), Err(RuntimeError::ModuloByZero));
+
+        let shl = jit.function::<(i64, i64), i64>("shl").unwrap();
+        assert_eq!(shl.call((1, 63)), Ok(i64::MIN));
+        assert_eq!(shl.call((1, 64)), Ok(0));
+        assert_eq!(shl.call((-1, -1)), Ok(i64::MAX));
+        let shr = jit.function::<(i64, i64), i64>("shr").unwrap();
+        assert_eq!(shr.call((-1, 60)), Ok(15));
+        assert_eq!(shr.call((1, -3)), Ok(8));
+        assert_eq!(shr.call((1, i64::MIN)), Ok(0));
+
+        let arith = jit.function::<(i64, i64), i64>("arith").unwrap();
+        assert_eq!(arith.call((5, 3)), Ok(16 ^ -5));
+        assert_eq!(arith.call((i64::MAX, 1)), Ok(i64::MIN.wrapping_mul(i64::MAX - 1) ^ -i64::MAX));
+    }
+
+    #[test]
+    fn floats() {
+        let src = r#"
+            local function idiv(a, b) return a // b end
+            local function mod(a, b) return a % b end
+            local function pow(a, b) return a ^ b end
+            local function div(a, b) return a / b end
+            local function hypot(a, b) return math.sqrt(a * a + b * b) end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+
+        assert_eq!(jit.function::<(f64, f64), f64>("idiv").unwrap().call((7.0, -2.0)), Ok(-4.0));
+        assert_eq!(jit.function::<(i64, f64), f64>("idiv").unwrap().call((7, 2.0)), Ok(3.0));
+        let modulo = jit.function::<(f64, f64), f64>("mod").unwrap();
+        assert_eq!(modulo.call((-7.5, 2.0)), Ok(0.5));
+        assert_eq!(modulo.call((7.5, -2.0)), Ok(-0.5));
+        assert!(modulo.call((1.0, 0.0)).unwrap().is_nan());
+        assert_eq!(jit.function::<(i64, i64), f64>("pow").unwrap().call((2, 10)), Ok(1024.0));
+        assert_eq!(jit.function::<(i64, i64), f64>("div").unwrap().call((7, 2)), Ok(3.5));
+        assert_eq!(jit.function::<(i64, i64), f64>("div").unwrap().call((1, 0)), Ok(f64::INFINITY));
+        assert_eq!(jit.function::<(i64, f64), f64>("hypot").unwrap().call((3, 4.0)), Ok(5.0));
+    }
+
+    #[test]
+    fn loops() {
+        let src = r#"
+            function sum(a, b, step)
+                local s = 0
+                for i = a, b, step do
+                    s = s + i
+                end
+                return s
+            end
+            function fsum(a, b, step)
+                local s = 0.0
+                for x = a, b, step do
+                    s = s + x
+                end
+                return s
+            end
+            function count(limit)
+                local n = 0
+                for i = math.maxinteger - 2, limit do
+                    n = n + 1
+                end
+                return n
+            end
+            function collatz(n)
+                local steps = 0
+                while n ~= 1 do
+                    if n % 2 == 0 then n = n // 2 else n = 3 * n + 1 end
+                    steps = steps + 1
+                end
+                return steps
+            end
+            function search(n)
+                local i = 0
+                repeat
+                    i = i + 1
+                    if i * i >= n then break end
+                until false
+                return i
+            end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+
+        let sum = jit.function::<(i64, i64, i64), i64>("sum").unwrap();
+        assert_eq!(sum.call((1, 10, 1)), Ok(55));
+        assert_eq!(sum.call((10, 1, -3)), Ok(10 + 7 + 4 + 1));
+        assert_eq!(sum.call((1, 0, 1)), Ok(0));
+        assert_eq!(sum.call((1, 2, 0)), Err(RuntimeError::ForStepZero));
+        let sum = jit.function::<(i64, f64, i64), i64>("sum").unwrap();
+        assert_eq!(sum.call((1, 3.5, 1)), Ok(6));
+        assert_eq!(sum.call((1, -f64::INFINITY, 1)), Ok(0));
+        let fsum = jit.function::<(f64, f64, f64), f64>("fsum").unwrap();
+        assert_eq!(fsum.call((0.0, 1.0, 0.25)), Ok(2.5));
+        assert_eq!(fsum.call((1.0, 0.0, -0.5)), Ok(1.5));
+        assert_eq!(fsum.call((0.0, f64::NAN, 1.0)), Ok(0.0));
+        assert_eq!(jit.function::<(i64, i64, f64), f64>("fsum").unwrap().call((1, 2, 0.0)), Err(RuntimeError::ForStepZero));
+
+        // the counter does not overflow
+        assert_eq!(jit.function::<(i64,), i64>("count").unwrap().call((i64::MAX,)), Ok(3));
+        assert_eq!(jit.function::<(f64,), i64>("count").unwrap().call((f64::INFINITY,)), Ok(3));
+        assert_eq!(jit.function::<(i64,), i64>("collatz").unwrap().call((27,)), Ok(111));
+        assert_eq!(jit.function::<(i64,), i64>("search").unwrap().call((50,)), Ok(8));
+    }
+
+    #[test]
+    fn booleans() {
+        let src = r#"
+            local function between(x, a, b) return a <= x and x <= b end
+            local function outside(x, a, b) return not between(x, a, b) or x ~= x end
+            local function greater(i, f) return i > f end
+            local function same(f, i) return f == i end
+            local function swap(a, b)
+                a, b = b, a
+                return a - b
+            end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let between = jit.function::<(i64, i64, i64), bool>("between").unwrap();
+        assert_eq!(between.call((2, 1, 3)), Ok(true));
+        assert_eq!(between.call((4, 1, 3)), Ok(false));
+        let outside = jit.function::<(f64, f64, f64), bool>("outside").unwrap();
+        assert_eq!(outside.call((4.0, 1.0, 3.0)), Ok(true));
+        assert_eq!(outside.call((f64::NAN, 1.0, 3.0)), Ok(true));
+        assert_eq!(outside.call((2.0, 1.0, 3.0)), Ok(false));
+        assert_eq!(jit.function::<(i64, i64), i64>("swap").unwrap().call((1, 5)), Ok(4));
+
+        // the integers and the floats are compared exactly, beyond 2^53 too
+        let greater = jit.function::<(i64, f64), bool>("greater").unwrap();
+        assert_eq!(greater.call(((1 << 53) + 1, (1u64 << 53) as f64)), Ok(true));
+        assert_eq!(greater.call((2, 1.5)), Ok(true));
+        assert_eq!(greater.call((1, f64::NAN)), Ok(false));
+        assert_eq!(greater.call((i64::MAX, 9.3e18)), Ok(false));
+        assert_eq!(greater.call((i64::MIN, -1e19)), Ok(true));
+        let same = jit.function::<(f64, i64), bool>("same").unwrap();
+        assert_eq!(same.call(((1u64 << 53) as f64, (1 << 53) + 1)), Ok(false));
+        assert_eq!(same.call((3.0, 3)), Ok(true));
+        assert_eq!(same.call((3.5, 3)), Ok(false));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let src = r#"
+            local function inner(n) return 1 // n end
+            local function outer(n)
+                local s = 0
+                for i = 1, 10 do
+                    s = s + inner(n - i)
+                end
+                return s
+            end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let outer = jit.function::<(i64,), i64>("outer").unwrap();
+        assert_eq!(outer.call((20,)), Ok(0));
+        assert_eq!(outer.call((5,)), Err(RuntimeError::DivisionByZero));
+        assert_eq!(RuntimeError::DivisionByZero.to_string(), "attempt to perform 'n//0'");
+        assert_eq!(RuntimeError::ModuloByZero.to_string(), "attempt to perform 'n%0'");
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let src = r#"
+            local function rec(n)
+                if n == 0 then return 0 end
+                return rec(n - 1) + 1
+            end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        let rec = jit.function::<(i64,), i64>("rec").unwrap();
+        assert_eq!(rec.call((1000,)), Ok(1000));
+        assert_eq!(rec.call((100_000_000,)), Err(RuntimeError::StackOverflow));
+        // the depth is given back, the next call starts with the whole limit
+        assert_eq!(rec.call((1000,)), Ok(1000));
+
+        let rec = rec.with_max_depth(10);
+        assert_eq!(rec.call((10,)), Ok(10));
+        assert_eq!(rec.call((11,)), Err(RuntimeError::StackOverflow));
+        assert_eq!(RuntimeError::StackOverflow.to_string(), "stack overflow");
+    }
+
+    #[test]
+    fn constants() {
+        let src = r#"
+            local A, B, C, D = 7 // 2, -7 % 3, 1 << 4, 2 ^ 2
+            local function f(n) return n + A + B + C end
+            local function g(n) return n + D end
+        "#;
+        let block = parse_chunk(src).unwrap();
+        let mut jit = Jit::new(&block).unwrap();
+        assert_eq!(jit.function::<(i64,), i64>("f").unwrap().call((0,)), Ok(3 + 2 + 16));
+        assert_eq!(jit.function::<(i64,), f64>("g").unwrap().call((0,)), Ok(4.0));
+        assert_eq!(error("local A = 1 // 0
+function f(n) return A end", "f"), "1:1: 'A' is not a numeric constant");
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(
+            error("print(1)", "f"),
+            "1:1: only the functions and the numeric constants are supported in the chunk"
+        );
+        assert_eq!(error("local x = {}", "f"), "1:1: 'x' is not a numeric constant");
+        assert_eq!(error("function f(n) end", "g"), "no function 'g' in the chunk");
+        assert_eq!(error("function f(n) return n < 1 end", "f"), "1:1: 'f' returns boolean");
+        assert_eq!(error("function f(a, b) end", "f"), "1:1: 'f' takes 2 arguments, not 1");
+        assert_eq!(
+            error("function f(n)\n  local x = 0\n  x = x + 0.5\n  return n\nend", "f"),
+            "3:3: 'x' holds integer and float"
+        );
+        assert_eq!(error("function f(n)\n  return {}\nend", "f"), "2:10: tables are not supported");
+        assert_eq!(error("function f(n)\n  print(n)\nend", "f"), "2:3: the function 'print' is unknown");
+        assert_eq!(
+            error("function f(n)\n  if n > 0 then return 1 end\nend", "f"),
+            "1:1: 'f' returns integer and nothing"
+        );
+        assert_eq!(
+            error("function f(n)\n  return f(n)\nend", "f"),
+            "1:1: the result of 'f' is unknown, it only calls itself"
+        );
+        assert_eq!(
+            error("function f(n)\n  return n < true\nend", "f"),
+            "2:10: attempt to compare number with boolean"
+        );
+    }
+}
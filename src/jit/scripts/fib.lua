@@ -0,0 +1,7 @@
+-- the naive recursion, the calls dominate
+function fib(n)
+    if n < 2 then
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
@@ -0,0 +1,29 @@
+-- the points of the size x size grid over [-1.5, 0.5] x [-1, 1] staying in the set
+local ITERATIONS = 50
+
+local function escapes(cr, ci)
+    local zr, zi = 0.0, 0.0
+    for i = 1, ITERATIONS do
+        local zr2, zi2 = zr * zr, zi * zi
+        if zr2 + zi2 > 4.0 then
+            return true
+        end
+        zi = 2.0 * zr * zi + ci
+        zr = zr2 - zi2 + cr
+    end
+    return false
+end
+
+function mandelbrot(size)
+    local count = 0
+    for y = 0, size - 1 do
+        local ci = 2.0 * y / size - 1.0
+        for x = 0, size - 1 do
+            local cr = 2.0 * x / size - 1.5
+            if not escapes(cr, ci) then
+                count = count + 1
+            end
+        end
+    end
+    return count
+end
@@ -0,0 +1,66 @@
+-- the sun, jupiter and saturn of the benchmarks game,
+-- the bodies live in the locals as the tables are not compiled
+local PI = 3.141592653589793
+local SOLAR_MASS = 4 * PI * PI
+local DAYS_PER_YEAR = 365.24
+
+local function distance(dx, dy, dz)
+    return math.sqrt(dx * dx + dy * dy + dz * dz)
+end
+
+-- the energy of the system after the steps
+function nbody(steps, dt)
+    local x1, y1, z1 = 0.0, 0.0, 0.0
+    local vx1, vy1, vz1 = 0.0, 0.0, 0.0
+    local m1 = SOLAR_MASS
+
+    local x2, y2, z2 = 4.84143144246472090e+00, -1.16032004402742839e+00, -1.03622044471123109e-01
+    local vx2 = 1.66007664274403694e-03 * DAYS_PER_YEAR
+    local vy2 = 7.69901118419740425e-03 * DAYS_PER_YEAR
+    local vz2 = -6.90460016972063023e-05 * DAYS_PER_YEAR
+    local m2 = 9.54791938424326609e-04 * SOLAR_MASS
+
+    local x3, y3, z3 = 8.34336671824457987e+00, 4.12479856412430479e+00, -4.03523417114321381e-01
+    local vx3 = -2.76742510726862411e-03 * DAYS_PER_YEAR
+    local vy3 = 4.99852801234917238e-03 * DAYS_PER_YEAR
+    local vz3 = 2.30417297573763929e-05 * DAYS_PER_YEAR
+    local m3 = 2.85885980666130812e-04 * SOLAR_MASS
+
+    -- the sun moves against the planets, the momentum of the system is zero
+    vx1 = -(vx2 * m2 + vx3 * m3) / SOLAR_MASS
+    vy1 = -(vy2 * m2 + vy3 * m3) / SOLAR_MASS
+    vz1 = -(vz2 * m2 + vz3 * m3) / SOLAR_MASS
+
+    for step = 1, steps do
+        local dx, dy, dz = x1 - x2, y1 - y2, z1 - z2
+        local d2 = dx * dx + dy * dy + dz * dz
+        local mag = dt / (d2 * math.sqrt(d2))
+        vx1, vy1, vz1 = vx1 - dx * m2 * mag, vy1 - dy * m2 * mag, vz1 - dz * m2 * mag
+        vx2, vy2, vz2 = vx2 + dx * m1 * mag, vy2 + dy * m1 * mag, vz2 + dz * m1 * mag
+
+        dx, dy, dz = x1 - x3, y1 - y3, z1 - z3
+        d2 = dx * dx + dy * dy + dz * dz
+        mag = dt / (d2 * math.sqrt(d2))
+        vx1, vy1, vz1 = vx1 - dx * m3 * mag, vy1 - dy * m3 * mag, vz1 - dz * m3 * mag
+        vx3, vy3, vz3 = vx3 + dx * m1 * mag, vy3 + dy * m1 * mag, vz3 + dz * m1 * mag
+
+        dx, dy, dz = x2 - x3, y2 - y3, z2 - z3
+        d2 = dx * dx + dy * dy + dz * dz
+        mag = dt / (d2 * math.sqrt(d2))
+        vx2, vy2, vz2 = vx2 - dx * m3 * mag, vy2 - dy * m3 * mag, vz2 - dz * m3 * mag
+        vx3, vy3, vz3 = vx3 + dx * m2 * mag, vy3 + dy * m2 * mag, vz3 + dz * m2 * mag
+
+        x1, y1, z1 = x1 + dt * vx1, y1 + dt * vy1, z1 + dt * vz1
+        x2, y2, z2 = x2 + dt * vx2, y2 + dt * vy2, z2 + dt * vz2
+        x3, y3, z3 = x3 + dt * vx3, y3 + dt * vy3, z3 + dt * vz3
+    end
+
+    local e = 0.0
+    e = e + 0.5 * m1 * (vx1 * vx1 + vy1 * vy1 + vz1 * vz1)
+    e = e + 0.5 * m2 * (vx2 * vx2 + vy2 * vy2 + vz2 * vz2)
+    e = e + 0.5 * m3 * (vx3 * vx3 + vy3 * vy3 + vz3 * vz3)
+    e = e - m1 * m2 / distance(x1 - x2, y1 - y2, z1 - z2)
+    e = e - m1 * m3 / distance(x1 - x3, y1 - y3, z1 - z3)
+    e = e - m2 * m3 / distance(x2 - x3, y2 - y3, z2 - z3)
+    return e
+end
@@ -0,0 +1,504 @@
+//! The types of the variables and of the results, inferred for the types of the arguments.
+//!
+//! The function is typed again and again along with the functions it calls until nothing changes:
+//! the result of the recursive call is unknown until a `return` of the function is typed,
+//! `fib(n - 1) + fib(n - 2)` gets its type from `return n`.
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::ops::{Index, IndexMut};
+use cranelift_module::FuncId;
+use crate::hir::{BinOp, Block, Expr, FnId, Place, Program, Stmt, UnOp, VarId};
+use crate::jit::{Callee, Chunk, CompileError};
+use crate::parser::span::Span;
+
+/// The type of the value, the variable keeps one type.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Ty {
+    Int,
+    Float,
+    Bool,
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Ty::Int => "integer",
+            Ty::Float => "float",
+            Ty::Bool => "boolean",
+        })
+    }
+}
+
+/// What the function gives back.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Ret {
+    /// No `return` is typed yet.
+    Unknown,
+    Nothing,
+    Value(Ty),
+}
+
+impl Display for Ret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ret::Unknown => f.write_str("unknown"),
+            Ret::Nothing => f.write_str("nothing"),
+            Ret::Value(ty) => ty.fmt(f),
+        }
+    }
+}
+
+/// The function compiled for the types of its arguments.
+#[derive(Debug)]
+pub(crate) struct Spec {
+    pub func: FnId,
+    pub params: Vec<Ty>,
+    pub ret: Ret,
+    /// The parameters and the locals.
+    pub vars: HashMap<VarId, Ty>,
+    /// The machine function, declared once the types are known.
+    pub id: Option<FuncId>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Specs {
+    list: Vec<Spec>,
+    index: HashMap<(FnId, Vec<Ty>), usize>,
+}
+
+impl Specs {
+    /// The function for the arguments, a new one is typed by the next round of `infer`.
+    pub fn request(&mut self, func: FnId, params: Vec<Ty>) -> usize {
+        if let Some(i) = self.index.get(&(func, params.clone())) {
+            return *i;
+        }
+        let spec = Spec { func, params: params.clone(), ret: Ret::Unknown, vars: HashMap::new(), id: None };
+        self.list.push(spec);
+        self.index.insert((func, params), self.list.len() - 1);
+        self.list.len() - 1
+    }
+
+    pub fn find(&self, func: FnId, params: &[Ty]) -> usize {
+        self.index[&(func, params.to_vec())]
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    /// Drops the functions requested by the failed compilation.
+    pub fn truncate(&mut self, len: usize) {
+        self.list.truncate(len);
+        self.index.retain(|_, i| *i < len);
+    }
+}
+
+impl Index<usize> for Specs {
+    type Output = Spec;
+
+    fn index(&self, i: usize) -> &Self::Output {
+        &self.list[i]
+    }
+}
+
+impl IndexMut<usize> for Specs {
+    fn index_mut(&mut self, i: usize) -> &mut Self::Output {
+        &mut self.list[i]
+    }
+}
+
+/// Types the functions from `first` on, the ones before are compiled already.
+pub(crate) fn infer(program: &Program, chunk: &Chunk, specs: &mut Specs, first: usize) -> Result<(), CompileError> {
+    loop {
+        let mut changed = false;
+        let mut i = first;
+        while i < specs.len() {
+            let len = specs.len();
+            let (vars, ret) = Typer::new(program, chunk, specs, i).run()?;
+            changed |= specs[i].ret != ret || specs[i].vars != vars || specs.len() != len;
+            let spec = &mut specs[i];
+            spec.vars = vars;
+            spec.ret = ret;
+            i += 1;
+        }
+        if !changed {
+            break;
+        }
+    }
+    for i in first..specs.len() {
+        let func = specs[i].func;
+        if specs[i].ret == Ret::Unknown {
+            let message = format!("the result of '{}' is unknown, it only calls itself", chunk.name(func));
+            return Err(CompileError::new(Some(program[func].span), message));
+        }
+    }
+    Ok(())
+}
+
+struct Typer<'j, 'a> {
+    program: &'j Program<'a>,
+    chunk: &'j Chunk,
+    specs: &'j mut Specs,
+    spec: usize,
+    func: FnId,
+    vars: HashMap<VarId, Ty>,
+    ret: Ret,
+    /// The statement being typed.
+    span: Span,
+}
+
+impl<'j, 'a> Typer<'j, 'a> {
+    fn new(program: &'j Program<'a>, chunk: &'j Chunk, specs: &'j mut Specs, spec: usize) -> Self {
+        let func = specs[spec].func;
+        let span = program[func].span;
+        Typer { program, chunk, specs, spec, func, vars: HashMap::new(), ret: Ret::Unknown, span }
+    }
+
+    fn run(mut self) -> Result<(HashMap<VarId, Ty>, Ret), CompileError> {
+        let func = &self.program[self.func];
+        if func.var_args {
+            return Err(self.error("'...' is not supported"));
+        }
+        for (param, ty) in func.params.iter().zip(&self.specs[self.spec].params) {
+            self.vars.insert(*param, *ty);
+        }
+        if self.block(&func.body)? {
+            self.span = func.span;
+            self.returns(Ret::Nothing)?;
+        }
+        Ok((self.vars, self.ret))
+    }
+
+    fn error(&self, message: impl Into<String>) -> CompileError {
+        CompileError::new(Some(self.span), message.into())
+    }
+
+    /// Whether the end of the block is reached.
+    fn block(&mut self, block: &Block<'a>) -> Result<bool, CompileError> {
+        let mut reached = true;
+        for st in block {
+            self.span = st.span;
+            reached = self.stmt(&st.kind)? && reached;
+        }
+        Ok(reached)
+    }
+
+    fn stmt(&mut self, st: &Stmt<'a>) -> Result<bool, CompileError> {
+        match st {
+            Stmt::Local(vars, values) => {
+                if vars.len() != values.len() {
+                    return Err(self.error("every local needs its own value"));
+                }
+                for (var, value) in vars.iter().zip(values) {
+                    let ty = self.expr(value)?;
+                    self.define(*var, ty)?;
+                }
+                Ok(true)
+            }
+            Stmt::Assign(Place::Var(var), value) => {
+                if self.program[*var].func != self.func {
+                    return Err(self.error(format!("assigning the upvalue '{}' is not supported", self.program[*var].name)));
+                }
+                let ty = self.expr(value)?;
+                self.define(*var, ty)?;
+                Ok(true)
+            }
+            Stmt::Assign(Place::Index(Expr::Env, Expr::Str(name)), _) => {
+                Err(self.error(format!("assigning the global '{}' is not supported", String::from_utf8_lossy(name))))
+            }
+            Stmt::Assign(..) => Err(self.error("tables are not supported")),
+            Stmt::Call(call) => self.call(call, false).map(|_| true),
+            Stmt::Do(block) => self.block(block),
+            Stmt::Loop(block) => {
+                self.block(block)?;
+                Ok(breaks(block))
+            }
+            Stmt::If(cond, then, otherwise) => {
+                self.expr(cond)?;
+                let then = self.block(then)?;
+                let otherwise = self.block(otherwise)?;
+                // `while true do` is `loop if true then ... else break end end`
+                Ok(then || otherwise && *cond != Expr::Bool(true))
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => {
+                let start = self.expr(start)?;
+                let limit = self.expr(limit)?;
+                let step = self.expr(step)?;
+                for ty in [start, limit, step] {
+                    if ty == Some(Ty::Bool) {
+                        return Err(self.error("'for' value must be a number"));
+                    }
+                }
+                let ty = match (start, limit, step) {
+                    (Some(Ty::Int), Some(_), Some(Ty::Int)) => Some(Ty::Int),
+                    (Some(_), Some(_), Some(_)) => Some(Ty::Float),
+                    _ => None,
+                };
+                self.define(*var, ty)?;
+                self.block(body)?;
+                Ok(true)
+            }
+            Stmt::Return(values) => {
+                let ret = match values.as_slice() {
+                    [] => Ret::Nothing,
+                    [value] => self.expr(value)?.map_or(Ret::Unknown, Ret::Value),
+                    _ => return Err(self.error("returning several values is not supported")),
+                };
+                self.returns(ret)?;
+                Ok(false)
+            }
+            Stmt::Break => Ok(false),
+            Stmt::Goto(_) | Stmt::Label(_) => Err(self.error("goto is not supported")),
+        }
+    }
+
+    /// Gives the type to the variable, `None` is the type not known yet.
+    fn define(&mut self, var: VarId, ty: Option<Ty>) -> Result<(), CompileError> {
+        let Some(ty) = ty else {
+            return Ok(());
+        };
+        match self.vars.insert(var, ty) {
+            Some(old) if old != ty => {
+                Err(self.error(format!("'{}' holds {} and {}", self.program[var].name, old, ty)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn returns(&mut self, ret: Ret) -> Result<(), CompileError> {
+        self.ret = match (self.ret, ret) {
+            (Ret::Unknown, ret) | (ret, Ret::Unknown) => ret,
+            (old, ret) if old == ret => ret,
+            (old, ret) => {
+                let name = self.chunk.name(self.func);
+                return Err(self.error(format!("'{}' returns {} and {}", name, old, ret)));
+            }
+        };
+        Ok(())
+    }
+
+    fn expr(&mut self, e: &Expr<'a>) -> Result<Option<Ty>, CompileError> {
+        match e {
+            Expr::Bool(_) => Ok(Some(Ty::Bool)),
+            Expr::Int(_) => Ok(Some(Ty::Int)),
+            Expr::Float(_) => Ok(Some(Ty::Float)),
+            Expr::Var(var) if self.program[*var].func == self.func => Ok(self.vars.get(var).copied()),
+            Expr::Var(var) => match self.chunk.constant(e) {
+                Some(value) => Ok(Some(value.ty())),
+                None if self.chunk.is_function(*var) => {
+                    Err(self.error(format!("the function '{}' can only be called", self.program[*var].name)))
+                }
+                None => Err(self.error(format!("the upvalue '{}' is not supported", self.program[*var].name))),
+            },
+            Expr::Index(obj, key) => match (self.chunk.constant(e), &**obj, &**key) {
+                (Some(value), _, _) => Ok(Some(value.ty())),
+                (None, Expr::Env, Expr::Str(name)) => {
+                    Err(self.error(format!("the global '{}' is not supported", String::from_utf8_lossy(name))))
+                }
+                _ => Err(self.error("tables are not supported")),
+            },
+            Expr::Call(..) => self.call(e, true),
+            Expr::First(e) => self.expr(e),
+            Expr::Unary(op, e) => {
+                let ty = self.expr(e)?;
+                match op {
+                    UnOp::Neg => self.arith(ty).map(|_| ty),
+                    UnOp::Not => Ok(Some(Ty::Bool)),
+                    UnOp::BNot => self.bitwise(ty),
+                    UnOp::Len => Err(self.error("the length operator is not supported")),
+                }
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                self.binary(lhs, *op, rhs)
+            }
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                if [lhs, rhs].iter().any(|ty| matches!(ty, Some(Ty::Int | Ty::Float))) {
+                    return Err(self.error("'and' and 'or' of the numbers are not supported"));
+                }
+                Ok(Some(Ty::Bool))
+            }
+            Expr::Nil => Err(self.error("nil is not supported")),
+            Expr::Str(_) => Err(self.error("strings are not supported")),
+            Expr::VarArgs => Err(self.error("'...' is not supported")),
+            Expr::Env => Err(self.error("_ENV is not supported")),
+            Expr::Function(_) => Err(self.error("closures are not supported")),
+            Expr::Table(_) => Err(self.error("tables are not supported")),
+            Expr::Let(..) => Err(self.error("methods are not supported")),
+        }
+    }
+
+    fn arith(&self, ty: Option<Ty>) -> Result<(), CompileError> {
+        match ty {
+            Some(Ty::Bool) => Err(self.error("attempt to perform arithmetic on a boolean value")),
+            _ => Ok(()),
+        }
+    }
+
+    fn bitwise(&self, ty: Option<Ty>) -> Result<Option<Ty>, CompileError> {
+        match ty {
+            Some(Ty::Bool) => Err(self.error("attempt to perform bitwise operation on a boolean value")),
+            Some(Ty::Float) => Err(self.error("the bitwise operators on floats are not supported")),
+            _ => Ok(Some(Ty::Int)),
+        }
+    }
+
+    fn binary(&self, lhs: Option<Ty>, op: BinOp, rhs: Option<Ty>) -> Result<Option<Ty>, CompileError> {
+        match op {
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::IDiv | BinOp::Mod => {
+                self.arith(lhs)?;
+                self.arith(rhs)?;
+                Ok(match (lhs, rhs) {
+                    (Some(Ty::Int), Some(Ty::Int)) => Some(Ty::Int),
+                    (Some(Ty::Float), _) | (_, Some(Ty::Float)) => Some(Ty::Float),
+                    _ => None,
+                })
+            }
+            BinOp::Div | BinOp::Pow => {
+                self.arith(lhs)?;
+                self.arith(rhs)?;
+                Ok(Some(Ty::Float))
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let order = !matches!(op, BinOp::Eq | BinOp::Ne);
+                match (lhs, rhs) {
+                    (Some(Ty::Bool), Some(Ty::Bool)) if order => {
+                        Err(self.error("attempt to compare two boolean values"))
+                    }
+                    (Some(Ty::Bool), Some(Ty::Int | Ty::Float)) | (Some(Ty::Int | Ty::Float), Some(Ty::Bool)) if order => {
+                        Err(self.error("attempt to compare number with boolean"))
+                    }
+                    (Some(Ty::Bool), Some(Ty::Int | Ty::Float)) | (Some(Ty::Int | Ty::Float), Some(Ty::Bool)) => {
+                        Err(self.error("comparing a boolean with a number is not supported"))
+                    }
+                    _ => Ok(Some(Ty::Bool)),
+                }
+            }
+            BinOp::BAnd | BinOp::BOr | BinOp::BXor | BinOp::Shl | BinOp::Shr => {
+                self.bitwise(lhs)?;
+                self.bitwise(rhs)
+            }
+            BinOp::Concat => Err(self.error("strings are not supported")),
+        }
+    }
+
+    /// The result of the call, `value` is false for the call statement which may give nothing.
+    fn call(&mut self, e: &Expr<'a>, value: bool) -> Result<Option<Ty>, CompileError> {
+        let Expr::Call(callee, args) = e else {
+            return Err(self.error("only the calls are statements"));
+        };
+        let Some(callee) = self.chunk.callee(callee) else {
+            return Err(match &**callee {
+                Expr::Index(env, name) if **env == Expr::Env => match &**name {
+                    Expr::Str(name) => self.error(format!("the function '{}' is unknown", String::from_utf8_lossy(name))),
+                    _ => self.error("the globals are not supported"),
+                },
+                _ => self.error("only the functions of the chunk, math.sqrt and math.abs can be called"),
+            });
+        };
+
+        let mut types = vec![];
+        for arg in args {
+            types.push(self.expr(arg)?);
+        }
+        match callee {
+            Callee::Func(func) => {
+                let name = self.chunk.name(func);
+                let params = self.program[func].params.len();
+                if self.program[func].var_args {
+                    return Err(self.error(format!("'{}' takes '...' which is not supported", name)));
+                }
+                if args.len() != params {
+                    return Err(self.error(format!("'{}' takes {} arguments, not {}", name, params, args.len())));
+                }
+                let Some(types) = types.into_iter().collect::<Option<Vec<_>>>() else {
+                    return Ok(None);
+                };
+                let spec = self.specs.request(func, types);
+                match self.specs[spec].ret {
+                    Ret::Unknown => Ok(None),
+                    Ret::Nothing if value => Err(self.error(format!("'{}' returns nothing", name))),
+                    Ret::Nothing => Ok(None),
+                    Ret::Value(ty) => Ok(Some(ty)),
+                }
+            }
+            Callee::Sqrt | Callee::Abs => {
+                let [ty] = types.as_slice() else {
+                    return Err(self.error("math.sqrt and math.abs take one argument"));
+                };
+                self.arith(*ty)?;
+                Ok(if callee == Callee::Sqrt { Some(Ty::Float) } else { *ty })
+            }
+        }
+    }
+}
+
+/// Whether the loop is left with `break`, the ones of the nested loops do not count.
+fn breaks(block: &Block) -> bool {
+    block.iter().any(|st| match &st.kind {
+        Stmt::Break => true,
+        Stmt::Do(block) => breaks(block),
+        Stmt::If(Expr::Bool(true), then, _) => breaks(then),
+        Stmt::If(_, then, otherwise) => breaks(then) || breaks(otherwise),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::hir::Program;
+    use crate::jit::types::{infer, Ret, Specs, Ty};
+    use crate::jit::Chunk;
+    use crate::parse_chunk;
+
+    fn infer_ret(src: &str, name: &str, params: Vec<Ty>) -> (Ret, HashMap<String, Ty>) {
+        let program = Program::new(&parse_chunk(src).unwrap());
+        let chunk = Chunk::new(&program).unwrap();
+        let mut specs = Specs::default();
+        let spec = specs.request(chunk.functions[name], params);
+        infer(&program, &chunk, &mut specs, 0).unwrap();
+        let vars = specs[spec].vars.iter().map(|(var, ty)| (program[*var].name.to_string(), *ty)).collect();
+        (specs[spec].ret, vars)
+    }
+
+    #[test]
+    fn recursion() {
+        let src = "function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end";
+        assert_eq!(infer_ret(src, "fib", vec![Ty::Int]).0, Ret::Value(Ty::Int));
+        assert_eq!(infer_ret(src, "fib", vec![Ty::Float]).0, Ret::Value(Ty::Float));
+
+        let src = r#"
+            local function even(n) if n == 0 then return true end return odd(n - 1) end
+            function odd(n) if n == 0 then return false end return even(n - 1) end
+        "#;
+        assert_eq!(infer_ret(src, "odd", vec![Ty::Int]).0, Ret::Value(Ty::Bool));
+    }
+
+    #[test]
+    fn locals() {
+        let src = r#"
+            function f(n)
+                local half, quarter = n / 2, n // 4
+                for i = 1, n do quarter = quarter + i end
+                for x = 0, 1, 0.5 do half = half + x end
+                return
+            end
+        "#;
+        let (ret, vars) = infer_ret(src, "f", vec![Ty::Int]);
+        assert_eq!(ret, Ret::Nothing);
+        let expected = [("n", Ty::Int), ("half", Ty::Float), ("quarter", Ty::Int), ("i", Ty::Int), ("x", Ty::Float)];
+        assert_eq!(vars, expected.into_iter().map(|(n, ty)| (n.to_string(), ty)).collect());
+    }
+
+    #[test]
+    fn reached() {
+        let src = "function f(n) if n > 0 then return 1 else return -1 end end";
+        assert_eq!(infer_ret(src, "f", vec![Ty::Int]).0, Ret::Value(Ty::Int));
+        let src = "function f(n) while true do return n end end";
+        assert_eq!(infer_ret(src, "f", vec![Ty::Float]).0, Ret::Value(Ty::Float));
+    }
+}
@@ -0,0 +1,23 @@
+//! A Lua frontend that is going to lower the source into cranelift
+//! [IR](https://github.com/bytecodealliance/wasmtime/blob/main/cranelift/docs/ir.md).
+//! `jit` compiles the numeric functions already and runs them as machine code.
+//!
+//! The crate exposes the lexer, the parser and the ast of Lua:
+//!
+//! ```
+//! use cran_lua::parse_chunk;
+//!
+//! let block = parse_chunk("local a = 1 + 2").unwrap();
+//! println!("{}", block);
+//! ```
+pub mod parser;
+pub mod hir;
+pub mod jit;
+
+pub use parser::{lexer, parse_chunk, parse_chunk_arena, parse_chunk_recovering, parse_chunk_recovering_with, parse_chunk_with, parse_cst, parse_expr, parse_statement};
+pub use parser::arena::Ast;
+pub use parser::dialect::{Dialect, Version};
+pub use parser::diff::unified_diff;
+pub use parser::format::{format_chunk, CallParentheses, Config, IndentType, QuoteStyle};
+pub use parser::sexp::ToSexp;
+pub use parser::tokens::Token;
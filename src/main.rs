@@ -1,5 +1,248 @@
-mod parser;
+use std::process::exit;
+use std::str::FromStr;
+use cran_lua::hir::Program;
+use cran_lua::parser::check::check;
+use cran_lua::parser::error::SyntaxError;
+use cran_lua::{format_chunk, parse_chunk_recovering_with, parse_chunk_with, unified_diff, Ast, Config, Dialect, ToSexp};
+
+const USAGE: &str = "usage: cran_lua parse [--json] [--dialect 5.1|5.2|5.3|5.4|luajit] <file.lua>
+       cran_lua fmt [--check | --diff] [--dialect <version>] [--indent-type spaces|tabs] [--indent-width <n>]
+                    [--column-width <n>] [--quote-style auto-prefer-double|auto-prefer-single|force-double|force-single]
+                    [--call-parentheses always|no-single-string|no-single-table|none|input] <file.lua>...
+       cran_lua dump-ast [--format json|sexp] [--dialect <version>] <file.lua>
+       cran_lua dump-hir [--dialect <version>] <file.lua>";
+
+/// What `fmt` does with the files that are not formatted.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Write,
+    /// Lists the files, for CI.
+    Check,
+    /// Prints the unified diff of the files, for CI.
+    Diff,
+}
+
+/// The output of `dump-ast`.
+#[derive(Clone, Copy, PartialEq)]
+enum DumpFormat {
+    /// Requires the `serde` feature.
+    Json,
+    Sexp,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "sexp" => Ok(DumpFormat::Sexp),
+            _ => Err(format!("unknown format '{}', expected one of json, sexp", s)),
+        }
+    }
+}
+
+/// The options of all subcommands, every subcommand accepts only its own flags.
+struct Options<'a> {
+    json: bool,
+    format: DumpFormat,
+    mode: Mode,
+    dialect: Dialect,
+    config: Config,
+    files: Vec<&'a str>,
+}
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        ["parse", args @ ..] => {
+            let opts = options(args, &["--json", "--dialect"]);
+            parse(single(&opts.files), opts.json, opts.dialect)
+        }
+        ["fmt", args @ ..] => {
+            let opts = options(
+                args,
+                &[
+                    "--check",
+                    "--diff",
+                    "--dialect",
+                    "--indent-type",
+                    "--indent-width",
+                    "--column-width",
+                    "--quote-style",
+                    "--call-parentheses",
+                ],
+            );
+            fmt(&opts.files, opts.mode, opts.dialect, &opts.config)
+        }
+        ["dump-ast", args @ ..] => {
+            let opts = options(args, &["--format", "--dialect"]);
+            dump_ast(single(&opts.files), opts.format, opts.dialect)
+        }
+        ["dump-hir", args @ ..] => {
+            let opts = options(args, &["--dialect"]);
+            dump_hir(single(&opts.files), opts.dialect)
+        }
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
+}
+
+/// Parses the flags in `allowed` and the files, at least one.
+fn options<'a>(args: &[&'a str], allowed: &[&str]) -> Options<'a> {
+    let mut opts = Options {
+        json: false,
+        format: DumpFormat::Sexp,
+        mode: Mode::Write,
+        dialect: Dialect::default(),
+        config: Config::default(),
+        files: vec![],
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            opt if opt.starts_with("--") && !allowed.contains(&opt) => usage(),
+            "--json" => opts.json = true,
+            "--format" => opts.format = value(args.next()),
+            "--check" => opts.mode = Mode::Check,
+            "--diff" => opts.mode = Mode::Diff,
+            "--dialect" => opts.dialect = Dialect::new(value(args.next())),
+            "--indent-type" => opts.config.indent_type = value(args.next()),
+            "--indent-width" => opts.config.indent_width = value(args.next()),
+            "--column-width" => opts.config.column_width = value(args.next()),
+            "--quote-style" => opts.config.quote_style = value(args.next()),
+            "--call-parentheses" => opts.config.call_parentheses = value(args.next()),
+            file => opts.files.push(file),
+        }
+    }
+    if opts.files.is_empty() {
+        usage()
+    }
+    opts
+}
+
+/// The only file of the subcommand.
+fn single<'a>(files: &[&'a str]) -> &'a str {
+    match files {
+        [file] => file,
+        _ => usage(),
+    }
+}
+
+fn value<T: FromStr>(opt: Option<&&str>) -> T where T::Err: ToString {
+    opt.unwrap_or_else(|| usage()).parse().unwrap_or_else(|e: T::Err| {
+        eprintln!("{}", e.to_string());
+        exit(2)
+    })
+}
+
+fn fmt(files: &[&str], mode: Mode, dialect: Dialect, config: &Config) {
+    let mut failed = false;
+    for file in files {
+        let src = read(file);
+        let formatted = match format_chunk(&src, dialect, config) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprint!("{}", e.with_file(file).render(&src));
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == src {
+            continue;
+        }
+        match mode {
+            Mode::Write => std::fs::write(file, formatted).unwrap_or_else(|e| {
+                eprintln!("can not write {}: {}", file, e);
+                exit(1)
+            }),
+            Mode::Check => {
+                println!("would reformat {}", file);
+                failed = true;
+            }
+            Mode::Diff => {
+                print!("{}", unified_diff(file, &src, &formatted));
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        exit(1)
+    }
+}
+
+fn read(file: &str) -> String {
+    std::fs::read_to_string(file).unwrap_or_else(|e| {
+        eprintln!("can not read {}: {}", file, e);
+        exit(1)
+    })
+}
+
+fn parse(file: &str, json: bool, dialect: Dialect) {
+    let src = read(file);
+
+    let (block, mut errors) = parse_chunk_recovering_with(&src, dialect);
+    if errors.is_empty() {
+        errors = check(&Ast::new(&block));
+    }
+    if errors.is_empty() {
+        println!("{}", block);
+        return;
+    }
+    for e in errors {
+        let e = e.with_file(file);
+        if json {
+            print_json(&e);
+        } else {
+            eprint!("{}", e.render(&src));
+        }
+    }
+    exit(1)
+}
+
+/// The error along with its message as one line of json.
+#[cfg(feature = "serde")]
+fn print_json(e: &SyntaxError) {
+    let mut json = serde_json::to_value(e).expect("the error is serializable");
+    json["message"] = e.message().into();
+    println!("{}", json)
+}
+
+#[cfg(not(feature = "serde"))]
+fn print_json(_: &SyntaxError) {
+    eprintln!("--json requires cran_lua built with the serde feature");
+    exit(2)
+}
+
+fn dump_ast(file: &str, format: DumpFormat, dialect: Dialect) {
+    let src = read(file);
+    let block = parse_chunk_with(&src, dialect).unwrap_or_else(|e| {
+        eprint!("{}", e.with_file(file).render(&src));
+        exit(1)
+    });
+    match format {
+        DumpFormat::Sexp => println!("{}", block.to_sexp()),
+        #[cfg(feature = "serde")]
+        DumpFormat::Json => println!("{}", serde_json::to_string_pretty(&block).expect("the ast is serializable")),
+        #[cfg(not(feature = "serde"))]
+        DumpFormat::Json => {
+            eprintln!("--format json requires cran_lua built with the serde feature");
+            exit(2)
+        }
+    }
+}
+
+fn dump_hir(file: &str, dialect: Dialect) {
+    let src = read(file);
+    let block = parse_chunk_with(&src, dialect).unwrap_or_else(|e| {
+        eprint!("{}", e.with_file(file).render(&src));
+        exit(1)
+    });
+    print!("{}", Program::new(&block))
 }
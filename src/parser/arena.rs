@@ -0,0 +1,1178 @@
+use std::borrow::Cow;
+use std::iter::once;
+use std::marker::PhantomData;
+use std::mem::take;
+use std::ops::Index;
+use crate::parser::ast;
+use crate::parser::ast::{BinaryType, Id, Number, UnaryType};
+use crate::parser::build::{Build, Head, Suffixed};
+use crate::parser::dialect::Dialect;
+use crate::parser::error::SyntaxError;
+use crate::parser::span::{Span, Spanned};
+use crate::parser::LuaParser;
+
+/// The index of a node in its arena.
+pub trait Idx: Copy {
+    fn from_index(index: usize) -> Self;
+    fn index(self) -> usize;
+}
+
+macro_rules! ids {
+    ($($(#[$doc:meta])* $name:ident),*) => {$(
+        $(#[$doc])*
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(u32);
+
+        impl Idx for $name {
+            fn from_index(index: usize) -> Self {
+                $name(u32::try_from(index).expect("too many nodes in the arena"))
+            }
+
+            fn index(self) -> usize {
+                self.0 as usize
+            }
+        }
+    )*};
+}
+
+pub(crate) use ids;
+
+ids!(
+    /// The expression in `Ast::exprs`.
+    ExprId,
+    /// The statement in `Ast::stmts`.
+    StmtId,
+    /// The block in `Ast::blocks`.
+    BlockId,
+    /// The function (the body with the parameters) in `Ast::funcs`.
+    FuncId
+);
+
+/// The node with its location in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<T> {
+    pub kind: T,
+    pub span: Span,
+}
+
+impl<T> Spanned for Node<T> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// The slice of one of the pools of the arena, `&ast[list]` gives the elements.
+#[derive(Debug)]
+pub struct List<T> {
+    start: u32,
+    len: u32,
+    elem: PhantomData<fn() -> T>,
+}
+
+impl<T> List<T> {
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn range(&self) -> std::ops::Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
+
+impl<T> Clone for List<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for List<T> {}
+
+impl<T> PartialEq for List<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start && self.len == other.len
+    }
+}
+
+/// The expression of the arena. The prefix expressions are flattened into the chains
+/// of the suffixes applied to a name or an expression in parens, so `a.b:c(1)` is
+/// `Method(Field(Name(a), b), c, (1))`.
+/// The `suffix` is the span of the suffix alone (`.b`, `[k]`, `:c(1)`, `(1)`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr<'a> {
+    Nil,
+    False,
+    True,
+    Number(Number),
+    Text(Cow<'a, [u8]>),
+    VarArgs,
+    Function(FuncId),
+    Table(List<Field<'a>>),
+    Name(Id<'a>),
+    Paren(ExprId),
+    Field { obj: ExprId, name: Id<'a>, suffix: Span },
+    Index { obj: ExprId, key: ExprId, suffix: Span },
+    Call { callee: ExprId, args: Args, suffix: Span },
+    Method { obj: ExprId, name: Id<'a>, args: Args, suffix: Span },
+    Unary(UnaryType, ExprId),
+    Binary(ExprId, BinaryType, ExprId),
+    Error,
+}
+
+/// The arguments of a call: the expressions in parens (with the span of the parens),
+/// the table and the string are the `Table` and `Text` expressions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Args {
+    List(List<ExprId>, Span),
+    Table(ExprId),
+    String(ExprId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field<'a> {
+    Named(Id<'a>, ExprId),
+    Keyed(ExprId, ExprId),
+    Positional(ExprId),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stmt<'a> {
+    Empty,
+    Assign { targets: List<ExprId>, values: List<ExprId> },
+    Call(ExprId),
+    Label(Id<'a>),
+    Break,
+    Goto(Id<'a>),
+    Do(BlockId),
+    While { cond: ExprId, body: BlockId },
+    Repeat { body: BlockId, until: ExprId },
+    /// `if`, every `elseif` and the `else` block.
+    If { branches: List<Branch>, otherwise: Option<BlockId> },
+    NumericFor { var: Id<'a>, start: ExprId, limit: ExprId, step: Option<ExprId>, body: BlockId },
+    GenericFor { names: List<Id<'a>>, exprs: List<ExprId>, body: BlockId },
+    Function { name: FuncName<'a>, func: FuncId },
+    LocalFunction { name: Id<'a>, func: FuncId },
+    Local { names: List<Local<'a>>, values: List<ExprId> },
+    Error,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Branch {
+    pub cond: ExprId,
+    pub body: BlockId,
+    pub span: Span,
+}
+
+/// `a.b.c:m` of `function a.b.c:m() end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName<'a> {
+    pub path: List<Id<'a>>,
+    pub method: Option<Id<'a>>,
+    pub span: Span,
+}
+
+/// The local name with the optional attribute (`<const>`, `<close>`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local<'a> {
+    pub name: Id<'a>,
+    pub attr: Option<Id<'a>>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Block {
+    pub stmts: List<StmtId>,
+    /// The expressions of the final `return`.
+    pub ret: Option<List<ExprId>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Func<'a> {
+    pub params: List<Id<'a>>,
+    pub var_args: bool,
+    pub body: BlockId,
+}
+
+/// The ast where every node lives in a flat arena and refers to its children by ids.
+///
+/// The parser builds it right away (`Ast::parse`, `parse_chunk_arena`) without boxing a node,
+/// or it is copied from the tree (`Ast::new`).
+/// Cloning it copies a handful of vectors instead of a box per node, and the ids are dense
+/// so the analyses can keep their results aside in `IdMap`s. `Ast::to_block` gives the tree back.
+/// The children are allocated before the parent, the root block is the last one.
+#[derive(Debug, Clone)]
+pub struct Ast<'a> {
+    exprs: Vec<Node<Expr<'a>>>,
+    stmts: Vec<Node<Stmt<'a>>>,
+    blocks: Vec<Node<Block>>,
+    funcs: Vec<Node<Func<'a>>>,
+    expr_lists: Vec<ExprId>,
+    stmt_lists: Vec<StmtId>,
+    ids: Vec<Id<'a>>,
+    fields: Vec<Field<'a>>,
+    branches: Vec<Branch>,
+    locals: Vec<Local<'a>>,
+    root: BlockId,
+}
+
+fn alloc<I: Idx, T>(arena: &mut Vec<Node<T>>, kind: T, span: Span) -> I {
+    arena.push(Node { kind, span });
+    I::from_index(arena.len() - 1)
+}
+
+fn list<T>(pool: &mut Vec<T>, items: Vec<T>) -> List<T> {
+    let start = pool.len();
+    pool.extend(items);
+    List { start: start as u32, len: (pool.len() - start) as u32, elem: PhantomData }
+}
+
+fn iter<I: Idx, T>(arena: &[Node<T>]) -> impl Iterator<Item = (I, &Node<T>)> {
+    arena.iter().enumerate().map(|(i, node)| (I::from_index(i), node))
+}
+
+/// The span from the start of the first span to the end of the second one.
+fn until(from: Span, to: Span) -> Span {
+    Span { end: to.end, ..from }
+}
+
+impl<'a> Ast<'a> {
+    /// Parses the chunk written in the given dialect into the arena.
+    pub fn parse(src: &'a str, dialect: Dialect) -> Result<Self, Box<SyntaxError>> {
+        let (root, ast) = LuaParser::with_builder(src, dialect, Ast::empty()).built_chunk()?;
+        Ok(Ast { root, ..ast })
+    }
+
+    /// Copies the parsed tree into the arena.
+    /// The tree does not keep where `:` and `.` stand after a call,
+    /// so the suffixes of the methods and of the fields following a call start at their names.
+    pub fn new(block: &ast::Block<'a>) -> Self {
+        let mut ast = Ast::empty();
+        ast.root = ast.block(block);
+        ast
+    }
+
+    fn empty() -> Self {
+        Ast {
+            exprs: vec![],
+            stmts: vec![],
+            blocks: vec![],
+            funcs: vec![],
+            expr_lists: vec![],
+            stmt_lists: vec![],
+            ids: vec![],
+            fields: vec![],
+            branches: vec![],
+            locals: vec![],
+            root: BlockId(0),
+        }
+    }
+
+    pub fn root(&self) -> BlockId {
+        self.root
+    }
+
+    pub fn exprs(&self) -> impl Iterator<Item = (ExprId, &Node<Expr<'a>>)> {
+        iter(&self.exprs)
+    }
+
+    pub fn stmts(&self) -> impl Iterator<Item = (StmtId, &Node<Stmt<'a>>)> {
+        iter(&self.stmts)
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockId, &Node<Block>)> {
+        iter(&self.blocks)
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = (FuncId, &Node<Func<'a>>)> {
+        iter(&self.funcs)
+    }
+
+    /// The tree of the arena.
+    pub fn to_block(&self) -> ast::Block<'a> {
+        self.raise_block(self.root)
+    }
+
+    pub fn to_expression(&self, id: ExprId) -> ast::Expression<'a> {
+        self.raise_expr(id)
+    }
+
+    pub fn to_statement(&self, id: StmtId) -> ast::Statement<'a> {
+        self.raise_stmt(id)
+    }
+
+    fn block(&mut self, block: &ast::Block<'a>) -> BlockId {
+        let (sts, ret) = match block {
+            ast::Block::Void(sts, _) => (sts, None),
+            ast::Block::Return(sts, exprs, _) => (sts, Some(exprs)),
+        };
+        let stmts = sts.iter().map(|st| self.stmt(st)).collect();
+        let stmts = list(&mut self.stmt_lists, stmts);
+        let ret = ret.map(|exprs| self.expr_list(exprs));
+        alloc(&mut self.blocks, Block { stmts, ret }, block.span())
+    }
+
+    fn expr_list(&mut self, exprs: &[ast::Expression<'a>]) -> List<ExprId> {
+        let ids = exprs.iter().map(|e| self.expr(e)).collect();
+        list(&mut self.expr_lists, ids)
+    }
+
+    fn id_list(&mut self, ids: &[Id<'a>]) -> List<Id<'a>> {
+        list(&mut self.ids, ids.to_vec())
+    }
+
+    fn func(&mut self, params: &ast::FnParams<'a>, body: &ast::Block<'a>, span: Span) -> FuncId {
+        let (params, var_args) = match params {
+            ast::FnParams::Args(names) => (self.id_list(names), false),
+            ast::FnParams::VarArgs => (self.id_list(&[]), true),
+            ast::FnParams::WithVarArgs(names) => (self.id_list(names), true),
+        };
+        let body = self.block(body);
+        alloc(&mut self.funcs, Func { params, var_args, body }, span)
+    }
+
+    fn stmt(&mut self, st: &ast::Statement<'a>) -> StmtId {
+        use ast::Statement as S;
+        let kind = match st {
+            S::Empty(_) => Stmt::Empty,
+            S::Assignment(vars, exprs) => {
+                let targets = vars.iter().map(|v| self.var(v)).collect();
+                let targets = list(&mut self.expr_lists, targets);
+                Stmt::Assign { targets, values: self.expr_list(exprs) }
+            }
+            S::FnCall(call) => Stmt::Call(self.call(call)),
+            S::Label(id, _) => Stmt::Label(id.clone()),
+            S::Break(_) => Stmt::Break,
+            S::Goto(id, _) => Stmt::Goto(id.clone()),
+            S::Do(block, _) => Stmt::Do(self.block(block)),
+            S::While(w) => Stmt::While { cond: self.expr(&w.cond), body: self.block(&w.body) },
+            S::Repeat(r) => Stmt::Repeat { body: self.block(&r.body), until: self.expr(&r.until) },
+            S::If(ast::If::If(first, others, _)) => Stmt::If { branches: self.branches(first, others), otherwise: None },
+            S::If(ast::If::IfElse(first, others, otherwise, _)) => {
+                let branches = self.branches(first, others);
+                Stmt::If { branches, otherwise: Some(self.block(otherwise)) }
+            }
+            S::For(ast::For::Plain(f)) => Stmt::NumericFor {
+                var: f.init.0.clone(),
+                start: self.expr(&f.init.1),
+                limit: self.expr(&f.border),
+                step: f.step.as_ref().map(|step| self.expr(step)),
+                body: self.block(&f.body),
+            },
+            S::For(ast::For::ForCol(f)) => Stmt::GenericFor {
+                names: self.id_list(&f.names),
+                exprs: self.expr_list(&f.expressions),
+                body: self.block(&f.body),
+            },
+            S::FnDef(def) => {
+                let path = self.id_list(&def.name.names);
+                let name = FuncName { path, method: def.name.last.clone(), span: def.name.span };
+                Stmt::Function { name, func: self.func(&def.params, &def.body, def.span) }
+            }
+            S::LocalFnDef(def) => Stmt::LocalFunction {
+                name: def.name.names[0].clone(),
+                func: self.func(&def.params, &def.body, def.span),
+            },
+            S::LocalAttrNames(names, exprs, _) => {
+                let names = names
+                    .iter()
+                    .map(|name| match name {
+                        ast::AttrName::Name(name) => Local { name: name.clone(), attr: None },
+                        ast::AttrName::AttrName(name, attr) => Local { name: name.clone(), attr: Some(attr.clone()) },
+                    })
+                    .collect();
+                let names = list(&mut self.locals, names);
+                Stmt::Local { names, values: self.expr_list(exprs) }
+            }
+            S::Error(_) => Stmt::Error,
+        };
+        alloc(&mut self.stmts, kind, st.span())
+    }
+
+    fn branches(&mut self, first: &ast::IfBranch<'a>, others: &[ast::IfBranch<'a>]) -> List<Branch> {
+        let branches = once(first)
+            .chain(others)
+            .map(|b| Branch { cond: self.expr(&b.cond), body: self.block(&b.body), span: b.span })
+            .collect();
+        list(&mut self.branches, branches)
+    }
+
+    fn expr(&mut self, e: &ast::Expression<'a>) -> ExprId {
+        use ast::Expression as E;
+        let kind = match e {
+            E::Nil(_) => Expr::Nil,
+            E::False(_) => Expr::False,
+            E::True(_) => Expr::True,
+            E::Number(n, _) => Expr::Number(*n),
+            E::Text(t) => Expr::Text(t.text.clone()),
+            E::VarArgs(_) => Expr::VarArgs,
+            E::FnDef(params, body, span) => Expr::Function(self.func(params, body, *span)),
+            E::PrefixExpr(call) => return self.call(call),
+            E::TableConstructor(table) => return self.table(table),
+            E::Unary(op, e, _) => Expr::Unary(*op, self.expr(e)),
+            E::Binary(lhs, op, rhs) => Expr::Binary(self.expr(lhs), *op, self.expr(rhs)),
+            E::Error(_) => Expr::Error,
+        };
+        alloc(&mut self.exprs, kind, e.span())
+    }
+
+    fn table(&mut self, table: &ast::TableConst<'a>) -> ExprId {
+        let fields = table
+            .fields
+            .iter()
+            .map(|field| match field {
+                ast::Field::Pair(ast::FieldKey::Id(id), v) => Field::Named(id.clone(), self.expr(v)),
+                ast::Field::Pair(ast::FieldKey::Expr(k), v) => Field::Keyed(self.expr(k), self.expr(v)),
+                ast::Field::Value(v) => Field::Positional(self.expr(v)),
+            })
+            .collect();
+        let fields = list(&mut self.fields, fields);
+        alloc(&mut self.exprs, Expr::Table(fields), table.span)
+    }
+
+    fn call(&mut self, call: &ast::FnCall<'a>) -> ExprId {
+        let mut obj = match &call.head {
+            ast::VarOrExpr::Var(var) => self.var(var),
+            // the parens end where the first suffix starts
+            ast::VarOrExpr::Expr(e) => {
+                let end = call.args.first().map_or(call.span.end, |args| args.span().start);
+                self.paren(e, Span { end, ..call.span })
+            }
+        };
+        for args in &call.args {
+            obj = self.name_args(obj, args, call.span, None);
+        }
+        obj
+    }
+
+    fn var(&mut self, var: &ast::Var<'a>) -> ExprId {
+        let mut obj = match &var.head {
+            ast::VarHead::Id(id) => alloc(&mut self.exprs, Expr::Name(id.clone()), id.span),
+            ast::VarHead::Expr(e, first) => {
+                let paren = self.paren(e, Span { end: first.span.start, ..var.span });
+                self.var_suffix(paren, first, var.span)
+            }
+        };
+        for suffix in &var.tail {
+            obj = self.var_suffix(obj, suffix, var.span);
+        }
+        obj
+    }
+
+    fn paren(&mut self, e: &ast::Expression<'a>, span: Span) -> ExprId {
+        let e = self.expr(e);
+        alloc(&mut self.exprs, Expr::Paren(e), span)
+    }
+
+    /// The calls and the index of the suffix, only the span of the whole suffix
+    /// tells where the punctuation of its first element starts.
+    fn var_suffix(&mut self, mut obj: ExprId, suffix: &ast::VarSuffix<'a>, base: Span) -> ExprId {
+        let mut start = Some(suffix.span);
+        for args in &suffix.var {
+            obj = self.name_args(obj, args, base, start.take());
+        }
+        let own = |span: Span| until(start.unwrap_or(span), suffix.span);
+        let kind = match &suffix.suffix {
+            ast::Suffix::Id(name) => Expr::Field { obj, name: name.clone(), suffix: own(name.span) },
+            ast::Suffix::Expr(key) => {
+                let suffix = own(key.span());
+                Expr::Index { obj, key: self.expr(key), suffix }
+            }
+        };
+        alloc(&mut self.exprs, kind, until(base, suffix.span))
+    }
+
+    fn name_args(&mut self, obj: ExprId, args: &ast::NameArgs<'a>, base: Span, start: Option<Span>) -> ExprId {
+        let kind = match args {
+            ast::NameArgs::Args(args) => {
+                let suffix = args.span();
+                Expr::Call { callee: obj, args: self.args(args), suffix }
+            }
+            ast::NameArgs::NameArgs(name, args) => {
+                let suffix = until(start.unwrap_or(name.span), args.span());
+                Expr::Method { obj, name: name.clone(), args: self.args(args), suffix }
+            }
+        };
+        alloc(&mut self.exprs, kind, until(base, args.span()))
+    }
+
+    fn args(&mut self, args: &ast::Args<'a>) -> Args {
+        match args {
+            ast::Args::Expressions(exprs, span) => Args::List(self.expr_list(exprs), *span),
+            ast::Args::Constructor(table) => Args::Table(self.table(table)),
+            ast::Args::String(text) => Args::String(alloc(&mut self.exprs, Expr::Text(text.text.clone()), text.span)),
+        }
+    }
+
+    fn raise_block(&self, id: BlockId) -> ast::Block<'a> {
+        let Node { kind, span } = &self[id];
+        let sts = self[kind.stmts].iter().map(|st| self.raise_stmt(*st)).collect();
+        match kind.ret {
+            Some(exprs) => ast::Block::Return(sts, self.raise_exprs(exprs), *span),
+            None => ast::Block::Void(sts, *span),
+        }
+    }
+
+    fn raise_exprs(&self, exprs: List<ExprId>) -> Vec<ast::Expression<'a>> {
+        self[exprs].iter().map(|e| self.raise_expr(*e)).collect()
+    }
+
+    fn raise_func(&self, id: FuncId) -> (ast::FnParams<'a>, ast::Block<'a>) {
+        let func = &self[id].kind;
+        let names = self[func.params].to_vec();
+        let params = match (func.var_args, names.is_empty()) {
+            (false, _) => ast::FnParams::Args(names),
+            (true, true) => ast::FnParams::VarArgs,
+            (true, false) => ast::FnParams::WithVarArgs(names),
+        };
+        (params, self.raise_block(func.body))
+    }
+
+    fn raise_stmt(&self, id: StmtId) -> ast::Statement<'a> {
+        use ast::Statement as S;
+        let Node { kind, span } = &self[id];
+        let span = *span;
+        match kind {
+            Stmt::Empty => S::Empty(span),
+            Stmt::Assign { targets, values } => {
+                let vars = self[*targets].iter().map(|t| self.raise_var(*t)).collect();
+                S::Assignment(vars, self.raise_exprs(*values))
+            }
+            Stmt::Call(call) => S::FnCall(self.raise_call(*call)),
+            Stmt::Label(id) => S::Label(id.clone(), span),
+            Stmt::Break => S::Break(span),
+            Stmt::Goto(id) => S::Goto(id.clone(), span),
+            Stmt::Do(block) => S::Do(self.raise_block(*block), span),
+            Stmt::While { cond, body } => S::While(ast::While {
+                cond: self.raise_expr(*cond),
+                body: self.raise_block(*body),
+                span,
+            }),
+            Stmt::Repeat { body, until } => S::Repeat(ast::Repeat {
+                until: self.raise_expr(*until),
+                body: self.raise_block(*body),
+                span,
+            }),
+            Stmt::If { branches, otherwise } => {
+                let mut branches = self[*branches].iter().map(|b| ast::IfBranch {
+                    cond: self.raise_expr(b.cond),
+                    body: self.raise_block(b.body),
+                    span: b.span,
+                });
+                let first = branches.next().expect("if has at least one branch");
+                let others = branches.collect();
+                S::If(match otherwise {
+                    Some(block) => ast::If::IfElse(first, others, self.raise_block(*block), span),
+                    None => ast::If::If(first, others, span),
+                })
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => S::For(ast::For::Plain(ast::PlainFor {
+                init: (var.clone(), self.raise_expr(*start)),
+                border: self.raise_expr(*limit),
+                step: step.map(|step| self.raise_expr(step)),
+                body: self.raise_block(*body),
+                span,
+            })),
+            Stmt::GenericFor { names, exprs, body } => S::For(ast::For::ForCol(ast::ExprFor {
+                names: self[*names].to_vec(),
+                expressions: self.raise_exprs(*exprs),
+                body: self.raise_block(*body),
+                span,
+            })),
+            Stmt::Function { name, func } => {
+                let (params, body) = self.raise_func(*func);
+                let name = ast::FnName { names: self[name.path].to_vec(), last: name.method.clone(), span: name.span };
+                S::FnDef(ast::FnDef { name, params, body, span })
+            }
+            Stmt::LocalFunction { name, func } => {
+                let (params, body) = self.raise_func(*func);
+                let name = ast::FnName { names: vec![name.clone()], last: None, span: name.span };
+                S::LocalFnDef(ast::FnDef { name, params, body, span })
+            }
+            Stmt::Local { names, values } => {
+                let names = self[*names]
+                    .iter()
+                    .map(|local| match &local.attr {
+                        Some(attr) => ast::AttrName::AttrName(local.name.clone(), attr.clone()),
+                        None => ast::AttrName::Name(local.name.clone()),
+                    })
+                    .collect();
+                S::LocalAttrNames(names, self.raise_exprs(*values), span)
+            }
+            Stmt::Error => S::Error(span),
+        }
+    }
+
+    fn raise_expr(&self, id: ExprId) -> ast::Expression<'a> {
+        use ast::Expression as E;
+        let Node { kind, span } = &self[id];
+        let span = *span;
+        match kind {
+            Expr::Nil => E::Nil(span),
+            Expr::False => E::False(span),
+            Expr::True => E::True(span),
+            Expr::Number(n) => E::Number(*n, span),
+            Expr::Text(text) => E::Text(ast::Text { text: text.clone(), span }),
+            Expr::VarArgs => E::VarArgs(span),
+            Expr::Function(func) => {
+                let (params, body) = self.raise_func(*func);
+                E::FnDef(params, body, span)
+            }
+            Expr::Table(_) => E::TableConstructor(self.raise_table(id)),
+            Expr::Unary(op, e) => E::Unary(*op, Box::new(self.raise_expr(*e)), span),
+            Expr::Binary(lhs, op, rhs) => {
+                E::Binary(Box::new(self.raise_expr(*lhs)), *op, Box::new(self.raise_expr(*rhs)))
+            }
+            Expr::Name(_)
+            | Expr::Paren(_)
+            | Expr::Field { .. }
+            | Expr::Index { .. }
+            | Expr::Call { .. }
+            | Expr::Method { .. } => E::PrefixExpr(Box::new(self.raise_call(id))),
+            Expr::Error => E::Error(span),
+        }
+    }
+
+    fn raise_table(&self, id: ExprId) -> ast::TableConst<'a> {
+        let Node { kind: Expr::Table(fields), span } = &self[id] else {
+            unreachable!("the table arguments are tables")
+        };
+        let fields = self[*fields]
+            .iter()
+            .map(|field| match field {
+                Field::Named(name, v) => ast::Field::Pair(ast::FieldKey::Id(name.clone()), self.raise_expr(*v)),
+                Field::Keyed(k, v) => ast::Field::Pair(ast::FieldKey::Expr(self.raise_expr(*k)), self.raise_expr(*v)),
+                Field::Positional(v) => ast::Field::Value(self.raise_expr(*v)),
+            })
+            .collect();
+        ast::TableConst { fields, span: *span }
+    }
+
+    fn raise_args(&self, args: Args) -> ast::Args<'a> {
+        match args {
+            Args::List(exprs, span) => ast::Args::Expressions(self.raise_exprs(exprs), span),
+            Args::Table(table) => ast::Args::Constructor(self.raise_table(table)),
+            Args::String(text) => {
+                let Node { kind: Expr::Text(text), span } = &self[text] else {
+                    unreachable!("the string arguments are strings")
+                };
+                ast::Args::String(ast::Text { text: text.clone(), span: *span })
+            }
+        }
+    }
+
+    fn raise_name_args(&self, id: ExprId) -> ast::NameArgs<'a> {
+        match &self[id].kind {
+            Expr::Call { args, .. } => ast::NameArgs::Args(self.raise_args(*args)),
+            Expr::Method { name, args, .. } => ast::NameArgs::NameArgs(name.clone(), self.raise_args(*args)),
+            _ => unreachable!("only the calls are the arguments"),
+        }
+    }
+
+    /// Groups the chain of the suffixes back into the var and the trailing calls:
+    /// the calls before the last index belong to the var.
+    fn raise_call(&self, id: ExprId) -> ast::FnCall<'a> {
+        let mut chain = vec![];
+        let mut base = id;
+        while let Expr::Field { obj, .. } | Expr::Index { obj, .. } | Expr::Call { callee: obj, .. } | Expr::Method { obj, .. } =
+            &self[base].kind
+        {
+            chain.push(base);
+            base = *obj;
+        }
+        chain.reverse();
+        let var_len = chain
+            .iter()
+            .rposition(|el| matches!(self[*el].kind, Expr::Field { .. } | Expr::Index { .. }))
+            .map_or(0, |i| i + 1);
+
+        let mut tail = vec![];
+        let mut calls = vec![];
+        let mut start = None;
+        for el in &chain[..var_len] {
+            let suffix = match &self[*el].kind {
+                Expr::Field { name, suffix, .. } => (ast::Suffix::Id(name.clone()), *suffix),
+                Expr::Index { key, suffix, .. } => (ast::Suffix::Expr(self.raise_expr(*key)), *suffix),
+                Expr::Call { suffix, .. } | Expr::Method { suffix, .. } => {
+                    start.get_or_insert(*suffix);
+                    calls.push(self.raise_name_args(*el));
+                    continue;
+                }
+                _ => unreachable!("the chain holds only the suffixes"),
+            };
+            let span = until(start.take().unwrap_or(suffix.1), suffix.1);
+            tail.push(ast::VarSuffix { var: take(&mut calls), suffix: suffix.0, span });
+        }
+        let args = chain[var_len..].iter().map(|el| self.raise_name_args(*el)).collect();
+
+        let var_span = chain[..var_len].last().map_or(self[base].span, |el| self[*el].span);
+        let head = match &self[base].kind {
+            Expr::Name(name) => {
+                ast::VarOrExpr::Var(ast::Var { head: ast::VarHead::Id(name.clone()), tail, span: var_span })
+            }
+            kind => {
+                let e = match kind {
+                    Expr::Paren(e) => self.raise_expr(*e),
+                    _ => self.raise_expr(base),
+                };
+                if tail.is_empty() {
+                    ast::VarOrExpr::Expr(e)
+                } else {
+                    let first = tail.remove(0);
+                    ast::VarOrExpr::Var(ast::Var { head: ast::VarHead::Expr(e, first), tail, span: var_span })
+                }
+            }
+        };
+        ast::FnCall { head, args, span: self[id].span }
+    }
+
+    fn raise_var(&self, id: ExprId) -> ast::Var<'a> {
+        match self.raise_call(id) {
+            ast::FnCall { head: ast::VarOrExpr::Var(var), args, .. } if args.is_empty() => var,
+            _ => panic!("the target of the assignment is not a variable"),
+        }
+    }
+}
+
+/// The parser fills the arena directly, the spans of the suffixes come from their tokens.
+impl<'a> Build<'a> for Ast<'a> {
+    type Expr = ExprId;
+    type Stmt = StmtId;
+    type Block = BlockId;
+    type Table = ExprId;
+    type Field = Field<'a>;
+    type Args = Args;
+    type Call = (Option<Id<'a>>, Args);
+    type Prefix = ExprId;
+    type Var = ExprId;
+    type Branch = Branch;
+
+    fn leaf(&mut self, e: ast::Expression<'a>) -> ExprId {
+        use ast::Expression as E;
+        let (kind, span) = match e {
+            E::Nil(span) => (Expr::Nil, span),
+            E::False(span) => (Expr::False, span),
+            E::True(span) => (Expr::True, span),
+            E::Number(n, span) => (Expr::Number(n), span),
+            E::Text(t) => (Expr::Text(t.text), t.span),
+            E::VarArgs(span) => (Expr::VarArgs, span),
+            E::Error(span) => (Expr::Error, span),
+            _ => unreachable!("the leaves have no children"),
+        };
+        alloc(&mut self.exprs, kind, span)
+    }
+
+    fn function(&mut self, params: ast::FnParams<'a>, body: BlockId, span: Span) -> ExprId {
+        let func = self.built_func(params, body, span);
+        alloc(&mut self.exprs, Expr::Function(func), span)
+    }
+
+    fn table_expr(&mut self, table: ExprId) -> ExprId {
+        table
+    }
+
+    fn prefix_expr(&mut self, prefix: ExprId) -> ExprId {
+        prefix
+    }
+
+    fn unary(&mut self, op: UnaryType, e: ExprId, span: Span) -> ExprId {
+        alloc(&mut self.exprs, Expr::Unary(op, e), span)
+    }
+
+    fn binary(&mut self, lhs: ExprId, op: BinaryType, rhs: ExprId) -> ExprId {
+        let span = until(self[lhs].span, self[rhs].span);
+        alloc(&mut self.exprs, Expr::Binary(lhs, op, rhs), span)
+    }
+
+    fn table(&mut self, fields: Vec<Field<'a>>, span: Span) -> ExprId {
+        let fields = list(&mut self.fields, fields);
+        alloc(&mut self.exprs, Expr::Table(fields), span)
+    }
+
+    fn named_field(&mut self, name: Id<'a>, value: ExprId) -> Field<'a> {
+        Field::Named(name, value)
+    }
+
+    fn keyed_field(&mut self, key: ExprId, value: ExprId) -> Field<'a> {
+        Field::Keyed(key, value)
+    }
+
+    fn value_field(&mut self, value: ExprId) -> Field<'a> {
+        Field::Positional(value)
+    }
+
+    fn list_args(&mut self, exprs: Vec<ExprId>, span: Span) -> Args {
+        Args::List(list(&mut self.expr_lists, exprs), span)
+    }
+
+    fn table_args(&mut self, table: ExprId) -> Args {
+        Args::Table(table)
+    }
+
+    fn string_args(&mut self, text: ast::Text<'a>) -> Args {
+        Args::String(alloc(&mut self.exprs, Expr::Text(text.text), text.span))
+    }
+
+    fn call(&mut self, method: Option<Id<'a>>, args: Args) -> (Option<Id<'a>>, Args) {
+        (method, args)
+    }
+
+    fn prefix(&mut self, head: Head<'a, ExprId>, suffixes: Vec<(Suffixed<'a, Self>, Span)>, span: Span) -> ExprId {
+        let mut obj = match head {
+            Head::Name(name) => {
+                let span = name.span;
+                alloc(&mut self.exprs, Expr::Name(name), span)
+            }
+            Head::Parens(e, span) => alloc(&mut self.exprs, Expr::Paren(e), span),
+        };
+        for (suffixed, suffix) in suffixes {
+            let kind = match suffixed {
+                Suffixed::Field(name) => Expr::Field { obj, name, suffix },
+                Suffixed::Index(key) => Expr::Index { obj, key, suffix },
+                Suffixed::Call((None, args)) => Expr::Call { callee: obj, args, suffix },
+                Suffixed::Call((Some(name), args)) => Expr::Method { obj, name, args, suffix },
+            };
+            obj = alloc(&mut self.exprs, kind, until(span, suffix));
+        }
+        obj
+    }
+
+    fn is_call(&self, prefix: &ExprId) -> bool {
+        matches!(self[*prefix].kind, Expr::Call { .. } | Expr::Method { .. })
+    }
+
+    fn var(&mut self, prefix: ExprId) -> Option<ExprId> {
+        matches!(self[prefix].kind, Expr::Name(_) | Expr::Field { .. } | Expr::Index { .. }).then_some(prefix)
+    }
+
+    fn leaf_stmt(&mut self, st: ast::Statement<'a>) -> StmtId {
+        use ast::Statement as S;
+        let (kind, span) = match st {
+            S::Empty(span) => (Stmt::Empty, span),
+            S::Break(span) => (Stmt::Break, span),
+            S::Label(id, span) => (Stmt::Label(id), span),
+            S::Goto(id, span) => (Stmt::Goto(id), span),
+            S::Error(span) => (Stmt::Error, span),
+            _ => unreachable!("the leaves have no children"),
+        };
+        alloc(&mut self.stmts, kind, span)
+    }
+
+    fn call_stmt(&mut self, prefix: ExprId) -> StmtId {
+        let span = self[prefix].span;
+        alloc(&mut self.stmts, Stmt::Call(prefix), span)
+    }
+
+    fn assignment(&mut self, vars: Vec<ExprId>, exprs: Vec<ExprId>, span: Span) -> StmtId {
+        let targets = list(&mut self.expr_lists, vars);
+        let values = list(&mut self.expr_lists, exprs);
+        alloc(&mut self.stmts, Stmt::Assign { targets, values }, span)
+    }
+
+    fn do_s(&mut self, body: BlockId, span: Span) -> StmtId {
+        alloc(&mut self.stmts, Stmt::Do(body), span)
+    }
+
+    fn while_s(&mut self, cond: ExprId, body: BlockId, span: Span) -> StmtId {
+        alloc(&mut self.stmts, Stmt::While { cond, body }, span)
+    }
+
+    fn repeat_s(&mut self, body: BlockId, until: ExprId, span: Span) -> StmtId {
+        alloc(&mut self.stmts, Stmt::Repeat { body, until }, span)
+    }
+
+    fn branch(&mut self, cond: ExprId, body: BlockId, span: Span) -> Branch {
+        Branch { cond, body, span }
+    }
+
+    fn if_s(&mut self, first: Branch, others: Vec<Branch>, otherwise: Option<BlockId>, span: Span) -> StmtId {
+        let branches = list(&mut self.branches, once(first).chain(others).collect());
+        alloc(&mut self.stmts, Stmt::If { branches, otherwise }, span)
+    }
+
+    fn numeric_for(
+        &mut self,
+        var: Id<'a>,
+        start: ExprId,
+        limit: ExprId,
+        step: Option<ExprId>,
+        body: BlockId,
+        span: Span,
+    ) -> StmtId {
+        alloc(&mut self.stmts, Stmt::NumericFor { var, start, limit, step, body }, span)
+    }
+
+    fn generic_for(&mut self, names: Vec<Id<'a>>, exprs: Vec<ExprId>, body: BlockId, span: Span) -> StmtId {
+        let names = list(&mut self.ids, names);
+        let exprs = list(&mut self.expr_lists, exprs);
+        alloc(&mut self.stmts, Stmt::GenericFor { names, exprs, body }, span)
+    }
+
+    fn function_s(&mut self, name: ast::FnName<'a>, params: ast::FnParams<'a>, body: BlockId, span: Span) -> StmtId {
+        let name = FuncName { path: list(&mut self.ids, name.names), method: name.last, span: name.span };
+        let func = self.built_func(params, body, span);
+        alloc(&mut self.stmts, Stmt::Function { name, func }, span)
+    }
+
+    fn local_function(&mut self, name: Id<'a>, params: ast::FnParams<'a>, body: BlockId, span: Span) -> StmtId {
+        let func = self.built_func(params, body, span);
+        alloc(&mut self.stmts, Stmt::LocalFunction { name, func }, span)
+    }
+
+    fn local(&mut self, names: Vec<ast::AttrName<'a>>, exprs: Vec<ExprId>, span: Span) -> StmtId {
+        let names = names
+            .into_iter()
+            .map(|name| match name {
+                ast::AttrName::Name(name) => Local { name, attr: None },
+                ast::AttrName::AttrName(name, attr) => Local { name, attr: Some(attr) },
+            })
+            .collect();
+        let names = list(&mut self.locals, names);
+        let values = list(&mut self.expr_lists, exprs);
+        alloc(&mut self.stmts, Stmt::Local { names, values }, span)
+    }
+
+    fn block(&mut self, stmts: Vec<StmtId>, ret: Option<Vec<ExprId>>, span: Span) -> BlockId {
+        let stmts = list(&mut self.stmt_lists, stmts);
+        let ret = ret.map(|exprs| list(&mut self.expr_lists, exprs));
+        alloc(&mut self.blocks, Block { stmts, ret }, span)
+    }
+}
+
+impl<'a> Ast<'a> {
+    /// The function of the parameters and the body the parser has built.
+    fn built_func(&mut self, params: ast::FnParams<'a>, body: BlockId, span: Span) -> FuncId {
+        let (params, var_args) = match params {
+            ast::FnParams::Args(names) => (names, false),
+            ast::FnParams::VarArgs => (vec![], true),
+            ast::FnParams::WithVarArgs(names) => (names, true),
+        };
+        let params = list(&mut self.ids, params);
+        alloc(&mut self.funcs, Func { params, var_args, body }, span)
+    }
+}
+
+macro_rules! index {
+    ($($id:ty => $arena:ident: $node:ty),*) => {$(
+        impl<'a> Index<$id> for Ast<'a> {
+            type Output = Node<$node>;
+
+            fn index(&self, id: $id) -> &Self::Output {
+                &self.$arena[id.index()]
+            }
+        }
+    )*};
+}
+
+index!(ExprId => exprs: Expr<'a>, StmtId => stmts: Stmt<'a>, BlockId => blocks: Block, FuncId => funcs: Func<'a>);
+
+macro_rules! index_list {
+    ($($elem:ty => $pool:ident),*) => {$(
+        impl<'a> Index<List<$elem>> for Ast<'a> {
+            type Output = [$elem];
+
+            fn index(&self, list: List<$elem>) -> &Self::Output {
+                &self.$pool[list.range()]
+            }
+        }
+    )*};
+}
+
+index_list!(
+    ExprId => expr_lists,
+    StmtId => stmt_lists,
+    Id<'a> => ids,
+    Field<'a> => fields,
+    Branch => branches,
+    Local<'a> => locals
+);
+
+impl<'a> From<&ast::Block<'a>> for Ast<'a> {
+    fn from(block: &ast::Block<'a>) -> Self {
+        Ast::new(block)
+    }
+}
+
+/// The side table keyed by the ids of the nodes: the scopes, the types
+/// or whatever an analysis finds out about the nodes without touching the ast.
+#[derive(Debug, Clone)]
+pub struct IdMap<I, V> {
+    values: Vec<Option<V>>,
+    id: PhantomData<fn(I)>,
+}
+
+impl<I: Idx, V> Default for IdMap<I, V> {
+    fn default() -> Self {
+        IdMap { values: vec![], id: PhantomData }
+    }
+}
+
+impl<I: Idx, V> IdMap<I, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of the node returning the previous one.
+    pub fn insert(&mut self, id: I, value: V) -> Option<V> {
+        if id.index() >= self.values.len() {
+            self.values.resize_with(id.index() + 1, || None);
+        }
+        self.values[id.index()].replace(value)
+    }
+
+    pub fn get(&self, id: I) -> Option<&V> {
+        self.values.get(id.index()).and_then(Option::as_ref)
+    }
+
+    pub fn get_mut(&mut self, id: I) -> Option<&mut V> {
+        self.values.get_mut(id.index()).and_then(Option::as_mut)
+    }
+
+    pub fn remove(&mut self, id: I) -> Option<V> {
+        self.values.get_mut(id.index()).and_then(Option::take)
+    }
+
+    pub fn contains_key(&self, id: I) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// The nodes with values in the order of the ids.
+    pub fn iter(&self) -> impl Iterator<Item = (I, &V)> {
+        self.values
+            .iter()
+            .enumerate()
+            .filter_map(|(i, v)| v.as_ref().map(|v| (I::from_index(i), v)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.iter().filter(|v| v.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(Option::is_none)
+    }
+}
+
+impl<I: Idx, V> Index<I> for IdMap<I, V> {
+    type Output = V;
+
+    fn index(&self, id: I) -> &Self::Output {
+        self.get(id).expect("no value for the node")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::arena::{Args, Ast, Expr, ExprId, IdMap, Stmt};
+    use crate::parser::{parse_chunk, parse_chunk_arena};
+
+    /// Both the copied arena and the one the parser builds give the parsed tree back.
+    fn round_trip(src: &str) {
+        let block = parse_chunk(src).unwrap();
+        let ast = Ast::new(&block);
+        let raised = ast.to_block();
+        assert_eq!(raised, block, "{}", src);
+        assert_eq!(raised.to_string(), block.to_string());
+
+        let parsed = parse_chunk_arena(src).unwrap();
+        assert_eq!(parsed.to_block(), block, "{}", src);
+        assert_eq!(parsed.exprs().count(), ast.exprs().count(), "{}", src);
+        assert_eq!(parsed.blocks().last().unwrap().0, parsed.root());
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let scripts = [
+            include_str!("scripts/lazy.lua"),
+            include_str!("scripts/cassandra.lua"),
+            include_str!("scripts/treesetter.lua"),
+            include_str!("scripts/server.lua"),
+            "a.b[c]:d(1)(2).e = (f)[g] 'x' {y = 1}",
+            "local x <const>, y = ... return (a)(b):c{} , -(x ^ 2)",
+            "function a.b:c(x, ...) return function(...) end end local function f() end",
+            "for i = 1, 2, 3 do break end for k, v in pairs(t) do goto l ::l:: end",
+            "if a then elseif b then else end repeat until x while y do ; end",
+            "((a)).b = ((c))(d) ((e)).f:g()",
+        ];
+        for src in scripts {
+            round_trip(src);
+        }
+    }
+
+    #[test]
+    fn chain_test() {
+        let src = "a.b[c]:d(1)(2)";
+        let block = parse_chunk(src).unwrap();
+        let ast = Ast::new(&block);
+        let Stmt::Call(call) = ast[ast[ast[ast.root()].kind.stmts][0]].kind else { panic!() };
+
+        let mut chain = vec![];
+        let mut id = call;
+        loop {
+            chain.push(&src[ast[id].span.range()]);
+            id = match &ast[id].kind {
+                Expr::Field { obj, .. } | Expr::Index { obj, .. } | Expr::Method { obj, .. } => *obj,
+                Expr::Call { callee, args: Args::List(args, _), suffix } => {
+                    assert_eq!(&src[suffix.range()], "(2)");
+                    assert_eq!(ast[*args].len(), 1);
+                    *callee
+                }
+                _ => break,
+            }
+        }
+        assert_eq!(chain, ["a.b[c]:d(1)(2)", "a.b[c]:d(1)", "a.b[c]", "a.b", "a"]);
+        let suffixes: Vec<_> = ast
+            .exprs()
+            .filter_map(|(_, e)| match &e.kind {
+                Expr::Field { suffix, .. } | Expr::Index { suffix, .. } => Some(&src[suffix.range()]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(suffixes, [".b", "[c]"]);
+
+        // the parser knows where the punctuation of every suffix starts
+        let ast = parse_chunk_arena("a:b().c:d{}").unwrap();
+        let suffixes: Vec<_> = ast.exprs().filter_map(|(_, e)| match &e.kind {
+            Expr::Field { suffix, .. } | Expr::Method { suffix, .. } => Some(suffix.range()),
+            _ => None,
+        }).collect();
+        assert_eq!(suffixes, [1..5, 5..7, 7..11]);
+    }
+
+    #[test]
+    fn parse_error_test() {
+        let src = "x = 1 +";
+        assert_eq!(parse_chunk_arena(src).unwrap_err(), parse_chunk(src).unwrap_err());
+    }
+
+    #[test]
+    fn side_table_test() {
+        let src = "local a = 1 a = a + b print(a)";
+        let ast = Ast::new(&parse_chunk(src).unwrap());
+        let mut names: IdMap<ExprId, &str> = IdMap::new();
+        for (id, node) in ast.exprs() {
+            if let Expr::Name(name) = &node.kind {
+                names.insert(id, name.v.as_str());
+                assert_eq!(&src[node.span.range()], name.v.as_str());
+            }
+        }
+        assert_eq!(names.iter().map(|(_, n)| *n).collect::<Vec<_>>(), ["a", "a", "b", "print", "a"]);
+        assert_eq!(names.len(), 5);
+
+        let (first, _) = names.iter().next().unwrap();
+        assert_eq!(names[first], "a");
+        assert_eq!(names.remove(first), Some("a"));
+        assert!(!names.contains_key(first));
+        assert_eq!(names.len(), 4);
+    }
+
+    #[test]
+    fn clone_test() {
+        let ast = Ast::new(&parse_chunk(include_str!("scripts/server.lua")).unwrap());
+        let copy = ast.clone();
+        assert_eq!(copy.to_block(), ast.to_block());
+        assert_eq!(copy.root(), ast.root());
+        assert_eq!(ast.blocks().last().unwrap().0, ast.root());
+    }
+}
@@ -1,31 +1,95 @@
-use std::collections::HashMap;
-use std::fmt::{Display, Formatter, write};
-use std::iter::Map;
-use BinaryType::*;
-use crate::parser::expression::fold_with_priority;
+// the nodes are kept unboxed, the indirection lives only in the recursive places
+#![allow(clippy::large_enum_variant)]
+
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter, Write};
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+use crate::parser::span::{Span, Spanned};
+use crate::parser::visit::*;
+
+/// The name in the ast: borrowed from the source or shared once the ast is owned.
+#[derive(Debug, Clone)]
+pub enum Name<'a> {
+    Borrowed(&'a str),
+    Shared(Arc<str>),
+}
+
+impl<'a> Name<'a> {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Name::Borrowed(v) => v,
+            Name::Shared(v) => v,
+        }
+    }
+}
+
+impl<'a> Deref for Name<'a> {
+    type Target = str;
 
-trait Show {
-    type Output;
-    fn show(&self) -> Self::Output;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
 }
 
+impl<'a> PartialEq for Name<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
 
-impl<T: Display> Show for Vec<T> {
-    type Output = Vec<String>;
+impl<'a> Eq for Name<'a> {}
 
-    fn show(&self) -> Self::Output {
-        self.iter().map(|el| el.to_string()).collect()
+impl<'a> Hash for Name<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+impl<'a> From<&'a str> for Name<'a> {
+    fn from(v: &'a str) -> Self {
+        Name::Borrowed(v)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'a> serde::Serialize for Name<'a> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// The name is shared after the deserialization since the input is not kept.
+#[cfg(feature = "serde")]
+impl<'de, 'a> serde::Deserialize<'de> for Name<'a> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|v| Name::Shared(v.into()))
+    }
+}
+
+impl<'a> Display for Name<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Id<'a> {
-    pub v: &'a str,
+    pub v: Name<'a>,
+    pub span: Span,
 }
 
 impl<'a> Id<'a> {
     pub fn new(v: &'a str) -> Self {
-        Self { v }
+        Self { v: Name::Borrowed(v), span: Span::default() }
+    }
+}
+
+impl<'a> Spanned for Id<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
@@ -36,6 +100,7 @@ impl<'a> Display for Id<'a> {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Number {
     Int(i64),
     Float(f64),
@@ -46,26 +111,100 @@ pub enum Number {
 impl Display for Number {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
+            // the minimal integer has no positive counterpart to negate
+            Number::Int(i64::MIN) => write!(f, "0x{:x}", i64::MIN),
             Number::Int(v) => write!(f, "{}", v),
-            Number::Float(v) => write!(f, "{}", v),
-            Number::Hex(v) => write!(f, "0x{}", v),
-            Number::Binary(v) => write!(f, "b{}", v),
+            Number::Float(v) if v.is_nan() => write!(f, "(0/0)"),
+            Number::Float(v) if v.is_infinite() => write!(f, "{}1e9999", if *v < 0.0 { "-" } else { "" }),
+            Number::Float(v) => write!(f, "{:?}", v),
+            Number::Hex(v) => write!(f, "0x{:x}", v),
+            Number::Binary(v) => write!(f, "0b{:b}", v),
         }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// The string literal. The `text` is the actual value with the escape sequences decoded,
+/// it borrows the source when there is nothing to decode.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Text<'a> {
-    pub text: &'a str,
+    #[cfg_attr(feature = "serde", serde(with = "text_serde"))]
+    pub text: Cow<'a, [u8]>,
+    pub span: Span,
+}
+
+impl<'a> Text<'a> {
+    pub fn new(text: &'a str) -> Self {
+        Self { text: Cow::Borrowed(text.as_bytes()), span: Span::default() }
+    }
+}
+
+impl<'a> Spanned for Text<'a> {
+    fn span(&self) -> Span {
+        self.span
+    }
+}
+
+/// The value of the string goes as a string when it is utf-8 and as the array of bytes otherwise.
+#[cfg(feature = "serde")]
+mod text_serde {
+    use std::borrow::Cow;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(text: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(text) {
+            Ok(v) => serializer.serialize_str(v),
+            Err(_) => serializer.serialize_bytes(text),
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Str(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn deserialize<'de, 'a, D: Deserializer<'de>>(deserializer: D) -> Result<Cow<'a, [u8]>, D::Error> {
+        Ok(Cow::Owned(match Value::deserialize(deserializer)? {
+            Value::Str(v) => v.into_bytes(),
+            Value::Bytes(v) => v,
+        }))
+    }
+}
+
+impl<'a> Text<'a> {
+    /// The literal in the given quotes with the escape sequences for the special characters.
+    pub(crate) fn write_quoted<W: Write>(&self, f: &mut W, quote: char) -> std::fmt::Result {
+        f.write_char(quote)?;
+        for chunk in self.text.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' => f.write_str("\\\\")?,
+                    '\n' => f.write_str("\\n")?,
+                    '\r' => f.write_str("\\r")?,
+                    '\t' => f.write_str("\\t")?,
+                    c if c == quote => write!(f, "\\{}", c)?,
+                    c if c.is_ascii_control() => write!(f, "\\{:03}", c as u32)?,
+                    c => f.write_char(c)?,
+                }
+            }
+            for b in chunk.invalid() {
+                write!(f, "\\x{:02X}", b)?;
+            }
+        }
+        f.write_char(quote)
+    }
 }
 
 impl<'a> Display for Text<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\"{}\"", self.text)
+        self.write_quoted(f, '"')
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Nil;
 
 impl Display for Nil {
@@ -75,59 +214,57 @@ impl Display for Nil {
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Bool { True, False }
 
 impl Display for Bool {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Bool::True => write!(f, "true"),
+            Bool::False => write!(f, "false"),
+        }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expression<'a> {
-    Nil,
-    False,
-    True,
-    Number(Number),
+    Nil(Span),
+    False(Span),
+    True(Span),
+    Number(Number, Span),
     Text(Text<'a>),
-    VarArgs,
-    FnDef(FnParams<'a>, Block<'a>),
+    VarArgs(Span),
+    FnDef(FnParams<'a>, Block<'a>, Span),
     PrefixExpr(Box<FnCall<'a>>),
     TableConstructor(TableConst<'a>),
-    Unary(UnaryType, Box<Expression<'a>>),
+    Unary(UnaryType, Box<Expression<'a>>, Span),
     Binary(Box<Expression<'a>>, BinaryType, Box<Expression<'a>>),
-
+    /// The placeholder for the expression that failed to parse (only in the recovery mode).
+    Error(Span),
 }
 
-impl<'a> Display for Expression<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for Expression<'a> {
+    fn span(&self) -> Span {
         match self {
-            Expression::Nil => write!(f, "nil"),
-            Expression::False => write!(f, "false"),
-            Expression::True => write!(f, "true"),
-            Expression::Number(n) => write!(f, "{}", n),
-            Expression::Text(t) => write!(f, "{}", t),
-            Expression::VarArgs => write!(f, "..."),
-            Expression::FnDef(params, body) => {
-                writeln!(f, "function {}", params)?;
-                writeln!(f, "{}", body)?;
-                write!(f, "end")
-            }
-            Expression::PrefixExpr(e) => write!(f, "{}", e),
-            Expression::TableConstructor(constr) => write!(f, "{}", constr),
-            Expression::Unary(s, e) => write!(f, "{}{}", s, e),
-            Expression::Binary(lhs, s, rhs) => write!(f, "({} {} {})", lhs, s, rhs),
+            Expression::Nil(s)
+            | Expression::False(s)
+            | Expression::True(s)
+            | Expression::Number(_, s)
+            | Expression::VarArgs(s)
+            | Expression::FnDef(_, _, s)
+            | Expression::Unary(_, _, s)
+            | Expression::Error(s) => *s,
+            Expression::Text(t) => t.span,
+            Expression::PrefixExpr(call) => call.span,
+            Expression::TableConstructor(table) => table.span,
+            Expression::Binary(lhs, _, rhs) => lhs.span().merge(rhs.span()),
         }
     }
 }
 
-impl<'a> Expression<'a> {
-    pub fn fold(first: Expression<'a>, elems: Vec<(BinaryType, Expression<'a>)>) -> Expression<'a> {
-        fold_with_priority(first, elems)
-    }
-}
-
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UnaryType {
     Not,
     Hash,
@@ -137,6 +274,7 @@ pub enum UnaryType {
 
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinaryType {
     Mult,
     Div,
@@ -163,49 +301,46 @@ pub enum BinaryType {
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FieldKey<'a> {
     Expr(Expression<'a>),
     Id(Id<'a>),
 }
 
+impl<'a> Spanned for FieldKey<'a> {
+    fn span(&self) -> Span {
+        match self {
+            FieldKey::Expr(e) => e.span(),
+            FieldKey::Id(id) => id.span,
+        }
+    }
+}
+
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Field<'a> {
     Pair(FieldKey<'a>, Expression<'a>),
     Value(Expression<'a>),
 }
 
-impl<'a> Display for Field<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for Field<'a> {
+    fn span(&self) -> Span {
         match self {
-            Field::Pair(FieldKey::Id(id), e) => write!(f, "{} = {}", id, e),
-            Field::Pair(FieldKey::Expr(e), ev) => write!(f, "[{}] = {}", e, ev),
-            Field::Value(e) => write!(f, "{}", e)
+            Field::Pair(k, v) => k.span().merge(v.span()),
+            Field::Value(v) => v.span(),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FnParams<'a> {
     Args(Vec<Id<'a>>),
     VarArgs,
     WithVarArgs(Vec<Id<'a>>),
 }
 
-impl<'a> Display for FnParams<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match self {
-            FnParams::Args(args) => {
-                write!(f, "({},...)", args.show().join(","))
-            }
-            FnParams::VarArgs => write!(f, "(...)"),
-            FnParams::WithVarArgs(args) => {
-                write!(f, "({},...)", args.show().join(","))
-            }
-        }
-    }
-}
-
 impl<'a> Default for FnParams<'a> {
     fn default() -> Self {
         FnParams::Args(vec![])
@@ -213,367 +348,516 @@ impl<'a> Default for FnParams<'a> {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TableConst<'a> {
     pub fields: Vec<Field<'a>>,
+    pub span: Span,
 }
 
-impl<'a> Display for TableConst<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{{{}}}", self.fields.show().join(","))
+impl<'a> Spanned for TableConst<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Args<'a> {
-    Expressions(Vec<Expression<'a>>),
+    Expressions(Vec<Expression<'a>>, Span),
     Constructor(TableConst<'a>),
     String(Text<'a>),
 }
 
+impl<'a> Spanned for Args<'a> {
+    fn span(&self) -> Span {
+        match self {
+            Args::Expressions(_, s) => *s,
+            Args::Constructor(table) => table.span,
+            Args::String(t) => t.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NameArgs<'a> {
     Args(Args<'a>),
     NameArgs(Id<'a>, Args<'a>),
 }
 
-impl<'a> Display for NameArgs<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let mut match_args = |args: &Args, prefix: &str| -> std::fmt::Result {
-            match args {
-                Args::Expressions(exprs) => {
-                    write!(f, "{} (", prefix)?;
-                    write!(f, "{}", exprs.show().join(","))?;
-                    write!(f, ")")
-                }
-                Args::Constructor(constr) => write!(f, "{} {}", prefix, constr),
-                Args::String(t) => write!(f, "{} {}", prefix, t.text),
-            }
-        };
-
+impl<'a> Spanned for NameArgs<'a> {
+    fn span(&self) -> Span {
         match self {
-            NameArgs::Args(args) => match_args(args, ""),
-            NameArgs::NameArgs(name, args) => {
-                match_args(args, format!(":{}", name).as_str())
-            }
+            NameArgs::Args(args) => args.span(),
+            NameArgs::NameArgs(name, args) => name.span.merge(args.span()),
         }
     }
 }
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VarSuffix<'a> {
     pub var: Vec<NameArgs<'a>>,
     pub suffix: Suffix<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for VarSuffix<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.var.show().join(" "))?;
-        match &self.suffix {
-            Suffix::Expr(e) => write!(f, "[{}]", e),
-            Suffix::Id(id) => write!(f, ".{}", id)
-        }
+impl<'a> Spanned for VarSuffix<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Suffix<'a> {
     Expr(Expression<'a>),
     Id(Id<'a>),
 }
 
+impl<'a> Spanned for Suffix<'a> {
+    fn span(&self) -> Span {
+        match self {
+            Suffix::Expr(e) => e.span(),
+            Suffix::Id(id) => id.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VarHead<'a> {
     Expr(Expression<'a>, VarSuffix<'a>),
     Id(Id<'a>),
 }
 
+impl<'a> Spanned for VarHead<'a> {
+    fn span(&self) -> Span {
+        match self {
+            VarHead::Expr(e, suffix) => e.span().merge(suffix.span),
+            VarHead::Id(id) => id.span,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Var<'a> {
     pub head: VarHead<'a>,
     pub tail: Vec<VarSuffix<'a>>,
+    pub span: Span,
 }
 
-impl<'a> Display for Var<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self.head {
-            VarHead::Expr(e, vs) => write!(f, "({}){}", e, vs)?,
-            VarHead::Id(id) => write!(f, "{}", id)?,
-        }
-        write!(f, "{}", self.tail.show().join(" "))
+impl<'a> Spanned for Var<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VarOrExpr<'a> {
     Expr(Expression<'a>),
     Var(Var<'a>),
 }
 
-impl<'a> Display for VarOrExpr<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        match &self {
-            VarOrExpr::Expr(e) => write!(f, "({})", e),
-            VarOrExpr::Var(v) => write!(f, "{}", v),
+impl<'a> Spanned for VarOrExpr<'a> {
+    fn span(&self) -> Span {
+        match self {
+            VarOrExpr::Expr(e) => e.span(),
+            VarOrExpr::Var(v) => v.span,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FnCall<'a> {
     pub head: VarOrExpr<'a>,
     pub args: Vec<NameArgs<'a>>,
+    pub span: Span,
 }
 
-impl<'a> Display for FnCall<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.head)?;
-        write!(f, "{}", self.args.show().join(""))
+impl<'a> Spanned for FnCall<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FnName<'a> {
     pub names: Vec<Id<'a>>,
     pub last: Option<Id<'a>>,
+    pub span: Span,
 }
 
-impl<'a> Display for FnName<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let args: Vec<String> = self.names.show();
-
-        match self.last {
-            None => write!(f, "{}", args.join(".")),
-            Some(last) => write!(f, "{}:{}", args.join("."), last)
-        }
+impl<'a> Spanned for FnName<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AttrName<'a> {
     Name(Id<'a>),
     AttrName(Id<'a>, Id<'a>),
 }
 
-impl<'a> Display for AttrName<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for AttrName<'a> {
+    fn span(&self) -> Span {
         match self {
-            AttrName::Name(name) => write!(f, "{}", name),
-            AttrName::AttrName(name, attr) => write!(f, "{}<{}>", name, attr)
+            AttrName::Name(name) => name.span,
+            AttrName::AttrName(name, attr) => name.span.merge(attr.span),
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Block<'a> {
-    Void(Vec<Statement<'a>>),
-    Return(Vec<Statement<'a>>, Vec<Expression<'a>>),
+    Void(Vec<Statement<'a>>, Span),
+    Return(Vec<Statement<'a>>, Vec<Expression<'a>>, Span),
 }
 
-impl<'a> Display for Block<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for Block<'a> {
+    fn span(&self) -> Span {
         match self {
-            Block::Void(sts) => {
-                for s in sts.iter() {
-                    writeln!(f, "{}", s)?;
-                }
-                write!(f, ";")
-            }
-            Block::Return(sts, exprs) => {
-                for s in sts.iter() {
-                    writeln!(f, "{}", s)?;
-                }
-                write!(f, "return {}", exprs.show().join(","))
-            }
+            Block::Void(_, s) | Block::Return(_, _, s) => *s,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct While<'a> {
     pub cond: Expression<'a>,
     pub body: Block<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for While<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "while {} do", self.cond)?;
-        writeln!(f, "{}", self.body)?;
-        write!(f, "end")
+impl<'a> Spanned for While<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Repeat<'a> {
     pub until: Expression<'a>,
     pub body: Block<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for Repeat<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "repeat {} do", self.body)?;
-        write!(f, "until {}", self.until)
+impl<'a> Spanned for Repeat<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IfBranch<'a> {
     pub cond: Expression<'a>,
     pub body: Block<'a>,
+    pub span: Span,
+}
+
+impl<'a> Spanned for IfBranch<'a> {
+    fn span(&self) -> Span {
+        self.span
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PlainFor<'a> {
     pub init: (Id<'a>, Expression<'a>),
     pub border: Expression<'a>,
     pub step: Option<Expression<'a>>,
     pub body: Block<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for PlainFor<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let step = self.step.as_ref().map(|e| e.to_string()).unwrap_or_default();
-        writeln!(f, "for {} = {}, {}, {} do", self.init.0, self.init.1, self.border, step)?;
-        writeln!(f, "{}", self.body)?;
-        write!(f, "end")
+impl<'a> Spanned for PlainFor<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExprFor<'a> {
     pub names: Vec<Id<'a>>,
     pub expressions: Vec<Expression<'a>>,
     pub body: Block<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for ExprFor<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "for {} in {}  do",
-                 self.names.show().join(","),
-                 self.expressions.show().join(","))?;
-        writeln!(f, "{}", self.body)?;
-        write!(f, "end")
+impl<'a> Spanned for ExprFor<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum If<'a> {
-    If(IfBranch<'a>, Vec<IfBranch<'a>>),
-    IfElse(IfBranch<'a>, Vec<IfBranch<'a>>, Block<'a>),
+    If(IfBranch<'a>, Vec<IfBranch<'a>>, Span),
+    IfElse(IfBranch<'a>, Vec<IfBranch<'a>>, Block<'a>, Span),
 }
 
-impl<'a> Display for If<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for If<'a> {
+    fn span(&self) -> Span {
         match self {
-            If::If(if_main, ifelses) => {
-                writeln!(f, "if {} then ", if_main.cond)?;
-                writeln!(f, "{} ", if_main.body)?;
-                for other in ifelses.iter() {
-                    writeln!(f, "elseif {} then ", other.cond)?;
-                    writeln!(f, "{} ", other.body)?;
-                }
-            }
-            If::IfElse(if_main, ifelses, else_block) => {
-                writeln!(f, "if {} then ", if_main.cond)?;
-                writeln!(f, "{} ", if_main.body)?;
-                for other in ifelses.iter() {
-                    writeln!(f, "elseif {} then ", other.cond)?;
-                    writeln!(f, "{} ", other.body)?;
-                }
-                writeln!(f, "else ")?;
-                writeln!(f, "{} ", else_block)?;
-            }
+            If::If(_, _, s) | If::IfElse(_, _, _, s) => *s,
         }
-        write!(f, "end")
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum For<'a> {
     Plain(PlainFor<'a>),
     ForCol(ExprFor<'a>),
 }
 
-impl<'a> Display for For<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for For<'a> {
+    fn span(&self) -> Span {
         match self {
-            For::Plain(plain) => write!(f, "{}", plain),
-            For::ForCol(expr_for) => write!(f, "{}", expr_for),
+            For::Plain(plain) => plain.span,
+            For::ForCol(expr_for) => expr_for.span,
         }
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FnDef<'a> {
     pub name: FnName<'a>,
     pub params: FnParams<'a>,
     pub body: Block<'a>,
+    pub span: Span,
 }
 
-impl<'a> Display for FnDef<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "function {}{}", self.name, self.params)?;
-        writeln!(f, "{}", self.body)?;
-        write!(f, "end")
+impl<'a> Spanned for FnDef<'a> {
+    fn span(&self) -> Span {
+        self.span
     }
 }
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Statement<'a> {
-    Empty,
+    Empty(Span),
     Assignment(Vec<Var<'a>>, Vec<Expression<'a>>),
     FnCall(FnCall<'a>),
-    Label(Id<'a>),
-    Break,
-    Goto(Id<'a>),
-    Do(Block<'a>),
+    Label(Id<'a>, Span),
+    Break(Span),
+    Goto(Id<'a>, Span),
+    Do(Block<'a>, Span),
     While(While<'a>),
     Repeat(Repeat<'a>),
     If(If<'a>),
     For(For<'a>),
     FnDef(FnDef<'a>),
     LocalFnDef(FnDef<'a>),
-    LocalAttrNames(Vec<AttrName<'a>>, Vec<Expression<'a>>),
+    LocalAttrNames(Vec<AttrName<'a>>, Vec<Expression<'a>>, Span),
+    /// The placeholder for the tokens skipped while recovering from a syntax error.
+    Error(Span),
 }
 
-impl<'a> Display for Statement<'a> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a> Spanned for Statement<'a> {
+    fn span(&self) -> Span {
         match self {
-            Statement::Empty => write!(f, ";"),
-            Statement::Assignment(lhs, rhs) => {
-                write!(f, "{} = {} ", lhs.show().join(","), rhs.show().join(","))
-            }
-            Statement::FnCall(fn_call) => write!(f, "{}", fn_call),
-            Statement::Label(id) => write!(f, "::{}::", id),
-            Statement::Break => write!(f, "break"),
-            Statement::Goto(id) => write!(f, "goto {}", id),
-            Statement::Do(body) => {
-                writeln!(f, "do ")?;
-                writeln!(f, "{}", body)?;
-                write!(f, "end")
-            }
-            Statement::While(body) => write!(f, "{}", body),
-            Statement::Repeat(repeat) => write!(f, "{}", repeat),
-            Statement::If(body) => write!(f, "{}", body),
-            Statement::For(body) => write!(f, "{}", body),
-            Statement::FnDef(body) => write!(f, "{}", body),
-            Statement::LocalFnDef(body) => write!(f, "local {}", body),
-            Statement::LocalAttrNames(names, exprs) => {
-                let names: Vec<String> = names.show();
-                let exprs: Vec<String> = exprs.show();
-                let exprs = if exprs.is_empty() { String::new() } else { format!("= {}", exprs.join(",")) };
-                write!(f, "local {}{}", names.join(","), exprs)
+            Statement::Empty(s)
+            | Statement::Label(_, s)
+            | Statement::Break(s)
+            | Statement::Goto(_, s)
+            | Statement::Do(_, s)
+            | Statement::LocalAttrNames(_, _, s)
+            | Statement::Error(s) => *s,
+            Statement::Assignment(vars, exprs) => vars.span().merge(exprs.span()),
+            Statement::FnCall(call) => call.span,
+            Statement::While(w) => w.span,
+            Statement::Repeat(r) => r.span,
+            Statement::If(i) => i.span(),
+            Statement::For(f) => f.span(),
+            Statement::FnDef(def) | Statement::LocalFnDef(def) => def.span,
+        }
+    }
+}
+
+/// The comparison of the nodes that ignores their spans,
+/// the trees parsed from differently formatted sources have the same shape.
+pub trait SameShape {
+    fn same_shape(&self, other: &Self) -> bool;
+}
+
+impl<T: SameShape> SameShape for [T] {
+    fn same_shape(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().zip(other).all(|(l, r)| l.same_shape(r))
+    }
+}
+
+impl<T: SameShape> SameShape for Vec<T> {
+    fn same_shape(&self, other: &Self) -> bool {
+        self.as_slice().same_shape(other.as_slice())
+    }
+}
+
+/// Compares the copies of the nodes with the spans reset to the default.
+macro_rules! same_shape {
+    ($($node:ident => $visit:ident),* $(,)?) => {$(
+        impl SameShape for $node<'_> {
+            fn same_shape(&self, other: &Self) -> bool {
+                let erased = |node: &Self| {
+                    let mut node = node.clone();
+                    EraseSpans.$visit(&mut node);
+                    node
+                };
+                erased(self) == erased(other)
             }
         }
+    )*};
+}
+
+same_shape!(
+    Block => visit_block_mut,
+    Statement => visit_statement_mut,
+    Expression => visit_expr_mut,
+    Id => visit_id_mut,
+    Text => visit_text_mut,
+    FnCall => visit_fn_call_mut,
+    Var => visit_var_mut,
+    TableConst => visit_table_mut,
+    Field => visit_field_mut,
+    Args => visit_args_mut,
+    NameArgs => visit_name_args_mut,
+    FnParams => visit_fn_params_mut,
+);
+
+struct EraseSpans;
+
+impl<'a> VisitorMut<'a> for EraseSpans {
+    fn visit_block_mut(&mut self, block: &mut Block<'a>) {
+        match block {
+            Block::Void(_, span) | Block::Return(_, _, span) => *span = Span::default(),
+        }
+        walk_block_mut(self, block)
+    }
+    fn visit_statement_mut(&mut self, st: &mut Statement<'a>) {
+        match st {
+            Statement::Empty(span)
+            | Statement::Label(_, span)
+            | Statement::Break(span)
+            | Statement::Goto(_, span)
+            | Statement::Do(_, span)
+            | Statement::LocalAttrNames(_, _, span)
+            | Statement::Error(span) => *span = Span::default(),
+            _ => {}
+        }
+        walk_statement_mut(self, st)
+    }
+    fn visit_expr_mut(&mut self, expr: &mut Expression<'a>) {
+        match expr {
+            Expression::Nil(span)
+            | Expression::False(span)
+            | Expression::True(span)
+            | Expression::Number(_, span)
+            | Expression::VarArgs(span)
+            | Expression::FnDef(_, _, span)
+            | Expression::Unary(_, _, span)
+            | Expression::Error(span) => *span = Span::default(),
+            _ => {}
+        }
+        walk_expr_mut(self, expr)
+    }
+    fn visit_id_mut(&mut self, id: &mut Id<'a>) {
+        id.span = Span::default();
+    }
+    fn visit_text_mut(&mut self, text: &mut Text<'a>) {
+        text.span = Span::default();
+    }
+    fn visit_fn_call_mut(&mut self, call: &mut FnCall<'a>) {
+        call.span = Span::default();
+        walk_fn_call_mut(self, call)
+    }
+    fn visit_var_mut(&mut self, var: &mut Var<'a>) {
+        var.span = Span::default();
+        walk_var_mut(self, var)
+    }
+    fn visit_var_suffix_mut(&mut self, suffix: &mut VarSuffix<'a>) {
+        suffix.span = Span::default();
+        walk_var_suffix_mut(self, suffix)
+    }
+    fn visit_args_mut(&mut self, args: &mut Args<'a>) {
+        if let Args::Expressions(_, span) = args {
+            *span = Span::default();
+        }
+        walk_args_mut(self, args)
+    }
+    fn visit_table_mut(&mut self, table: &mut TableConst<'a>) {
+        table.span = Span::default();
+        walk_table_mut(self, table)
+    }
+    fn visit_fn_name_mut(&mut self, name: &mut FnName<'a>) {
+        name.span = Span::default();
+        walk_fn_name_mut(self, name)
+    }
+    fn visit_fn_def_mut(&mut self, def: &mut FnDef<'a>) {
+        def.span = Span::default();
+        walk_fn_def_mut(self, def)
+    }
+    fn visit_while_mut(&mut self, w: &mut While<'a>) {
+        w.span = Span::default();
+        walk_while_mut(self, w)
+    }
+    fn visit_repeat_mut(&mut self, r: &mut Repeat<'a>) {
+        r.span = Span::default();
+        walk_repeat_mut(self, r)
+    }
+    fn visit_if_mut(&mut self, i: &mut If<'a>) {
+        match i {
+            If::If(_, _, span) | If::IfElse(_, _, _, span) => *span = Span::default(),
+        }
+        walk_if_mut(self, i)
+    }
+    fn visit_if_branch_mut(&mut self, branch: &mut IfBranch<'a>) {
+        branch.span = Span::default();
+        walk_if_branch_mut(self, branch)
+    }
+    fn visit_plain_for_mut(&mut self, f: &mut PlainFor<'a>) {
+        f.span = Span::default();
+        walk_plain_for_mut(self, f)
+    }
+    fn visit_expr_for_mut(&mut self, f: &mut ExprFor<'a>) {
+        f.span = Span::default();
+        walk_expr_for_mut(self, f)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::fmt::Display;
-    use crate::parser::ast::{Args, Expression, Field, FieldKey, FnParams, Id, NameArgs, TableConst, Text};
+    use crate::parser::ast::{Args, Expression, Field, FieldKey, FnParams, Id, NameArgs, SameShape, TableConst, Text};
+    use crate::parser::{parse_chunk, parse_expr};
+    use crate::parser::span::Span;
+
+    #[test]
+    fn same_shape_test() {
+        let block = parse_chunk("x = f(1, {a = -2})").unwrap();
+        let spaced = parse_chunk("x  =  f( 1 , { a = - 2 } )").unwrap();
+        assert_ne!(block, spaced);
+        assert!(block.same_shape(&spaced));
+        assert!(!block.same_shape(&parse_chunk("x = f(1, {a = -3})").unwrap()));
+        assert!(vec![Expression::Nil(Span::default())].same_shape(&vec![parse_expr("nil").unwrap()]));
+    }
 
     fn display<T: Display>(v: &T, expect: &str) {
         assert_eq!(format!("{}", v), expect)
@@ -582,8 +866,8 @@ mod tests {
     #[test]
     fn fn_param_display_test() {
         display(
-            &FnParams::WithVarArgs(vec![Id { v: "a" }, Id { v: "b" }]),
-            "(a,b,...)",
+            &FnParams::WithVarArgs(vec![Id::new("a"), Id::new("b")]),
+            "(a, b, ...)",
         )
     }
 
@@ -592,12 +876,13 @@ mod tests {
         display(
             &TableConst {
                 fields: vec![
-                    Field::Value(Expression::Nil),
-                    Field::Pair(FieldKey::Id(Id { v: "a" }), Expression::Text(Text { text: "t" })),
-                    Field::Pair(FieldKey::Expr(Expression::True), Expression::Text(Text { text: "t" })),
-                ]
+                    Field::Value(Expression::Nil(Span::default())),
+                    Field::Pair(FieldKey::Id(Id::new("a")), Expression::Text(Text::new("t"))),
+                    Field::Pair(FieldKey::Expr(Expression::True(Span::default())), Expression::Text(Text::new("t"))),
+                ],
+                span: Span::default(),
             },
-            "{nil,a = \"t\",[true] = \"t\"}",
+            "{nil, a = \"t\", [true] = \"t\"}",
         )
     }
 
@@ -607,26 +892,46 @@ mod tests {
             &NameArgs::Args(
                 Args::Constructor(TableConst {
                     fields: vec![
-                        Field::Value(Expression::Nil),
-                        Field::Pair(FieldKey::Id(Id { v: "a" }), Expression::Text(Text { text: "t" })),
-                        Field::Pair(FieldKey::Expr(Expression::True), Expression::Text(Text { text: "t" })),
-                    ]
+                        Field::Value(Expression::Nil(Span::default())),
+                        Field::Pair(FieldKey::Id(Id::new("a")), Expression::Text(Text::new("t"))),
+                        Field::Pair(FieldKey::Expr(Expression::True(Span::default())), Expression::Text(Text::new("t"))),
+                    ],
+                    span: Span::default(),
                 })
             ),
-            " {!,a = !,[!] = !}",
+            " {nil, a = \"t\", [true] = \"t\"}",
         );
         display(
-            &NameArgs::NameArgs(Id { v: "name" },
+            &NameArgs::NameArgs(Id::new("name"),
                                 Args::Constructor(TableConst {
                                     fields: vec![
-                                        Field::Value(Expression::Nil),
-                                        Field::Pair(FieldKey::Id(Id { v: "a" }), Expression::Text(Text { text: "t" })),
-                                        Field::Pair(FieldKey::Expr(Expression::True), Expression::Text(Text { text: "t" })),
-                                    ]
+                                        Field::Value(Expression::Nil(Span::default())),
+                                        Field::Pair(FieldKey::Id(Id::new("a")), Expression::Text(Text::new("t"))),
+                                        Field::Pair(FieldKey::Expr(Expression::True(Span::default())), Expression::Text(Text::new("t"))),
+                                    ],
+                                    span: Span::default(),
                                 }),
             ),
-            ":name {!,a = !,[!] = !}",
+            ":name {nil, a = \"t\", [true] = \"t\"}",
         )
     }
 
-}
\ No newline at end of file
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        use crate::parser::ast::Block;
+        use crate::parser::parse_chunk;
+
+        let src = r"local s = 'caf\xC3\xA9' .. '\xFF' x = 0x10 + 1.5";
+        let block = parse_chunk(src).unwrap();
+        let json = serde_json::to_string(&block).unwrap();
+        assert!(json.contains(r#"{"Hex":16}"#), "{}", json);
+        assert!(json.contains(r#""Concat""#), "{}", json);
+        assert!(json.contains(r#""text":"café""#), "{}", json);
+        assert!(json.contains(r#""text":[255]"#), "{}", json);
+        assert!(json.contains(r#""span":{"start":27,"end":33,"line":1,"col":28}"#), "{}", json);
+
+        let back: Block = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, block);
+    }
+}
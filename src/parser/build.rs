@@ -0,0 +1,288 @@
+use std::mem::take;
+use crate::parser::ast::*;
+use crate::parser::span::Span;
+
+/// What the parser makes of the rules: the tree of `ast` (`Tree`) or the flat arena (`arena::Ast`).
+/// The parser hands over every node once its children are built, the spans come from the tokens.
+/// It is public only to bound the impls of `LuaParser`, the module is private.
+pub trait Build<'a> {
+    type Expr;
+    type Stmt;
+    type Block;
+    type Table;
+    type Field;
+    type Args;
+    /// `:name(args)` or `(args)` of a prefix expression.
+    type Call;
+    /// The whole prefix expression.
+    type Prefix;
+    /// The prefix expression as the target of an assignment.
+    type Var;
+    type Branch;
+
+    /// The expression without children: a literal, `...` or `Expression::Error`.
+    fn leaf(&mut self, e: Expression<'a>) -> Self::Expr;
+    fn function(&mut self, params: FnParams<'a>, body: Self::Block, span: Span) -> Self::Expr;
+    fn table_expr(&mut self, table: Self::Table) -> Self::Expr;
+    fn prefix_expr(&mut self, prefix: Self::Prefix) -> Self::Expr;
+    fn unary(&mut self, op: UnaryType, e: Self::Expr, span: Span) -> Self::Expr;
+    fn binary(&mut self, lhs: Self::Expr, op: BinaryType, rhs: Self::Expr) -> Self::Expr;
+
+    fn table(&mut self, fields: Vec<Self::Field>, span: Span) -> Self::Table;
+    fn named_field(&mut self, name: Id<'a>, value: Self::Expr) -> Self::Field;
+    fn keyed_field(&mut self, key: Self::Expr, value: Self::Expr) -> Self::Field;
+    fn value_field(&mut self, value: Self::Expr) -> Self::Field;
+
+    fn list_args(&mut self, exprs: Vec<Self::Expr>, span: Span) -> Self::Args;
+    fn table_args(&mut self, table: Self::Table) -> Self::Args;
+    fn string_args(&mut self, text: Text<'a>) -> Self::Args;
+    fn call(&mut self, method: Option<Id<'a>>, args: Self::Args) -> Self::Call;
+
+    /// The head followed by the suffixes, every suffix comes with the span of its tokens.
+    fn prefix(&mut self, head: Head<'a, Self::Expr>, suffixes: Vec<(Suffixed<'a, Self>, Span)>, span: Span) -> Self::Prefix;
+    /// Whether the prefix expression ends with a call.
+    fn is_call(&self, prefix: &Self::Prefix) -> bool;
+    fn var(&mut self, prefix: Self::Prefix) -> Option<Self::Var>;
+
+    /// The statement without children: `;`, `break`, a label, `goto` or `Statement::Error`.
+    fn leaf_stmt(&mut self, st: Statement<'a>) -> Self::Stmt;
+    fn call_stmt(&mut self, prefix: Self::Prefix) -> Self::Stmt;
+    fn assignment(&mut self, vars: Vec<Self::Var>, exprs: Vec<Self::Expr>, span: Span) -> Self::Stmt;
+    fn do_s(&mut self, body: Self::Block, span: Span) -> Self::Stmt;
+    fn while_s(&mut self, cond: Self::Expr, body: Self::Block, span: Span) -> Self::Stmt;
+    fn repeat_s(&mut self, body: Self::Block, until: Self::Expr, span: Span) -> Self::Stmt;
+    fn branch(&mut self, cond: Self::Expr, body: Self::Block, span: Span) -> Self::Branch;
+    fn if_s(&mut self, first: Self::Branch, others: Vec<Self::Branch>, otherwise: Option<Self::Block>, span: Span) -> Self::Stmt;
+    #[allow(clippy::too_many_arguments)]
+    fn numeric_for(
+        &mut self,
+        var: Id<'a>,
+        init: Self::Expr,
+        limit: Self::Expr,
+        step: Option<Self::Expr>,
+        body: Self::Block,
+        span: Span,
+    ) -> Self::Stmt;
+    fn generic_for(&mut self, names: Vec<Id<'a>>, exprs: Vec<Self::Expr>, body: Self::Block, span: Span) -> Self::Stmt;
+    fn function_s(&mut self, name: FnName<'a>, params: FnParams<'a>, body: Self::Block, span: Span) -> Self::Stmt;
+    fn local_function(&mut self, name: Id<'a>, params: FnParams<'a>, body: Self::Block, span: Span) -> Self::Stmt;
+    fn local(&mut self, names: Vec<AttrName<'a>>, exprs: Vec<Self::Expr>, span: Span) -> Self::Stmt;
+
+    fn block(&mut self, stmts: Vec<Self::Stmt>, ret: Option<Vec<Self::Expr>>, span: Span) -> Self::Block;
+}
+
+/// The head of the prefix expression: the name or the expression in parens along with the span of the parens.
+pub enum Head<'a, E> {
+    Name(Id<'a>),
+    Parens(E, Span),
+}
+
+/// The part of the prefix expression after its head: `.name`, `[expr]`, `:name(args)` or `(args)`.
+pub enum Suffixed<'a, B: Build<'a> + ?Sized> {
+    Call(B::Call),
+    Field(Id<'a>),
+    Index(B::Expr),
+}
+
+/// Builds the tree of `ast`, the default of `LuaParser`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Tree;
+
+impl<'a> Build<'a> for Tree {
+    type Expr = Expression<'a>;
+    type Stmt = Statement<'a>;
+    type Block = Block<'a>;
+    type Table = TableConst<'a>;
+    type Field = Field<'a>;
+    type Args = Args<'a>;
+    type Call = NameArgs<'a>;
+    /// The call is boxed right away keeping the frames of the nested prefix expressions small.
+    type Prefix = Box<FnCall<'a>>;
+    type Var = Var<'a>;
+    type Branch = IfBranch<'a>;
+
+    fn leaf(&mut self, e: Expression<'a>) -> Expression<'a> {
+        e
+    }
+
+    fn function(&mut self, params: FnParams<'a>, body: Block<'a>, span: Span) -> Expression<'a> {
+        Expression::FnDef(params, body, span)
+    }
+
+    fn table_expr(&mut self, table: TableConst<'a>) -> Expression<'a> {
+        Expression::TableConstructor(table)
+    }
+
+    fn prefix_expr(&mut self, prefix: Box<FnCall<'a>>) -> Expression<'a> {
+        Expression::PrefixExpr(prefix)
+    }
+
+    fn unary(&mut self, op: UnaryType, e: Expression<'a>, span: Span) -> Expression<'a> {
+        Expression::Unary(op, Box::new(e), span)
+    }
+
+    fn binary(&mut self, lhs: Expression<'a>, op: BinaryType, rhs: Expression<'a>) -> Expression<'a> {
+        Expression::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    fn table(&mut self, fields: Vec<Field<'a>>, span: Span) -> TableConst<'a> {
+        TableConst { fields, span }
+    }
+
+    fn named_field(&mut self, name: Id<'a>, value: Expression<'a>) -> Field<'a> {
+        Field::Pair(FieldKey::Id(name), value)
+    }
+
+    fn keyed_field(&mut self, key: Expression<'a>, value: Expression<'a>) -> Field<'a> {
+        Field::Pair(FieldKey::Expr(key), value)
+    }
+
+    fn value_field(&mut self, value: Expression<'a>) -> Field<'a> {
+        Field::Value(value)
+    }
+
+    fn list_args(&mut self, exprs: Vec<Expression<'a>>, span: Span) -> Args<'a> {
+        Args::Expressions(exprs, span)
+    }
+
+    fn table_args(&mut self, table: TableConst<'a>) -> Args<'a> {
+        Args::Constructor(table)
+    }
+
+    fn string_args(&mut self, text: Text<'a>) -> Args<'a> {
+        Args::String(text)
+    }
+
+    fn call(&mut self, method: Option<Id<'a>>, args: Args<'a>) -> NameArgs<'a> {
+        match method {
+            Some(name) => NameArgs::NameArgs(name, args),
+            None => NameArgs::Args(args),
+        }
+    }
+
+    /// Groups the suffixes into the variable and the trailing calls.
+    /// The calls before the last index are a part of the variable, the trailing ones are the calls of it,
+    /// thus `a.b(c).d(e)` is the call `(e)` of the variable `a.b(c).d`.
+    fn prefix(&mut self, head: Head<'a, Expression<'a>>, suffixes: Vec<(Suffixed<'a, Self>, Span)>, span: Span) -> Box<FnCall<'a>> {
+        let var_len = suffixes.iter()
+            .rposition(|(s, _)| !matches!(s, Suffixed::Call(_)))
+            .map_or(0, |i| i + 1);
+        let var_span = suffixes.get(var_len.wrapping_sub(1)).map(|(_, end)| Span { end: end.end, ..span });
+
+        let mut tail = vec![];
+        let mut calls = vec![];
+        let mut args = vec![];
+        let mut from: Option<Span> = None;
+        for (idx, (suffix, s_span)) in suffixes.into_iter().enumerate() {
+            let suffix = match suffix {
+                Suffixed::Call(call) if idx < var_len => {
+                    from.get_or_insert(s_span);
+                    calls.push(call);
+                    continue;
+                }
+                Suffixed::Call(call) => {
+                    args.push(call);
+                    continue;
+                }
+                Suffixed::Field(id) => Suffix::Id(id),
+                Suffixed::Index(expr) => Suffix::Expr(expr),
+            };
+            let span = Span { end: s_span.end, ..from.take().unwrap_or(s_span) };
+            tail.push(VarSuffix { var: take(&mut calls), suffix, span })
+        }
+
+        let head = match (head, var_span) {
+            (Head::Name(id), Some(span)) => VarOrExpr::Var(Var { head: VarHead::Id(id), tail, span }),
+            (Head::Name(id), None) => VarOrExpr::Var(Var { span: id.span, head: VarHead::Id(id), tail }),
+            (Head::Parens(expr, _), Some(span)) => {
+                let mut tail = tail.into_iter();
+                let first = tail.next().expect("the variable has an index");
+                VarOrExpr::Var(Var { head: VarHead::Expr(expr, first), tail: tail.collect(), span })
+            }
+            (Head::Parens(expr, _), None) => VarOrExpr::Expr(expr),
+        };
+        Box::new(FnCall { head, args, span })
+    }
+
+    fn is_call(&self, prefix: &Box<FnCall<'a>>) -> bool {
+        !prefix.args.is_empty()
+    }
+
+    fn var(&mut self, prefix: Box<FnCall<'a>>) -> Option<Var<'a>> {
+        match *prefix {
+            FnCall { head: VarOrExpr::Var(var), args, .. } if args.is_empty() => Some(var),
+            _ => None,
+        }
+    }
+
+    fn leaf_stmt(&mut self, st: Statement<'a>) -> Statement<'a> {
+        st
+    }
+
+    fn call_stmt(&mut self, prefix: Box<FnCall<'a>>) -> Statement<'a> {
+        Statement::FnCall(*prefix)
+    }
+
+    fn assignment(&mut self, vars: Vec<Var<'a>>, exprs: Vec<Expression<'a>>, _: Span) -> Statement<'a> {
+        Statement::Assignment(vars, exprs)
+    }
+
+    fn do_s(&mut self, body: Block<'a>, span: Span) -> Statement<'a> {
+        Statement::Do(body, span)
+    }
+
+    fn while_s(&mut self, cond: Expression<'a>, body: Block<'a>, span: Span) -> Statement<'a> {
+        Statement::While(While { cond, body, span })
+    }
+
+    fn repeat_s(&mut self, body: Block<'a>, until: Expression<'a>, span: Span) -> Statement<'a> {
+        Statement::Repeat(Repeat { until, body, span })
+    }
+
+    fn branch(&mut self, cond: Expression<'a>, body: Block<'a>, span: Span) -> IfBranch<'a> {
+        IfBranch { cond, body, span }
+    }
+
+    fn if_s(&mut self, first: IfBranch<'a>, others: Vec<IfBranch<'a>>, otherwise: Option<Block<'a>>, span: Span) -> Statement<'a> {
+        Statement::If(match otherwise {
+            Some(otherwise) => If::IfElse(first, others, otherwise, span),
+            None => If::If(first, others, span),
+        })
+    }
+
+    fn numeric_for(
+        &mut self,
+        var: Id<'a>,
+        init: Expression<'a>,
+        border: Expression<'a>,
+        step: Option<Expression<'a>>,
+        body: Block<'a>,
+        span: Span,
+    ) -> Statement<'a> {
+        Statement::For(For::Plain(PlainFor { init: (var, init), border, step, body, span }))
+    }
+
+    fn generic_for(&mut self, names: Vec<Id<'a>>, expressions: Vec<Expression<'a>>, body: Block<'a>, span: Span) -> Statement<'a> {
+        Statement::For(For::ForCol(ExprFor { names, expressions, body, span }))
+    }
+
+    fn function_s(&mut self, name: FnName<'a>, params: FnParams<'a>, body: Block<'a>, span: Span) -> Statement<'a> {
+        Statement::FnDef(FnDef { name, params, body, span })
+    }
+
+    fn local_function(&mut self, name: Id<'a>, params: FnParams<'a>, body: Block<'a>, span: Span) -> Statement<'a> {
+        let name = FnName { span: name.span, names: vec![name], last: None };
+        Statement::LocalFnDef(FnDef { name, params, body, span })
+    }
+
+    fn local(&mut self, names: Vec<AttrName<'a>>, exprs: Vec<Expression<'a>>, span: Span) -> Statement<'a> {
+        Statement::LocalAttrNames(names, exprs, span)
+    }
+
+    fn block(&mut self, stmts: Vec<Statement<'a>>, ret: Option<Vec<Expression<'a>>>, span: Span) -> Block<'a> {
+        match ret {
+            Some(exprs) => Block::Return(stmts, exprs, span),
+            None => Block::Void(stmts, span),
+        }
+    }
+}
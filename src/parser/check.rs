@@ -0,0 +1,346 @@
+use crate::parser::arena::{Ast, BlockId, Expr, Stmt, StmtId};
+use crate::parser::ast::Id;
+use crate::parser::error::SyntaxError;
+use crate::parser::resolve::{Resolved, Scopes};
+use crate::parser::span::Span;
+
+/// The errors Lua reports at compile time for the chunk that parses:
+/// `break` outside of a loop, repeated labels, `goto` without a visible label,
+/// `goto` jumping into the scope of a local and the misuse of the local attributes.
+/// The errors are sorted by the position in the source.
+pub fn check(ast: &Ast) -> Vec<SyntaxError> {
+    let mut errors = check_gotos(ast);
+    errors.extend(check_attributes(ast, &Scopes::resolve(ast)));
+    errors.sort_by_key(|e| e.span.start);
+    errors
+}
+
+/// Only `<const>` and `<close>` are known, a local list has at most one `<close>`
+/// and neither of them can be assigned, by `x = v` or by `function x() end`.
+pub fn check_attributes(ast: &Ast, scopes: &Scopes) -> Vec<SyntaxError> {
+    let mut errors = vec![];
+    let mut assigned = |name: &Id, span: Span, resolved: Option<&Resolved>| {
+        if resolved.is_some_and(|r| scopes[r.binding].is_const()) {
+            let message = format!("attempt to assign to const variable '{}'", name.v);
+            errors.push(SyntaxError::semantic(span, message));
+        }
+    };
+    for (id, st) in ast.stmts() {
+        match &st.kind {
+            Stmt::Assign { targets, .. } => {
+                for target in &ast[*targets] {
+                    if let Expr::Name(name) = &ast[*target].kind {
+                        assigned(name, ast[*target].span, scopes.reference(*target));
+                    }
+                }
+            }
+            Stmt::Function { name, .. } if name.path.len() == 1 && name.method.is_none() => {
+                let first = &ast[name.path][0];
+                assigned(first, first.span, scopes.function_name(id));
+            }
+            _ => {}
+        }
+    }
+    for (_, st) in ast.stmts() {
+        let Stmt::Local { names, .. } = &st.kind else { continue };
+        let mut close = false;
+        for attr in ast[*names].iter().filter_map(|l| l.attr.as_ref()) {
+            match attr.v.as_str() {
+                "const" => {}
+                "close" if close => {
+                    let message = "multiple to-be-closed variables in local list".to_string();
+                    errors.push(SyntaxError::semantic(attr.span, message));
+                }
+                "close" => close = true,
+                _ => errors.push(SyntaxError::semantic(attr.span, format!("unknown attribute '{}'", attr.v))),
+            }
+        }
+    }
+    errors
+}
+
+/// The jumps of every function follow the rules of Lua 5.4:
+/// a label is visible in the block where it is defined and in the nested blocks of the same function,
+/// a label at the end of the block (followed only by `;` and labels) is out of the scope
+/// of the locals of the block, so `goto continue` can skip them.
+pub fn check_gotos(ast: &Ast) -> Vec<SyntaxError> {
+    let mut errors = vec![];
+    Gotos::new(ast, &mut errors).function(ast.root());
+    for (_, func) in ast.funcs() {
+        Gotos::new(ast, &mut errors).function(func.kind.body);
+    }
+    errors
+}
+
+struct Label<'s, 'a> {
+    name: &'s Id<'a>,
+    span: Span,
+}
+
+struct Goto<'s, 'a> {
+    name: &'s Id<'a>,
+    span: Span,
+    /// The number of the active locals at the goto, lowered when the goto leaves a block.
+    locals: usize,
+}
+
+/// The block being checked.
+struct Scope {
+    labels: usize,
+    gotos: usize,
+    locals: usize,
+}
+
+/// The labels and the pending gotos of a function.
+struct Gotos<'s, 'a> {
+    ast: &'s Ast<'a>,
+    errors: &'s mut Vec<SyntaxError>,
+    /// The names of the active locals.
+    locals: Vec<&'s Id<'a>>,
+    /// The labels of the enclosing blocks.
+    labels: Vec<Label<'s, 'a>>,
+    /// The forward gotos waiting for their labels.
+    gotos: Vec<Goto<'s, 'a>>,
+    /// The first pending goto of the current block, the labels of the block can resolve only the gotos after it.
+    block_gotos: usize,
+    loops: usize,
+}
+
+impl<'s, 'a> Gotos<'s, 'a> {
+    fn new(ast: &'s Ast<'a>, errors: &'s mut Vec<SyntaxError>) -> Self {
+        Gotos { ast, errors, locals: vec![], labels: vec![], gotos: vec![], block_gotos: 0, loops: 0 }
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(SyntaxError::semantic(span, message));
+    }
+
+    fn function(mut self, body: BlockId) {
+        self.block(body, false);
+        for goto in std::mem::take(&mut self.gotos) {
+            let message = format!("no visible label '{}' for <goto> at line {}", goto.name.v, goto.span.line);
+            self.error(goto.span, message);
+        }
+    }
+
+    /// The body of `repeat` keeps its locals visible up to the condition,
+    /// so the label at its end is not out of their scope.
+    fn block(&mut self, id: BlockId, repeat: bool) {
+        let ast = self.ast;
+        let scope = Scope { labels: self.labels.len(), gotos: self.gotos.len(), locals: self.locals.len() };
+        let stmts = &ast[ast[id].kind.stmts];
+        let ret = ast[id].kind.ret.is_some();
+        let block_gotos = std::mem::replace(&mut self.block_gotos, scope.gotos);
+        for (i, st) in stmts.iter().enumerate() {
+            let last = !repeat
+                && !ret
+                && stmts[i + 1..].iter().all(|st| matches!(ast[*st].kind, Stmt::Empty | Stmt::Label(_)));
+            self.stmt(*st, if last { scope.locals } else { self.locals.len() });
+        }
+        self.block_gotos = block_gotos;
+        self.labels.truncate(scope.labels);
+        self.locals.truncate(scope.locals);
+        for goto in &mut self.gotos[scope.gotos..] {
+            goto.locals = goto.locals.min(scope.locals);
+        }
+    }
+
+    fn looped(&mut self, body: BlockId, repeat: bool) {
+        self.loops += 1;
+        self.block(body, repeat);
+        self.loops -= 1;
+    }
+
+    /// `label_locals` is the number of the locals in the scope of a label defined by the statement.
+    fn stmt(&mut self, id: StmtId, label_locals: usize) {
+        let ast = self.ast;
+        let span = ast[id].span;
+        match &ast[id].kind {
+            Stmt::Break if self.loops == 0 => self.error(span, format!("break outside a loop at line {}", span.line)),
+            Stmt::Label(name) => self.label(name, span, label_locals),
+            // the backward jumps are resolved right away
+            Stmt::Goto(name) if !self.labels.iter().any(|l| l.name.v == name.v) => {
+                self.gotos.push(Goto { name, span, locals: self.locals.len() })
+            }
+            Stmt::Do(body) => self.block(*body, false),
+            Stmt::While { body, .. } | Stmt::NumericFor { body, .. } | Stmt::GenericFor { body, .. } => {
+                self.looped(*body, false)
+            }
+            Stmt::Repeat { body, .. } => self.looped(*body, true),
+            Stmt::If { branches, otherwise } => {
+                for branch in &ast[*branches] {
+                    self.block(branch.body, false);
+                }
+                if let Some(block) = otherwise {
+                    self.block(*block, false);
+                }
+            }
+            Stmt::LocalFunction { name, .. } => self.locals.push(name),
+            Stmt::Local { names, .. } => self.locals.extend(ast[*names].iter().map(|l| &l.name)),
+            _ => {}
+        }
+    }
+
+    /// Defines the label and resolves the pending gotos of the current block jumping to it.
+    fn label(&mut self, name: &'s Id<'a>, span: Span, locals: usize) {
+        if let Some(prev) = self.labels.iter().find(|l| l.name.v == name.v) {
+            let message = format!("label '{}' already defined on line {}", name.v, prev.span.line);
+            self.error(span, message);
+            return;
+        }
+        self.labels.push(Label { name, span });
+        let mut i = self.block_gotos;
+        while i < self.gotos.len() {
+            if self.gotos[i].name.v != name.v {
+                i += 1;
+                continue;
+            }
+            let goto = self.gotos.remove(i);
+            if goto.locals < locals {
+                let local = &self.locals[goto.locals].v;
+                let message =
+                    format!("<goto {}> at line {} jumps into the scope of local '{}'", name.v, goto.span.line, local);
+                self.error(goto.span, message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::arena::{Ast, Expr};
+    use crate::parser::check::check;
+    use crate::parser::parse_chunk;
+    use crate::parser::resolve::Scopes;
+
+    fn errors(src: &str) -> Vec<(String, usize)> {
+        let ast = Ast::new(&parse_chunk(src).unwrap());
+        check(&ast).into_iter().map(|e| (e.message(), e.span.line)).collect()
+    }
+
+    fn messages(src: &str) -> Vec<String> {
+        errors(src).into_iter().map(|(m, _)| m).collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn break_test() {
+        assert_eq!(errors("while true do break end"), vec![]);
+        assert_eq!(errors("for i = 1, 2 do if i then do break end end end"), vec![]);
+        assert_eq!(errors("repeat break until true"), vec![]);
+        assert_eq!(errors("local a\nbreak"), vec![("break outside a loop at line 2".to_string(), 2)]);
+        assert_eq!(
+            messages("while true do local f = function() break end end"),
+            vec!["break outside a loop at line 1"]
+        );
+    }
+
+    #[test]
+    fn continue_test() {
+        let src = r#"
+            for i = 1, 10 do
+                if i % 2 == 0 then goto continue end
+                local x = i * 2
+                print(x)
+                ::continue::
+            end
+            for _, v in ipairs(t) do
+                while v do
+                    if v then goto continue end
+                    v = nil
+                end
+                local y = v
+                ::continue:: ;
+            end
+        "#;
+        assert_eq!(errors(src), vec![]);
+    }
+
+    #[test]
+    fn backward_test() {
+        assert_eq!(errors("::top:: local a = 1 goto top"), vec![]);
+        assert_eq!(errors("do ::top:: end goto top"), vec![("no visible label 'top' for <goto> at line 1".to_string(), 1)]);
+    }
+
+    #[test]
+    fn local_scope_test() {
+        assert_eq!(
+            errors("goto l\nlocal a = 1\n::l::\nprint(a)"),
+            vec![("<goto l> at line 1 jumps into the scope of local 'a'".to_string(), 1)]
+        );
+        // the label at the end of the block
+        assert_eq!(errors("do goto l\nlocal a = 1\n::l:: end"), vec![]);
+        // the locals of the body are visible in `until`
+        assert_eq!(
+            messages("repeat goto l\nlocal a = 1\n::l:: until a"),
+            vec!["<goto l> at line 1 jumps into the scope of local 'a'"]
+        );
+        // the block ends with `return`
+        assert_eq!(messages("goto l\nlocal a\n::l::\nreturn"), vec!["<goto l> at line 1 jumps into the scope of local 'a'"]);
+        // the locals of the nested block are out of scope
+        assert_eq!(errors("do goto l local a end local b ::l::"), vec![]);
+        assert_eq!(
+            messages("do goto l end local b ::l:: print(b)"),
+            vec!["<goto l> at line 1 jumps into the scope of local 'b'"]
+        );
+    }
+
+    #[test]
+    fn labels_test() {
+        assert_eq!(
+            errors("::a::\ndo\n::a::\nend"),
+            vec![("label 'a' already defined on line 1".to_string(), 3)]
+        );
+        assert_eq!(errors("do ::a:: end do ::a:: end"), vec![]);
+        // the functions have their own labels
+        assert_eq!(errors("::a:: local function f() ::a:: goto a end"), vec![]);
+        assert_eq!(
+            messages("::a:: function f() goto a end"),
+            vec!["no visible label 'a' for <goto> at line 1"]
+        );
+        // the label of a nested block is not visible
+        assert_eq!(messages("goto a do ::a:: end"), vec!["no visible label 'a' for <goto> at line 1"]);
+    }
+
+    #[test]
+    fn attributes_test() {
+        assert_eq!(errors("local a <const>, b <close> = 1, nil print(a, b)"), vec![]);
+        assert_eq!(errors("local a <constant> = 1"), vec![("unknown attribute 'constant'".to_string(), 1)]);
+        assert_eq!(
+            errors("local a <close>, b <const>,\nc <close> = f()"),
+            vec![("multiple to-be-closed variables in local list".to_string(), 2)]
+        );
+        let src = "local a <const> = 1\na = 2\nlocal function f() a, b = 3, 4 end\nfunction a() end";
+        let ast = Ast::new(&parse_chunk(src).unwrap());
+        let located: Vec<_> = check(&ast).into_iter().map(|e| (e.message(), (e.span.line, e.span.col))).collect();
+        assert_eq!(located, vec![
+            ("attempt to assign to const variable 'a'".to_string(), (2, 1)),
+            ("attempt to assign to const variable 'a'".to_string(), (3, 20)),
+            ("attempt to assign to const variable 'a'".to_string(), (4, 10)),
+        ]);
+        // the shadowing local and the fields are fine
+        assert_eq!(errors("local t <const> = {} t.x = 1 t[1] = 2 do local t = 1 t = 2 end"), vec![]);
+    }
+
+    #[test]
+    fn constness_test() {
+        let src = "local a <const>, b <close>, c = 42, f() local d <const>";
+        let ast = Ast::new(&parse_chunk(src).unwrap());
+        let scopes = Scopes::resolve(&ast);
+        let flags: Vec<_> = scopes.bindings().skip(1).map(|(_, b)| (b.is_const(), b.is_close(), b.init.is_some())).collect();
+        assert_eq!(flags, vec![(true, false, true), (true, true, true), (false, false, false), (true, false, false)]);
+        let (_, a) = scopes.bindings().nth(1).unwrap();
+        assert!(matches!(ast[a.init.unwrap()].kind, Expr::Number(_)));
+    }
+
+    #[test]
+    fn scripts_test() {
+        for src in [
+            include_str!("scripts/lazy.lua"),
+            include_str!("scripts/cassandra.lua"),
+            include_str!("scripts/treesetter.lua"),
+            include_str!("scripts/server.lua"),
+        ] {
+            assert_eq!(errors(src), vec![]);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::mem::take;
+use crate::parser::ast::*;
+use crate::parser::cst::{CstToken, Element, Node, SyntaxKind};
+use crate::parser::expression::{binary_op, unary_op};
+use crate::parser::span::Span;
+use crate::parser::tokens::Token;
+
+/// Reads the block off its node, the tree is expected to come from `Cst::parse`.
+pub(super) fn block<'a>(node: &Node<'a>) -> Block<'a> {
+    let mut sts = vec![];
+    let mut ret: Option<Vec<Expression<'a>>> = None;
+    for child in node.children.iter() {
+        match (child, &mut ret) {
+            (Element::Token(t), _) if t.token == Token::Return => ret = Some(vec![]),
+            (Element::Node(n), Some(exprs)) => exprs.push(expr(n)),
+            (Element::Node(n), None) => sts.push(statement(n)),
+            (Element::Token(_), _) => {}
+        }
+    }
+    match ret {
+        Some(exprs) => Block::Return(sts, exprs, node.span),
+        None => Block::Void(sts, node.span),
+    }
+}
+
+fn statement<'a>(node: &Node<'a>) -> Statement<'a> {
+    let span = node.span;
+    let mut nodes = nodes(node);
+    match node.kind {
+        SyntaxKind::Empty => Statement::Empty(span),
+        SyntaxKind::Break => Statement::Break(span),
+        SyntaxKind::Error => Statement::Error(span),
+        SyntaxKind::Label => Statement::Label(names(node).next().expect("the label has a name"), span),
+        SyntaxKind::Goto => Statement::Goto(names(node).next().expect("goto has a label"), span),
+        SyntaxKind::Do => Statement::Do(block(next(&mut nodes)), span),
+        SyntaxKind::While => {
+            let cond = expr(next(&mut nodes));
+            Statement::While(While { cond, body: block(next(&mut nodes)), span })
+        }
+        SyntaxKind::Repeat => {
+            let body = block(next(&mut nodes));
+            Statement::Repeat(Repeat { body, until: expr(next(&mut nodes)), span })
+        }
+        SyntaxKind::If => Statement::If(if_s(node)),
+        SyntaxKind::NumericFor => {
+            let (exprs, body) = exprs_and_body(node);
+            let mut exprs = exprs.into_iter();
+            let mut next_expr = || exprs.next().expect("the numeric for has the bounds");
+            let init = (names(node).next().expect("the numeric for has a variable"), next_expr());
+            let border = next_expr();
+            Statement::For(For::Plain(PlainFor { init, border, step: exprs.next(), body, span }))
+        }
+        SyntaxKind::GenericFor => {
+            let (expressions, body) = exprs_and_body(node);
+            Statement::For(For::ForCol(ExprFor { names: names(node).collect(), expressions, body, span }))
+        }
+        SyntaxKind::Function => Statement::FnDef(fn_def(node)),
+        SyntaxKind::LocalFunction => Statement::LocalFnDef(fn_def(node)),
+        SyntaxKind::Local => Statement::LocalAttrNames(attr_names(node), nodes.map(expr).collect(), span),
+        SyntaxKind::Assignment => {
+            let mut vars = vec![];
+            let mut exprs = vec![];
+            let mut rhs = false;
+            for child in node.children.iter() {
+                match child {
+                    Element::Token(t) if t.token == Token::Assign => rhs = true,
+                    Element::Node(n) if rhs => exprs.push(expr(n)),
+                    Element::Node(n) => vars.push(var(n)),
+                    Element::Token(_) => {}
+                }
+            }
+            Statement::Assignment(vars, exprs)
+        }
+        SyntaxKind::Call => Statement::FnCall(fn_call(node)),
+        kind => unreachable!("{:?} is not a statement", kind),
+    }
+}
+
+fn if_s<'a>(node: &Node<'a>) -> If<'a> {
+    let mut branches = vec![];
+    let mut otherwise = None;
+    for n in nodes(node) {
+        match n.kind {
+            SyntaxKind::Branch => {
+                let mut nodes = nodes(n);
+                let cond = expr(next(&mut nodes));
+                branches.push(IfBranch { cond, body: block(next(&mut nodes)), span: n.span });
+            }
+            _ => otherwise = Some(block(n)),
+        }
+    }
+    let mut branches = branches.into_iter();
+    let main = branches.next().expect("if has a branch");
+    match otherwise {
+        Some(otherwise) => If::IfElse(main, branches.collect(), otherwise, node.span),
+        None => If::If(main, branches.collect(), node.span),
+    }
+}
+
+/// The expressions of the loop header and the body after them.
+fn exprs_and_body<'a>(node: &Node<'a>) -> (Vec<Expression<'a>>, Block<'a>) {
+    let mut nodes: Vec<_> = nodes(node).collect();
+    let body = block(nodes.pop().expect("the loop has a body"));
+    (nodes.into_iter().map(expr).collect(), body)
+}
+
+/// The function statement, the name is made of the tokens before the parameters.
+fn fn_def<'a>(node: &Node<'a>) -> FnDef<'a> {
+    let mut names = vec![];
+    let mut last = None;
+    let mut method = false;
+    for t in tokens(node).take_while(|t| t.token != Token::LParen) {
+        match t.token {
+            Token::Colon => method = true,
+            Token::Id(_) if method => last = Some(id(t)),
+            Token::Id(_) => names.push(id(t)),
+            _ => {}
+        }
+    }
+    let first = names.first().expect("the function has a name").span;
+    let span = first.merge(last.as_ref().or(names.last()).map_or(first, |id| id.span));
+    FnDef {
+        name: FnName { names, last, span },
+        params: params(node),
+        body: block(next(&mut nodes(node))),
+        span: node.span,
+    }
+}
+
+/// The names of `local` with their attributes up to `=`.
+fn attr_names<'a>(node: &Node<'a>) -> Vec<AttrName<'a>> {
+    let mut names = vec![];
+    let mut in_attr = false;
+    for t in tokens(node).take_while(|t| t.token != Token::Assign) {
+        match t.token {
+            Token::Lt => in_attr = true,
+            Token::Gt => in_attr = false,
+            Token::Id(_) if in_attr => {
+                if let Some(AttrName::Name(name)) = names.pop() {
+                    names.push(AttrName::AttrName(name, id(t)));
+                }
+            }
+            Token::Id(_) => names.push(AttrName::Name(id(t))),
+            _ => {}
+        }
+    }
+    names
+}
+
+/// The parameters between the parens of the function.
+fn params<'a>(node: &Node<'a>) -> FnParams<'a> {
+    let mut names = vec![];
+    let mut var_args = false;
+    let inside = tokens(node).skip_while(|t| t.token != Token::LParen).take_while(|t| t.token != Token::RParen);
+    for t in inside {
+        match t.token {
+            Token::Id(_) => names.push(id(t)),
+            Token::EllipsisOut => var_args = true,
+            _ => {}
+        }
+    }
+    match (var_args, names.is_empty()) {
+        (false, _) => FnParams::Args(names),
+        (true, true) => FnParams::VarArgs,
+        (true, false) => FnParams::WithVarArgs(names),
+    }
+}
+
+fn expr<'a>(node: &Node<'a>) -> Expression<'a> {
+    let span = node.span;
+    match node.kind {
+        SyntaxKind::Nil => Expression::Nil(span),
+        SyntaxKind::True => Expression::True(span),
+        SyntaxKind::False => Expression::False(span),
+        SyntaxKind::VarArgs => Expression::VarArgs(span),
+        SyntaxKind::Error => Expression::Error(span),
+        SyntaxKind::Number => match tokens(node).next().map(|t| &t.token) {
+            Some(Token::Digit(n)) => Expression::Number(*n, span),
+            _ => unreachable!("the number node holds a numeral"),
+        },
+        SyntaxKind::String => Expression::Text(text(node)),
+        SyntaxKind::FunctionDef => Expression::FnDef(params(node), block(next(&mut nodes(node))), span),
+        SyntaxKind::Prefix => Expression::PrefixExpr(Box::new(fn_call(node))),
+        SyntaxKind::Table => Expression::TableConstructor(table(node)),
+        SyntaxKind::Unary => {
+            let op = tokens(node).find_map(|t| unary_op(&t.token)).expect("the unary node holds the operator");
+            Expression::Unary(op, Box::new(expr(next(&mut nodes(node)))), span)
+        }
+        SyntaxKind::Binary => {
+            let op = tokens(node).find_map(|t| binary_op(&t.token)).expect("the binary node holds the operator");
+            let mut nodes = nodes(node);
+            let lhs = expr(next(&mut nodes));
+            Expression::Binary(Box::new(lhs), op, Box::new(expr(next(&mut nodes))))
+        }
+        kind => unreachable!("{:?} is not an expression", kind),
+    }
+}
+
+fn table<'a>(node: &Node<'a>) -> TableConst<'a> {
+    TableConst { fields: nodes(node).map(field).collect(), span: node.span }
+}
+
+/// The `[` of the key lies before the field, so the key is either the second expression or the name.
+fn field<'a>(node: &Node<'a>) -> Field<'a> {
+    let mut values = nodes(node).map(expr);
+    let first = values.next().expect("the field has a value");
+    match (values.next(), tokens(node).next()) {
+        (Some(value), _) => Field::Pair(FieldKey::Expr(first), value),
+        (None, Some(key)) if matches!(key.token, Token::Id(_)) => Field::Pair(FieldKey::Id(id(key)), first),
+        (None, _) => Field::Value(first),
+    }
+}
+
+/// The head in parens or the variable followed by the calls.
+fn fn_call<'a>(node: &Node<'a>) -> FnCall<'a> {
+    let mut children = node.children.iter();
+    let head = match children.next() {
+        Some(Element::Node(var_node)) => VarOrExpr::Var(var(var_node)),
+        _ => {
+            let head = VarOrExpr::Expr(expr(next_node(&mut children)));
+            next_token(&mut children);
+            head
+        }
+    };
+    let mut args = vec![];
+    let mut method = false;
+    for child in children {
+        match child {
+            Element::Token(t) => method |= t.token == Token::Colon,
+            Element::Node(n) => args.push(name_args(n, take(&mut method))),
+        }
+    }
+    FnCall { head, args, span: node.span }
+}
+
+/// The variable: the name or the expression in parens followed by the suffixes,
+/// the calls go to the suffix of the index after them.
+fn var<'a>(node: &Node<'a>) -> Var<'a> {
+    let mut children = node.children.iter();
+    let head = match children.next() {
+        Some(Element::Token(t)) if t.token == Token::LParen => {
+            let head = expr(next_node(&mut children));
+            next_token(&mut children);
+            Err(head)
+        }
+        Some(Element::Token(t)) => Ok(id(t)),
+        _ => unreachable!("the variable starts with a name or a paren"),
+    };
+
+    let mut suffixes = vec![];
+    let mut calls = vec![];
+    let mut from: Option<Span> = None;
+    let mut method = false;
+    while let Some(child) = children.next() {
+        let (suffix, start, end) = match child {
+            Element::Node(n) => {
+                from.get_or_insert(n.span);
+                calls.push(name_args(n, take(&mut method)));
+                continue;
+            }
+            Element::Token(t) if t.token == Token::Colon => {
+                from.get_or_insert(t.span);
+                method = true;
+                continue;
+            }
+            Element::Token(t) if t.token == Token::Dot => {
+                let name = id(next_token(&mut children));
+                let end = name.span;
+                (Suffix::Id(name), t.span, end)
+            }
+            Element::Token(t) => {
+                let key = expr(next_node(&mut children));
+                (Suffix::Expr(key), t.span, next_token(&mut children).span)
+            }
+        };
+        let span = from.take().unwrap_or(start).merge(end);
+        suffixes.push(VarSuffix { var: take(&mut calls), suffix, span });
+    }
+
+    let head = match head {
+        Ok(id) => VarHead::Id(id),
+        Err(expr) => {
+            let first = suffixes.remove(0);
+            VarHead::Expr(expr, first)
+        }
+    };
+    Var { head, tail: suffixes, span: node.span }
+}
+
+/// The arguments of the call, the method name is their first token.
+fn name_args<'a>(node: &Node<'a>, method: bool) -> NameArgs<'a> {
+    let mut tokens = tokens(node);
+    let name = if method { tokens.next().map(id) } else { None };
+    let args = match (tokens.next(), nodes(node).next()) {
+        (Some(open), _) => {
+            let close = tokens.last().unwrap_or(open);
+            Args::Expressions(nodes(node).map(expr).collect(), open.span.merge(close.span))
+        }
+        (None, Some(table_node)) if table_node.kind == SyntaxKind::Table => Args::Constructor(table(table_node)),
+        (None, Some(string)) => Args::String(text(string)),
+        (None, None) => unreachable!("the call has arguments"),
+    };
+    match name {
+        Some(name) => NameArgs::NameArgs(name, args),
+        None => NameArgs::Args(args),
+    }
+}
+
+fn text<'a>(node: &Node<'a>) -> Text<'a> {
+    match tokens(node).next() {
+        Some(CstToken { token: Token::StringLit(text), span, .. }) => Text { text: text.clone(), span: *span },
+        _ => unreachable!("the string node holds a literal"),
+    }
+}
+
+fn id<'a>(token: &CstToken<'a>) -> Id<'a> {
+    match token.token {
+        Token::Id(v) => Id { v: Name::Borrowed(v), span: token.span },
+        _ => unreachable!("{} is not a name", token.text),
+    }
+}
+
+/// The names right under the node.
+fn names<'n, 'a: 'n>(node: &'n Node<'a>) -> impl Iterator<Item=Id<'a>> + 'n {
+    tokens(node).filter(|t| matches!(t.token, Token::Id(_))).map(id)
+}
+
+/// The child nodes in the source order.
+fn nodes<'n, 'a>(node: &'n Node<'a>) -> impl Iterator<Item=&'n Node<'a>> {
+    node.children.iter().filter_map(|c| match c {
+        Element::Node(n) => Some(n),
+        Element::Token(_) => None,
+    })
+}
+
+/// The tokens right under the node, the ones of the child nodes are not included.
+fn tokens<'n, 'a>(node: &'n Node<'a>) -> impl Iterator<Item=&'n CstToken<'a>> {
+    node.children.iter().filter_map(|c| match c {
+        Element::Token(t) => Some(t),
+        Element::Node(_) => None,
+    })
+}
+
+fn next<'n, 'a: 'n>(nodes: &mut impl Iterator<Item=&'n Node<'a>>) -> &'n Node<'a> {
+    nodes.next().expect("the node is missing")
+}
+
+fn next_node<'n, 'a: 'n>(children: &mut impl Iterator<Item=&'n Element<'a>>) -> &'n Node<'a> {
+    children.find_map(|c| match c {
+        Element::Node(n) => Some(n),
+        Element::Token(_) => None,
+    }).expect("the node is missing")
+}
+
+fn next_token<'n, 'a: 'n>(children: &mut impl Iterator<Item=&'n Element<'a>>) -> &'n CstToken<'a> {
+    children.find_map(|c| match c {
+        Element::Token(t) => Some(t),
+        Element::Node(_) => None,
+    }).expect("the token is missing")
+}
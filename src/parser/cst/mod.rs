@@ -0,0 +1,510 @@
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::ops::Range;
+use crate::parser::ast::*;
+use crate::parser::dialect::Dialect;
+use crate::parser::error::SyntaxError;
+use crate::parser::LuaParser;
+use crate::parser::span::{LineIndex, Span, Spanned};
+use crate::parser::tokens::{comment_len, Token};
+
+mod lower;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    /// Both the line comment `-- ..` and the long one `--[[ .. ]]`.
+    Comment,
+    /// The first line of the file starting with `#!`.
+    Shebang,
+}
+
+/// The part of the source the parser skips.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trivia<'a> {
+    pub kind: TriviaKind,
+    pub text: &'a str,
+    pub span: Span,
+}
+
+/// The token along with the trivia around it.
+/// The trailing trivia runs up to the end of the line of the token including the line break,
+/// the rest belongs to the leading trivia of the next token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CstToken<'a> {
+    pub token: Token<'a>,
+    pub text: &'a str,
+    pub span: Span,
+    pub leading: Vec<Trivia<'a>>,
+    pub trailing: Vec<Trivia<'a>>,
+}
+
+impl<'a> Display for CstToken<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.leading.iter().try_for_each(|t| f.write_str(t.text))?;
+        f.write_str(self.text)?;
+        self.trailing.iter().try_for_each(|t| f.write_str(t.text))
+    }
+}
+
+/// The kind of the node, it follows the ast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    Chunk,
+    Block,
+
+    Empty,
+    Assignment,
+    Call,
+    Label,
+    Break,
+    Goto,
+    Do,
+    While,
+    Repeat,
+    If,
+    NumericFor,
+    GenericFor,
+    Function,
+    LocalFunction,
+    Local,
+
+    Nil,
+    True,
+    False,
+    Number,
+    String,
+    VarArgs,
+    FunctionDef,
+    Prefix,
+    Table,
+    Unary,
+    Binary,
+
+    /// The condition and the body of `if` or `elseif`.
+    Branch,
+    Var,
+    Args,
+    Field,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    pub kind: SyntaxKind,
+    pub span: Span,
+    pub children: Vec<Element<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element<'a> {
+    Node(Node<'a>),
+    Token(CstToken<'a>),
+}
+
+impl<'a> Node<'a> {
+    /// The tokens of the node in the source order.
+    pub fn tokens(&self) -> Vec<&CstToken<'a>> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'n>(&'n self, tokens: &mut Vec<&'n CstToken<'a>>) {
+        for child in self.children.iter() {
+            match child {
+                Element::Node(node) => node.collect_tokens(tokens),
+                Element::Token(token) => tokens.push(token),
+            }
+        }
+    }
+}
+
+impl<'a> Display for Node<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.children.iter().try_for_each(|child| match child {
+            Element::Node(node) => write!(f, "{}", node),
+            Element::Token(token) => write!(f, "{}", token),
+        })
+    }
+}
+
+/// The lossless tree of the chunk, every byte of the source belongs either to a token or to its trivia,
+/// hence printing the tree gives the source back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst<'a> {
+    pub root: Node<'a>,
+    /// The trivia after the last token.
+    pub eof: Vec<Trivia<'a>>,
+}
+
+impl<'a> Cst<'a> {
+    pub fn parse(src: &'a str, dialect: Dialect) -> Result<Self, Box<SyntaxError>> {
+        let (ast, tokens, spans) = LuaParser::new(src, dialect).chunk_tokens()?;
+        let (tokens, eof) = with_trivia(src, dialect, tokens, spans);
+        let chunk = Shape {
+            kind: SyntaxKind::Chunk,
+            span: LineIndex::new(src).span(0..src.len()),
+            children: vec![block(&ast)],
+        };
+        let root = build(chunk, &mut tokens.into_iter().peekable());
+        Ok(Cst { root, eof })
+    }
+
+    /// The ast read off the tree, the same the parser gives for the source.
+    pub fn ast(&self) -> Block<'a> {
+        match &self.root.children[..] {
+            [Element::Node(block)] => lower::block(block),
+            _ => unreachable!("the chunk is a single block"),
+        }
+    }
+}
+
+impl<'a> Display for Cst<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root)?;
+        self.eof.iter().try_for_each(|t| f.write_str(t.text))
+    }
+}
+
+/// Attaches the trivia to the tokens of the parser, returns them along with the trivia at the end of the source.
+/// The numerals of the tokens are the ones of the dialect.
+fn with_trivia<'a>(src: &'a str,
+                   dialect: Dialect,
+                   lexed: Vec<Token<'a>>,
+                   spans: Vec<Span>) -> (Vec<CstToken<'a>>, Vec<Trivia<'a>>) {
+    let lines = LineIndex::new(src);
+    let mut tokens: Vec<CstToken> = vec![];
+    let mut last = 0;
+    for (token, span) in lexed.into_iter().zip(spans) {
+        let token = match token {
+            Token::Digit(n) => Token::Digit(dialect.number(n, &src[span.range()])),
+            t => t,
+        };
+        let leading = split_trivia(src, last..span.start, &lines, tokens.last_mut());
+        tokens.push(CstToken { token, text: &src[span.range()], span, leading, trailing: vec![] });
+        last = span.end;
+    }
+    let eof = split_trivia(src, last..src.len(), &lines, tokens.last_mut());
+    (tokens, eof)
+}
+
+/// Gives the trivia of the range to the trailing trivia of the previous token up to the end of its line,
+/// and returns the rest.
+fn split_trivia<'a>(src: &'a str,
+                    range: Range<usize>,
+                    lines: &LineIndex,
+                    mut prev: Option<&mut CstToken<'a>>) -> Vec<Trivia<'a>> {
+    let mut leading = vec![];
+    for (kind, r) in trivia(src, range) {
+        match prev.as_deref_mut() {
+            Some(token) => {
+                let text = &src[r.clone()];
+                let line_end = if kind == TriviaKind::Whitespace { line_break_end(text) } else { None };
+                match line_end {
+                    Some(end) => {
+                        let end = r.start + end;
+                        token.trailing.push(Trivia { kind, text: &src[r.start..end], span: lines.span(r.start..end) });
+                        if end < r.end {
+                            leading.push(Trivia { kind, text: &src[end..r.end], span: lines.span(end..r.end) });
+                        }
+                        prev = None;
+                    }
+                    None => token.trailing.push(Trivia { kind, text, span: lines.span(r) }),
+                }
+            }
+            None => leading.push(Trivia { kind, text: &src[r.clone()], span: lines.span(r) }),
+        }
+    }
+    leading
+}
+
+/// The end of the first line break (`\n`, `\r`, `\r\n` or `\n\r`) in the whitespace.
+fn line_break_end(ws: &str) -> Option<usize> {
+    let i = ws.find(['\n', '\r'])?;
+    let pair = matches!(&ws.as_bytes()[i..], [b'\r', b'\n', ..] | [b'\n', b'\r', ..]);
+    Some(i + if pair { 2 } else { 1 })
+}
+
+/// Splits the text between two tokens into the comments and the whitespaces.
+fn trivia(src: &str, range: Range<usize>) -> Vec<(TriviaKind, Range<usize>)> {
+    let mut res = vec![];
+    let mut pos = range.start;
+    while pos < range.end {
+        let rest = &src[pos..range.end];
+        let (kind, len) = if rest.starts_with("--") {
+            (TriviaKind::Comment, comment_len(rest).unwrap_or(rest.len()))
+        } else if rest.starts_with("#!") {
+            (TriviaKind::Shebang, rest.find(['\r', '\n']).unwrap_or(rest.len()))
+        } else {
+            let len = rest.find(|c: char| !matches!(c, ' ' | '\t' | '\u{c}' | '\r' | '\n')).unwrap_or(rest.len());
+            (TriviaKind::Whitespace, len.max(rest.chars().next().map_or(1, char::len_utf8)))
+        };
+        res.push((kind, pos..pos + len));
+        pos += len;
+    }
+    res
+}
+
+/// The outline of the tree taken from the ast before the tokens are distributed over it.
+struct Shape {
+    kind: SyntaxKind,
+    span: Span,
+    children: Vec<Shape>,
+}
+
+impl Shape {
+    fn new(kind: SyntaxKind, span: Span, children: Vec<Shape>) -> Self {
+        Shape { kind, span, children }
+    }
+    fn leaf(kind: SyntaxKind, span: Span) -> Self {
+        Shape::new(kind, span, vec![])
+    }
+}
+
+/// Puts every token that lies inside the span of the shape either into the node or into one of its children.
+fn build<'a, I>(shape: Shape, tokens: &mut Peekable<I>) -> Node<'a>
+    where I: Iterator<Item=CstToken<'a>>
+{
+    let end = shape.span.end;
+    let mut shapes = shape.children.into_iter().peekable();
+    let mut children = vec![];
+    loop {
+        let next = tokens.peek().map(|t| t.span.start).filter(|start| *start < end);
+        if let Some(child) = shapes.next_if(|c| next.is_none_or(|start| c.span.start <= start)) {
+            children.push(Element::Node(build(child, tokens)));
+        } else if next.is_some() {
+            children.extend(tokens.next().map(Element::Token));
+        } else {
+            break;
+        }
+    }
+    Node { kind: shape.kind, span: shape.span, children }
+}
+
+fn block(block: &Block) -> Shape {
+    let children = match block {
+        Block::Void(sts, _) => sts.iter().map(statement).collect(),
+        Block::Return(sts, exprs, _) => sts.iter().map(statement).chain(exprs.iter().map(expr)).collect(),
+    };
+    Shape::new(SyntaxKind::Block, block.span(), children)
+}
+
+fn statement(st: &Statement) -> Shape {
+    let span = st.span();
+    match st {
+        Statement::Empty(_) => Shape::leaf(SyntaxKind::Empty, span),
+        Statement::Error(_) => Shape::leaf(SyntaxKind::Error, span),
+        Statement::Break(_) => Shape::leaf(SyntaxKind::Break, span),
+        Statement::Label(..) => Shape::leaf(SyntaxKind::Label, span),
+        Statement::Goto(..) => Shape::leaf(SyntaxKind::Goto, span),
+        Statement::Assignment(vars, exprs) => Shape::new(
+            SyntaxKind::Assignment,
+            span,
+            vars.iter().map(var).chain(exprs.iter().map(expr)).collect(),
+        ),
+        Statement::FnCall(call) => Shape::new(SyntaxKind::Call, span, fn_call(call)),
+        Statement::Do(body, _) => Shape::new(SyntaxKind::Do, span, vec![block(body)]),
+        Statement::While(w) => Shape::new(SyntaxKind::While, span, vec![expr(&w.cond), block(&w.body)]),
+        Statement::Repeat(r) => Shape::new(SyntaxKind::Repeat, span, vec![block(&r.body), expr(&r.until)]),
+        Statement::If(If::If(main, others, _)) => Shape::new(
+            SyntaxKind::If,
+            span,
+            Some(main).into_iter().chain(others).map(branch).collect(),
+        ),
+        Statement::If(If::IfElse(main, others, els, _)) => Shape::new(
+            SyntaxKind::If,
+            span,
+            Some(main).into_iter().chain(others).map(branch).chain(Some(block(els))).collect(),
+        ),
+        Statement::For(For::Plain(f)) => Shape::new(
+            SyntaxKind::NumericFor,
+            span,
+            vec![expr(&f.init.1), expr(&f.border)].into_iter()
+                .chain(f.step.iter().map(expr))
+                .chain(Some(block(&f.body)))
+                .collect(),
+        ),
+        Statement::For(For::ForCol(f)) => Shape::new(
+            SyntaxKind::GenericFor,
+            span,
+            f.expressions.iter().map(expr).chain(Some(block(&f.body))).collect(),
+        ),
+        Statement::FnDef(def) => Shape::new(SyntaxKind::Function, span, vec![block(&def.body)]),
+        Statement::LocalFnDef(def) => Shape::new(SyntaxKind::LocalFunction, span, vec![block(&def.body)]),
+        Statement::LocalAttrNames(_, exprs, _) => Shape::new(SyntaxKind::Local, span, exprs.iter().map(expr).collect()),
+    }
+}
+
+fn branch(branch: &IfBranch) -> Shape {
+    Shape::new(SyntaxKind::Branch, branch.span, vec![expr(&branch.cond), block(&branch.body)])
+}
+
+fn expr(e: &Expression) -> Shape {
+    let span = e.span();
+    match e {
+        Expression::Nil(_) => Shape::leaf(SyntaxKind::Nil, span),
+        Expression::True(_) => Shape::leaf(SyntaxKind::True, span),
+        Expression::False(_) => Shape::leaf(SyntaxKind::False, span),
+        Expression::Number(..) => Shape::leaf(SyntaxKind::Number, span),
+        Expression::Text(_) => Shape::leaf(SyntaxKind::String, span),
+        Expression::VarArgs(_) => Shape::leaf(SyntaxKind::VarArgs, span),
+        Expression::Error(_) => Shape::leaf(SyntaxKind::Error, span),
+        Expression::FnDef(_, body, _) => Shape::new(SyntaxKind::FunctionDef, span, vec![block(body)]),
+        Expression::PrefixExpr(call) => Shape::new(SyntaxKind::Prefix, span, fn_call(call)),
+        Expression::TableConstructor(table) => Shape::new(SyntaxKind::Table, span, fields(table)),
+        Expression::Unary(_, operand, _) => Shape::new(SyntaxKind::Unary, span, vec![expr(operand)]),
+        Expression::Binary(lhs, _, rhs) => Shape::new(SyntaxKind::Binary, span, vec![expr(lhs), expr(rhs)]),
+    }
+}
+
+fn fn_call(call: &FnCall) -> Vec<Shape> {
+    let head = match &call.head {
+        VarOrExpr::Expr(e) => expr(e),
+        VarOrExpr::Var(v) => var(v),
+    };
+    Some(head).into_iter().chain(call.args.iter().map(name_args)).collect()
+}
+
+fn var(v: &Var) -> Shape {
+    let mut children = vec![];
+    if let VarHead::Expr(e, suffix) = &v.head {
+        children.push(expr(e));
+        children.extend(var_suffix(suffix));
+    }
+    children.extend(v.tail.iter().flat_map(var_suffix));
+    Shape::new(SyntaxKind::Var, v.span, children)
+}
+
+fn var_suffix(suffix: &VarSuffix) -> Vec<Shape> {
+    let mut children: Vec<Shape> = suffix.var.iter().map(name_args).collect();
+    if let Suffix::Expr(e) = &suffix.suffix {
+        children.push(expr(e));
+    }
+    children
+}
+
+fn name_args(name_args: &NameArgs) -> Shape {
+    let children = match name_args {
+        NameArgs::Args(args) | NameArgs::NameArgs(_, args) => match args {
+            Args::Expressions(exprs, _) => exprs.iter().map(expr).collect(),
+            Args::Constructor(table) => vec![Shape::new(SyntaxKind::Table, table.span, fields(table))],
+            Args::String(text) => vec![Shape::leaf(SyntaxKind::String, text.span)],
+        },
+    };
+    Shape::new(SyntaxKind::Args, name_args.span(), children)
+}
+
+fn fields(table: &TableConst) -> Vec<Shape> {
+    table.fields.iter()
+        .map(|field| {
+            let children = match field {
+                Field::Pair(FieldKey::Expr(k), v) => vec![expr(k), expr(v)],
+                Field::Pair(FieldKey::Id(_), v) | Field::Value(v) => vec![expr(v)],
+            };
+            Shape::new(SyntaxKind::Field, field.span(), children)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::cst::{Cst, Element, SyntaxKind, TriviaKind};
+    use crate::parser::dialect::{Dialect, Version};
+    use crate::parser::{parse_chunk, parse_chunk_with};
+    use crate::parser::tokens::Token;
+
+    fn cst(src: &str) -> Cst<'_> {
+        Cst::parse(src, Dialect::default()).unwrap()
+    }
+
+    #[test]
+    fn lossless_test() {
+        let scripts = [
+            include_str!("../scripts/lazy.lua"),
+            include_str!("../scripts/cassandra.lua"),
+            include_str!("../scripts/treesetter.lua"),
+            include_str!("../scripts/server.lua"),
+            "#!/usr/bin/lua\r\n-- header\r\n\r\nlocal a = { 1; 2, [3] = f 'x' } --[[ tail ]]\r\n\n",
+            "",
+            "  -- only trivia\n",
+        ];
+        for src in scripts {
+            let cst = cst(src);
+            assert_eq!(cst.to_string(), src);
+            assert!(matches!(&cst.root.children[..], [Element::Node(b)] if b.kind == SyntaxKind::Block));
+            assert_eq!(cst.ast(), parse_chunk(src).unwrap());
+        }
+    }
+
+    #[test]
+    fn ast_test() {
+        let scripts = [
+            "a.b:c(1)('x'){2}.d[(e)] = ((f))(g):h 's'",
+            "(f):m().x, t[#t + 1] = -(a) ^ -b .. c, not (d == e) and {x = 1, [y] = 2; 3}",
+            "local a <const>, b <close>, c = ... return (a), b",
+            "for i = 1, (n), -1 do break end for k, v in pairs(t), nil do ::l:: goto l end",
+            "if a then elseif (b) then ; else return end while a do repeat until b end do end",
+            "function a.b.c:d(x, ...) return function(...) end end local function f() end",
+        ];
+        for src in scripts {
+            assert_eq!(cst(src).ast(), parse_chunk(src).unwrap(), "{}", src);
+        }
+
+        let src = "local goto = 0x10 goto.x = 1.5 return goto";
+        let dialect = Version::Lua51.into();
+        assert_eq!(Cst::parse(src, dialect).unwrap().ast(), parse_chunk_with(src, dialect).unwrap());
+    }
+
+    #[test]
+    fn trivia_test() {
+        let src = "---@param a number\n-- doc\nfunction f(a) -- tail\n\n  return a --[[ x\n]] end\n-- eof\n";
+        let cst = cst(src);
+        let tokens = cst.root.tokens();
+
+        let function = tokens[0];
+        assert_eq!(function.token, Token::Function);
+        let leading: Vec<_> = function.leading.iter().map(|t| (t.kind, t.text)).collect();
+        assert_eq!(leading, vec![
+            (TriviaKind::Comment, "---@param a number"),
+            (TriviaKind::Whitespace, "\n"),
+            (TriviaKind::Comment, "-- doc"),
+            (TriviaKind::Whitespace, "\n"),
+        ]);
+
+        let r_pr = tokens.iter().find(|t| t.token == Token::RParen).unwrap();
+        let trailing: Vec<_> = r_pr.trailing.iter().map(|t| t.text).collect();
+        assert_eq!(trailing, vec![" ", "-- tail", "\n"]);
+
+        let ret = tokens.iter().find(|t| t.token == Token::Return).unwrap();
+        assert_eq!(ret.leading.iter().map(|t| t.text).collect::<String>(), "\n  ");
+        assert_eq!((ret.span.line, ret.leading[0].span.line), (5, 4));
+
+        assert_eq!(cst.eof.iter().map(|t| t.text).collect::<String>(), "-- eof\n");
+
+        let cst = Cst::parse("local goto = 1", Version::Lua51.into()).unwrap();
+        assert_eq!(cst.root.tokens()[1].token, Token::Id("goto"));
+    }
+
+    #[test]
+    fn node_test() {
+        let cst = cst("local x = -a + f(1)");
+        let block = match &cst.root.children[..] {
+            [Element::Node(block)] => block,
+            other => panic!("unexpected {:?}", other),
+        };
+        let local = match &block.children[..] {
+            [Element::Node(local)] => local,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!((block.kind, local.kind), (SyntaxKind::Block, SyntaxKind::Local));
+        let kinds: Vec<_> = local.children.iter()
+            .map(|c| match c {
+                Element::Node(n) => format!("{:?}", n.kind),
+                Element::Token(t) => t.text.to_string(),
+            })
+            .collect();
+        assert_eq!(kinds, vec!["local", "x", "=", "Binary"]);
+        assert_eq!(local.to_string(), "local x = -a + f(1)");
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::parser::ast::Number;
+use crate::parser::tokens::Token;
+
+/// The version of lua the source is written for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    Lua51,
+    Lua52,
+    Lua53,
+    #[default]
+    Lua54,
+    LuaJIT,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version::Lua51 => f.write_str("Lua 5.1"),
+            Version::Lua52 => f.write_str("Lua 5.2"),
+            Version::Lua53 => f.write_str("Lua 5.3"),
+            Version::Lua54 => f.write_str("Lua 5.4"),
+            Version::LuaJIT => f.write_str("LuaJIT"),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "5.1" => Ok(Version::Lua51),
+            "5.2" => Ok(Version::Lua52),
+            "5.3" => Ok(Version::Lua53),
+            "5.4" => Ok(Version::Lua54),
+            "luajit" | "jit" => Ok(Version::LuaJIT),
+            _ => Err(format!("unknown lua version '{}', expected one of 5.1, 5.2, 5.3, 5.4, luajit", s)),
+        }
+    }
+}
+
+/// The flavour of lua the parser accepts.
+/// The features are derived from the version and can be toggled one by one afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dialect {
+    pub version: Version,
+    /// `goto` and `::labels::`, since 5.2 and in LuaJIT.
+    /// Without it `goto` is an ordinary name.
+    pub goto: bool,
+    /// The floor division `//`, since 5.3.
+    pub floor_division: bool,
+    /// The operators `&`, `|`, `~`, `<<` and `>>`, since 5.3.
+    pub bitwise: bool,
+    /// The attributes `<const>` and `<close>` of the local variables, since 5.4.
+    pub attributes: bool,
+    /// The integer subtype, since 5.3. Otherwise every numeral is a float.
+    pub integers: bool,
+    /// The binary integers `0b101`. It is an extension, no lua version has them.
+    pub binary_literals: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect::new(Version::default())
+    }
+}
+
+impl From<Version> for Dialect {
+    fn from(version: Version) -> Self {
+        Dialect::new(version)
+    }
+}
+
+impl Dialect {
+    pub fn new(version: Version) -> Self {
+        let (goto, since_53, since_54) = match version {
+            Version::Lua51 => (false, false, false),
+            Version::Lua52 | Version::LuaJIT => (true, false, false),
+            Version::Lua53 => (true, true, false),
+            Version::Lua54 => (true, true, true),
+        };
+        Dialect {
+            version,
+            goto,
+            floor_division: since_53,
+            bitwise: since_53,
+            attributes: since_54,
+            integers: since_53,
+            binary_literals: false,
+        }
+    }
+
+    pub fn with_binary_literals(mut self) -> Self {
+        self.binary_literals = true;
+        self
+    }
+
+    /// The numeral as the dialect sees it, `lexeme` is its source text.
+    pub(crate) fn number(&self, number: Number, lexeme: &str) -> Number {
+        match number {
+            Number::Int(v) if !self.integers => Number::Float(v as f64),
+            Number::Hex(_) if !self.integers => Number::Float(hex_float(lexeme)),
+            n => n,
+        }
+    }
+
+    /// The token as the dialect sees it, `goto` is a name before 5.2.
+    pub(crate) fn token<'a>(&self, token: Token<'a>) -> Token<'a> {
+        match token {
+            Token::Goto if !self.goto => Token::Id("goto"),
+            t => t,
+        }
+    }
+
+    /// The reason to reject the token if it belongs to a feature the dialect lacks.
+    /// `<` is not checked here, it opens an attribute only after a name of `local`.
+    pub(crate) fn check(&self, token: &Token) -> Option<String> {
+        match token {
+            Token::DColon if !self.goto => Some(self.unsupported("label")),
+            Token::FDiv if !self.floor_division => Some(self.unsupported("floor division")),
+            Token::Ampersand | Token::Stick | Token::Tilde | Token::LShift | Token::RShift if !self.bitwise =>
+                Some(self.unsupported("bitwise operator")),
+            Token::Digit(Number::Binary(_)) if !self.binary_literals => Some("malformed number".to_string()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn unsupported(&self, feature: &str) -> String {
+        format!("{} is not supported by {}", feature, self.version)
+    }
+}
+
+/// The hexadecimal integer without the wrap around, lua before 5.3 reads them as floats.
+fn hex_float(lexeme: &str) -> f64 {
+    lexeme[2..].chars()
+        .filter_map(|c| c.to_digit(16))
+        .fold(0.0, |acc, d| acc * 16.0 + d as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::Number;
+    use crate::parser::dialect::{Dialect, Version};
+    use crate::parser::lexer;
+
+    fn reasons(src: &str, version: Version) -> Vec<String> {
+        let dialect = Dialect::from(version);
+        lexer(src).filter_map(|(t, _)| dialect.check(&dialect.token(t.unwrap()))).collect()
+    }
+
+    #[test]
+    fn version_test() {
+        assert_eq!("5.1".parse::<Version>(), Ok(Version::Lua51));
+        assert_eq!("LuaJIT".parse::<Version>(), Ok(Version::LuaJIT));
+        assert!("5.5".parse::<Version>().is_err());
+        assert_eq!(Dialect::default(), Dialect::new(Version::Lua54));
+        assert!(!Dialect::new(Version::LuaJIT).bitwise);
+        assert!(Dialect::new(Version::LuaJIT).goto);
+    }
+
+    #[test]
+    fn feature_test() {
+        let src = "local a <const>, b <close> = x // 2, y & 1 goto l ::l:: if a < b then end";
+        assert!(reasons(src, Version::Lua54).is_empty());
+        assert!(reasons(src, Version::Lua53).is_empty());
+        assert_eq!(reasons(src, Version::Lua51), vec![
+            "floor division is not supported by Lua 5.1",
+            "bitwise operator is not supported by Lua 5.1",
+            "label is not supported by Lua 5.1",
+            "label is not supported by Lua 5.1",
+        ]);
+        assert!(reasons("local goto = t.goto goto = 1", Version::Lua51).is_empty());
+        assert_eq!(reasons("a = 0b101", Version::Lua54), vec!["malformed number"]);
+    }
+
+    #[test]
+    fn number_test() {
+        let jit = Dialect::new(Version::LuaJIT);
+        assert_eq!(jit.number(Number::Int(3), "3"), Number::Float(3.0));
+        assert_eq!(jit.number(Number::Hex(-1), "0xffffffffffffffff"), Number::Float(18446744073709551615.0));
+        assert_eq!(Dialect::default().number(Number::Hex(-1), "0xffffffffffffffff"), Number::Hex(-1));
+    }
+}
@@ -0,0 +1,256 @@
+//! The line diff of `cran_lua fmt --diff`: the shortest edit script of Myers
+//! found with the middle snakes, so it takes the memory linear in the number of lines.
+//! The lines of one file only are left out of the search, so rewriting every line is fast too.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::iter::repeat_n;
+
+/// The line of the edit script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// The unified diff of the lines with 3 lines of context, only the header when the texts are the same.
+pub fn unified_diff(file: &str, old: &str, new: &str) -> String {
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // every line of the script along with its line numbers in both files
+    let mut script = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j) = (0, 0);
+    for edit in diff(&a, &b) {
+        let line = if edit == Edit::Insert { b[j] } else { a[i] };
+        script.push((edit, line, i, j));
+        match edit {
+            Edit::Equal => (i, j) = (i + 1, j + 1),
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+
+    const CONTEXT: usize = 3;
+    let mut out = format!("--- {}\n+++ {}\n", file, file);
+    let changes: Vec<usize> = (0..script.len()).filter(|k| script[*k].0 != Edit::Equal).collect();
+    let mut k = 0;
+    while k < changes.len() {
+        let start = changes[k].saturating_sub(CONTEXT);
+        let mut end = changes[k];
+        while k < changes.len() && changes[k] <= end + 2 * CONTEXT {
+            end = changes[k];
+            k += 1;
+        }
+        let end = (end + CONTEXT + 1).min(script.len());
+        let hunk = &script[start..end];
+        let old_len = hunk.iter().filter(|l| l.0 != Edit::Insert).count();
+        let new_len = hunk.iter().filter(|l| l.0 != Edit::Delete).count();
+        let (_, _, old_start, new_start) = hunk[0];
+        out.push_str(&format!("@@ -{},{} +{},{} @@\n", old_start + 1, old_len, new_start + 1, new_len));
+        for (edit, line, _, _) in hunk {
+            let tag = match edit {
+                Edit::Equal => ' ',
+                Edit::Delete => '-',
+                Edit::Insert => '+',
+            };
+            out.push_str(&format!("{}{}\n", tag, line));
+        }
+    }
+    out
+}
+
+/// The shortest edit script turning `a` into `b`.
+pub fn diff<T: Eq + Hash>(a: &[T], b: &[T]) -> Vec<Edit> {
+    // the lines of one side only are never equal, the search goes without them
+    let (in_a, in_b): (HashSet<&T>, HashSet<&T>) = (a.iter().collect(), b.iter().collect());
+    let common_a: Vec<usize> = (0..a.len()).filter(|i| in_b.contains(&a[*i])).collect();
+    let common_b: Vec<usize> = (0..b.len()).filter(|j| in_a.contains(&b[*j])).collect();
+    let (ra, rb): (Vec<&T>, Vec<&T>) = (common_a.iter().map(|i| &a[*i]).collect(), common_b.iter().map(|j| &b[*j]).collect());
+
+    let mut reduced = Vec::with_capacity(ra.len().max(rb.len()));
+    let size = 2 * (ra.len() + rb.len()) + 3;
+    let mut snakes = Snakes { forward: vec![0; size], backward: vec![0; size], offset: (ra.len() + rb.len() + 1) as isize };
+    snakes.conquer(&ra, &rb, &mut reduced);
+
+    let mut script = Vec::with_capacity(a.len().max(b.len()));
+    let (mut i, mut j, mut p, mut q) = (0, 0, 0, 0);
+    for edit in reduced {
+        match edit {
+            Edit::Equal => {
+                script.extend(repeat_n(Edit::Delete, common_a[p] - i));
+                script.extend(repeat_n(Edit::Insert, common_b[q] - j));
+                script.push(Edit::Equal);
+                (i, j) = (common_a[p] + 1, common_b[q] + 1);
+                (p, q) = (p + 1, q + 1);
+            }
+            Edit::Delete => {
+                script.extend(repeat_n(Edit::Delete, common_a[p] + 1 - i));
+                (i, p) = (common_a[p] + 1, p + 1);
+            }
+            Edit::Insert => {
+                script.extend(repeat_n(Edit::Insert, common_b[q] + 1 - j));
+                (j, q) = (common_b[q] + 1, q + 1);
+            }
+        }
+    }
+    script.extend(repeat_n(Edit::Delete, a.len() - i));
+    script.extend(repeat_n(Edit::Insert, b.len() - j));
+    script
+}
+
+/// The furthest reaching paths by the diagonals, shared by all steps of the recursion.
+struct Snakes {
+    forward: Vec<usize>,
+    backward: Vec<usize>,
+    offset: isize,
+}
+
+impl Snakes {
+    fn conquer<T: PartialEq>(&mut self, a: &[T], b: &[T], script: &mut Vec<Edit>) {
+        let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+        let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev()).take_while(|(x, y)| x == y).count();
+        script.extend(repeat_n(Edit::Equal, prefix));
+        let (a, b) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+        if a.is_empty() || b.is_empty() {
+            script.extend(repeat_n(Edit::Delete, a.len()));
+            script.extend(repeat_n(Edit::Insert, b.len()));
+        } else {
+            let (x, y, end_x) = self.middle_snake(a, b);
+            self.conquer(&a[..x], &b[..y], script);
+            script.extend(repeat_n(Edit::Equal, end_x - x));
+            self.conquer(&a[end_x..], &b[y + end_x - x..], script);
+        }
+        script.extend(repeat_n(Edit::Equal, suffix));
+    }
+
+    /// The start (`x`, `y`) and the end (`x`) of the snake in the middle of a shortest path,
+    /// the paths go from both corners until they meet on a diagonal.
+    fn middle_snake<T: PartialEq>(&mut self, a: &[T], b: &[T]) -> (usize, usize, usize) {
+        let (n, m) = (a.len() as isize, b.len() as isize);
+        // the diagonal `k` of the forward path is `x - y`, the one of the backward path `(n - x) - (m - y)`
+        let delta = n - m;
+        let odd = delta & 1 == 1;
+        let at = |k: isize| (k + self.offset) as usize;
+        self.forward[at(1)] = 0;
+        self.backward[at(1)] = 0;
+
+        for d in 0..=(n + m + 1) / 2 {
+            for k in (-d..=d).step_by(2) {
+                let mut x = if k == -d || (k != d && self.forward[at(k - 1)] < self.forward[at(k + 1)]) {
+                    self.forward[at(k + 1)] as isize
+                } else {
+                    self.forward[at(k - 1)] as isize + 1
+                };
+                let (start_x, start_y) = (x, x - k);
+                let mut y = start_y;
+                while x < n && y < m && a[x as usize] == b[y as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                self.forward[at(k)] = x as usize;
+                let back = delta - k;
+                if odd && (-(d - 1)..=d - 1).contains(&back) && x + self.backward[at(back)] as isize >= n {
+                    return (start_x as usize, start_y as usize, x as usize);
+                }
+            }
+
+            for k in (-d..=d).step_by(2) {
+                // `x` and `y` are counted from the ends of `a` and `b`
+                let mut x = if k == -d || (k != d && self.backward[at(k - 1)] < self.backward[at(k + 1)]) {
+                    self.backward[at(k + 1)] as isize
+                } else {
+                    self.backward[at(k - 1)] as isize + 1
+                };
+                let end_x = x;
+                let mut y = x - k;
+                while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                    x += 1;
+                    y += 1;
+                }
+                self.backward[at(k)] = x as usize;
+                let forth = delta - k;
+                if !odd && (-d..=d).contains(&forth) && x + self.forward[at(forth)] as isize >= n {
+                    return ((n - x) as usize, (m - y) as usize, (n - end_x) as usize);
+                }
+            }
+        }
+        unreachable!("the paths meet in (n + m) / 2 steps")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use crate::parser::diff::{diff, unified_diff, Edit};
+
+    /// The length of the longest common subsequence, the edit script keeps all of it.
+    fn lcs(a: &[u8], b: &[u8]) -> usize {
+        let mut row = vec![0; b.len() + 1];
+        for x in a {
+            let mut diagonal = 0;
+            for (j, y) in b.iter().enumerate() {
+                let next = if x == y { diagonal + 1 } else { row[j + 1].max(row[j]) };
+                diagonal = row[j + 1];
+                row[j + 1] = next;
+            }
+        }
+        row[b.len()]
+    }
+
+    /// Applies the script to `a`.
+    fn apply(a: &[u8], b: &[u8], script: &[Edit]) -> Vec<u8> {
+        let (mut i, mut j, mut res) = (0, 0, vec![]);
+        for edit in script {
+            match edit {
+                Edit::Equal => {
+                    assert_eq!(a[i], b[j]);
+                    res.push(a[i]);
+                    i += 1;
+                    j += 1;
+                }
+                Edit::Delete => i += 1,
+                Edit::Insert => {
+                    res.push(b[j]);
+                    j += 1;
+                }
+            }
+        }
+        assert_eq!(i, a.len());
+        res
+    }
+
+    #[test]
+    fn unified_diff_test() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(unified_diff("x.lua", old, new), "--- x.lua\n+++ x.lua\n\
+            @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+            @@ -8,3 +8,4 @@\n h\n i\n j\n+k\n");
+        assert_eq!(unified_diff("x.lua", "a\n", "a\n"), "--- x.lua\n+++ x.lua\n");
+        assert_eq!(unified_diff("x.lua", "", "a\n"), "--- x.lua\n+++ x.lua\n@@ -1,0 +1,1 @@\n+a\n");
+    }
+
+    #[test]
+    fn large_test() {
+        // the table of the common subsequences would take 1.6GB here
+        let old: String = (0..20_000).map(|i| format!("line {}\n", i)).collect();
+        let new: String = (0..20_000).map(|i| if i % 1000 == 0 { "changed\n".to_string() } else { format!("line {}\n", i) }).collect();
+        let diff = unified_diff("x.lua", &old, &new);
+        assert_eq!(diff.lines().filter(|l| l.starts_with("@@")).count(), 20);
+        assert_eq!(diff.lines().filter(|l| l.starts_with('-') && !l.starts_with("---")).count(), 20);
+        assert_eq!(diff.lines().filter(|l| l.starts_with('+') && !l.starts_with("+++")).count(), 20);
+    }
+
+    proptest! {
+        #[test]
+        fn shortest_script(a in prop::collection::vec(0u8..6, 0..40), b in prop::collection::vec(2u8..8, 0..40)) {
+            let script = diff(&a, &b);
+            prop_assert_eq!(apply(&a, &b, &script), b.clone());
+            let equal = script.iter().filter(|e| **e == Edit::Equal).count();
+            prop_assert_eq!(equal, lcs(&a, &b));
+        }
+    }
+}
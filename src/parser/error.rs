@@ -0,0 +1,145 @@
+use std::fmt::{Display, Formatter};
+use crate::parser::span::{LineIndex, Span};
+
+/// The syntax error in a lua source:
+/// the place where the parser stopped along with what it expected and what it found there.
+/// The `serde` feature serializes the span inline: `file`, `start`, `end`, `line`, `col`, `expected`, `found`, `reason`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SyntaxError {
+    pub file: Option<String>,
+    #[cfg_attr(feature = "serde", serde(flatten))]
+    pub span: Span,
+    pub expected: Vec<String>,
+    pub found: String,
+    /// The reason given by the lexer when the token can not be recognized at all.
+    pub reason: Option<String>,
+}
+
+impl SyntaxError {
+    pub fn new(span: Span, expected: Vec<String>, found: String) -> Self {
+        SyntaxError { file: None, span, expected, found, reason: None }
+    }
+
+    pub fn lexical(span: Span, found: String, reason: String) -> Self {
+        let reason = if reason.is_empty() { None } else { Some(reason) };
+        SyntaxError { file: None, span, expected: vec![], found, reason }
+    }
+
+    /// The error found in the well formed source, like `break` outside of a loop.
+    pub fn semantic(span: Span, message: String) -> Self {
+        SyntaxError { file: None, span, expected: vec![], found: String::new(), reason: Some(message) }
+    }
+
+    pub fn with_file(self, file: &str) -> Self {
+        SyntaxError { file: Some(file.to_string()), ..self }
+    }
+
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.span.col
+    }
+
+    pub fn message(&self) -> String {
+        match (&self.reason, self.expected.as_slice()) {
+            (Some(reason), _) if self.found.is_empty() => reason.clone(),
+            (Some(reason), _) => format!("{} near {}", reason, self.found),
+            (None, []) => format!("unexpected symbol near {}", self.found),
+            (None, [single]) => format!("expected {}, found {}", single, self.found),
+            (None, many) => format!("expected one of {}, found {}", many.join(", "), self.found),
+        }
+    }
+
+    /// Renders the error with the line of the source and the caret under the place of the error.
+    pub fn render(&self, src: &str) -> String {
+        let lines = LineIndex::new(src);
+        let line = lines.line(self.span.line);
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        let prefix: String = line
+            .chars()
+            .take(self.span.col.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = src
+            .get(self.span.range())
+            .and_then(|s| s.lines().next())
+            .map(|s| s.chars().count())
+            .unwrap_or(0)
+            .max(1);
+
+        format!(
+            "error: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            self.message(),
+            gutter, self.location(),
+            gutter,
+            number, line,
+            gutter, prefix, "^".repeat(width)
+        )
+    }
+
+    fn location(&self) -> String {
+        format!("{}:{}", self.file.as_deref().unwrap_or("<chunk>"), self.span)
+    }
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location(), self.message())
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::error::SyntaxError;
+    use crate::parser::span::Span;
+
+    fn error() -> SyntaxError {
+        SyntaxError::new(
+            Span::new(11, 12, 2, 6),
+            vec!["'then'".to_string(), "'and'".to_string()],
+            "'x'".to_string(),
+        )
+    }
+
+    #[test]
+    fn message_test() {
+        assert_eq!(error().message(), "expected one of 'then', 'and', found 'x'");
+        assert_eq!(error().with_file("a.lua").to_string(), "a.lua:2:6: expected one of 'then', 'and', found 'x'");
+        let lex = SyntaxError::lexical(Span::new(0, 1, 1, 1), "'@'".to_string(), String::new());
+        assert_eq!(lex.message(), "unexpected symbol near '@'");
+        let sem = SyntaxError::semantic(Span::new(0, 5, 1, 1), "break outside a loop at line 1".to_string());
+        assert_eq!(sem.with_file("a.lua").to_string(), "a.lua:1:1: break outside a loop at line 1");
+    }
+
+    #[test]
+    fn render_test() {
+        let src = "local a\nif a x\n  f()\nend";
+        assert_eq!(
+            error().with_file("a.lua").render(src),
+            "error: expected one of 'then', 'and', found 'x'\n \
+               --> a.lua:2:6\n  \
+                |\n\
+             2 | if a x\n  \
+                |      ^\n"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        let e = error().with_file("dir\\a \"b\".lua");
+        let json = serde_json::to_string(&e).unwrap();
+        assert_eq!(
+            json,
+            r#"{"file":"dir\\a \"b\".lua","start":11,"end":12,"line":2,"col":6,"expected":["'then'","'and'"],"found":"'x'","reason":null}"#
+        );
+        assert_eq!(serde_json::from_str::<SyntaxError>(&json).unwrap(), e);
+    }
+}
@@ -1,11 +1,12 @@
-use std::collections::HashMap;
-use std::fmt::{Display, format, Formatter};
-use std::vec::IntoIter;
-use crate::parser::ast::{BinaryType, Expression, Number, UnaryType};
+use std::fmt::{Display, Formatter};
+use crate::parser::ast::{BinaryType, UnaryType};
+#[cfg(test)]
+use crate::parser::ast::Expression;
 use crate::parser::ast::BinaryType::*;
+use crate::parser::tokens::Token;
 
 
-const fn expr_priority(tp: &BinaryType) -> (i32, i32) {
+pub(crate) const fn expr_priority(tp: &BinaryType) -> (i32, i32) {
     match tp {
         Pov => (14, 13),
         Mult | Div | FDiv | Mod => (11, 11),
@@ -22,54 +23,63 @@ const fn expr_priority(tp: &BinaryType) -> (i32, i32) {
 }
 
 
-pub(crate) fn fold_with_priority<'a>(first: Expression<'a>, elems: Vec<(BinaryType, Expression<'a>)>) -> Expression<'a> {
-    fold(first, &mut Elems { elems }, 0)
-}
+/// The unary operators bind tighter than any binary one except `^`.
+pub(crate) const UNARY_PRIORITY: i32 = 12;
 
-// TODO reverse the vec
-struct Elems<'a> {
-    elems: Vec<(BinaryType, Expression<'a>)>,
+pub(crate) fn binary_op(token: &Token) -> Option<BinaryType> {
+    let op = match token {
+        Token::Mult => Mult,
+        Token::Div => Div,
+        Token::FDiv => FDiv,
+        Token::Mod => Mod,
+        Token::Plus => Add,
+        Token::EllipsisIn => Concat,
+        Token::Gt => Gt,
+        Token::Lt => Lt,
+        Token::Ge => Ge,
+        Token::Le => Le,
+        Token::Eq => Eq,
+        Token::TEq => TEq,
+        Token::And => And,
+        Token::Or => Or,
+        Token::LShift => LShift,
+        Token::RShift => RShift,
+        Token::Ampersand => Amper,
+        Token::Stick => Stick,
+        Token::Tilde => Tilde,
+        Token::Minus => Sub,
+        Token::Caret => Pov,
+        _ => return None,
+    };
+    Some(op)
 }
 
-impl<'a> Elems<'a> {
-    fn peek(&self) -> Option<&(BinaryType, Expression<'a>)> {
-        self.elems.get(0)
-    }
-    fn next(&mut self) -> (BinaryType, Expression<'a>) {
-        self.elems.remove(0)
+pub(crate) fn unary_op(token: &Token) -> Option<UnaryType> {
+    match token {
+        Token::Not => Some(UnaryType::Not),
+        Token::Hash => Some(UnaryType::Hash),
+        Token::Tilde => Some(UnaryType::Tilde),
+        Token::Minus => Some(UnaryType::Minus),
+        _ => None,
     }
 }
 
-/// pratt parsing algorithm
-fn fold<'a>(lhs: Expression<'a>, elems: &mut Elems<'a>, min_priority: i32) -> Expression<'a> {
-    let mut lhs = lhs;
-
-    while let Some((tp, _)) = elems.peek() {
-        let (l_prior, r_prior) = expr_priority(tp);
-        if l_prior >= min_priority {
-            let (tp, rhs) = elems.next();
-            let rhs = fold(rhs, elems, r_prior);
-            lhs = Expression::Binary(Box::new(lhs), tp, Box::new(rhs));
-        } else { break; }
-    }
-
-    lhs
-}
-
-
+#[cfg(test)]
 pub(crate) fn print(expr: &Expression) -> String {
     match expr {
-        Expression::Nil => "nil".to_string(),
-        Expression::False => "false".to_string(),
-        Expression::True => "true".to_string(),
-        Expression::Number(n) => format!("{}", n),
-        Expression::Text(t) => t.text.to_string(),
-        Expression::VarArgs => "...".to_string(),
-        Expression::FnDef(_, _) => "fn_def".to_string(),
+        Expression::Nil(_) => "nil".to_string(),
+        Expression::False(_) => "false".to_string(),
+        Expression::True(_) => "true".to_string(),
+        Expression::Number(n, _) => format!("{}", n),
+        Expression::Text(t) => String::from_utf8_lossy(&t.text).to_string(),
+        Expression::VarArgs(_) => "...".to_string(),
+        Expression::FnDef(..) => "fn_def".to_string(),
         Expression::PrefixExpr(_) => "pref".to_string(),
         Expression::TableConstructor(_) => "table".to_string(),
-        Expression::Unary(s, e) => format!("{}{}", s, print(e)),
-        Expression::Binary(lhs, op, rhs) => format!("({} {} {})", print(lhs), op, print(rhs))
+        Expression::Unary(UnaryType::Not, e, _) => format!("not {}", print(e)),
+        Expression::Unary(s, e, _) => format!("{}{}", s, print(e)),
+        Expression::Binary(lhs, op, rhs) => format!("({} {} {})", print(lhs), op, print(rhs)),
+        Expression::Error(_) => "error".to_string(),
     }
 }
 
@@ -105,7 +115,7 @@ impl Display for BinaryType {
 impl Display for UnaryType {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            UnaryType::Not => f.write_str("!"),
+            UnaryType::Not => f.write_str("not"),
             UnaryType::Hash => f.write_str("#"),
             UnaryType::Minus => f.write_str("-"),
             UnaryType::Tilde => f.write_str("~"),
@@ -115,17 +125,17 @@ impl Display for UnaryType {
 
 #[macro_export]
 macro_rules! expr {
-  () => {Expression::Nil};
-  (f) => {Expression::False};
-  (t) => {Expression::True};
-  (i$e:literal) => {Expression::Number(Number::Int($e))};
-  (f$e:literal) => {Expression::Number(Number::Float($e))};
-  (text $e:literal) => {Expression::Text(Text{text:$e})};
-  (...) => {Expression::VarArgs};
-  (!$expr:expr) => {Expression::Unary(UnaryType::Not,Box::new($expr))};
-  (#$expr:expr) => {Expression::Unary(UnaryType::Hash,Box::new($expr))};
-  (-$expr:expr) => {Expression::Unary(UnaryType::Minus,Box::new($expr))};
-  (~$expr:expr) => {Expression::Unary(UnaryType::Tilde,Box::new($expr))};
+  () => {Expression::Nil(Default::default())};
+  (f) => {Expression::False(Default::default())};
+  (t) => {Expression::True(Default::default())};
+  (i$e:literal) => {Expression::Number(Number::Int($e), Default::default())};
+  (f$e:literal) => {Expression::Number(Number::Float($e), Default::default())};
+  (text $e:literal) => {Expression::Text(Text::new($e))};
+  (...) => {Expression::VarArgs(Default::default())};
+  (!$expr:expr) => {Expression::Unary(UnaryType::Not,Box::new($expr), Default::default())};
+  (#$expr:expr) => {Expression::Unary(UnaryType::Hash,Box::new($expr), Default::default())};
+  (-$expr:expr) => {Expression::Unary(UnaryType::Minus,Box::new($expr), Default::default())};
+  (~$expr:expr) => {Expression::Unary(UnaryType::Tilde,Box::new($expr), Default::default())};
   ($lhs:expr, *, $rhs:expr) => {Expression::Binary(Box::new($lhs),BinaryType::Mult, Box::new($rhs))};
   ($lhs:expr, /, $rhs:expr) => {Expression::Binary(Box::new($lhs),BinaryType::Div, Box::new($rhs))};
   ($lhs:expr, d/, $rhs:expr) => {Expression::Binary(Box::new($lhs),BinaryType::FDiv, Box::new($rhs))};
@@ -152,8 +162,68 @@ macro_rules! expr {
 
 #[cfg(test)]
 mod test {
-    use crate::parser::expression::{fold, fold_with_priority, print};
+    use crate::parser::expression::print;
     use crate::parser::ast::*;
+    use crate::parser::parse_expr;
+    use crate::parser::span::Span;
+
+    /// The precedence table from the lua reference manual (3.4.8), from the lower priority to the higher.
+    /// The flag marks the right associative levels.
+    const PRECEDENCE: &[(&[&str], bool)] = &[
+        (&["or"], false),
+        (&["and"], false),
+        (&["<", ">", "<=", ">=", "~=", "=="], false),
+        (&["|"], false),
+        (&["~"], false),
+        (&["&"], false),
+        (&["<<", ">>"], false),
+        (&[".."], true),
+        (&["+", "-"], false),
+        (&["*", "/", "//", "%"], false),
+        (&["not", "#", "-", "~"], false),
+        (&["^"], true),
+    ];
+    const UNARY_LEVEL: usize = 10;
+
+    fn level(op: &str) -> (usize, bool) {
+        PRECEDENCE.iter().enumerate()
+            .find(|(l, (ops, _))| *l != UNARY_LEVEL && ops.contains(&op))
+            .map(|(l, (_, right))| (l, *right))
+            .unwrap()
+    }
+
+    fn binary_ops() -> Vec<&'static str> {
+        PRECEDENCE.iter().enumerate()
+            .filter(|(l, _)| *l != UNARY_LEVEL)
+            .flat_map(|(_, (ops, _))| ops.iter().copied())
+            .collect()
+    }
+
+    fn unary_ops() -> Vec<&'static str> {
+        PRECEDENCE[UNARY_LEVEL].0.to_vec()
+    }
+
+    /// Prints the expression with every binary and unary operation in parens.
+    fn grouped(expr: &Expression) -> String {
+        match expr {
+            Expression::Binary(l, op, r) => format!("({} {} {})", grouped(l), op, grouped(r)),
+            Expression::Unary(op, e, _) => {
+                let op = match op {
+                    UnaryType::Not => "not",
+                    UnaryType::Hash => "#",
+                    UnaryType::Minus => "-",
+                    UnaryType::Tilde => "~",
+                };
+                format!("({} {})", op, grouped(e))
+            }
+            e => e.to_string(),
+        }
+    }
+
+    fn assert_grouped(src: &str, expected: &str) {
+        let expr = parse_expr(src).unwrap_or_else(|e| panic!("{}: {}", src, e));
+        assert_eq!(grouped(&expr), expected, "{}", src);
+    }
 
     fn assert_expr<'a>(actual: &'a Expression<'a>, expected: &'a Expression<'a>) {
         assert_eq!(print(actual), print(expected));
@@ -165,13 +235,13 @@ mod test {
 
     #[test]
     fn expr_test() {
-        assert_expr(&expr!(), &Expression::Nil);
-        assert_expr(&expr!(t), &Expression::True);
-        assert_expr(&expr!(f), &Expression::False);
+        assert_expr(&expr!(), &Expression::Nil(Span::default()));
+        assert_expr(&expr!(t), &Expression::True(Span::default()));
+        assert_expr(&expr!(f), &Expression::False(Span::default()));
         assert_expr_str(&expr!(i 1), "1");
         assert_expr_str(&expr!(text "abc"), "abc");
         assert_expr_str(&expr!(...), "...");
-        assert_expr_str(&expr!(! expr!(- expr!(i 1))), "!-1");
+        assert_expr_str(&expr!(! expr!(- expr!(i 1))), "not -1");
         assert_expr_str(&expr!(# expr!(i 1)), "#1");
         assert_expr_str(&expr!(- expr!(i 1)), "-1");
         assert_expr_str(&expr!(~ expr!(i 1)), "~1");
@@ -181,32 +251,49 @@ mod test {
 
     #[test]
     fn fold_test() {
-        assert_expr_str(&fold_with_priority(
-            expr!(f),
-            vec![],
-        ), "false");
-        assert_expr_str(&fold_with_priority(
-            expr!(f),
-            vec![
-                (BinaryType::And, expr!(i 1)),
-                (BinaryType::Gt, expr!(i 0)),
-            ],
-        ), "(false and (1 > 0))");
-
-        assert_expr_str(&fold_with_priority(
-            expr!(i 1),
-            vec![
-                (BinaryType::Add, expr!(i 1)),
-                (BinaryType::Mult, expr!(i 0)),
-            ],
-        ), "(1 + (1 * 0))");
-        assert_expr_str(&fold_with_priority(
-            expr!(i 1),
-            vec![
-                (BinaryType::Add, expr!(i 1)),
-                (BinaryType::Mult, expr!(i 0)),
-                (BinaryType::Sub, expr!(i 0)),
-            ],
-        ), "(1 + ((1 * 0) - 0))")
-    }
-}
\ No newline at end of file
+        assert_expr_str(&parse_expr("false").unwrap(), "false");
+        assert_expr_str(&parse_expr("false and 1 > 0").unwrap(), "(false and (1 > 0))");
+        assert_expr_str(&parse_expr("1 + 1 * 0").unwrap(), "(1 + (1 * 0))");
+        assert_expr_str(&parse_expr("1 + 1 * 0 - 0").unwrap(), "((1 + (1 * 0)) - 0)");
+    }
+
+    #[test]
+    fn binary_precedence_test() {
+        for l_op in binary_ops() {
+            for r_op in binary_ops() {
+                let (l_level, l_right) = level(l_op);
+                let (r_level, _) = level(r_op);
+                let left_first = l_level > r_level || (l_level == r_level && !l_right);
+                let expected = if left_first {
+                    format!("((a {} b) {} c)", l_op, r_op)
+                } else {
+                    format!("(a {} (b {} c))", l_op, r_op)
+                };
+                assert_grouped(&format!("a {} b {} c", l_op, r_op), &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn unary_precedence_test() {
+        for u_op in unary_ops() {
+            for op in binary_ops() {
+                let expected = if level(op).0 > UNARY_LEVEL {
+                    format!("({} (a {} b))", u_op, op)
+                } else {
+                    format!("(({} a) {} b)", u_op, op)
+                };
+                assert_grouped(&format!("{} a {} b", u_op, op), &expected);
+                assert_grouped(&format!("a {} {} b", op, u_op), &format!("(a {} ({} b))", op, u_op));
+            }
+            for inner in unary_ops() {
+                assert_grouped(&format!("{} {} a ^ b", u_op, inner), &format!("({} ({} (a ^ b)))", u_op, inner));
+            }
+        }
+
+        assert_grouped("not a == b", "((not a) == b)");
+        assert_grouped("-x ^ 2 + 1", "((- (x ^ 2)) + 1)");
+        assert_grouped("2 ^ -3 ^ 2", "(2 ^ (- (3 ^ 2)))");
+        assert_grouped("#t - 1 .. 'x'", "(((# t) - 1) .. \"x\")");
+    }
+}
@@ -0,0 +1,543 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use logos::Logos;
+use crate::parser::ast::*;
+use crate::parser::span::{Span, Spanned};
+use crate::parser::tokens::Token;
+use crate::parser::visit::{VisitorMut, walk_expr_mut};
+
+/// The lexer produces only unsigned numerals thus `-1` comes out of the parser
+/// as the unary minus applied to `1`.
+/// The pass turns such expressions back into the negative literals
+/// keeping the span of the whole expression.
+pub fn fold_negative_literals(block: &mut Block<'_>) {
+    NegativeLiterals.visit_block_mut(block)
+}
+
+/// The negation of the numeral as lua does it, the integers wrap around.
+pub fn negate(number: Number) -> Number {
+    match number {
+        Number::Int(v) => Number::Int(v.wrapping_neg()),
+        Number::Float(v) => Number::Float(-v),
+        Number::Hex(v) => Number::Hex(v.wrapping_neg()),
+        Number::Binary(v) => Number::Binary(v.wrapping_neg()),
+    }
+}
+
+struct NegativeLiterals;
+
+impl<'a> VisitorMut<'a> for NegativeLiterals {
+    fn visit_expr_mut(&mut self, expr: &mut Expression<'a>) {
+        walk_expr_mut(self, expr);
+        if let Expression::Unary(UnaryType::Minus, operand, span) = expr {
+            if let Expression::Number(n, _) = operand.as_ref() {
+                *expr = Expression::Number(negate(*n), *span);
+            }
+        }
+    }
+}
+
+/// Evaluates the operators applied to the literals the way Lua 5.4 does at runtime:
+/// the integers wrap around, `/` and `^` give floats, `//` and `%` round towards minus infinity,
+/// the strings turn into numbers for the arithmetic and the numbers into strings for `..`.
+/// The operations that raise an error (`1 // 0`, `1.5 & 1`, `1 < "2"`) are left as they are.
+/// The folded node takes the span of the whole expression, the parenthesized constants are folded too.
+pub fn fold_constants(block: &mut Block<'_>) {
+    Constants.visit_block_mut(block)
+}
+
+struct Constants;
+
+impl<'a> VisitorMut<'a> for Constants {
+    fn visit_expr_mut(&mut self, expr: &mut Expression<'a>) {
+        walk_expr_mut(self, expr);
+        if let Some(folded) = fold(expr) {
+            *expr = folded;
+        }
+    }
+}
+
+/// The value of a literal.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Const<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, [u8]>),
+}
+
+fn constant<'a>(expr: &Expression<'a>) -> Option<Const<'a>> {
+    match expr {
+        Expression::Nil(_) => Some(Const::Nil),
+        Expression::False(_) => Some(Const::Bool(false)),
+        Expression::True(_) => Some(Const::Bool(true)),
+        Expression::Number(Number::Int(v) | Number::Hex(v), _) => Some(Const::Int(*v)),
+        Expression::Number(Number::Binary(v), _) => Some(Const::Int(*v as i64)),
+        Expression::Number(Number::Float(v), _) => Some(Const::Float(*v)),
+        Expression::Text(text) => Some(Const::Str(text.text.clone())),
+        _ => None,
+    }
+}
+
+fn literal(value: Const<'_>, span: Span) -> Expression<'_> {
+    match value {
+        Const::Nil => Expression::Nil(span),
+        Const::Bool(true) => Expression::True(span),
+        Const::Bool(false) => Expression::False(span),
+        Const::Int(v) => Expression::Number(Number::Int(v), span),
+        Const::Float(v) => Expression::Number(Number::Float(v), span),
+        Const::Str(text) => Expression::Text(Text { text, span }),
+    }
+}
+
+/// The literal the expression evaluates to if it is not a literal already.
+fn fold<'a>(expr: &Expression<'a>) -> Option<Expression<'a>> {
+    let value = match expr {
+        Expression::Unary(op, operand, _) => unary(*op, constant(operand)?)?,
+        // `1 and f()` keeps only the first value of the call, so the right operand has to be a constant
+        Expression::Binary(lhs, BinaryType::And, rhs) => match constant(lhs)? {
+            l if truthy(&l) => constant(rhs)?,
+            l => l,
+        },
+        Expression::Binary(lhs, BinaryType::Or, rhs) => match constant(lhs)? {
+            l if truthy(&l) => l,
+            _ => constant(rhs)?,
+        },
+        Expression::Binary(lhs, op, rhs) => binary(constant(lhs)?, *op, constant(rhs)?)?,
+        Expression::PrefixExpr(call) => match &call.head {
+            VarOrExpr::Expr(e) if call.args.is_empty() => constant(e)?,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(literal(value, expr.span()))
+}
+
+fn truthy(value: &Const) -> bool {
+    !matches!(value, Const::Nil | Const::Bool(false))
+}
+
+/// The operator applied to the value, `None` when lua raises an error.
+pub(crate) fn unary(op: UnaryType, value: Const<'_>) -> Option<Const<'_>> {
+    match op {
+        UnaryType::Not => Some(Const::Bool(!truthy(&value))),
+        UnaryType::Hash => match value {
+            Const::Str(s) => Some(Const::Int(s.len() as i64)),
+            _ => None,
+        },
+        UnaryType::Minus => match to_number(value)? {
+            Const::Int(v) => Some(Const::Int(v.wrapping_neg())),
+            Const::Float(v) => Some(Const::Float(-v)),
+            _ => None,
+        },
+        UnaryType::Tilde => Some(Const::Int(!to_integer(&value)?)),
+    }
+}
+
+/// The operator applied to the values, `None` when lua raises an error.
+/// The short circuit operators are not evaluated.
+pub(crate) fn binary<'a>(lhs: Const<'a>, op: BinaryType, rhs: Const<'a>) -> Option<Const<'a>> {
+    match op {
+        BinaryType::Add | BinaryType::Sub | BinaryType::Mult | BinaryType::Mod | BinaryType::FDiv => {
+            match (to_number(lhs)?, to_number(rhs)?) {
+                (Const::Int(a), Const::Int(b)) => int_arith(a, op, b).map(Const::Int),
+                (a, b) => Some(Const::Float(float_arith(to_float(&a)?, op, to_float(&b)?))),
+            }
+        }
+        BinaryType::Div | BinaryType::Pov => {
+            let (a, b) = (to_float(&to_number(lhs)?)?, to_float(&to_number(rhs)?)?);
+            Some(Const::Float(float_arith(a, op, b)))
+        }
+        BinaryType::Amper | BinaryType::Stick | BinaryType::Tilde | BinaryType::LShift | BinaryType::RShift => {
+            let (a, b) = (to_integer(&lhs)?, to_integer(&rhs)?);
+            Some(Const::Int(match op {
+                BinaryType::Amper => a & b,
+                BinaryType::Stick => a | b,
+                BinaryType::Tilde => a ^ b,
+                BinaryType::LShift => shift_left(a, b),
+                _ => shift_left(a, b.wrapping_neg()),
+            }))
+        }
+        BinaryType::Concat => {
+            let mut text = to_text(lhs)?.into_owned();
+            text.extend_from_slice(&to_text(rhs)?);
+            Some(Const::Str(Cow::Owned(text)))
+        }
+        BinaryType::Eq => Some(Const::Bool(equal(&lhs, &rhs))),
+        BinaryType::TEq => Some(Const::Bool(!equal(&lhs, &rhs))),
+        BinaryType::Lt => Some(Const::Bool(compare(&lhs, &rhs)? == Some(Ordering::Less))),
+        BinaryType::Le => Some(Const::Bool(matches!(compare(&lhs, &rhs)?, Some(Ordering::Less | Ordering::Equal)))),
+        BinaryType::Gt => Some(Const::Bool(compare(&rhs, &lhs)? == Some(Ordering::Less))),
+        BinaryType::Ge => Some(Const::Bool(matches!(compare(&rhs, &lhs)?, Some(Ordering::Less | Ordering::Equal)))),
+        BinaryType::And | BinaryType::Or => None,
+    }
+}
+
+/// `None` is the division by zero.
+fn int_arith(a: i64, op: BinaryType, b: i64) -> Option<i64> {
+    match op {
+        BinaryType::Add => Some(a.wrapping_add(b)),
+        BinaryType::Sub => Some(a.wrapping_sub(b)),
+        BinaryType::Mult => Some(a.wrapping_mul(b)),
+        BinaryType::FDiv => match b {
+            0 => None,
+            -1 => Some(a.wrapping_neg()),
+            _ => {
+                let q = a / b;
+                Some(if a % b != 0 && (a ^ b) < 0 { q - 1 } else { q })
+            }
+        },
+        BinaryType::Mod => match b {
+            0 => None,
+            -1 => Some(0),
+            _ => {
+                let r = a % b;
+                Some(if r != 0 && (r ^ b) < 0 { r + b } else { r })
+            }
+        },
+        _ => None,
+    }
+}
+
+fn float_arith(a: f64, op: BinaryType, b: f64) -> f64 {
+    match op {
+        BinaryType::Add => a + b,
+        BinaryType::Sub => a - b,
+        BinaryType::Mult => a * b,
+        BinaryType::Div => a / b,
+        BinaryType::FDiv => (a / b).floor(),
+        BinaryType::Pov => float_pow(a, b),
+        _ => float_mod(a, b),
+    }
+}
+
+/// `a ^ b`, the square is the product as in lua.
+pub(crate) fn float_pow(a: f64, b: f64) -> f64 {
+    if b == 2.0 {
+        a * a
+    } else {
+        a.powf(b)
+    }
+}
+
+/// `a % b`, the remainder of `fmod` takes the sign of the divisor.
+pub(crate) fn float_mod(a: f64, b: f64) -> f64 {
+    let m = a % b;
+    if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) {
+        m + b
+    } else {
+        m
+    }
+}
+
+/// The shift to the right by the negative amount, the bits shifted out of 64 give zero.
+fn shift_left(a: i64, b: i64) -> i64 {
+    match b {
+        ..=-64 | 64.. => 0,
+        0.. => ((a as u64) << b) as i64,
+        _ => ((a as u64) >> -b) as i64,
+    }
+}
+
+/// The operand of the arithmetic, the strings are converted like `tonumber` does.
+fn to_number(value: Const<'_>) -> Option<Const<'static>> {
+    match value {
+        Const::Int(v) => Some(Const::Int(v)),
+        Const::Float(v) => Some(Const::Float(v)),
+        Const::Str(s) => str_to_number(&s),
+        _ => None,
+    }
+}
+
+fn to_float(value: &Const) -> Option<f64> {
+    match value {
+        Const::Int(v) => Some(*v as f64),
+        Const::Float(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// The operand of the bitwise operators, only the floats with the exact integer value pass,
+/// the strings are not converted since Lua 5.4.
+fn to_integer(value: &Const) -> Option<i64> {
+    match value {
+        Const::Int(v) => Some(*v),
+        Const::Float(v) => float_to_int(*v),
+        _ => None,
+    }
+}
+
+fn float_to_int(v: f64) -> Option<i64> {
+    (v.floor() == v && (-9223372036854775808.0..9223372036854775808.0).contains(&v)).then_some(v as i64)
+}
+
+fn to_text(value: Const<'_>) -> Option<Cow<'_, [u8]>> {
+    match value {
+        Const::Str(s) => Some(s),
+        Const::Int(v) => Some(Cow::Owned(v.to_string().into_bytes())),
+        Const::Float(v) => float_to_text(v).map(|s| Cow::Owned(s.into_bytes())),
+        _ => None,
+    }
+}
+
+/// The float as `%.14g` prints it with `.0` added to the integral values.
+/// The infinities and `nan` are spelled by the C library, so they are not converted.
+fn float_to_text(v: f64) -> Option<String> {
+    if !v.is_finite() {
+        return None;
+    }
+    let trim = |s: String| if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.').to_string() } else { s };
+    let sci = format!("{:.13e}", v);
+    let (mantissa, exp) = sci.split_once('e')?;
+    let exp: i32 = exp.parse().ok()?;
+    let text = if (-4..14).contains(&exp) {
+        trim(format!("{:.*}", (13 - exp) as usize, v))
+    } else {
+        format!("{}e{}{:02}", trim(mantissa.to_string()), if exp < 0 { '-' } else { '+' }, exp.abs())
+    };
+    if text.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        Some(text + ".0")
+    } else {
+        Some(text)
+    }
+}
+
+/// The string as a numeral: the spaces around it and one sign are allowed,
+/// the decimal integers that do not fit into 64 bits are floats, the hexadecimal ones wrap around.
+fn str_to_number(s: &[u8]) -> Option<Const<'static>> {
+    let s = std::str::from_utf8(s).ok()?.trim_matches([' ', '\t', '\n', '\r', '\x0b', '\x0c']);
+    let (neg, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    // the sign keeps the minimal integer an integer
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        if let Ok(v) = format!("{}{}", if neg { "-" } else { "" }, digits).parse::<i64>() {
+            return Some(Const::Int(v));
+        }
+    }
+    let mut lexer = Token::lexer(digits);
+    let number = match lexer.next() {
+        Some(Ok(Token::Digit(number))) if lexer.span() == (0..digits.len()) => number,
+        _ => return None,
+    };
+    match number {
+        Number::Int(v) | Number::Hex(v) => Some(Const::Int(if neg { v.wrapping_neg() } else { v })),
+        Number::Float(v) => Some(Const::Float(if neg { -v } else { v })),
+        Number::Binary(_) => None,
+    }
+}
+
+fn equal(lhs: &Const, rhs: &Const) -> bool {
+    match (lhs, rhs) {
+        (Const::Int(_) | Const::Float(_), Const::Int(_) | Const::Float(_)) => {
+            compare(lhs, rhs) == Some(Some(Ordering::Equal))
+        }
+        _ => lhs == rhs,
+    }
+}
+
+/// `None` for the values Lua can not compare, `Some(None)` when one of them is `nan`.
+/// The integers and the floats are compared by their exact values, the strings byte by byte.
+fn compare(lhs: &Const, rhs: &Const) -> Option<Option<Ordering>> {
+    match (lhs, rhs) {
+        (Const::Int(a), Const::Int(b)) => Some(Some(a.cmp(b))),
+        (Const::Float(a), Const::Float(b)) => Some(a.partial_cmp(b)),
+        (Const::Int(a), Const::Float(b)) => Some(cmp_int_float(*a, *b)),
+        (Const::Float(a), Const::Int(b)) => Some(cmp_int_float(*b, *a).map(Ordering::reverse)),
+        (Const::Str(a), Const::Str(b)) => Some(Some(a.cmp(b))),
+        _ => None,
+    }
+}
+
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9223372036854775808.0 {
+        Some(Ordering::Less)
+    } else if f < -9223372036854775808.0 {
+        Some(Ordering::Greater)
+    } else {
+        let t = f.trunc();
+        Some(i.cmp(&(t as i64)).then(0.0.partial_cmp(&(f - t))?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::{Block, Expression, Number, Statement};
+    use crate::parser::fold::{fold_constants, fold_negative_literals, Constants, NegativeLiterals};
+    use crate::parser::visit::VisitorMut;
+    use crate::parser::{parse_chunk, parse_expr};
+    use crate::parser::span::Spanned;
+
+    fn folded(src: &str) -> Expression<'_> {
+        let mut e = parse_expr(src).unwrap();
+        NegativeLiterals.visit_expr_mut(&mut e);
+        e
+    }
+
+    #[test]
+    fn negative_literal_test() {
+        assert!(matches!(folded("-1"), Expression::Number(Number::Int(-1), _)));
+        assert!(matches!(folded("- 1.5"), Expression::Number(Number::Float(v), _) if v == -1.5));
+        assert!(matches!(folded("- -2"), Expression::Number(Number::Int(2), _)));
+        assert!(matches!(folded("-0x10"), Expression::Number(Number::Hex(-16), _)));
+        assert!(matches!(folded("-0x8000000000000000"), Expression::Number(Number::Hex(i64::MIN), _)));
+        assert!(matches!(folded("-9223372036854775808"), Expression::Number(Number::Float(v), _) if v == -9223372036854775808.0));
+        assert!(matches!(folded("-x"), Expression::Unary(..)));
+        assert!(matches!(folded("not 1"), Expression::Unary(..)));
+        assert_ne!(folded("f({-1})"), parse_expr("f({-1})").unwrap());
+    }
+
+    #[test]
+    fn fold_span_test() {
+        let src = "local a = x - - 1";
+        let mut block = parse_chunk(src).unwrap();
+        fold_negative_literals(&mut block);
+        match &block {
+            Block::Void(sts, _) => match &sts[0] {
+                Statement::LocalAttrNames(_, exprs, _) => match &exprs[0] {
+                    Expression::Binary(_, _, rhs) => {
+                        assert!(matches!(rhs.as_ref(), Expression::Number(Number::Int(-1), _)));
+                        assert_eq!(&src[rhs.span().range()], "- 1");
+                    }
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    /// The folded expression printed back, the text of the strings is shown as is.
+    fn constant(src: &str) -> String {
+        let mut e = parse_expr(src).unwrap();
+        Constants.visit_expr_mut(&mut e);
+        match e {
+            Expression::Number(Number::Int(v), _) => format!("{}", v),
+            Expression::Number(Number::Float(v), _) => format!("{:?}", v),
+            Expression::Text(t) => format!("'{}'", String::from_utf8_lossy(&t.text)),
+            e => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn arith_test() {
+        assert_eq!(constant("1024 * 1024"), "1048576");
+        assert_eq!(constant("9223372036854775807 + 1"), "-9223372036854775808");
+        assert_eq!(constant("-9223372036854775807 - 1"), "-9223372036854775808");
+        assert_eq!(constant("2 * 3.0"), "6.0");
+        assert_eq!(constant("3 / 1"), "3.0");
+        assert_eq!(constant("1 / 0"), "inf");
+        assert_eq!(constant("2 ^ 10"), "1024.0");
+        assert_eq!(constant("(1 + 2) * 3"), "9");
+        assert_eq!(constant("-(2 - 3)"), "1");
+        assert_eq!(constant("1 + x"), "1 + x");
+    }
+
+    #[test]
+    fn floor_test() {
+        assert_eq!(constant("7 // 2"), "3");
+        assert_eq!(constant("-7 // 2"), "-4");
+        assert_eq!(constant("7 // -2"), "-4");
+        assert_eq!(constant("-7 // -2"), "3");
+        assert_eq!(constant("7 // -2.0"), "-4.0");
+        assert_eq!(constant("(-9223372036854775807 - 1) // -1"), "-9223372036854775808");
+        assert_eq!(constant("1 // 0.0"), "inf");
+        assert_eq!(constant("7 % -3"), "-2");
+        assert_eq!(constant("-7 % 3"), "2");
+        assert_eq!(constant("(-9223372036854775807 - 1) % -1"), "0");
+        assert_eq!(constant("5.5 % -2"), "-0.5");
+        assert_eq!(constant("-5.5 % 2"), "0.5");
+        assert_eq!(constant("-5.5 % -2"), "-1.5");
+        assert_eq!(constant("1 % (1 / 0)"), "1.0");
+        // raise at runtime
+        assert_eq!(constant("1 // 0"), "1 // 0");
+        assert_eq!(constant("1 % 0"), "1 % 0");
+    }
+
+    #[test]
+    fn coercion_test() {
+        assert_eq!(constant("'10' + 1"), "11");
+        assert_eq!(constant("'0x10' * 2"), "32");
+        assert_eq!(constant("' 1e1 ' + 0"), "10.0");
+        assert_eq!(constant("'-9223372036854775808' + 0"), "-9223372036854775808");
+        assert_eq!(constant("'9223372036854775808' + 0"), "9.223372036854776e18");
+        assert_eq!(constant("-'2'"), "-2");
+        assert_eq!(constant("'10' / '4'"), "2.5");
+        assert_eq!(constant("'abc' + 1"), "\"abc\" + 1");
+        assert_eq!(constant("'1 --' + 1"), "\"1 --\" + 1");
+        assert_eq!(constant("'- 1' + 1"), "\"- 1\" + 1");
+        // the strings are not converted for the bitwise operators
+        assert_eq!(constant("'3' & 1"), "\"3\" & 1");
+    }
+
+    #[test]
+    fn bitwise_test() {
+        assert_eq!(constant("6 & 3.0"), "2");
+        assert_eq!(constant("6 | 3"), "7");
+        assert_eq!(constant("6 ~ 3"), "5");
+        assert_eq!(constant("~0"), "-1");
+        assert_eq!(constant("1 << 63"), "-9223372036854775808");
+        assert_eq!(constant("1 << 64"), "0");
+        assert_eq!(constant("-1 >> 1"), "9223372036854775807");
+        assert_eq!(constant("2 >> -1"), "4");
+        assert_eq!(constant("1 & 1.5"), "1 & 1.5");
+        assert_eq!(constant("1 | 2 ^ 63"), "1 | 9.223372036854776e18");
+    }
+
+    #[test]
+    fn concat_test() {
+        assert_eq!(constant("'a' .. 'b'"), "'ab'");
+        assert_eq!(constant("1 .. 2"), "'12'");
+        assert_eq!(constant("1.5 .. ''"), "'1.5'");
+        assert_eq!(constant("10 / 2 .. ''"), "'5.0'");
+        assert_eq!(constant("-0.0 .. ''"), "'-0.0'");
+        assert_eq!(constant("2 ^ 53 .. ''"), "'9.007199254741e+15'");
+        assert_eq!(constant("1e100 .. ''"), "'1e+100'");
+        assert_eq!(constant("0.1 + 0.2 .. ''"), "'0.3'");
+        assert_eq!(constant("1e-5 .. ''"), "'1e-05'");
+        assert_eq!(constant("'a' .. nil"), "\"a\" .. nil");
+        assert_eq!(constant("1 / 0 .. ''"), "1e9999 .. \"\"");
+    }
+
+    #[test]
+    fn logic_test() {
+        assert_eq!(constant("1 == 1.0"), "true");
+        assert_eq!(constant("'1' == 1"), "false");
+        assert_eq!(constant("0 / 0 == 0 / 0"), "false");
+        assert_eq!(constant("0 / 0 ~= 0 / 0"), "true");
+        assert_eq!(constant("1 < 1.5"), "true");
+        assert_eq!(constant("9007199254740993 > 2 ^ 53"), "true");
+        assert_eq!(constant("'a' < 'b'"), "true");
+        assert_eq!(constant("'b' >= 'ab'"), "true");
+        assert_eq!(constant("1 < '2'"), "1 < \"2\"");
+        assert_eq!(constant("nil and f()"), "nil");
+        assert_eq!(constant("false or 2"), "2");
+        assert_eq!(constant("1 or f()"), "1");
+        assert_eq!(constant("1 and f()"), "1 and f()");
+        assert_eq!(constant("not nil"), "true");
+        assert_eq!(constant("#'abc'"), "3");
+        assert_eq!(constant("#{}"), "#{}");
+    }
+
+    #[test]
+    fn constants_span_test() {
+        let src = "local a = (1024 * 1024) .. 'b'";
+        let mut block = parse_chunk(src).unwrap();
+        fold_constants(&mut block);
+        match &block {
+            Block::Void(sts, _) => match &sts[0] {
+                Statement::LocalAttrNames(_, exprs, _) => {
+                    assert!(matches!(&exprs[0], Expression::Text(t) if t.text.as_ref() == b"1048576b"));
+                    assert_eq!(&src[exprs[0].span().range()], "(1024 * 1024) .. 'b'");
+                }
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
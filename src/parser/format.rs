@@ -0,0 +1,294 @@
+//! The opinionated formatter: the ast is printed with the given config
+//! keeping the comments and the blank lines of the source.
+
+use std::str::FromStr;
+use crate::parser::ast::{Block, Statement};
+use crate::parser::cst::{Cst, TriviaKind};
+use crate::parser::dialect::Dialect;
+use crate::parser::error::SyntaxError;
+use crate::parser::printer::{Comment, Printer};
+use crate::parser::visit::{walk_block_mut, VisitorMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndentType {
+    Spaces,
+    Tabs,
+}
+
+/// The quotes of the string literals, the long brackets stay as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteStyle {
+    /// The double quotes unless the string has more double quotes than single ones.
+    AutoPreferDouble,
+    AutoPreferSingle,
+    ForceDouble,
+    ForceSingle,
+}
+
+/// When the parens of the call with the only string or table argument are omitted (`f "x"`, `f {}`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallParentheses {
+    Always,
+    NoSingleString,
+    NoSingleTable,
+    None,
+    /// As they are written.
+    Input,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub indent_type: IndentType,
+    /// The width of the indentation level, also the width of the tab to compute the column.
+    pub indent_width: usize,
+    /// The calls and the tables longer than that are split into lines.
+    pub column_width: usize,
+    pub quote_style: QuoteStyle,
+    pub call_parentheses: CallParentheses,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            indent_type: IndentType::Spaces,
+            indent_width: 4,
+            column_width: 120,
+            quote_style: QuoteStyle::AutoPreferDouble,
+            call_parentheses: CallParentheses::Always,
+        }
+    }
+}
+
+impl QuoteStyle {
+    pub(crate) fn quote(&self, text: &[u8]) -> char {
+        let count = |q: u8| text.iter().filter(|b| **b == q).count();
+        match self {
+            QuoteStyle::AutoPreferDouble if count(b'"') > count(b'\'') => '\'',
+            QuoteStyle::AutoPreferSingle if count(b'\'') > count(b'"') => '"',
+            QuoteStyle::AutoPreferDouble | QuoteStyle::ForceDouble => '"',
+            QuoteStyle::AutoPreferSingle | QuoteStyle::ForceSingle => '\'',
+        }
+    }
+}
+
+impl CallParentheses {
+    /// `written` tells if the source omits them.
+    pub(crate) fn omit_string(&self, written: bool) -> bool {
+        match self {
+            CallParentheses::NoSingleString | CallParentheses::None => true,
+            CallParentheses::Input => written,
+            CallParentheses::Always | CallParentheses::NoSingleTable => false,
+        }
+    }
+
+    pub(crate) fn omit_table(&self, written: bool) -> bool {
+        match self {
+            CallParentheses::NoSingleTable | CallParentheses::None => true,
+            CallParentheses::Input => written,
+            CallParentheses::Always | CallParentheses::NoSingleString => false,
+        }
+    }
+}
+
+fn unknown<T>(what: &str, v: &str, expected: &str) -> Result<T, String> {
+    Err(format!("unknown {} '{}', expected one of {}", what, v, expected))
+}
+
+impl FromStr for IndentType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "spaces" => Ok(IndentType::Spaces),
+            "tabs" => Ok(IndentType::Tabs),
+            _ => unknown("indent type", s, "spaces, tabs"),
+        }
+    }
+}
+
+impl FromStr for QuoteStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto-prefer-double" => Ok(QuoteStyle::AutoPreferDouble),
+            "auto-prefer-single" => Ok(QuoteStyle::AutoPreferSingle),
+            "force-double" => Ok(QuoteStyle::ForceDouble),
+            "force-single" => Ok(QuoteStyle::ForceSingle),
+            _ => unknown("quote style", s, "auto-prefer-double, auto-prefer-single, force-double, force-single"),
+        }
+    }
+}
+
+impl FromStr for CallParentheses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(CallParentheses::Always),
+            "no-single-string" => Ok(CallParentheses::NoSingleString),
+            "no-single-table" => Ok(CallParentheses::NoSingleTable),
+            "none" => Ok(CallParentheses::None),
+            "input" => Ok(CallParentheses::Input),
+            _ => unknown("call parentheses", s, "always, no-single-string, no-single-table, none, input"),
+        }
+    }
+}
+
+/// The empty statements are dropped, the printer puts `;` back where it is required.
+struct DropEmpty;
+
+impl<'a> VisitorMut<'a> for DropEmpty {
+    fn visit_block_mut(&mut self, block: &mut Block<'a>) {
+        match block {
+            Block::Void(sts, _) | Block::Return(sts, _, _) => sts.retain(|st| !matches!(st, Statement::Empty(_))),
+        }
+        walk_block_mut(self, block)
+    }
+}
+
+/// Formats the chunk, the output ends with a line break.
+pub fn format_chunk(src: &str, dialect: Dialect, config: &Config) -> Result<String, Box<SyntaxError>> {
+    let cst = Cst::parse(src, dialect)?;
+    let comments: Vec<Comment> = cst.root.tokens().into_iter()
+        .flat_map(|t| t.leading.iter().chain(t.trailing.iter()))
+        .chain(cst.eof.iter())
+        .filter(|t| matches!(t.kind, TriviaKind::Comment | TriviaKind::Shebang))
+        .map(|t| Comment { text: t.text.trim_end(), span: t.span })
+        .collect();
+    let mut block = cst.ast();
+    DropEmpty.visit_block_mut(&mut block);
+
+    let mut out = String::new();
+    Printer::with_source(&mut out, *config, src, comments)
+        .chunk(&block)
+        .expect("writing to a string");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::SameShape;
+    use crate::parser::cst::{Cst, TriviaKind};
+    use crate::parser::dialect::Dialect;
+    use crate::parser::format::{format_chunk, CallParentheses, Config, IndentType, QuoteStyle};
+    use crate::parser::parse_chunk;
+
+    fn format(src: &str, config: Config) -> String {
+        format_chunk(src, Dialect::default(), &config).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn comments(src: &str) -> Vec<String> {
+        let cst = Cst::parse(src, Dialect::default()).unwrap();
+        cst.root.tokens().into_iter()
+            .flat_map(|t| t.leading.iter().chain(t.trailing.iter()))
+            .chain(cst.eof.iter())
+            .filter(|t| t.kind == TriviaKind::Comment)
+            .map(|t| t.text.trim_end().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn scripts_test() {
+        let input = Config { call_parentheses: CallParentheses::Input, ..Config::default() };
+        for src in [
+            include_str!("scripts/treesetter.lua"),
+            include_str!("scripts/lazy.lua"),
+            include_str!("scripts/cassandra.lua"),
+            include_str!("scripts/server.lua"),
+        ] {
+            let formatted = format(src, input);
+            assert!(parse_chunk(&formatted).unwrap().same_shape(&parse_chunk(src).unwrap()), "{}", formatted);
+            assert_eq!(comments(&formatted), comments(src));
+            assert_eq!(format(&formatted, input), formatted);
+
+            let formatted = format(src, Config::default());
+            assert_eq!(format(&formatted, Config::default()), formatted);
+            assert!(formatted.lines().all(|l| !l.ends_with(' ')), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn lazy_indent_test() {
+        let formatted = format(include_str!("scripts/lazy.lua"), Config::default());
+        assert!(formatted.contains("\n    autocmd(tb.events, {\n        group = "), "{}", formatted);
+    }
+
+    #[test]
+    fn comments_test() {
+        let src = r#"#!/usr/bin/lua
+-- header
+
+local a = 1 -- one
+local b = { -- table
+  x = 1, -- x
+
+  -- y
+  y = 2
+}
+if a then -- cond
+   -- inside
+   f(a)
+
+   --[[ long
+   comment ]]
+   g()
+   -- last
+end;;
+-- tail
+"#;
+        let expected = r#"#!/usr/bin/lua
+-- header
+
+local a = 1 -- one
+local b = { -- table
+    x = 1, -- x
+
+    -- y
+    y = 2,
+}
+if a then -- cond
+    -- inside
+    f(a)
+
+    --[[ long
+   comment ]]
+    g()
+    -- last
+end
+-- tail
+"#;
+        assert_eq!(format(src, Config::default()), expected);
+        assert_eq!(format(expected, Config::default()), expected);
+        assert_eq!(format("do -- x\nend", Config::default()), "do -- x\nend\n");
+        assert_eq!(format("", Config::default()), "");
+        assert_eq!(format("-- only", Config::default()), "-- only\n");
+    }
+
+    #[test]
+    fn config_test() {
+        let src = "local s = f('a', \"b\", 'it\\'s', [[c]]) g'x' h{1} h({1}) if x then\nreturn 0xFF end";
+        assert_eq!(format(src, Config::default()),
+                   "local s = f(\"a\", \"b\", \"it's\", [[c]])\ng(\"x\")\nh({1})\nh({1})\nif x then\n    return 0xFF\nend\n");
+        let config = Config {
+            indent_type: IndentType::Tabs,
+            quote_style: QuoteStyle::ForceSingle,
+            call_parentheses: CallParentheses::None,
+            ..Config::default()
+        };
+        assert_eq!(format(src, config),
+                   "local s = f('a', 'b', 'it\\'s', [[c]])\ng 'x'\nh {1}\nh {1}\nif x then\n\treturn 0xFF\nend\n");
+        let config = Config { quote_style: QuoteStyle::AutoPreferDouble, ..Config::default() };
+        assert_eq!(format("x = 'say \"hi\"'", config), "x = 'say \"hi\"'\n");
+        assert_eq!(format("f(a)\n;(g)()", config), "f(a)\n;(g)()\n");
+    }
+
+    #[test]
+    fn width_test() {
+        let config = Config { column_width: 20, ..Config::default() };
+        assert_eq!(format("t = {1, 2}\nf(a, b)", config), "t = {1, 2}\nf(a, b)\n");
+        assert_eq!(format("t = {alpha = 1, beta = 2}", config), "t = {\n    alpha = 1,\n    beta = 2,\n}\n");
+        assert_eq!(format("call(alpha, beta, gamma)", config), "call(\n    alpha,\n    beta,\n    gamma\n)\n");
+        assert_eq!(format("f(a, function() return 1 end)", config), "f(a, function()\n    return 1\nend)\n");
+    }
+}
@@ -1,686 +1,1178 @@
-use parsit::error::ParseError;
-use parsit::parser::ParseIt;
-use parsit::step::Step;
-use parsit::{seq, token, wrap};
-use parsit::parser::EmptyToken;
+use std::borrow::Cow;
+use std::mem::discriminant;
+use crate::parser::arena::Ast;
 use crate::parser::ast::*;
-use crate::parser::tokens::Token;
-
-mod tokens;
-mod ast;
+use crate::parser::build::{Build, Head, Suffixed, Tree};
+use crate::parser::cst::Cst;
+use crate::parser::dialect::Dialect;
+use crate::parser::error::SyntaxError;
+use crate::parser::expression::{binary_op, expr_priority, unary_op, UNARY_PRIORITY};
+use crate::parser::span::{LineIndex, Span};
+use crate::parser::tokens::{SpannedLexer, Token};
+
+pub mod tokens;
+pub mod ast;
+pub mod span;
+pub mod error;
+pub mod dialect;
+pub mod fold;
+pub mod cst;
+pub mod owned;
+pub mod visit;
+pub mod printer;
+pub mod format;
+pub mod diff;
+pub mod sexp;
+pub mod arena;
+pub mod resolve;
+pub mod check;
 mod expression;
+mod build;
 
+/// Turns the source into the stream of lua tokens with their locations
+/// skipping whitespaces and comments.
+pub fn lexer(src: &str) -> SpannedLexer<'_> {
+    SpannedLexer::new(src)
+}
 
-struct LuaParser<'a> {
-    delegate: ParseIt<'a, Token<'a>>,
+/// Parses the whole chunk, namely a file or a string of lua code.
+pub fn parse_chunk(src: &str) -> Result<Block<'_>, Box<SyntaxError>> {
+    LuaParser::parse(src)
 }
 
-impl<'a> LuaParser<'a> {
-    fn id(&self, pos: usize) -> Step<'a, Id<'a>> {
-        token!(self.token(pos) => Token::Id(v) => Id{v} )
+/// Parses the whole chunk written in the given dialect.
+pub fn parse_chunk_with(src: &str, dialect: Dialect) -> Result<Block<'_>, Box<SyntaxError>> {
+    LuaParser::new(src, dialect).chunk()
+}
+
+/// Parses the whole chunk into the lossless tree that keeps the comments and the whitespaces.
+pub fn parse_cst(src: &str) -> Result<Cst<'_>, Box<SyntaxError>> {
+    Cst::parse(src, Dialect::default())
+}
+
+/// Parses the whole chunk right into the arena, the tree is not built on the way.
+pub fn parse_chunk_arena(src: &str) -> Result<Ast<'_>, Box<SyntaxError>> {
+    Ast::parse(src, Dialect::default())
+}
+
+/// Parses a single expression. The source should contain nothing but the expression.
+pub fn parse_expr(src: &str) -> Result<Expression<'_>, Box<SyntaxError>> {
+    let mut parser = LuaParser::new(src, Dialect::default());
+    let expr = parser.expr();
+    parser.finish(expr)
+}
+
+/// Parses a single statement. The source should contain nothing but the statement.
+pub fn parse_statement(src: &str) -> Result<Statement<'_>, Box<SyntaxError>> {
+    let mut parser = LuaParser::new(src, Dialect::default());
+    let statement = parser.statement();
+    parser.finish(statement)
+}
+
+/// Parses the whole chunk without stopping at the first syntax error.
+/// Returns the partial tree, where the broken parts are replaced with
+/// `Statement::Error` and `Expression::Error`, along with all the errors found.
+pub fn parse_chunk_recovering(src: &str) -> (Block<'_>, Vec<SyntaxError>) {
+    LuaParser::parse_recovering(src, Dialect::default())
+}
+
+/// Parses the whole chunk written in the given dialect without stopping at the first syntax error.
+pub fn parse_chunk_recovering_with(src: &str, dialect: Dialect) -> (Block<'_>, Vec<SyntaxError>) {
+    LuaParser::parse_recovering(src, dialect)
+}
+
+type Parsed<T> = Result<T, Box<SyntaxError>>;
+
+/// The recursive descent parser of lua looking one token ahead.
+/// It never backtracks, every token is visited once, so the time is linear in the size of the source.
+/// The binary operators are parsed by precedence climbing.
+/// The nodes are made by the builder `B`, the tree of `ast` by default.
+pub struct LuaParser<'a, B = Tree> {
+    tokens: Vec<Token<'a>>,
+    spans: Vec<Span>,
+    /// The current token.
+    pos: usize,
+    src: &'a str,
+    dialect: Dialect,
+    eof: Span,
+    expected: Expected<'a>,
+    /// Whether the parser recovers from the syntax errors collecting them into `errors`.
+    recover: bool,
+    errors: Vec<SyntaxError>,
+    /// The position of every `Token::Error` along with the span of its broken part and the reason of the lexer.
+    lexical: Vec<(usize, Span, String)>,
+    /// The number of the enclosing statements and expressions.
+    depth: usize,
+    max_depth: usize,
+    build: B,
+}
+
+/// The furthest position where the parser failed and the tokens it expected there.
+#[derive(Default)]
+struct Expected<'a> {
+    pos: usize,
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> Expected<'a> {
+    fn add(&mut self, pos: usize, tokens: &[Token<'a>]) {
+        if pos > self.pos {
+            self.pos = pos;
+            self.tokens.clear();
+        }
+        if pos == self.pos {
+            for t in tokens {
+                if !self.tokens.iter().any(|e| discriminant(e) == discriminant(t)) {
+                    self.tokens.push(t.clone())
+                }
+            }
+        }
     }
-    fn text(&self, pos: usize) -> Step<'a, Text<'a>> {
-        token!(self.token(pos) => Token::StringLit(v) => Text{text: v} )
+}
+
+/// The operator of `LuaParser::climb` that waits for its right operand,
+/// the unary one keeps the position of its token.
+enum Pending<'a, B: Build<'a>> {
+    Unary(UnaryType, usize),
+    Binary(B::Expr, BinaryType),
+}
+
+/// The default limit of the nested statements and expressions, the same as in PUC lua.
+/// The rules keep their frames small, so the deepest source fits into 2MB, the stack of a spawned thread,
+/// even in the debug builds. The parsers running on the smaller stacks lower it with `LuaParser::with_max_depth`.
+pub const MAX_DEPTH: usize = 200;
+
+/// Any string literal as the expected token.
+const STRING: Token = Token::StringLit(Cow::Borrowed(&[]));
+
+impl<'a, B: Build<'a>> LuaParser<'a, B> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
     }
-    fn nil(&self, pos: usize) -> Step<'a, Nil> {
-        token!(self.token(pos) => Token::Nil => Nil )
+
+    /// Whether the current token is of the given kind, it is recorded as expected otherwise.
+    fn check(&mut self, expected: Token<'a>) -> bool {
+        let found = matches!(self.peek(), Some(t) if discriminant(t) == discriminant(&expected));
+        if !found {
+            self.expected.add(self.pos, &[expected]);
+        }
+        found
     }
-    fn bool(&self, pos: usize) -> Step<'a, Bool> {
-        token!(self.token(pos) =>
-                Token::True => Bool::True,
-                Token::False => Bool::False
-        )
+
+    /// Consumes the current token if it is of the given kind.
+    fn accept(&mut self, expected: Token<'a>) -> bool {
+        let found = self.check(expected);
+        if found {
+            self.pos += 1;
+        }
+        found
     }
-    fn number(&self, pos: usize) -> Step<'a, Number> {
-        token!(self.token(pos) =>Token::Digit(n) => *n)
+
+    /// The single token that does not carry any value.
+    fn tok(&mut self, expected: Token<'a>) -> Parsed<()> {
+        if self.accept(expected) {
+            Ok(())
+        } else {
+            Err(self.error(self.pos))
+        }
     }
 
-    fn comma(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::Comma)
+    /// Fails on the current token that is none of the expected ones.
+    fn fail(&mut self, expected: &[Token<'a>]) -> Box<SyntaxError> {
+        self.expected.add(self.pos, expected);
+        self.error(self.pos)
     }
-    fn semi(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::Semi)
+
+    /// Whether the current token closes the block.
+    fn block_end(&self) -> bool {
+        matches!(self.peek(), None | Some(Token::End | Token::Else | Token::Elseif | Token::Until))
     }
-    fn l_br(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::LBrack)
+
+    fn id(&mut self) -> Parsed<Id<'a>> {
+        match self.peek() {
+            Some(Token::Id(v)) => {
+                let id = Id { v: Name::Borrowed(v), span: self.span(self.pos, self.pos + 1) };
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => Err(self.fail(&[Token::Id("")])),
+        }
     }
-    fn r_br(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::RBrack)
+    fn text(&mut self) -> Parsed<Text<'a>> {
+        match self.peek() {
+            Some(Token::StringLit(v)) => {
+                let text = Text { text: v.clone(), span: self.span(self.pos, self.pos + 1) };
+                self.pos += 1;
+                Ok(text)
+            }
+            _ => Err(self.fail(&[STRING])),
+        }
     }
-    fn l_pr(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::LParen)
+    fn number(&mut self) -> Parsed<Number> {
+        match self.peek() {
+            Some(Token::Digit(n)) => {
+                let number = self.dialect.number(*n, &self.src[self.spans[self.pos].range()]);
+                self.check_dialect(self.pos)?;
+                self.pos += 1;
+                Ok(number)
+            }
+            _ => Err(self.fail(&[Token::Digit(Number::Int(0))])),
+        }
     }
-    fn r_pr(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::RParen)
+}
+
+impl<'a, B: Build<'a>> LuaParser<'a, B> {
+    fn expr(&mut self) -> Parsed<B::Expr> {
+        self.sub_expr(0)
     }
-    fn assign(&self, pos: usize) -> Step<'a, EmptyToken> {
-        token!(self.token(pos) => Token::Assign)
+
+    /// Precedence climbing: the binary operator is taken while its left priority is greater than `limit`,
+    /// thus the operators with equal priorities are left associative unless the right priority is lower.
+    /// The unary operators take the operand up to the operators of a higher priority, namely `^`.
+    ///
+    /// The operators waiting for their right operand are kept on a stack instead of the native one,
+    /// so they are not syntax levels. The stack is bounded by the same limit though,
+    /// since `a .. b .. c` nests the tree as deep as the stack grows while `a + b + c` does not.
+    fn sub_expr(&mut self, limit: i32) -> Parsed<B::Expr> {
+        self.nested(|p| p.climb(limit))
     }
-}
 
-impl<'a> LuaParser<'a> {
-    fn expr(&self, pos: usize) -> Step<'a, Expression<'a>> {
-        let atom = |p: usize| { self.atom(p) };
-        let sign = |p: usize| {
-            token!(self.token(p) =>
-                    Token::Mult => BinaryType::Mult,
-                    Token::Div => BinaryType::Div,
-                    Token::FDiv => BinaryType::FDiv,
-                    Token::Mod => BinaryType::Mod,
-                    Token::Plus => BinaryType::Add,
-                    Token::EllipsisIn => BinaryType::Concat,
-                    Token::Gt => BinaryType::Gt,
-                    Token::Lt => BinaryType::Lt,
-                    Token::Ge => BinaryType::Ge,
-                    Token::Le => BinaryType::Le,
-                    Token::Eq => BinaryType::Eq,
-                    Token::TEq => BinaryType::TEq,
-                    Token::And => BinaryType::And,
-                    Token::Or => BinaryType::Or,
-                    Token::LShift => BinaryType::LShift,
-                    Token::RShift => BinaryType::RShift,
-                    Token::Ampersand => BinaryType::Amper,
-                    Token::Stick => BinaryType::Stick,
-                    Token::Tilde => BinaryType::Tilde,
-                    Token::Minus => BinaryType::Sub,
-                    Token::Caret => BinaryType::Pov
-                )
-        };
+    fn climb(&mut self, limit: i32) -> Parsed<B::Expr> {
+        let mut pending: Vec<(Pending<'a, B>, i32)> = vec![];
+        loop {
+            while let Some(op) = self.unary() {
+                self.check_dialect(self.pos - 1)?;
+                self.push_pending(&mut pending, Pending::Unary(op, self.pos - 1), UNARY_PRIORITY)?;
+            }
+            let mut operand = self.atom()?;
+            loop {
+                let limit = pending.last().map_or(limit, |(_, limit)| *limit);
+                if let Some(op) = self.binary().filter(|op| expr_priority(op).0 > limit) {
+                    self.check_dialect(self.pos)?;
+                    self.pos += 1;
+                    self.push_pending(&mut pending, Pending::Binary(operand, op), expr_priority(&op).1)?;
+                    break;
+                }
+                match pending.pop() {
+                    None => return Ok(operand),
+                    Some((op, _)) => operand = self.apply(op, operand),
+                }
+            }
+        }
+    }
 
-        atom(pos)
-            .then_multi_zip(|p| sign(p).then_zip(atom))
-            .map(|(first, others)| Expression::fold(first, others))
+    fn push_pending(&self, pending: &mut Vec<(Pending<'a, B>, i32)>, op: Pending<'a, B>, limit: i32) -> Parsed<()> {
+        if pending.len() >= self.max_depth {
+            return Err(self.too_deep());
+        }
+        pending.push((op, limit));
+        Ok(())
     }
 
-    fn table_const(&self, pos: usize) -> Step<'a, TableConst<'a>> {
-        let sep = |p| {
-            self.comma(p).or(|p| self.semi(p))
-        };
+    /// The operator gets its right operand, the one just parsed, the unary operator spans up to its end.
+    fn apply(&mut self, op: Pending<'a, B>, operand: B::Expr) -> B::Expr {
+        match op {
+            Pending::Unary(op, start) => {
+                let span = self.span(start, self.pos);
+                self.build.unary(op, operand, span)
+            }
+            Pending::Binary(lhs, op) => self.build.binary(lhs, op, operand),
+        }
+    }
 
-        let field = |p| {
-            let pair_expr_as_key = |p| {
-                self.l_br(p)
-                    .then(|p| self.expr(p))
-                    .then_skip(|p| self.r_br(p))
-                    .then_skip(|p| self.assign(p))
-                    .then_zip(|p| self.expr(p))
-                    .map(|(k, v)| Field::Pair(FieldKey::Expr(k), v))
-            };
-            let pair_id_as_key = |p| {
-                self.id(p)
-                    .then_skip(|p| self.assign(p))
-                    .then_zip(|p| self.expr(p))
-                    .map(|(id, v)| Field::Pair(FieldKey::Id(id), v))
-            };
-
-            let value = |p| {
-                self.expr(p).map(Field::Value)
-            };
-
-            let step: Step<'a, Field<'a>> = pair_expr_as_key(p).or_from(p)
-                .or(pair_id_as_key)
-                .or(value)
-                .into();
-            step
+    fn unary(&mut self) -> Option<UnaryType> {
+        let Some(op) = self.peek().and_then(unary_op) else {
+            self.expected.add(self.pos, &[Token::Not, Token::Hash, Token::Tilde, Token::Minus]);
+            return None;
         };
+        self.pos += 1;
+        Some(op)
+    }
 
-        let fields = |p| seq!(p => field, sep,);
+    /// The binary operator at the current position, it is not consumed.
+    fn binary(&self) -> Option<BinaryType> {
+        self.peek().and_then(binary_op)
+    }
 
-        let l_brace = |p: usize| token!(self.token(p) => Token::LBrace);
-        let r_brace = |p: usize| token!(self.token(p) => Token::RBrace);
-        let empt_vec = vec![];
+    fn atom(&mut self) -> Parsed<B::Expr> {
+        let literal: fn(Span) -> Expression<'a> = match self.peek() {
+            Some(Token::True) => Expression::True,
+            Some(Token::False) => Expression::False,
+            Some(Token::Nil) => Expression::Nil,
+            Some(Token::EllipsisOut) => Expression::VarArgs,
+            Some(Token::StringLit(_)) => return self.text().map(|text| self.build.leaf(Expression::Text(text))),
+            Some(Token::Digit(_)) => {
+                let span = self.span(self.pos, self.pos + 1);
+                return self.number().map(|n| self.build.leaf(Expression::Number(n, span)));
+            }
+            Some(Token::Function) => return self.function_e(),
+            Some(Token::Id(_) | Token::LParen) => return self.prefix_expr().map(|prefix| self.build.prefix_expr(prefix)),
+            Some(Token::LBrace) => return self.table_const().map(|table| self.build.table_expr(table)),
+            Some(Token::Error) if self.recover => {
+                self.report(*self.error(self.pos));
+                Expression::Error
+            }
+            _ => return Err(self.fail(&[
+                Token::True, Token::False, Token::Nil, Token::EllipsisOut, STRING, Token::Digit(Number::Int(0)),
+                Token::Function, Token::Id(""), Token::LParen, Token::LBrace,
+            ])),
+        };
+        self.pos += 1;
+        Ok(self.build.leaf(literal(self.span(self.pos - 1, self.pos))))
+    }
 
-        wrap!(pos => l_brace; fields or empt_vec; r_brace)
-            .map(|fields| TableConst { fields })
+    fn function_e(&mut self) -> Parsed<B::Expr> {
+        let start = self.pos;
+        self.tok(Token::Function)?;
+        let (params, body) = self.fn_body()?;
+        Ok(self.build.function(params, body, self.span(start, self.pos)))
     }
 
-    fn names(&self, pos: usize) -> Step<'a, Vec<Id<'a>>> {
-        let comma = |p: usize| self.comma(p);
-        let id = |p: usize| self.id(p);
-        seq!(pos => id, comma)
+    fn table_const(&mut self) -> Parsed<B::Table> {
+        let start = self.pos;
+        self.tok(Token::LBrace)?;
+        let mut fields = vec![];
+        while !self.check(Token::RBrace) {
+            self.field(&mut fields)?;
+            if !self.accept(Token::Comma) && !self.accept(Token::Semi) {
+                break;
+            }
+        }
+        self.tok(Token::RBrace)?;
+        Ok(self.build.table(fields, self.span(start, self.pos)))
     }
 
-    fn params(&self, pos: usize) -> Step<'a, FnParams<'a>> {
-        let varags = |p: usize|
-            self.comma(p)
-                .then(|p| token!(self.token(p) => Token::EllipsisOut))
-                .or_none();
+    /// `[k] = v`, `name = v` or `v`, the name is told from the value by the `=` after it.
+    /// The field goes right into the table, so the nested tables do not carry it in their frames.
+    fn field(&mut self, fields: &mut Vec<B::Field>) -> Parsed<()> {
+        let named = matches!(self.peek(), Some(Token::Id(_))) && matches!(self.tokens.get(self.pos + 1), Some(Token::Assign));
+        if named {
+            return self.named_field(fields);
+        }
+        if self.check(Token::LBrack) {
+            return self.keyed_field(fields);
+        }
+        let value = self.expr()?;
+        fields.push(self.build.value_field(value));
+        Ok(())
+    }
 
-        let transform = |(names, vargs): (Vec<Id<'a>>, Option<EmptyToken>)| {
-            if vargs.is_some() {
-                FnParams::WithVarArgs(names)
-            } else { FnParams::Args(names) }
-        };
+    /// `name = v`
+    fn named_field(&mut self, fields: &mut Vec<B::Field>) -> Parsed<()> {
+        let name = self.id()?;
+        self.tok(Token::Assign)?;
+        let value = self.expr()?;
+        fields.push(self.build.named_field(name, value));
+        Ok(())
+    }
 
+    /// `[k] = v`
+    fn keyed_field(&mut self, fields: &mut Vec<B::Field>) -> Parsed<()> {
+        let key = self.index()?;
+        self.tok(Token::Assign)?;
+        let value = self.expr()?;
+        fields.push(self.build.keyed_field(key, value));
+        Ok(())
+    }
 
-        self.names(pos)
-            .then_or_none_zip(varags)
-            .map(transform)
-            .or_from(pos)
-            .or(|p| token!(self.token(p) => Token::EllipsisOut).map(|_| FnParams::VarArgs))
-            .into()
-    }
-
-    fn expr_list(&self, pos: usize) -> Step<'a, Vec<Expression<'a>>> {
-        let e = |p: usize| self.expr(p);
-        let comma = |p: usize| self.comma(p);
-        seq!(pos => e,comma)
-    }
-    fn id_list(&self, pos: usize) -> Step<'a, Vec<Id<'a>>> {
-        let id = |p: usize| self.id(p);
-        let comma = |p: usize| self.comma(p);
-        seq!(pos => id,comma)
-    }
-    fn var_list(&self, pos: usize) -> Step<'a, Vec<Var<'a>>> {
-        let v = |p: usize| self.var(p);
-        let comma = |p: usize| self.comma(p);
-        seq!(pos => v,comma)
-    }
-    fn attr_name_list(&self, pos: usize) -> Step<'a, Vec<AttrName<'a>>> {
-        let attr = |p: usize| {
-            let l = |p: usize| { token!(self.token(p) => Token::Lt) };
-            let r = |p: usize| { token!(self.token(p) => Token::Gt) };
-            let id = |p: usize| { self.id(p) };
-
-            id(p)
-                .then_or_none_zip(|p| wrap!(p => l;id;r).or_none())
-                .map(|(id, opt)| {
-                    if let Some(a) = opt {
-                        AttrName::AttrName(id, a)
-                    } else {
-                        AttrName::Name(id)
-                    }
-                })
-        };
-        let comma = |p: usize| self.comma(p);
+    fn names(&mut self) -> Parsed<Vec<Id<'a>>> {
+        let mut names = vec![self.id()?];
+        while self.accept(Token::Comma) {
+            names.push(self.id()?);
+        }
+        Ok(names)
+    }
 
-        seq!(pos => attr, comma)
+    fn params(&mut self) -> Parsed<FnParams<'a>> {
+        if self.accept(Token::EllipsisOut) {
+            return Ok(FnParams::VarArgs);
+        }
+        let mut names = vec![self.id()?];
+        while self.accept(Token::Comma) {
+            if matches!(self.peek(), Some(Token::EllipsisOut)) {
+                self.pos += 1;
+                return Ok(FnParams::WithVarArgs(names));
+            }
+            names.push(self.id().map_err(|_| self.fail(&[Token::Id(""), Token::EllipsisOut]))?);
+        }
+        Ok(FnParams::Args(names))
     }
 
-    fn fn_params(&self, pos: usize) -> Step<'a, FnParams<'a>> {
-        let l = |p: usize| self.l_pr(p);
-        let r = |p: usize| self.r_pr(p);
-        let params = |p: usize| self.params(p);
-        let def = FnParams::default();
+    fn expr_list(&mut self) -> Parsed<Vec<B::Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.accept(Token::Comma) {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
 
-        wrap!(pos => l;params or def;r)
+    fn attr_name_list(&mut self) -> Parsed<Vec<AttrName<'a>>> {
+        let mut names = vec![];
+        loop {
+            let id = self.id()?;
+            if self.accept(Token::Lt) {
+                if !self.dialect.attributes {
+                    self.unsupported(self.pos - 1, self.dialect.unsupported("variable attribute"))?;
+                }
+                let attr = self.id()?;
+                self.tok(Token::Gt)?;
+                names.push(AttrName::AttrName(id, attr));
+            } else {
+                names.push(AttrName::Name(id));
+            }
+            if !self.accept(Token::Comma) {
+                return Ok(names);
+            }
+        }
     }
-    fn name_args(&self, pos: usize) -> Step<'a, NameArgs<'a>> {
-        let args = |p| {
-            let expr_args = self.l_pr(p)
-                .then_or_default(|p| self.expr_list(p))
-                .then_skip(|p| self.r_pr(p))
-                .map(Args::Expressions);
 
+    fn fn_params(&mut self) -> Parsed<FnParams<'a>> {
+        self.tok(Token::LParen)?;
+        let params = if self.check(Token::RParen) { FnParams::default() } else { self.params()? };
+        self.tok(Token::RParen)?;
+        Ok(params)
+    }
 
-            let step: Step<'a, Args> = expr_args
-                .or_from(p)
-                .or(|p| self.table_const(p).map(Args::Constructor))
-                .or(|p| self.text(p).map(Args::String))
-                .into();
-            step
-        };
-        let name = token!(self.token(pos) => Token::Colon).then(|p| self.id(p));
-        name.or_none().then_zip(args).map(|(opt, args)| {
-            if let Some(v) = opt {
-                NameArgs::NameArgs(v, args)
-            } else {
-                NameArgs::Args(args)
+    /// The parameters and the body of the function up to `end`.
+    fn fn_body(&mut self) -> Parsed<(FnParams<'a>, B::Block)> {
+        let params = self.fn_params()?;
+        let body = self.block()?;
+        self.tok(Token::End)?;
+        Ok((params, body))
+    }
+
+    fn args(&mut self) -> Parsed<B::Args> {
+        let start = self.pos;
+        match self.peek() {
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let exprs = if self.check(Token::RParen) { vec![] } else { self.expr_list()? };
+                self.tok(Token::RParen)?;
+                Ok(self.build.list_args(exprs, self.span(start, self.pos)))
             }
-        })
+            Some(Token::LBrace) => self.table_const().map(|table| self.build.table_args(table)),
+            Some(Token::StringLit(_)) => self.text().map(|text| self.build.string_args(text)),
+            _ => Err(self.fail(&[Token::LParen, Token::LBrace, STRING])),
+        }
     }
-    fn var_suffix(&self, pos: usize) -> Step<'a, VarSuffix<'a>> {
-        let lb = |p: usize| self.l_br(p);
-        let rb = |p: usize| self.r_br(p);
-        let e = |p: usize| self.expr(p);
 
-        let expr = |p: usize| wrap!(p => lb;e;rb).map(Suffix::Expr);
-        let name = |p: usize| {
-            token!(self.token(p) => Token::Dot)
-                .then(|p| self.id(p))
-                .map(Suffix::Id)
-        };
-        self.delegate.zero_or_more(pos, |p| self.name_args(p))
-            .then_zip(|p| expr(p).or_from(p).or(name).into())
-            .map(|(a, r)| VarSuffix { var: a, suffix: r })
-    }
-    fn var(&self, pos: usize) -> Step<'a, Var<'a>> {
-        let lp = |p: usize| self.l_pr(p);
-        let rp = |p: usize| self.r_pr(p);
-        let e = |p: usize| self.expr(p);
-        let expr = |p: usize| {
-            wrap!(p => lp;e;rp)
-                .then_zip(|p| self.var_suffix(p))
-                .map(|(e, s)| VarHead::Expr(e, s))
-        };
+    fn name_args(&mut self) -> Parsed<B::Call> {
+        let name = if self.accept(Token::Colon) { Some(self.id()?) } else { None };
+        self.args().map(|args| self.build.call(name, args))
+    }
 
-        self.id(pos)
-            .map(VarHead::Id)
-            .or(expr)
-            .then_zip(|p| self.delegate.zero_or_more(p, |p| self.var_suffix(p)))
-            .map(|(head, tail)| Var { head, tail })
-    }
-    fn var_or_expr(&self, pos: usize) -> Step<'a, VarOrExpr<'a>> {
-        let lp = |p: usize| self.l_pr(p);
-        let rp = |p: usize| self.r_pr(p);
-        let e = |p: usize| self.expr(p);
-        let expr = |p: usize| {
-            wrap!(p => lp;e;rp)
-                .map(VarOrExpr::Expr)
-        };
+    /// The name or the expression in parens followed by any number of suffixes and calls.
+    fn prefix_expr(&mut self) -> Parsed<B::Prefix> {
+        let start = self.pos;
+        let head = match self.peek() {
+            Some(Token::LParen) => self.parens(),
+            _ => self.id().map(Head::Name),
+        }?;
+
+        let mut suffixes = vec![];
+        while self.suffix(&mut suffixes)? {}
+        Ok(self.build.prefix(head, suffixes, self.span(start, self.pos)))
+    }
 
-        self.var(pos).map(VarOrExpr::Var)
-            .or_from(pos)
-            .or(expr)
-            .into()
+    /// Adds the suffix at the current position along with the span of its tokens, if there is one.
+    fn suffix(&mut self, suffixes: &mut Vec<(Suffixed<'a, B>, Span)>) -> Parsed<bool> {
+        let from = self.pos;
+        let suffix = match self.peek() {
+            Some(Token::Dot) => {
+                self.pos += 1;
+                self.id().map(Suffixed::Field)
+            }
+            Some(Token::LBrack) => self.index().map(Suffixed::Index),
+            Some(Token::Colon | Token::LParen | Token::LBrace | Token::StringLit(_)) => self.name_args().map(Suffixed::Call),
+            _ => {
+                self.expected.add(self.pos, &[Token::Colon, Token::LParen, Token::LBrace, STRING, Token::LBrack, Token::Dot]);
+                return Ok(false);
+            }
+        }?;
+        suffixes.push((suffix, self.span(from, self.pos)));
+        Ok(true)
     }
-    fn fn_call(&self, pos: usize) -> Step<'a, FnCall<'a>> {
-        self.var_or_expr(pos)
-            .then_zip(|p| self.delegate.one_or_more(p, |p| self.name_args(p)))
-            .map(|(head, args)| FnCall { head, args })
+
+    /// `(expr)`
+    fn parens(&mut self) -> Parsed<Head<'a, B::Expr>> {
+        let start = self.pos;
+        self.tok(Token::LParen)?;
+        let expr = self.expr()?;
+        self.tok(Token::RParen)?;
+        Ok(Head::Parens(expr, self.span(start, self.pos)))
     }
-    fn fn_name(&self, pos: usize) -> Step<'a, FnName<'a>> {
-        let id = |p: usize| self.id(p);
-        let c = |p: usize| token!(self.token(p) => Token::Dot);
-        let end = |p: usize| token!(self.token(p) => Token::Colon).then(id);
 
-        seq!(pos => id,c)
-            .then_or_none_zip(|p| end(p).or_none())
-            .map(|(mut names, last)| { FnName { names, last } })
+    /// `[expr]`
+    fn index(&mut self) -> Parsed<B::Expr> {
+        self.tok(Token::LBrack)?;
+        let expr = self.expr()?;
+        self.tok(Token::RBrack)?;
+        Ok(expr)
     }
 
-    fn block(&self, pos: usize) -> Step<'a, Block<'a>> {
-        let return_s = |p: usize| {
-            token!(self.token(p) => Token::Return)
-                .then_or_default(|p| self.expr_list(p))
-                .then_or_none_zip(|p| token!(self.token(p) => Token::Semi).or_none())
-                .take_left()
-        };
+    fn fn_name(&mut self) -> Parsed<FnName<'a>> {
+        let start = self.pos;
+        let mut names = vec![self.id()?];
+        while self.accept(Token::Dot) {
+            names.push(self.id()?);
+        }
+        let last = if self.accept(Token::Colon) { Some(self.id()?) } else { None };
+        Ok(FnName { names, last, span: self.span(start, self.pos) })
+    }
+
+    fn block(&mut self) -> Parsed<B::Block> {
+        if self.recover {
+            return Ok(self.recovering_block(false));
+        }
+        let start = self.pos;
+        let mut sts = vec![];
+        while !self.block_end() && !matches!(self.peek(), Some(Token::Return)) {
+            self.statement().map(|st| sts.push(st))?;
+        }
+        let ret = if matches!(self.peek(), Some(Token::Return)) { Some(self.return_s()?) } else { None };
+        Ok(self.build.block(sts, ret, self.span(start, self.pos)))
+    }
 
-        self.delegate.zero_or_more(pos, |p| self.statement(p))
-            .then_or_none_zip(|p| return_s(p).or_none())
-            .map(|(sts, ret)| {
-                if let Some(r) = ret {
-                    Block::Return(sts, r)
-                } else {
-                    Block::Void(sts)
+    /// The block that never fails: every statement that can not be parsed
+    /// is reported and replaced with `Statement::Error` covering the skipped tokens.
+    /// The chunk (`top`) does not have an enclosing statement
+    /// thus the stray block terminators are skipped as well.
+    fn recovering_block(&mut self, top: bool) -> B::Block {
+        let start = self.pos;
+        let mut sts = vec![];
+        let mut ret = None;
+        while let Some(t) = self.peek() {
+            let p = self.pos;
+            let terminator = matches!(t, Token::End | Token::Else | Token::Elseif | Token::Until);
+            let lexical = matches!(t, Token::Error);
+            let depth = usize::from(matches!(t, Token::Function | Token::Do | Token::If | Token::Repeat));
+            if matches!(t, Token::Return) {
+                match self.return_s() {
+                    Ok(exprs) => ret = Some(exprs),
+                    Err(e) => {
+                        self.report(*e);
+                        let next = self.sync(p + 1, 0);
+                        self.skip(&mut sts, p, next);
+                        continue;
+                    }
                 }
-            })
+                if top && self.pos < self.tokens.len() {
+                    self.report(*self.error(self.pos));
+                    self.skip(&mut sts, self.pos, self.tokens.len());
+                }
+                break;
+            } else if terminator && !top {
+                break;
+            } else if terminator || lexical {
+                self.report(*self.error(p));
+                self.skip(&mut sts, p, p + 1);
+            } else if let Err(e) = self.statement().map(|st| sts.push(st)) {
+                self.report(*e);
+                let next = self.sync(p + 1, depth);
+                self.skip(&mut sts, p, next);
+            }
+        }
+        self.build.block(sts, ret, self.span(start, self.pos))
     }
 
-    fn statement(&self, pos: usize) -> Step<'a, Statement<'a>> {
-        let fn_t = |p: usize| token!(self.token(p) => Token::Function);
-        let end_t = |p: usize| token!(self.token(p) => Token::End);
-        let block = |p: usize| self.block(p);
-        let local = |p: usize| token!(self.token(p) => Token::Local);
-        let id = |p: usize| self.id(p);
-        let do_t = |p: usize| token!(self.token(p) => Token::Do);
-        let expr = |p: usize| self.expr(p);
-        let then_t = |p: usize| token!(self.token(p) => Token::Then);
-        let assign = |p: usize| self.assign(p);
-
-        let empty = |p: usize| token!(self.token(p) => Token::Semi => Statement::Empty);
-        let assignment = |p: usize| {
-            self.var_list(p)
-                .then_skip(assign)
-                .then_zip(|p| self.expr_list(p))
-                .map(|(vs, es)| Statement::Assignment(vs, es))
-        };
-        let fn_call = |p: usize| self.fn_call(p).map(Statement::FnCall);
-        let label = |p: usize| {
-            let del = |p: usize| token!(self.token(p) => Token::DColon);
-            wrap!(p => del;id;del).map(Statement::Label)
-        };
-        let break_s = |p: usize| token!(self.token(p) => Token::Break => Statement::Break);
-        let goto = |p: usize| {
-            token!(self.token(p) => Token::Goto).then(|p| self.id(p)).map(Statement::Goto)
-        };
+    /// Replaces the tokens from `from` up to `to` with `Statement::Error` and moves past them.
+    fn skip(&mut self, sts: &mut Vec<B::Stmt>, from: usize, to: usize) {
+        sts.push(self.build.leaf_stmt(Statement::Error(self.span(from, to))));
+        self.pos = to;
+    }
 
-        let do_s = |p: usize| {
-            wrap!(p => do_t;block;end_t).map(Statement::Do)
-        };
+    fn return_s(&mut self) -> Parsed<Vec<B::Expr>> {
+        self.tok(Token::Return)?;
+        let exprs = if self.block_end() || self.check(Token::Semi) { vec![] } else { self.expr_list()? };
+        self.accept(Token::Semi);
+        Ok(exprs)
+    }
 
-        let while_s = |p: usize| {
-            let while_t = |p: usize| token!(self.token(p) => Token::While);
-            while_t(p)
-                .then(expr)
-                .then_zip(|p| wrap!(p => do_t;block;end_t))
-                .map(|(cond, body)|
-                    Statement::While(While { cond, body }))
-        };
+    /// The right side of an assignment.
+    /// In the recovery mode the broken list of expressions is reported
+    /// and replaced with `Expression::Error` covering the skipped tokens.
+    fn rhs(&mut self) -> Parsed<Vec<B::Expr>> {
+        let start = self.pos;
+        match self.expr_list() {
+            Err(e) if self.recover => {
+                self.report(*e);
+                let next = self.sync(start, 0);
+                self.pos = next;
+                Ok(vec![self.build.leaf(Expression::Error(self.span(start, next)))])
+            }
+            other => other,
+        }
+    }
 
-        let repeat_s = |p: usize| {
-            let repeat_t = |p: usize| token!(self.token(p) => Token::Repeat);
-            let until_t = |p: usize| token!(self.token(p) => Token::Until);
+    fn statement(&mut self) -> Parsed<B::Stmt> {
+        self.nested(Self::single_statement)
+    }
 
-            repeat_t(p)
-                .then(block)
-                .then_skip(until_t)
-                .then_zip(expr)
-                .map(|(body, until)| Statement::Repeat(Repeat { until, body }))
-        };
+    /// The statement is told by its first token,
+    /// the assignment and the call both start with a prefix expression and are told by what follows it.
+    /// Every statement has its own rule keeping the frame of this one small for the deeply nested blocks.
+    fn single_statement(&mut self) -> Parsed<B::Stmt> {
+        match self.peek() {
+            Some(Token::Semi) => self.keyword_s(Statement::Empty),
+            Some(Token::Break) => self.keyword_s(Statement::Break),
+            Some(Token::DColon) => self.label_s(),
+            Some(Token::Goto) => self.goto_s(),
+            Some(Token::Do) => self.do_s(),
+            Some(Token::While) => self.while_s(),
+            Some(Token::Repeat) => self.repeat_s(),
+            Some(Token::If) => self.if_s(),
+            Some(Token::For) => self.for_s(),
+            Some(Token::Function) => self.function_s(),
+            Some(Token::Local) => self.local(),
+            Some(Token::Id(_) | Token::LParen) => self.assignment_or_call(),
+            _ => Err(self.fail(&[
+                Token::Semi, Token::Id(""), Token::LParen, Token::DColon, Token::Break, Token::Goto, Token::Do,
+                Token::While, Token::Repeat, Token::If, Token::For, Token::Function, Token::Local,
+            ])),
+        }
+    }
 
-        let if_s = |p: usize| {
-            let if_t = |p: usize| token!(self.token(p) => Token::If);
-            let else_if_t = |p: usize| token!(self.token(p) => Token::Elseif);
-            let else_t = |p: usize| token!(self.token(p) => Token::Else);
-
-            let if_main = |p: usize| {
-                wrap!(p => if_t;expr;then_t)
-                    .then_zip(block)
-                    .map(|(cond, body)| IfBranch { cond, body })
-            };
-            let else_if = |p: usize| {
-                wrap!(p => else_if_t;expr;then_t)
-                    .then_zip(block)
-                    .map(|(cond, body)| IfBranch { cond, body })
-            };
-            let else_b = |p: usize| {
-                else_t(p).then(block)
-            };
-
-            if_main(p)
-                .then_multi_zip(else_if)
-                .then_or_none_zip(|p| else_b(p).or_none())
-                .then_skip(end_t)
-                .map(|((main, elseifs), else_opt)| {
-                    if let Some(opt) = else_opt {
-                        Statement::If(If::IfElse(main, elseifs, opt))
-                    } else {
-                        Statement::If(If::If(main, elseifs))
-                    }
-                })
-        };
+    /// The statement of a single keyword: `;` or `break`.
+    fn keyword_s(&mut self, statement: fn(Span) -> Statement<'a>) -> Parsed<B::Stmt> {
+        self.pos += 1;
+        Ok(self.build.leaf_stmt(statement(self.span(self.pos - 1, self.pos))))
+    }
 
-        let for_s = |p: usize| {
-            let for_t = |p: usize| token!(self.token(p) => Token::For);
-            let in_t = |p: usize| token!(self.token(p) => Token::In);
-            let exprs = |p: usize| self.expr_list(p);
-
-            let names = |p: usize| self.names(p);
-
-            let plain = |p: usize| {
-                for_t(p)
-                    .then(id)
-                    .then_skip(assign)
-                    .then_zip(expr)
-                    .then_skip(|p: usize| self.comma(p))
-                    .then_zip(expr)
-                    .then_or_none_zip(|p| self.comma(p).then(expr).or_none())
-                    .then_skip(do_t)
-                    .then_zip(block)
-                    .then_skip(end_t)
-                    .map(|(((init, border), step), body)|
-                        Statement::For(For::Plain(PlainFor {
-                            init,
-                            border,
-                            step,
-                            body,
-                        })))
-            };
-            let col = |p: usize| {
-                for_t(p)
-                    .then(names)
-                    .then_skip(in_t)
-                    .then_zip(exprs)
-                    .then_skip(do_t)
-                    .then_zip(block)
-                    .then_skip(end_t)
-                    .map(|((names, expressions), body)|
-                        Statement::For(For::ForCol(ExprFor {
-                            names,
-                            expressions,
-                            body,
-                        })))
-            };
-            let res: Step<'a, Statement> = plain(p).or_from(p).or(col).into();
-            res
-        };
+    fn label_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.check_dialect(self.pos)?;
+        self.tok(Token::DColon)?;
+        let id = self.id()?;
+        self.tok(Token::DColon)?;
+        Ok(self.build.leaf_stmt(Statement::Label(id, self.span(start, self.pos))))
+    }
 
-        let function = |p: usize| {
-            let fn_name = |p: usize| self.fn_name(p);
-            let fn_params = |p: usize| self.fn_params(p);
-
-            fn_t(p)
-                .then(fn_name)
-                .then_zip(fn_params)
-                .then_zip(block)
-                .then_skip(end_t)
-                .map(|((name, params), body)| Statement::FnDef(FnDef {
-                    name,
-                    params,
-                    body,
-                }))
-        };
-        let local_function = |p: usize| {
-            let name = |p: usize| self.id(p);
-            let fn_params = |p: usize| self.fn_params(p);
-
-            local(p)
-                .then(fn_t)
-                .then(name)
-                .then_zip(fn_params)
-                .then_zip(block)
-                .then_skip(end_t)
-                .map(|((name, params), body)| Statement::LocalFnDef(FnDef {
-                    name: FnName { names: vec![name], last:None },
-                    params,
-                    body,
-                }))
-        };
-        let local_attrs = |p: usize| {
-            let attr_names = |p: usize| self.attr_name_list(p);
-            let exprs = |p: usize| self.expr_list(p);
+    fn goto_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::Goto)?;
+        let id = self.id()?;
+        Ok(self.build.leaf_stmt(Statement::Goto(id, self.span(start, self.pos))))
+    }
 
-            local(p).then(attr_names)
-                .then_or_default_zip(|p| self.assign(p).then(exprs))
-                .map(|(attrs, exprs)| Statement::LocalAttrNames(attrs, exprs))
-        };
+    fn do_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        let body = self.do_block()?;
+        Ok(self.build.do_s(body, self.span(start, self.pos)))
+    }
 
-        empty(pos).or_from(pos)
-            .or(assignment)
-            .or(fn_call)
-            .or(label)
-            .or(break_s)
-            .or(goto)
-            .or(do_s)
-            .or(while_s)
-            .or(repeat_s)
-            .or(if_s)
-            .or(for_s)
-            .or(function)
-            .or(local_function)
-            .or(local_attrs)
-            .into()
-    }
-
-    fn atom(&self, pos: usize) -> Step<'a, Expression<'a>> {
-        let primitive = |p: usize|
-            token!(self.token(p) =>
-                        Token::True => Expression::True,
-                        Token::False => Expression::False,
-                        Token::Nil => Expression::Nil,
-                        Token::EllipsisOut => Expression::VarArgs)
-                .or(|p| self.text(p).map(Expression::Text))
-                .or(|p| self.number(p).map(Expression::Number));
-
-        let fn_def = |p: usize|
-            token!(self.token(p) => Token::Function)
-                .then(|p| self.fn_params(p))
-                .then_zip(|p| self.block(p))
-                .then_skip(|p| token!(self.token(p) => Token::End))
-                .map(|(params, body)|
-                    Expression::FnDef(params, body));
-
-        let prefix_expr = |p: usize| {
-            self.var_or_expr(p)
-                .then_multi_zip(|p| self.name_args(p))
-                .map(|(head, args)|
-                    Expression::PrefixExpr(Box::new(FnCall { head, args })))
-        };
+    fn while_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::While)?;
+        let cond = self.expr()?;
+        let body = self.do_block()?;
+        Ok(self.build.while_s(cond, body, self.span(start, self.pos)))
+    }
 
-        let unary = |p: usize| {
-            token!(self.token(p) =>
-                    Token::Not => UnaryType::Not,
-                    Token::Hash => UnaryType::Hash,
-                    Token::Tilde => UnaryType::Tilde,
-                    Token::Minus => UnaryType::Minus)
-                .then_zip(|p| self.expr(p))
-                .map(|(t, e)|
-                    Expression::Unary(t, Box::new(e)))
-        };
+    fn repeat_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::Repeat)?;
+        let body = self.block()?;
+        self.tok(Token::Until)?;
+        let until = self.expr()?;
+        Ok(self.build.repeat_s(body, until, self.span(start, self.pos)))
+    }
+
+    fn function_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::Function)?;
+        let name = self.fn_name()?;
+        let (params, body) = self.fn_body()?;
+        Ok(self.build.function_s(name, params, body, self.span(start, self.pos)))
+    }
+
+    /// `do block end`
+    fn do_block(&mut self) -> Parsed<B::Block> {
+        self.tok(Token::Do)?;
+        let body = self.block()?;
+        self.tok(Token::End)?;
+        Ok(body)
+    }
+
+    fn if_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        let main = self.if_branch()?;
+        let mut elseifs = vec![];
+        while self.check(Token::Elseif) {
+            self.if_branch().map(|branch| elseifs.push(branch))?;
+        }
+        let otherwise = if self.accept(Token::Else) { self.block().map(Some)? } else { None };
+        self.tok(Token::End)?;
+        Ok(self.build.if_s(main, elseifs, otherwise, self.span(start, self.pos)))
+    }
+
+    /// The condition and the body after `if` or `elseif`.
+    fn if_branch(&mut self) -> Parsed<B::Branch> {
+        let start = self.pos;
+        self.pos += 1;
+        let cond = self.expr()?;
+        self.tok(Token::Then)?;
+        let body = self.block()?;
+        Ok(self.build.branch(cond, body, self.span(start, self.pos)))
+    }
 
-        primitive(pos)
-            .or_from(pos)
-            .or(fn_def)
-            .or(prefix_expr)
-            .or(unary)
-            .or(|p| self.table_const(p).map(Expression::TableConstructor))
-            .into()
+    /// The numeric for is told from the generic one by the `=` after the first name.
+    fn for_s(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::For)?;
+        let first = self.id()?;
+        if self.accept(Token::Assign) {
+            self.numeric_for(start, first)
+        } else {
+            self.generic_for(start, first)
+        }
+    }
+
+    /// `for i = init, border, step do ... end` after `=`.
+    fn numeric_for(&mut self, start: usize, var: Id<'a>) -> Parsed<B::Stmt> {
+        let init = self.expr()?;
+        self.tok(Token::Comma)?;
+        let border = self.expr()?;
+        let step = if self.accept(Token::Comma) { Some(self.expr()?) } else { None };
+        self.do_block().map(|body| {
+            let span = self.span(start, self.pos);
+            self.build.numeric_for(var, init, border, step, body, span)
+        })
+    }
+
+    /// `for k, v in exprs do ... end` after the first name.
+    fn generic_for(&mut self, start: usize, first: Id<'a>) -> Parsed<B::Stmt> {
+        let mut names = vec![first];
+        if self.accept(Token::Comma) {
+            names.extend(self.names()?);
+        }
+        self.tok(Token::In)?;
+        let expressions = self.expr_list()?;
+        let body = self.do_block()?;
+        Ok(self.build.generic_for(names, expressions, body, self.span(start, self.pos)))
+    }
+
+    fn local(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        self.tok(Token::Local)?;
+        if self.accept(Token::Function) {
+            let name = self.id()?;
+            let (params, body) = self.fn_body()?;
+            return Ok(self.build.local_function(name, params, body, self.span(start, self.pos)));
+        }
+        let names = self.attr_name_list()?;
+        let exprs = if self.accept(Token::Assign) { self.rhs()? } else { vec![] };
+        Ok(self.build.local(names, exprs, self.span(start, self.pos)))
+    }
+
+    /// `a, b.c = ...` or `f(x)`: the list of variables requires `=`, otherwise the prefix expression is a call.
+    fn assignment_or_call(&mut self) -> Parsed<B::Stmt> {
+        let start = self.pos;
+        let first = self.prefix_expr()?;
+        if !matches!(self.peek(), Some(Token::Assign | Token::Comma)) && self.build.is_call(&first) {
+            return Ok(self.build.call_stmt(first));
+        }
+
+        let mut vars = vec![];
+        self.push_var(&mut vars, first)?;
+        while self.accept(Token::Comma) {
+            let next = self.prefix_expr()?;
+            self.push_var(&mut vars, next)?;
+        }
+        self.tok(Token::Assign)?;
+        let exprs = self.rhs()?;
+        Ok(self.build.assignment(vars, exprs, self.span(start, self.pos)))
+    }
+
+    /// The prefix expression as the target of an assignment, it can not end with a call.
+    fn push_var(&mut self, vars: &mut Vec<B::Var>, prefix: B::Prefix) -> Parsed<()> {
+        match self.build.var(prefix) {
+            Some(var) => vars.push(var),
+            None => return Err(self.error(self.pos)),
+        }
+        Ok(())
     }
 }
 
 impl<'a> LuaParser<'a> {
-    pub fn new(src: &'a str) -> Result<Self, ParseError> {
-        Ok(LuaParser {
-            delegate: ParseIt::new(src)?,
+    /// Lexes the source. The input the lexer rejects becomes `Token::Error`,
+    /// the error is reported with the reason of the lexer once the parser reaches it.
+    pub fn new(src: &'a str, dialect: Dialect) -> Self {
+        LuaParser::with_builder(src, dialect, Tree)
+    }
+
+    pub fn parse(src: &'a str) -> Result<Block<'a>, Box<SyntaxError>> {
+        parse_chunk_with(src, Dialect::default())
+    }
+
+    /// Parses the chunk in the recovery mode.
+    pub fn parse_recovering(src: &'a str, dialect: Dialect) -> (Block<'a>, Vec<SyntaxError>) {
+        LuaParser::new(src, dialect).chunk_recovering()
+    }
+
+    /// Parses the whole source as a chunk.
+    pub fn chunk(self) -> Result<Block<'a>, Box<SyntaxError>> {
+        self.built_chunk().map(|(block, _)| block)
+    }
+
+    /// Parses the whole source as a chunk giving back the tokens along with their spans.
+    pub(crate) fn chunk_tokens(mut self) -> Result<(Block<'a>, Vec<Token<'a>>, Vec<Span>), Box<SyntaxError>> {
+        let block = self.block();
+        let block = self.finish(block)?;
+        Ok((block, self.tokens, self.spans))
+    }
+
+    /// Parses the whole source as a chunk reporting all the syntax errors.
+    pub fn chunk_recovering(mut self) -> (Block<'a>, Vec<SyntaxError>) {
+        self.recover = true;
+        let block = self.recovering_block(true);
+        // the lexical errors of the nested blocks skipped after a syntax error
+        for i in 0..self.lexical.len() {
+            self.report(*self.lexical_error(i));
+        }
+        self.errors.sort_by_key(|e| e.span.start);
+        (block, self.errors)
+    }
+}
+
+impl<'a, B> LuaParser<'a, B> {
+    /// Sets the limit of the nested statements and expressions (`MAX_DEPTH` by default),
+    /// the deeper source is rejected with `chunk has too many syntax levels`.
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        LuaParser { max_depth, ..self }
+    }
+}
+
+impl<'a, B: Build<'a>> LuaParser<'a, B> {
+    fn with_builder(src: &'a str, dialect: Dialect, build: B) -> Self {
+        let eof = LineIndex::new(src).span(src.len()..src.len());
+        let mut tokens = vec![];
+        let mut spans = vec![];
+        let mut lexical = vec![];
+        let mut lexer = SpannedLexer::new(src);
+        while let Some((token, span)) = lexer.next() {
+            match token {
+                Ok(token) => tokens.push(dialect.token(token)),
+                Err(e) => {
+                    lexical.push((tokens.len(), span, e.reason));
+                    tokens.push(Token::Error);
+                }
+            }
+            spans.push(lexer.token_span());
+        }
+
+        LuaParser {
+            tokens,
+            spans,
+            pos: 0,
+            src,
+            dialect,
+            eof,
+            expected: Expected::default(),
+            recover: false,
+            errors: vec![],
+            lexical,
+            depth: 0,
+            max_depth: MAX_DEPTH,
+            build,
+        }
+    }
+
+    /// The span of the tokens in the range `[from, to)`.
+    /// The empty range gives the empty span right before the token `from`.
+    fn span(&self, from: usize, to: usize) -> Span {
+        if to > from {
+            self.spans[from].merge(self.spans[to - 1])
+        } else {
+            self.spans.get(from).map(|s| Span { end: s.start, ..*s }).unwrap_or(self.eof)
+        }
+    }
+
+    /// Parses the whole source as a chunk giving back the builder along with the block.
+    fn built_chunk(mut self) -> Result<(B::Block, B), Box<SyntaxError>> {
+        let block = self.block();
+        let block = self.finish(block)?;
+        Ok((block, self.build))
+    }
+
+    /// Ensures the whole input is consumed.
+    fn finish<T>(&self, parsed: Parsed<T>) -> Result<T, Box<SyntaxError>> {
+        let v = parsed?;
+        if self.pos < self.tokens.len() {
+            return Err(self.error(self.pos));
+        }
+        Ok(v)
+    }
+
+    /// The syntax error that points to the furthest position the parser reached.
+    /// The error at `Token::Error` is the one of the lexer.
+    fn error(&self, pos: usize) -> Box<SyntaxError> {
+        let pos = pos.max(self.expected.pos);
+        if let Ok(i) = self.lexical.binary_search_by_key(&pos, |(p, ..)| *p) {
+            return self.lexical_error(i);
+        }
+        let tokens = if self.expected.pos == pos {
+            self.expected.tokens.iter().map(ToString::to_string).collect()
+        } else {
+            vec![]
+        };
+        let (span, found) = self.found(pos);
+        Box::new(SyntaxError::new(span, tokens, found))
+    }
+
+    /// The error of the `i`th `Token::Error` pointing at its broken part.
+    fn lexical_error(&self, i: usize) -> Box<SyntaxError> {
+        let (_, span, reason) = &self.lexical[i];
+        Box::new(SyntaxError::lexical(*span, quote(&self.src[span.range()]), reason.clone()))
+    }
+
+    fn found(&self, pos: usize) -> (Span, String) {
+        match self.spans.get(pos) {
+            Some(span) => (*span, quote(&self.src[span.range()])),
+            None => (self.eof, "<eof>".to_string()),
+        }
+    }
+
+    /// Goes one syntax level deeper failing when the source nests too deep for the limit.
+    fn enter(&mut self) -> Parsed<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(self.too_deep());
+        }
+        Ok(())
+    }
+
+    fn too_deep(&self) -> Box<SyntaxError> {
+        let (span, found) = self.found(self.pos);
+        Box::new(SyntaxError {
+            reason: Some("chunk has too many syntax levels".to_string()),
+            ..SyntaxError::new(span, vec![], found)
         })
     }
-    fn token(&self, pos: usize) -> Result<(&Token<'a>, usize), ParseError<'a>> {
-        self.delegate.token(pos)
+
+    /// Rejects the token at `pos` if the dialect lacks its feature.
+    fn check_dialect(&mut self, pos: usize) -> Parsed<()> {
+        match self.dialect.check(&self.tokens[pos]) {
+            Some(reason) => self.unsupported(pos, reason),
+            None => Ok(()),
+        }
+    }
+
+    /// Rejects the token at `pos` for the reason. In the recovery mode the error is reported
+    /// and the token is parsed as if the dialect had the feature, the tree and the other errors are kept.
+    fn unsupported(&mut self, pos: usize, reason: String) -> Parsed<()> {
+        let (span, found) = self.found(pos);
+        let error = SyntaxError::lexical(span, found, reason);
+        if !self.recover {
+            return Err(Box::new(error));
+        }
+        self.report(error);
+        Ok(())
+    }
+
+    /// Parses the rule one level deeper, the level is restored even when the rule fails.
+    fn nested<T>(&mut self, rule: impl FnOnce(&mut Self) -> Parsed<T>) -> Parsed<T> {
+        let depth = self.depth;
+        let parsed = self.enter().and_then(|_| rule(self));
+        self.depth = depth;
+        parsed
+    }
+
+    /// Keeps the error found in the recovery mode and starts looking for the next one.
+    /// The nested blocks report their errors first, the enclosing statement may fail on the same place.
+    fn report(&mut self, error: SyntaxError) {
+        if !self.errors.iter().any(|e| e.span.start == error.span.start) {
+            self.errors.push(error);
+        }
+        self.expected = Expected::default();
+    }
+
+    /// The position to resume parsing from after a syntax error:
+    /// the next `local`, `function`, `;` (consumed), the input the lexer rejected or the end of the enclosing block.
+    /// The nested blocks (`depth`) are skipped entirely.
+    fn sync(&self, from: usize, mut depth: usize) -> usize {
+        let mut pos = from;
+        while let Some(t) = self.tokens.get(pos) {
+            let after_local = pos > 0 && matches!(self.tokens.get(pos - 1), Some(Token::Local));
+            match t {
+                Token::Semi if depth == 0 => return pos + 1,
+                Token::Local | Token::Error if depth == 0 => return pos,
+                Token::Function if depth == 0 && !after_local => return pos,
+                Token::End | Token::Else | Token::Elseif | Token::Until if depth == 0 => return pos,
+                Token::Function | Token::Do | Token::If | Token::Repeat => depth += 1,
+                Token::End | Token::Until => depth -= 1,
+                _ => {}
+            }
+            pos += 1;
+        }
+        pos
     }
+}
 
-    pub fn parse(src: &'a str) -> Result<Block<'a>, ParseError<'a>> {
-        let parser = LuaParser::new(src)?;
-        parser
-            .delegate
-            .validate_eof(parser.block(0))
-            .into()
+/// Quotes the lexeme keeping only the beginning of the long ones.
+fn quote(lexeme: &str) -> String {
+    let first_line = lexeme.lines().next().unwrap_or_default();
+    if first_line.len() < lexeme.len() || first_line.chars().count() > 24 {
+        format!("'{}...'", first_line.chars().take(24).collect::<String>())
+    } else {
+        format!("'{}'", lexeme)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use parsit::step::Step;
-    use parsit::test::parser_test::*;
-    use crate::parser::ast::{Expression, Field, FieldKey, FnParams, Id, Number, TableConst, Text};
-    use crate::parser::ast::Field::{Pair, Value};
-    use crate::parser::LuaParser;
-    use crate::parser::tokens::Token;
+    use std::fmt::Debug;
+    use crate::parser::ast::{BinaryType, Block, Expression, FnDef, FnParams, Id, If, IfBranch, Number, SameShape, Statement, Text, UnaryType, Var, VarHead, VarOrExpr, VarSuffix, While};
+    use crate::parser::{LuaParser, MAX_DEPTH, Parsed, parse_chunk, parse_chunk_arena, parse_chunk_recovering, parse_chunk_recovering_with, parse_chunk_with, parse_expr, parse_statement};
+    use crate::parser::dialect::{Dialect, Version};
+    use crate::parser::expression::UNARY_PRIORITY;
+    use crate::parser::span::Spanned;
+
+    fn p(src: &str) -> LuaParser<'_> {
+        LuaParser::new(src, Dialect::default())
+    }
 
-    fn p(src: &str) -> LuaParser {
-        LuaParser::new(src).unwrap()
+    /// Applies the rule to the source and checks the position of the parser after it.
+    fn expect_pos<'a, T: Debug>(src: &'a str, rule: impl FnOnce(&mut LuaParser<'a>) -> Parsed<T>, pos: usize) {
+        let mut parser = p(src);
+        rule(&mut parser).unwrap_or_else(|e| panic!("{}: {}", src, e));
+        assert_eq!(parser.pos, pos, "{}", src);
+    }
+
+    fn expect<'a, T: Debug + SameShape>(src: &'a str, rule: impl FnOnce(&mut LuaParser<'a>) -> Parsed<T>, expected: T) {
+        let actual = rule(&mut p(src)).unwrap_or_else(|e| panic!("{}: {}", src, e));
+        assert!(actual.same_shape(&expected), "{:?} != {:?}", actual, expected);
     }
 
     #[test]
     fn atom_expr_test() {
-        expect_pos(p("true").atom(0), 1);
-        expect_pos(p("1").atom(0), 1);
-        expect_pos(p("false").atom(0), 1);
-        expect_pos(p("nil").atom(0), 1);
-        expect_pos(p("[[some text ]]").atom(0), 1);
-        expect_pos(p("\"sometext\"").atom(0), 1);
-        expect_pos(p("function() return 0 end").atom(0), 6);
-        expect_pos(p("function()  end").atom(0), 4);
-        expect_pos(p("not function() end").atom(0), 5);
+        expect_pos("true", LuaParser::atom, 1);
+        expect_pos("1", LuaParser::atom, 1);
+        expect_pos("false", LuaParser::atom, 1);
+        expect_pos("nil", LuaParser::atom, 1);
+        expect_pos("[[some text ]]", LuaParser::atom, 1);
+        expect_pos("\"sometext\"", LuaParser::atom, 1);
+        expect_pos("function() return 0 end", LuaParser::atom, 6);
+        expect_pos("function()  end", LuaParser::atom, 4);
+        expect_pos("not function() end", |p| p.sub_expr(UNARY_PRIORITY), 5);
+        expect_pos("- - #t", |p| p.sub_expr(UNARY_PRIORITY), 4);
     }
 
     #[test]
     fn atom_prefix_expr_test() {
-        expect_pos(p("name").atom(0), 1);
-        expect_pos(p("name : name (1,2) [function(a);end]").atom(0), 16);
-        expect_pos(p("(x) : name (1,2) [function(a);end] ").atom(0), 18);
-        expect_pos(p("(x) : name (1,2) [function(a);end] : name (1,2) [function(a);end]").atom(0), 33);
-        expect_pos(p("(x){[1] = \"c\"}  ").atom(0), 10);
-        expect_pos(p("(x){[1] = \"c\"}{[1] = \"c\"}  ").atom(0), 17);
+        expect_pos("name", LuaParser::atom, 1);
+        expect_pos("name : name (1,2) [function(a);end]", LuaParser::atom, 16);
+        expect_pos("(x) : name (1,2) [function(a);end] ", LuaParser::atom, 18);
+        expect_pos("(x) : name (1,2) [function(a);end] : name (1,2) [function(a);end]", LuaParser::atom, 33);
+        expect_pos("(x){[1] = \"c\"}  ", LuaParser::atom, 10);
+        expect_pos("(x){[1] = \"c\"}{[1] = \"c\"}  ", LuaParser::atom, 17);
     }
 
     #[test]
     fn names_test() {
-        expect_pos(p("a,b").names(0), 3);
-        expect_pos(p("a").names(0), 1);
+        expect_pos("a,b", LuaParser::names, 3);
+        expect_pos("a", LuaParser::names, 1);
     }
 
     #[test]
     fn expr_test() {
-        expect_pos(p("nil").expr(0), 1);
-        expect_pos(p("false").expr(0), 1);
-        expect_pos(p("\"xxx\"").expr(0), 1);
-        expect_pos(p("[[some text ]]").expr(0), 1);
-        expect_pos(p("...").expr(0), 1);
-        expect_pos(p("1").expr(0), 1);
-        expect_pos(p("id").expr(0), 1);
-        expect_pos(p("id + 1").expr(0), 3);
-        expect_pos(p("a > 0 and (b > 0 or a > b )").expr(0), 13);
+        expect_pos("nil", LuaParser::expr, 1);
+        expect_pos("false", LuaParser::expr, 1);
+        expect_pos("\"xxx\"", LuaParser::expr, 1);
+        expect_pos("[[some text ]]", LuaParser::expr, 1);
+        expect_pos("...", LuaParser::expr, 1);
+        expect_pos("1", LuaParser::expr, 1);
+        expect_pos("id", LuaParser::expr, 1);
+        expect_pos("id + 1", LuaParser::expr, 3);
+        expect_pos("a > 0 and (b > 0 or a > b )", LuaParser::expr, 13);
     }
 
     #[test]
     fn atom_test() {
-        expect_pos(p("function();end").expr(0), 5);
-        expect_pos(p("function(...);end").expr(0), 6);
-        expect_pos(p("function(a);end").expr(0), 6);
-        expect_pos(p("function(a,b);end").expr(0), 8);
-        expect_pos(p("function(a,b,...);end").expr(0), 10);
-        expect_pos(p("function(a,b,...);end").expr(0), 10);
-        expect_pos(p("id").expr(0), 1);
-        expect_pos(p("a + 1").expr(0), 3);
+        expect_pos("function();end", LuaParser::expr, 5);
+        expect_pos("function(...);end", LuaParser::expr, 6);
+        expect_pos("function(a);end", LuaParser::expr, 6);
+        expect_pos("function(a,b);end", LuaParser::expr, 8);
+        expect_pos("function(a,b,...);end", LuaParser::expr, 10);
+        expect_pos("function(a,b,...);end", LuaParser::expr, 10);
+        expect_pos("id", LuaParser::expr, 1);
+        expect_pos("a + 1", LuaParser::expr, 3);
     }
 
     #[test]
     fn var() {
-        expect_pos(p("x").var(0), 1);
+        expect_pos("x", LuaParser::prefix_expr, 1);
     }
 
     #[test]
     fn block_test() {
-        expect_pos(p("; return ;").block(0), 3);
-        expect_pos(p("; return 1;").block(0), 4);
-        expect_pos(p("; return true, 2 ;").block(0), 6);
-        expect_pos(p("goto a return 1, 0 ;").block(0), 7);
+        expect_pos("; return ;", LuaParser::block, 3);
+        expect_pos("; return 1;", LuaParser::block, 4);
+        expect_pos("; return true, 2 ;", LuaParser::block, 6);
+        expect_pos("goto a return 1, 0 ;", LuaParser::block, 7);
     }
 
     #[test]
     fn var_or_expr_test() {
-        expect_pos(p("(true)").var_or_expr(0), 3);
-        expect_pos(p("id").var_or_expr(0), 1);
+        expect_pos("(true)", LuaParser::prefix_expr, 3);
+        expect_pos("id", LuaParser::prefix_expr, 1);
+    }
+
+    #[test]
+    fn prefix_expr_test() {
+        let call = p("a.b(c).d(e)").prefix_expr().unwrap();
+        match &call.head {
+            VarOrExpr::Var(Var { head: VarHead::Id(_), tail, .. }) => {
+                assert_eq!(tail.iter().map(|s| s.var.len()).collect::<Vec<_>>(), vec![0, 1]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(call.args.len(), 1);
+
+        let call = p("(f)(x)(y)").prefix_expr().unwrap();
+        assert!(matches!(call.head, VarOrExpr::Expr(_)));
+        assert_eq!(call.args.len(), 2);
+
+        let call = p("(f):m().x").prefix_expr().unwrap();
+        assert!(matches!(call.head, VarOrExpr::Var(Var { head: VarHead::Expr(_, VarSuffix { ref var, .. }), .. }) if var.len() == 1));
+        assert!(call.args.is_empty());
     }
 
     #[test]
     fn statement_test() {
-        expect_pos(p(";").statement(0), 1);
-        expect_pos(p("a = 1").statement(0), 3);
-        expect_pos(p("a = 1 + 1").statement(0), 5);
-        expect_pos(p("a = 1 + 1  * some_op[1]").statement(0), 10);
-        expect_pos(p("a = (1 + 1)  * some_op[1]").statement(0), 12);
-        expect_pos(p("a,b ={}, some_var[{1}]").statement(0), 13);
-        expect_pos(p("a:a(not a)").statement(0), 7);
-        expect_pos(p("::q::").statement(0), 3);
-        expect_pos(p("break").statement(0), 1);
-        expect_pos(p("goto to").statement(0), 2);
-        expect_pos(p("do ; end").statement(0), 3);
-        expect_pos(p("do return 1 end").statement(0), 4);
-        expect_pos(p(r#"
+        expect_pos(";", LuaParser::statement, 1);
+        expect_pos("a = 1", LuaParser::statement, 3);
+        expect_pos("a = 1 + 1", LuaParser::statement, 5);
+        expect_pos("a = 1 + 1  * some_op[1]", LuaParser::statement, 10);
+        expect_pos("a = (1 + 1)  * some_op[1]", LuaParser::statement, 12);
+        expect_pos("a,b ={}, some_var[{1}]", LuaParser::statement, 13);
+        expect_pos("a:a(not a)", LuaParser::statement, 7);
+        expect_pos("::q::", LuaParser::statement, 3);
+        expect_pos("break", LuaParser::statement, 1);
+        expect_pos("goto to", LuaParser::statement, 2);
+        expect_pos("do ; end", LuaParser::statement, 3);
+        expect_pos("do return 1 end", LuaParser::statement, 4);
+        expect_pos(r#"
         do
          function name(a)
            b = a + 1
            return b
            end
-        end"#).statement(0), 15);
-        expect_pos(p(r#"
+        end"#, LuaParser::statement, 15);
+        expect_pos(r#"
         while a > b or a == c do a = b + c ; return a; end
-        "#).statement(0), 19);
-        expect_pos(p("repeat a = a + 1  until a < 100 ").statement(0), 10);
-        expect_pos(p("if x then ::q:: ; end  ").statement(0), 8);
-        expect_pos(p("if a > b[0] then return 1 else return 2 end  ").statement(0), 14);
-        expect_pos(p("if a > b[0] then ::q:: ; elseif a == b[0] then ; else ; end  ").statement(0), 24);
-        expect_pos(p("for x = arr[0] , x < 10 do ; end").statement(0), 14);
-        expect_pos(p("for x = 0 , x > 0 , x + 1 do ; end").statement(0), 15);
-        expect_pos(p("for x in arr do ; end").statement(0), 7);
-        expect_pos(p("for x,y in a, b  do ; end").statement(0), 11);
-        expect_pos(p("function x.y:z(a) ; end").statement(0), 11);
-        expect_pos(p("local function x(a) ; end").statement(0), 8);
-        expect_pos(p("local x<y>").statement(0), 5);
-        expect_pos(p("local x<y> = 1").statement(0), 7);
+        "#, LuaParser::statement, 19);
+        expect_pos("repeat a = a + 1  until a < 100 ", LuaParser::statement, 10);
+        expect_pos("if x then ::q:: ; end  ", LuaParser::statement, 8);
+        expect_pos("if a > b[0] then return 1 else return 2 end  ", LuaParser::statement, 14);
+        expect_pos("if a > b[0] then ::q:: ; elseif a == b[0] then ; else ; end  ", LuaParser::statement, 24);
+        expect_pos("for x = arr[0] , x < 10 do ; end", LuaParser::statement, 14);
+        expect_pos("for x = 0 , x > 0 , x + 1 do ; end", LuaParser::statement, 15);
+        expect_pos("for x in arr do ; end", LuaParser::statement, 7);
+        expect_pos("for x,y in a, b  do ; end", LuaParser::statement, 11);
+        expect_pos("function x.y:z(a) ; end", LuaParser::statement, 11);
+        expect_pos("local function x(a) ; end", LuaParser::statement, 8);
+        expect_pos("local x<y>", LuaParser::statement, 5);
+        expect_pos("local x<y> = 1", LuaParser::statement, 7);
     }
 
     #[test]
@@ -695,8 +1187,7 @@ mod tests {
         //     configs.setup({})
         //     "#
         // ).fn_call(0), 7);
-        expect_pos(p(
-            r#"
+        expect_pos(r#"
             configs.setup({
             ensure_installed = { "lua", "markdown", "markdown_inline", "bash", "python" }, -- put the language you want in this array
 
@@ -704,50 +1195,49 @@ mod tests {
             sync_install = false -- install languages synchronously (only applied to `ensure_installed`)
 
             })
-            "#
-        ).fn_call(0), 7);
+            "#, LuaParser::statement, 30);
     }
 
     #[test]
     fn text_test() {
         expect(
-            p("\"text\"").text(0),
-            Text { text: "text" },
+            "\"text\"", LuaParser::text,
+            Text::new("text"),
         );
         expect(
-            p("\'text\'").text(0),
-            Text { text: "text" },
+            "\'text\'", LuaParser::text,
+            Text::new("text"),
         );
         expect(
-            p(r#"[[
+            r#"[[
             sometext
-            ]]"#).text(0),
-            Text { text: "\n            sometext\n            " },
+            ]]"#, LuaParser::text,
+            Text::new("            sometext\n            "),
         );
         expect(
-            p(r#"[=[
+            r#"[=[
             sometext
-            ]=]"#).text(0),
-            Text { text: "\n            sometext\n            " },
-        )
+            ]=]"#, LuaParser::text,
+            Text::new("            sometext\n            "),
+        );
+        expect(r#"'a\tb\65'"#, LuaParser::text, Text::new("a\tbA"));
     }
 
     #[test]
     fn table_const_test() {
-        expect_pos(p("{}").table_const(0), 2);
-        expect_pos(p("{true}").table_const(0), 3);
-        expect_pos(p("{{}}").table_const(0), 4);
-        expect_pos(p("{{x = 1}}").table_const(0), 7);
-        expect_pos(p("{some_id = function(a);end}").table_const(0), 10);
-        expect_pos(p("{some_id = function(a)end}").table_const(0), 9);
-        expect_pos(p("{[\"a\"] = nil}").table_const(0), 7);
-        expect_pos(p("{1 ; [1] = 2 ; [3] = function(a);end, [\"z\"] = true or false,some_id = 1 + 2 }")
-                       .table_const(0), 34);
+        expect_pos("{}", LuaParser::table_const, 2);
+        expect_pos("{true}", LuaParser::table_const, 3);
+        expect_pos("{{}}", LuaParser::table_const, 4);
+        expect_pos("{{x = 1}}", LuaParser::table_const, 7);
+        expect_pos("{some_id = function(a);end}", LuaParser::table_const, 10);
+        expect_pos("{some_id = function(a)end}", LuaParser::table_const, 9);
+        expect_pos("{[\"a\"] = nil}", LuaParser::table_const, 7);
+        expect_pos("{1 ; [1] = 2 ; [3] = function(a);end, [\"z\"] = true or false,some_id = 1 + 2 }", LuaParser::table_const, 34);
     }
 
     #[test]
     fn table_const_spec_test() {
-        expect_pos(p(r#"
+        expect_pos(r#"
 {
     ensure_installed = { "lua", "markdown", "markdown_inline", "bash", "python" }, -- put the language you want in this array
     -- ensure_installed = "all", -- one of "all" or a list of languages
@@ -769,69 +1259,69 @@ mod tests {
     },
 
 }
-        "#).table_const(0), 79);
+        "#, LuaParser::table_const, 79);
 
-        expect_pos(p(r#"
+        expect_pos(r#"
         {
     ensure_installed = { "lua", "markdown", "markdown_inline", "bash", "python" },
         }
-        "#).table_const(0), 16);
+        "#, LuaParser::table_const, 16);
     }
 
 
     #[test]
     fn params() {
-        expect(p("...").params(0), FnParams::VarArgs);
-        expect(p("a").params(0), FnParams::Args(vec![Id::new("a")]));
-        expect(p("a,b").params(0), FnParams::Args(vec![Id::new("a"), Id::new("b")]));
-        expect(p("a,b, ... ").params(0), FnParams::WithVarArgs(vec![Id::new("a"), Id::new("b")]));
+        expect("...", LuaParser::params, FnParams::VarArgs);
+        expect("a", LuaParser::params, FnParams::Args(vec![Id::new("a")]));
+        expect("a,b", LuaParser::params, FnParams::Args(vec![Id::new("a"), Id::new("b")]));
+        expect("a,b, ... ", LuaParser::params, FnParams::WithVarArgs(vec![Id::new("a"), Id::new("b")]));
     }
 
     #[test]
     fn fn_params() {
-        expect(p("()").fn_params(0), FnParams::Args(vec![]));
-        expect(p("(...)").fn_params(0), FnParams::VarArgs);
-        expect(p("(a)").fn_params(0), FnParams::Args(vec![Id::new("a")]));
-        expect(p("(a,b)").fn_params(0), FnParams::Args(vec![Id::new("a"), Id::new("b")]));
-        expect(p("(a,b, ... )").fn_params(0), FnParams::WithVarArgs(vec![Id::new("a"), Id::new("b")]));
+        expect("()", LuaParser::fn_params, FnParams::Args(vec![]));
+        expect("(...)", LuaParser::fn_params, FnParams::VarArgs);
+        expect("(a)", LuaParser::fn_params, FnParams::Args(vec![Id::new("a")]));
+        expect("(a,b)", LuaParser::fn_params, FnParams::Args(vec![Id::new("a"), Id::new("b")]));
+        expect("(a,b, ... )", LuaParser::fn_params, FnParams::WithVarArgs(vec![Id::new("a"), Id::new("b")]));
     }
 
     #[test]
     fn var_suffix() {
-        expect_pos(p(": name (1,2) [function(a);end]").var_suffix(0), 15);
-        expect_pos(p(": name (nil).id").var_suffix(0), 7);
-        expect_pos(p(": name (1,2) : name (3) [function(a);end]").var_suffix(0), 20);
-        expect_pos(p("[function(a);end]").var_suffix(0), 8);
-        expect_pos(p(".id").var_suffix(0), 2);
-        expect_pos(p("[x + 1]").var_suffix(0), 5);
+        expect_pos("x: name (1,2) [function(a);end]", LuaParser::prefix_expr, 16);
+        expect_pos("x: name (nil).id", LuaParser::prefix_expr, 8);
+        expect_pos("x: name (1,2) : name (3) [function(a);end]", LuaParser::prefix_expr, 21);
+        expect_pos("x[function(a);end]", LuaParser::prefix_expr, 9);
+        expect_pos("x.id", LuaParser::prefix_expr, 3);
+        expect_pos("x[x + 1]", LuaParser::prefix_expr, 6);
     }
 
     #[test]
     fn att_name_list_test() {
-        expect_pos(p("id").attr_name_list(0), 1);
-        expect_pos(p("id <id>").attr_name_list(0), 4);
-        expect_pos(p("id <id>,id <id>").attr_name_list(0), 9);
+        expect_pos("id", LuaParser::attr_name_list, 1);
+        expect_pos("id <id>", LuaParser::attr_name_list, 4);
+        expect_pos("id <id>,id <id>", LuaParser::attr_name_list, 9);
     }
 
     #[test]
     fn name_args_test() {
-        expect_pos(p(": name \"a\"").name_args(0), 3);
-        expect_pos(p("\"a\"").name_args(0), 1);
-        expect_pos(p(": name (false,nil)").name_args(0), 7);
-        expect_pos(p(" (1,2)").name_args(0), 5);
-        expect_pos(p(": name (1,true or false)").name_args(0), 9);
-        expect_pos(p(" (2,3)").name_args(0), 5);
-        expect_pos(p(": name {[1] = 1}").name_args(0), 9);
-        expect_pos(p("{[1] = \"c\"}").name_args(0), 7);
+        expect_pos(": name \"a\"", LuaParser::name_args, 3);
+        expect_pos("\"a\"", LuaParser::name_args, 1);
+        expect_pos(": name (false,nil)", LuaParser::name_args, 7);
+        expect_pos(" (1,2)", LuaParser::name_args, 5);
+        expect_pos(": name (1,true or false)", LuaParser::name_args, 9);
+        expect_pos(" (2,3)", LuaParser::name_args, 5);
+        expect_pos(": name {[1] = 1}", LuaParser::name_args, 9);
+        expect_pos("{[1] = \"c\"}", LuaParser::name_args, 7);
     }
 
 
     #[test]
     fn fn_name_test() {
-        expect_pos(p("a.b.c").fn_name(0), 5);
-        expect_pos(p("a").fn_name(0), 1);
-        expect_pos(p("a:b").fn_name(0), 3);
-        expect_pos(p("a.b:c").fn_name(0), 5);
+        expect_pos("a.b.c", LuaParser::fn_name, 5);
+        expect_pos("a", LuaParser::fn_name, 1);
+        expect_pos("a:b", LuaParser::fn_name, 3);
+        expect_pos("a.b:c", LuaParser::fn_name, 5);
     }
 
     #[test]
@@ -852,4 +1342,278 @@ mod tests {
         let result = LuaParser::parse(script).unwrap();
         println!("{}", result);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn public_api_test() {
+        assert!(parse_chunk("local a = 1 return a").is_ok());
+        assert!(parse_chunk("local = 1").is_err());
+        assert!(parse_expr("a + 1 * b").is_ok());
+        assert!(parse_expr("a + 1 b").is_err());
+        assert!(parse_statement("a.b = {1, 2}").is_ok());
+        assert!(parse_statement("a = 1 b = 2").is_err());
+    }
+
+    #[test]
+    fn span_test() {
+        let src = "local a = 1\nwhile a < 10 do\n  a = a + 1\nend";
+        let block = parse_chunk(src).unwrap();
+        assert_eq!(block.span().range(), 0..src.len());
+
+        let sts = match block {
+            Block::Void(sts, _) => sts,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(&src[sts[0].span().range()], "local a = 1");
+        let (cond, body, span) = match &sts[1] {
+            Statement::While(While { cond, body, span }) => (cond, body, span),
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!((span.line, span.col), (2, 1));
+        assert_eq!(&src[span.range()], "while a < 10 do\n  a = a + 1\nend");
+        assert_eq!(&src[cond.span().range()], "a < 10");
+        assert_eq!(&src[body.span().range()], "a = a + 1");
+        assert_eq!((body.span().line, body.span().col), (3, 3));
+    }
+
+    #[test]
+    fn expr_span_test() {
+        let src = "f(x).y + -#t[1] .. 'z'";
+        let expr = parse_expr(src).unwrap();
+        assert_eq!(expr.span().range(), 0..src.len());
+
+        if let Expression::Binary(lhs, BinaryType::Concat, rhs) = &expr {
+            assert_eq!(&src[lhs.span().range()], "f(x).y + -#t[1]");
+            assert_eq!(&src[rhs.span().range()], "'z'");
+            match lhs.as_ref() {
+                Expression::Binary(_, BinaryType::Add, unary) => assert_eq!(&src[unary.span().range()], "-#t[1]"),
+                other => panic!("unexpected {:?}", other),
+            }
+        } else {
+            panic!("unexpected {:?}", expr)
+        }
+
+        let empty = parse_chunk("  ").unwrap();
+        assert!(empty.span().is_empty());
+    }
+
+    #[test]
+    fn syntax_error_test() {
+        let err = parse_chunk("local a = 1\nif a > 1 x\n  f()\nend").unwrap_err();
+        assert_eq!((err.line(), err.column()), (2, 10));
+        assert_eq!(err.expected, vec!["'then'"]);
+        assert_eq!(err.found, "'x'");
+
+        let err = parse_chunk("local t = {1, 2\nf(t)").unwrap_err();
+        assert_eq!((err.line(), err.column()), (2, 1));
+        assert_eq!(err.message(), "expected one of ',', ';', '}', found 'f'");
+
+        let err = parse_chunk("x = 1 +").unwrap_err();
+        assert_eq!((err.line(), err.column()), (1, 8));
+        assert_eq!(err.found, "<eof>");
+        assert!(err.expected.contains(&"<name>".to_string()));
+
+        let err = parse_chunk("a = 1\nb = \"abc").unwrap_err();
+        assert_eq!((err.line(), err.column()), (2, 5));
+        assert_eq!(err.found, "'\"abc'");
+    }
+
+    #[test]
+    fn recovery_test() {
+        let src = "local a = 1\nif a then local x = = 2 local y = 3 end\nfunction f() x x end\nlocal b = 2";
+        let (block, errors) = parse_chunk_recovering(src);
+        assert_eq!(errors.iter().map(|e| (e.line(), e.column())).collect::<Vec<_>>(), vec![(2, 21), (3, 16)]);
+
+        let sts = match block {
+            Block::Void(sts, _) => sts,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(sts.len(), 4);
+        assert_eq!(&src[sts[3].span().range()], "local b = 2");
+        match &sts[1] {
+            Statement::If(If::If(IfBranch { body: Block::Void(body, _), .. }, _, _)) => {
+                assert!(matches!(&body[0], Statement::LocalAttrNames(_, e, _) if matches!(e[0], Expression::Error(_))));
+                assert_eq!(&src[body[1].span().range()], "local y = 3");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match &sts[2] {
+            Statement::FnDef(FnDef { body: Block::Void(body, _), .. }) => {
+                assert_eq!(&src[body[0].span().range()], "x x");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn recovery_sync_test() {
+        let (block, errors) = parse_chunk_recovering("a = 1 end b = 2; c c; d = 3");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].found, "'end'");
+        assert_eq!(errors[1].found, "'c'");
+        assert!(matches!(block, Block::Void(ref sts, _) if sts.len() == 6));
+
+        let (_, errors) = parse_chunk_recovering("while x do a b");
+        assert_eq!(errors.iter().map(|e| e.found.as_str()).collect::<Vec<_>>(), vec!["'b'", "<eof>"]);
+
+        let (block, errors) = parse_chunk_recovering("local a = 1 return a");
+        assert!(errors.is_empty());
+        assert_eq!(block, parse_chunk("local a = 1 return a").unwrap());
+    }
+
+    #[test]
+    fn recovery_lexical_test() {
+        let src = "a = \"x\\qy\"\nb = 1 c c\nd = 'abc\ne = 2 do x x if '\\q' then end end --[[ unclosed\nf = 3";
+        let (block, errors) = parse_chunk_recovering(src);
+        assert_eq!(errors.iter().map(|e| (e.line(), e.found.as_str())).collect::<Vec<_>>(), [
+            (1, "'\\q'"), (2, "'c'"), (3, "''abc'"), (4, "'x'"), (4, "'\\q'"), (4, "'--[['"),
+        ]);
+        assert_eq!(errors[0].message(), "invalid escape sequence near '\\q'");
+        assert_eq!(errors[5].message(), "unclosed --[[ near '--[['");
+
+        let sts = match block {
+            Block::Void(sts, _) => sts,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(sts.iter().map(|st| &src[st.span().range()]).collect::<Vec<_>>(), [
+            "a = \"x\\qy\"", "b = 1", "c c\nd =", "'abc", "e = 2", "do x x if '\\q' then end end", "--[[ unclosed\nf = 3",
+        ]);
+        assert!(matches!(&sts[0], Statement::Assignment(_, e) if matches!(e[0], Expression::Error(_))));
+        assert!(matches!(sts[4], Statement::Assignment(..)));
+
+        let err = parse_chunk("x = = 1 y = '\\q'").unwrap_err();
+        assert_eq!(err.found, "'='");
+    }
+
+    #[test]
+    fn binary_literals_test() {
+        let err = parse_chunk("a = 0b101").unwrap_err();
+        assert_eq!(err.message(), "malformed number near '0b101'");
+        assert_eq!((err.line(), err.column()), (1, 5));
+
+        let block = parse_chunk_with("a = 0b101", Dialect::default().with_binary_literals()).unwrap();
+        match block {
+            Block::Void(sts, _) => assert!(matches!(
+                &sts[0],
+                Statement::Assignment(_, e) if matches!(e[0], Expression::Number(Number::Binary(5), _))
+            )),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_chunk("a = 0XFF + 0x1p4 + 5.").is_ok());
+    }
+
+    #[test]
+    fn dialect_test() {
+        let src = "local a <const> = 1\nb = a // 2";
+        assert!(parse_chunk_with(src, Dialect::new(Version::Lua54)).is_ok());
+        let err = parse_chunk_with(src, Dialect::new(Version::Lua53)).unwrap_err();
+        assert_eq!(err.message(), "variable attribute is not supported by Lua 5.3 near '<'");
+        assert_eq!((err.line(), err.column()), (1, 9));
+        let err = parse_chunk_with("b = a // 2", Dialect::new(Version::LuaJIT)).unwrap_err();
+        assert_eq!(err.message(), "floor division is not supported by LuaJIT near '//'");
+        assert_eq!((err.line(), err.column()), (1, 7));
+
+        let lua51 = Dialect::new(Version::Lua51);
+        assert!(parse_chunk_with("local goto = 1 t.goto = goto goto()", lua51).is_ok());
+        let err = parse_chunk_with("for i = 1, 2 do ::continue:: end", lua51).unwrap_err();
+        assert_eq!(err.message(), "label is not supported by Lua 5.1 near '::'");
+        let err = parse_chunk_with("goto continue ::continue::", lua51).unwrap_err();
+        assert_eq!(err.found, "'continue'");
+        assert!(parse_chunk_with("goto continue ::continue::", Dialect::new(Version::LuaJIT)).is_ok());
+        assert!(parse_chunk_with("local a, b = 1, 2 < 3", Dialect::new(Version::Lua51)).is_ok());
+
+        let (_, errors) = parse_chunk_recovering_with("a = ~b", Dialect::new(Version::Lua52));
+        assert_eq!(errors[0].message(), "bitwise operator is not supported by Lua 5.2 near '~'");
+
+        // the missing features do not stop the recovery, the tree and the other errors are kept
+        let src = "local a <const> = 1\nb = a // 2 & 1\n::l:: c = 0b1\nd = = 1\nlocal e = 2";
+        let (block, errors) = parse_chunk_recovering_with(src, lua51);
+        assert_eq!(errors.iter().map(|e| e.message()).take(5).collect::<Vec<_>>(), [
+            "variable attribute is not supported by Lua 5.1 near '<'",
+            "floor division is not supported by Lua 5.1 near '//'",
+            "bitwise operator is not supported by Lua 5.1 near '&'",
+            "label is not supported by Lua 5.1 near '::'",
+            "malformed number near '0b1'",
+        ]);
+        assert_eq!((errors.len(), errors[5].span.line, errors[5].found.as_str()), (6, 4, "'='"));
+        match block {
+            Block::Void(sts, _) => {
+                assert_eq!(sts.len(), 6);
+                assert!(matches!(&sts[4], Statement::Assignment(_, e) if matches!(e[0], Expression::Error(_))));
+                assert!(matches!(sts[5], Statement::LocalAttrNames(..)));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let block = parse_chunk_with("a = 1 + 0xff", Dialect::new(Version::Lua51)).unwrap();
+        match block {
+            Block::Void(sts, _) => assert!(matches!(
+                &sts[0],
+                Statement::Assignment(_, e) if matches!(&e[0], Expression::Binary(l, _, r)
+                    if matches!(l.as_ref(), Expression::Number(Number::Float(v), _) if *v == 1.0)
+                    && matches!(r.as_ref(), Expression::Number(Number::Float(v), _) if *v == 255.0))
+            )),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn minus_test() {
+        for src in ["x-1", "x - 1", "x -1"] {
+            assert!(matches!(parse_expr(src).unwrap(), Expression::Binary(_, BinaryType::Sub, _)), "{}", src);
+        }
+        assert!(matches!(parse_expr("-1").unwrap(), Expression::Unary(UnaryType::Minus, _, _)));
+        assert!(parse_chunk("a = t[i-1]-0x1").is_ok());
+    }
+
+    #[test]
+    fn depth_test() {
+        // the heaviest syntax levels nested up to the default limit fit into the 2MB stack of the test thread
+        let nested = |open: &str, middle: &str, close: &str, n: usize| {
+            format!("{}{}{}", open.repeat(n), middle, close.repeat(n))
+        };
+        let heavy = [
+            ("x = {", "1", "}"),
+            ("f{", "1", "}"),
+            ("a.b:c(", "1", ")"),
+            ("f(function() return ", "1", " end)"),
+            ("x = function() ", "x = 1", " end"),
+            ("for i = 1, 2 do ", "x = 1", " end"),
+            ("if x then ", "x = 1", " end"),
+        ];
+        for (open, middle, close) in heavy {
+            let src = |n| nested(open, middle, close, n);
+            let deepest = (1..).find(|n| parse_chunk(&src(*n)).is_err()).unwrap() - 1;
+            assert!(deepest * 2 >= MAX_DEPTH - 2, "{}: {}", open, deepest);
+            let err = parse_chunk(&src(deepest + 1)).unwrap_err();
+            assert!(err.message().starts_with("chunk has too many syntax levels"), "{}: {}", open, err.message());
+            assert!(!parse_chunk_recovering(&src(deepest + 1)).1.is_empty(), "{}", open);
+            assert!(parse_chunk_arena(&src(deepest)).is_ok(), "{}", open);
+        }
+
+        assert!(parse_chunk(&nested("x = {", "1", "}", 150)).is_ok());
+        let err = parse_chunk(&nested("x = {", "1", "}", 100_000)).unwrap_err();
+        assert_eq!(err.message(), "chunk has too many syntax levels near '{'");
+        assert!(parse_chunk(&format!("x = {}", nested("(", "1", ")", 100_000))).is_err());
+        assert!(parse_chunk(&format!("x = {}1", "- ".repeat(100_000))).is_err());
+        assert!(parse_chunk(&nested("f(function() return ", "1", " end)", 100_000)).is_err());
+
+        let blocks = format!("{}{}", "do ".repeat(100_000), "end ".repeat(100_000));
+        let err = parse_chunk(&blocks).unwrap_err();
+        assert_eq!(err.message(), "chunk has too many syntax levels near 'do'");
+        let (_, errors) = parse_chunk_recovering(&format!("{}\nlocal a = 1 a a", blocks));
+        assert_eq!(errors.iter().map(|e| e.found.as_str()).collect::<Vec<_>>(), ["'do'", "'a'"]);
+        assert_eq!(errors[0].message(), "chunk has too many syntax levels near 'do'");
+
+        // the right associative operators nest, the left associative ones do not
+        let chain = |op: &str, n: usize| format!("x = a{}", format!(" {} a", op).repeat(n));
+        assert!(parse_chunk(&chain("..", 150)).is_ok());
+        assert!(parse_chunk(&chain("..", 100_000)).is_err());
+        assert!(parse_chunk(&chain("+", 10_000)).is_ok());
+
+        let src = format!("x = {}", nested("(", "1", ")", 10));
+        let parser = |max_depth| LuaParser::new(&src, Dialect::default()).with_max_depth(max_depth);
+        assert!(parser(10).chunk().is_err());
+        assert!(parser(30).chunk().is_ok());
+        assert_eq!(parser(10).chunk_recovering().1.len(), 1);
+    }
+}
@@ -0,0 +1,425 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use crate::parser::ast::*;
+
+/// The conversion of the ast into the one that does not borrow the source,
+/// so it can outlive the source and move across threads.
+pub trait IntoOwned {
+    type Owned: 'static;
+
+    /// Detaches the node from the source, the equal names share the same allocation.
+    fn into_owned(self) -> Self::Owned where Self: Sized {
+        self.owned(&mut Interner::default())
+    }
+
+    fn owned(self, names: &mut Interner) -> Self::Owned;
+}
+
+/// Keeps a single shared copy of every name.
+/// The same interner can be passed to several conversions to share the names between the trees.
+#[derive(Debug, Default)]
+pub struct Interner {
+    names: HashSet<Arc<str>>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, name: &str) -> Arc<str> {
+        match self.names.get(name) {
+            Some(shared) => shared.clone(),
+            None => {
+                let shared: Arc<str> = Arc::from(name);
+                self.names.insert(shared.clone());
+                shared
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Vec<T> {
+    type Owned = Vec<T::Owned>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        self.into_iter().map(|v| v.owned(names)).collect()
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Option<T> {
+    type Owned = Option<T::Owned>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        self.map(|v| v.owned(names))
+    }
+}
+
+impl<T: IntoOwned> IntoOwned for Box<T> {
+    type Owned = Box<T::Owned>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        Box::new((*self).owned(names))
+    }
+}
+
+impl<'a> IntoOwned for Name<'a> {
+    type Owned = Name<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Name::Borrowed(v) => Name::Shared(names.intern(v)),
+            Name::Shared(v) => Name::Shared(v),
+        }
+    }
+}
+
+impl<'a> IntoOwned for Id<'a> {
+    type Owned = Id<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        Id { v: self.v.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for Text<'a> {
+    type Owned = Text<'static>;
+
+    fn owned(self, _names: &mut Interner) -> Self::Owned {
+        Text { text: Cow::Owned(self.text.into_owned()), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for Expression<'a> {
+    type Owned = Expression<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Expression::Nil(s) => Expression::Nil(s),
+            Expression::False(s) => Expression::False(s),
+            Expression::True(s) => Expression::True(s),
+            Expression::Number(n, s) => Expression::Number(n, s),
+            Expression::Text(t) => Expression::Text(t.owned(names)),
+            Expression::VarArgs(s) => Expression::VarArgs(s),
+            Expression::FnDef(params, body, s) => Expression::FnDef(params.owned(names), body.owned(names), s),
+            Expression::PrefixExpr(call) => Expression::PrefixExpr(call.owned(names)),
+            Expression::TableConstructor(table) => Expression::TableConstructor(table.owned(names)),
+            Expression::Unary(tp, e, s) => Expression::Unary(tp, e.owned(names), s),
+            Expression::Binary(lhs, tp, rhs) => Expression::Binary(lhs.owned(names), tp, rhs.owned(names)),
+            Expression::Error(s) => Expression::Error(s),
+        }
+    }
+}
+
+impl<'a> IntoOwned for FieldKey<'a> {
+    type Owned = FieldKey<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            FieldKey::Expr(e) => FieldKey::Expr(e.owned(names)),
+            FieldKey::Id(id) => FieldKey::Id(id.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for Field<'a> {
+    type Owned = Field<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Field::Pair(k, v) => Field::Pair(k.owned(names), v.owned(names)),
+            Field::Value(v) => Field::Value(v.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for FnParams<'a> {
+    type Owned = FnParams<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            FnParams::Args(args) => FnParams::Args(args.owned(names)),
+            FnParams::VarArgs => FnParams::VarArgs,
+            FnParams::WithVarArgs(args) => FnParams::WithVarArgs(args.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for TableConst<'a> {
+    type Owned = TableConst<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        TableConst { fields: self.fields.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for Args<'a> {
+    type Owned = Args<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Args::Expressions(exprs, s) => Args::Expressions(exprs.owned(names), s),
+            Args::Constructor(table) => Args::Constructor(table.owned(names)),
+            Args::String(t) => Args::String(t.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for NameArgs<'a> {
+    type Owned = NameArgs<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            NameArgs::Args(args) => NameArgs::Args(args.owned(names)),
+            NameArgs::NameArgs(name, args) => NameArgs::NameArgs(name.owned(names), args.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for Suffix<'a> {
+    type Owned = Suffix<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Suffix::Expr(e) => Suffix::Expr(e.owned(names)),
+            Suffix::Id(id) => Suffix::Id(id.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for VarSuffix<'a> {
+    type Owned = VarSuffix<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        VarSuffix { var: self.var.owned(names), suffix: self.suffix.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for VarHead<'a> {
+    type Owned = VarHead<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            VarHead::Expr(e, suffix) => VarHead::Expr(e.owned(names), suffix.owned(names)),
+            VarHead::Id(id) => VarHead::Id(id.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for Var<'a> {
+    type Owned = Var<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        Var { head: self.head.owned(names), tail: self.tail.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for VarOrExpr<'a> {
+    type Owned = VarOrExpr<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            VarOrExpr::Expr(e) => VarOrExpr::Expr(e.owned(names)),
+            VarOrExpr::Var(v) => VarOrExpr::Var(v.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for FnCall<'a> {
+    type Owned = FnCall<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        FnCall { head: self.head.owned(names), args: self.args.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for FnName<'a> {
+    type Owned = FnName<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        FnName { names: self.names.owned(names), last: self.last.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for AttrName<'a> {
+    type Owned = AttrName<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            AttrName::Name(name) => AttrName::Name(name.owned(names)),
+            AttrName::AttrName(name, attr) => AttrName::AttrName(name.owned(names), attr.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for Block<'a> {
+    type Owned = Block<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Block::Void(sts, s) => Block::Void(sts.owned(names), s),
+            Block::Return(sts, exprs, s) => Block::Return(sts.owned(names), exprs.owned(names), s),
+        }
+    }
+}
+
+impl<'a> IntoOwned for While<'a> {
+    type Owned = While<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        While { cond: self.cond.owned(names), body: self.body.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for Repeat<'a> {
+    type Owned = Repeat<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        Repeat { until: self.until.owned(names), body: self.body.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for IfBranch<'a> {
+    type Owned = IfBranch<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        IfBranch { cond: self.cond.owned(names), body: self.body.owned(names), span: self.span }
+    }
+}
+
+impl<'a> IntoOwned for If<'a> {
+    type Owned = If<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            If::If(main, others, s) => If::If(main.owned(names), others.owned(names), s),
+            If::IfElse(main, others, els, s) =>
+                If::IfElse(main.owned(names), others.owned(names), els.owned(names), s),
+        }
+    }
+}
+
+impl<'a> IntoOwned for PlainFor<'a> {
+    type Owned = PlainFor<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        PlainFor {
+            init: (self.init.0.owned(names), self.init.1.owned(names)),
+            border: self.border.owned(names),
+            step: self.step.owned(names),
+            body: self.body.owned(names),
+            span: self.span,
+        }
+    }
+}
+
+impl<'a> IntoOwned for ExprFor<'a> {
+    type Owned = ExprFor<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        ExprFor {
+            names: self.names.owned(names),
+            expressions: self.expressions.owned(names),
+            body: self.body.owned(names),
+            span: self.span,
+        }
+    }
+}
+
+impl<'a> IntoOwned for For<'a> {
+    type Owned = For<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            For::Plain(f) => For::Plain(f.owned(names)),
+            For::ForCol(f) => For::ForCol(f.owned(names)),
+        }
+    }
+}
+
+impl<'a> IntoOwned for FnDef<'a> {
+    type Owned = FnDef<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        FnDef {
+            name: self.name.owned(names),
+            params: self.params.owned(names),
+            body: self.body.owned(names),
+            span: self.span,
+        }
+    }
+}
+
+impl<'a> IntoOwned for Statement<'a> {
+    type Owned = Statement<'static>;
+
+    fn owned(self, names: &mut Interner) -> Self::Owned {
+        match self {
+            Statement::Empty(s) => Statement::Empty(s),
+            Statement::Assignment(vars, exprs) => Statement::Assignment(vars.owned(names), exprs.owned(names)),
+            Statement::FnCall(call) => Statement::FnCall(call.owned(names)),
+            Statement::Label(id, s) => Statement::Label(id.owned(names), s),
+            Statement::Break(s) => Statement::Break(s),
+            Statement::Goto(id, s) => Statement::Goto(id.owned(names), s),
+            Statement::Do(body, s) => Statement::Do(body.owned(names), s),
+            Statement::While(w) => Statement::While(w.owned(names)),
+            Statement::Repeat(r) => Statement::Repeat(r.owned(names)),
+            Statement::If(i) => Statement::If(i.owned(names)),
+            Statement::For(f) => Statement::For(f.owned(names)),
+            Statement::FnDef(def) => Statement::FnDef(def.owned(names)),
+            Statement::LocalFnDef(def) => Statement::LocalFnDef(def.owned(names)),
+            Statement::LocalAttrNames(attrs, exprs, s) =>
+                Statement::LocalAttrNames(attrs.owned(names), exprs.owned(names), s),
+            Statement::Error(s) => Statement::Error(s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::parser::ast::{AttrName, Block, Id, Name, Statement};
+    use crate::parser::owned::{IntoOwned, Interner};
+    use crate::parser::parse_chunk;
+
+    fn assert_send<T: Send + Sync + 'static>(_: &T) {}
+
+    #[test]
+    fn owned_test() {
+        let src = include_str!("scripts/server.lua").to_string();
+        let block = parse_chunk(&src).unwrap();
+        let owned: Block<'static> = block.clone().into_owned();
+        assert_eq!(owned, block);
+        drop(block);
+        drop(src);
+
+        assert_send(&owned);
+        let printed = owned.to_string();
+        let from_thread = std::thread::spawn(move || owned.to_string()).join().unwrap();
+        assert_eq!(printed, from_thread);
+    }
+
+    #[test]
+    fn interner_test() {
+        let src = String::from("local a = a .. 'a' a = f(a)");
+        let mut names = Interner::default();
+        let block = parse_chunk(&src).unwrap().owned(&mut names);
+        assert_eq!(names.len(), 2);
+
+        match &block {
+            Block::Void(sts, _) => match &sts[0] {
+                Statement::LocalAttrNames(attrs, _, _) => match &attrs[0] {
+                    AttrName::Name(Id { v: Name::Shared(v), .. }) => assert!(Arc::ptr_eq(v, &names.intern("a"))),
+                    other => panic!("unexpected {:?}", other),
+                },
+                other => panic!("unexpected {:?}", other),
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,948 @@
+//! The printer of the ast back into the lua source.
+//!
+//! The output is parsed back into the same ast: the parens are only added
+//! where the tree can not be expressed by the precedence of the operators.
+//! The formatter prints with its own config and puts the comments of the source back.
+
+use std::fmt;
+use std::fmt::{Display, Formatter, Result, Write};
+use crate::parser::ast::*;
+use crate::parser::expression::{expr_priority, UNARY_PRIORITY};
+use crate::parser::format::{CallParentheses, Config, IndentType, QuoteStyle};
+use crate::parser::span::{Span, Spanned};
+
+/// The config of `Display`, it keeps the ast as it is.
+const DISPLAY: Config = Config {
+    indent_type: IndentType::Spaces,
+    indent_width: 4,
+    column_width: 80,
+    quote_style: QuoteStyle::ForceDouble,
+    call_parentheses: CallParentheses::Input,
+};
+
+/// The comment of the source to put back into the output.
+pub(crate) struct Comment<'s> {
+    pub(crate) text: &'s str,
+    pub(crate) span: Span,
+}
+
+/// Writes the nodes keeping track of the indentation and the column.
+pub(crate) struct Printer<'s, W: Write> {
+    out: W,
+    config: Config,
+    indent: usize,
+    col: usize,
+    /// The line is started but the indentation is not written yet, thus the empty lines stay empty.
+    line_start: bool,
+    blank: bool,
+    /// The nodes are printed on one line to measure them.
+    measuring: bool,
+    /// The source of the ast: the numerals and the long strings are printed as they are written.
+    src: Option<&'s str>,
+    /// The comments not printed yet, in the reverse order.
+    comments: Vec<Comment<'s>>,
+}
+
+/// The node that can be printed as lua source.
+pub(crate) trait Print {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result;
+}
+
+impl<'s, W: Write> Printer<'s, W> {
+    pub(crate) fn new(out: W) -> Self {
+        Printer::with_config(out, DISPLAY)
+    }
+
+    pub(crate) fn with_config(out: W, config: Config) -> Self {
+        Printer {
+            out,
+            config,
+            indent: 0,
+            col: 0,
+            line_start: true,
+            blank: true,
+            measuring: false,
+            src: None,
+            comments: vec![],
+        }
+    }
+
+    /// The printer of the ast parsed from `src`, the comments go in the source order.
+    pub(crate) fn with_source(out: W, config: Config, src: &'s str, mut comments: Vec<Comment<'s>>) -> Self {
+        comments.reverse();
+        Printer { src: Some(src), comments, ..Printer::with_config(out, config) }
+    }
+
+    /// The whole chunk followed by the comments at the end of the source.
+    pub(crate) fn chunk(&mut self, block: &Block) -> Result {
+        let first = matches!(block, Block::Void(sts, _) if sts.is_empty());
+        self.statements(block, usize::MAX)?;
+        self.new_line()?;
+        self.leading(usize::MAX, first)?;
+        self.new_line()
+    }
+
+    fn str(&mut self, s: &str) -> Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+        if self.line_start {
+            self.line_start = false;
+            self.blank = false;
+            for _ in 0..self.indent {
+                match self.config.indent_type {
+                    IndentType::Spaces => self.out.write_str(&" ".repeat(self.config.indent_width))?,
+                    IndentType::Tabs => self.out.write_str("\t")?,
+                }
+            }
+            self.col = self.indent * self.config.indent_width;
+        }
+        match s.rfind('\n') {
+            Some(i) => self.col = s[i + 1..].chars().count(),
+            None => self.col += s.chars().count(),
+        }
+        self.out.write_str(s)
+    }
+
+    fn node<T: Print>(&mut self, node: &T) -> Result {
+        node.print(self)
+    }
+
+    fn list<T: Print>(&mut self, nodes: &[T]) -> Result {
+        for (i, node) in nodes.iter().enumerate() {
+            if i > 0 {
+                self.str(", ")?;
+            }
+            node.print(self)?;
+        }
+        Ok(())
+    }
+
+    fn new_line(&mut self) -> Result {
+        if !self.line_start {
+            self.line_start = true;
+            self.col = 0;
+            self.out.write_str("\n")?;
+        }
+        Ok(())
+    }
+
+    fn blank_line(&mut self) -> Result {
+        self.new_line()?;
+        if !self.blank {
+            self.blank = true;
+            self.out.write_str("\n")?;
+        }
+        Ok(())
+    }
+
+    /// The source has an empty line right before `pos`.
+    fn blank_before(&self, pos: usize) -> bool {
+        self.src.is_some_and(|src| {
+            src[..pos].bytes().rev()
+                .take_while(u8::is_ascii_whitespace)
+                .filter(|b| *b == b'\n')
+                .count() > 1
+        })
+    }
+
+    fn comment_before(&self, pos: usize) -> bool {
+        self.comments.last().is_some_and(|c| c.span.start < pos)
+    }
+
+    /// The comments before `pos` each on its own line, `first` is the beginning of the block.
+    /// Returns if the block still has nothing printed.
+    fn leading(&mut self, pos: usize, mut first: bool) -> std::result::Result<bool, fmt::Error> {
+        while self.comment_before(pos) {
+            let comment = self.comments.pop().unwrap();
+            if !first && self.blank_before(comment.span.start) {
+                self.blank_line()?;
+            }
+            self.str(comment.text)?;
+            self.new_line()?;
+            first = false;
+        }
+        Ok(first)
+    }
+
+    /// The comment on the line of the opening keyword or brace, before `limit`.
+    fn opening(&mut self, limit: usize) -> Result {
+        while let (Some(comment), Some(src)) = (self.comments.last(), self.src) {
+            let before = src[..comment.span.start].trim_end_matches([' ', '\t']);
+            if comment.span.start >= limit || before.ends_with('\n') {
+                break;
+            }
+            let comment = self.comments.pop().unwrap();
+            self.str(" ")?;
+            self.str(comment.text)?;
+        }
+        Ok(())
+    }
+
+    /// The comments inside the node ending at `end` and the ones on the same line after it up to `limit`.
+    /// The caller starts a new line afterwards.
+    fn trailing(&mut self, end: usize, limit: usize) -> Result {
+        let mut first = true;
+        while let Some(comment) = self.comments.last() {
+            let start = comment.span.start;
+            let same_line = start < limit && self.src.is_some_and(|src| !src[end.min(start)..start].contains('\n'));
+            if start >= end && !same_line {
+                break;
+            }
+            let comment = self.comments.pop().unwrap();
+            if first {
+                self.str(" ")?;
+            } else {
+                self.new_line()?;
+            }
+            self.str(comment.text)?;
+            first = false;
+        }
+        Ok(())
+    }
+
+    /// Prints the node on one line into a string to see if it fits.
+    fn measure(&self, print: impl FnOnce(&mut Printer<'s, String>) -> Result) -> String {
+        let mut p = Printer::with_config(String::new(), self.config);
+        p.line_start = false;
+        p.indent = self.indent;
+        p.col = self.col;
+        p.measuring = true;
+        p.src = self.src;
+        print(&mut p).expect("writing to a string");
+        p.out
+    }
+
+    fn fits(&self, line: &str) -> bool {
+        self.col + line.chars().count() <= self.config.column_width
+    }
+
+    /// The statements of the block, each one on its own line.
+    /// `limit` is the end of the enclosing statement.
+    fn statements(&mut self, block: &Block, limit: usize) -> Result {
+        let (statements, ret) = match block {
+            Block::Void(statements, _) => (statements, None),
+            Block::Return(statements, exprs, _) => (statements, Some(exprs)),
+        };
+        let ret_start = ret.map(|exprs| exprs.first().map(|e| e.span().start).unwrap_or(limit));
+        let mut first = true;
+        for (i, st) in statements.iter().enumerate() {
+            let start = st.span().start;
+            self.new_line()?;
+            first = self.leading(start, first)?;
+            if !first && self.blank_before(start) {
+                self.blank_line()?;
+            }
+            // otherwise the parens are read as the call of the previous statement
+            if i > 0 && starts_with_paren(st) && !matches!(statements[i - 1], Statement::Empty(_)) {
+                self.str(";")?;
+            }
+            st.print(self)?;
+            first = false;
+            let next = statements.get(i + 1).map(|st| st.span().start).or(ret_start).unwrap_or(limit);
+            self.trailing(st.span().end, next)?;
+        }
+        if let (Some(exprs), Some(start)) = (ret, ret_start) {
+            self.new_line()?;
+            first = self.leading(start, first)?;
+            if !first && self.blank_before(start) {
+                self.blank_line()?;
+            }
+            self.str("return")?;
+            if !exprs.is_empty() {
+                self.str(" ")?;
+                self.list(exprs)?;
+                self.trailing(exprs.span().end, limit)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The indented body followed by the keyword closing it, the empty body stays on the same line.
+    /// `limit` is the position of the closing keyword.
+    fn body(&mut self, block: &Block, end: &str, limit: usize) -> Result {
+        let empty = matches!(block, Block::Void(sts, _) if sts.is_empty());
+        if empty && !self.comment_before(limit) {
+            self.str(" ")?;
+        } else {
+            self.indent += 1;
+            let start = block_start(block).unwrap_or(limit);
+            self.opening(start)?;
+            self.statements(block, limit)?;
+            self.new_line()?;
+            self.leading(limit, empty)?;
+            self.indent -= 1;
+            self.new_line()?;
+        }
+        self.str(end)
+    }
+
+    fn function(&mut self, params: &FnParams, body: &Block, span: Span) -> Result {
+        self.node(params)?;
+        self.body(body, "end", span.end)
+    }
+
+    fn operand(&mut self, e: &Expression, parens: bool) -> Result {
+        if parens {
+            self.str("(")?;
+            self.node(e)?;
+            self.str(")")
+        } else {
+            self.node(e)
+        }
+    }
+
+    fn number(&mut self, n: &Number, span: Span) -> Result {
+        match self.src {
+            Some(src) if !span.is_empty() => self.str(&src[span.range()]),
+            _ => self.str(&n.to_string()),
+        }
+    }
+
+    fn text(&mut self, text: &Text) -> Result {
+        let quote = self.config.quote_style.quote(&text.text);
+        match self.src.map(|src| &src[text.span.range()]) {
+            Some(raw) if raw.starts_with('[') => self.str(raw),
+            Some(raw) if !raw.is_empty() => self.str(&requote(raw, quote)),
+            _ => {
+                let mut quoted = String::new();
+                text.write_quoted(&mut quoted, quote)?;
+                self.str(&quoted)
+            }
+        }
+    }
+
+    /// The arguments in parens, split into lines when they do not fit.
+    /// The last table or function stays on the line of the call and breaks on its own (`f(a, function() .. end)`).
+    fn args(&mut self, exprs: &[Expression]) -> Result {
+        self.str("(")?;
+        let hug = self.measuring || exprs.is_empty() || {
+            let (last, init) = exprs.split_last().unwrap();
+            let head = self.measure(|p| {
+                p.list(init)?;
+                p.str(if init.is_empty() { "" } else { ", " })
+            });
+            let line = self.measure(|p| {
+                p.list(exprs)?;
+                p.str(")")
+            });
+            let breaks = matches!(last, Expression::TableConstructor(_) | Expression::FnDef(..));
+            !head.contains('\n') && self.fits(if breaks { &head } else { line.lines().next().unwrap_or_default() })
+        };
+        if hug {
+            self.list(exprs)?;
+        } else {
+            self.indent += 1;
+            for (i, e) in exprs.iter().enumerate() {
+                self.new_line()?;
+                self.node(e)?;
+                if i + 1 < exprs.len() {
+                    self.str(",")?;
+                }
+            }
+            self.indent -= 1;
+            self.new_line()?;
+        }
+        self.str(")")
+    }
+
+    /// The table on one line when it fits, otherwise one field per line.
+    fn table(&mut self, table: &TableConst) -> Result {
+        let end = table.span.end;
+        if table.fields.is_empty() && !self.comment_before(end) {
+            return self.str("{}");
+        }
+        let inline = self.measuring || (!self.comment_before(end) && {
+            let line = self.measure(|p| p.inline_table(table));
+            !line.contains('\n') && self.fits(&line)
+        });
+        if inline {
+            return self.inline_table(table);
+        }
+
+        self.str("{")?;
+        self.opening(table.fields.first().map(|f| f.span().start).unwrap_or(end))?;
+        self.indent += 1;
+        let mut first = true;
+        for (i, field) in table.fields.iter().enumerate() {
+            let start = field.span().start;
+            self.new_line()?;
+            first = self.leading(start, first)?;
+            if !first && self.blank_before(start) {
+                self.blank_line()?;
+            }
+            self.node(field)?;
+            self.str(",")?;
+            first = false;
+            let next = table.fields.get(i + 1).map(|f| f.span().start).unwrap_or(end);
+            self.trailing(field.span().end, next)?;
+        }
+        self.new_line()?;
+        self.leading(end, first)?;
+        self.indent -= 1;
+        self.new_line()?;
+        self.str("}")
+    }
+
+    fn inline_table(&mut self, table: &TableConst) -> Result {
+        self.str("{")?;
+        self.list(&table.fields)?;
+        self.str("}")
+    }
+
+    fn call_args(&mut self, args: &Args) -> Result {
+        let style = self.config.call_parentheses;
+        match args {
+            Args::Expressions(exprs, _) => match exprs.as_slice() {
+                [Expression::Text(t)] if style.omit_string(false) => {
+                    self.str(" ")?;
+                    self.text(t)
+                }
+                [Expression::TableConstructor(t)] if style.omit_table(false) => {
+                    self.str(" ")?;
+                    self.table(t)
+                }
+                exprs => self.args(exprs),
+            },
+            Args::Constructor(t) if style.omit_table(true) => {
+                self.str(" ")?;
+                self.table(t)
+            }
+            Args::String(t) if style.omit_string(true) => {
+                self.str(" ")?;
+                self.text(t)
+            }
+            Args::Constructor(t) => {
+                self.str("(")?;
+                self.table(t)?;
+                self.str(")")
+            }
+            Args::String(t) => {
+                self.str("(")?;
+                self.text(t)?;
+                self.str(")")
+            }
+        }
+    }
+}
+
+/// The quoted string literal with the other quotes, the escape sequences stay as they are.
+fn requote(raw: &str, quote: char) -> String {
+    if raw.starts_with(quote) {
+        return raw.to_string();
+    }
+    let mut out = String::from(quote);
+    let mut chars = raw[1..raw.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(q @ ('"' | '\'')) if q != quote => out.push(q),
+                Some(c) => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                None => out.push('\\'),
+            },
+            c if c == quote => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push(quote);
+    out
+}
+
+fn block_start(block: &Block) -> Option<usize> {
+    match block {
+        Block::Void(sts, _) => sts.first().map(|st| st.span().start),
+        Block::Return(sts, exprs, _) => sts.first().map(|st| st.span().start).or(exprs.first().map(|e| e.span().start)),
+    }
+}
+
+/// The printed expression starts with an unary operator, the numbers are negative only after folding.
+fn is_unary(e: &Expression) -> bool {
+    match e {
+        Expression::Unary(..) => true,
+        Expression::Number(Number::Int(v), _) => *v < 0 && *v != i64::MIN,
+        Expression::Number(Number::Float(v), _) => v.is_sign_negative() && !v.is_nan(),
+        _ => false,
+    }
+}
+
+fn starts_with_paren(st: &Statement) -> bool {
+    let var = |v: &Var| matches!(v.head, VarHead::Expr(..));
+    match st {
+        Statement::FnCall(call) => match &call.head {
+            VarOrExpr::Expr(_) => true,
+            VarOrExpr::Var(v) => var(v),
+        },
+        Statement::Assignment(vars, _) => vars.first().map(var).unwrap_or_default(),
+        _ => false,
+    }
+}
+
+impl Print for Expression<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            Expression::Nil(_) => p.str("nil"),
+            Expression::False(_) => p.str("false"),
+            Expression::True(_) => p.str("true"),
+            Expression::Number(n, span) => p.number(n, *span),
+            Expression::Text(t) => p.text(t),
+            Expression::VarArgs(_) => p.str("..."),
+            Expression::FnDef(params, body, span) => {
+                p.str("function")?;
+                p.function(params, body, *span)
+            }
+            Expression::PrefixExpr(call) => p.node(call.as_ref()),
+            Expression::TableConstructor(table) => p.table(table),
+            Expression::Unary(tp, e, _) => {
+                p.str(&tp.to_string())?;
+                let parens = matches!(e.as_ref(), Expression::Binary(_, op, _) if expr_priority(op).0 <= UNARY_PRIORITY);
+                if !parens && (*tp == UnaryType::Not || (*tp == UnaryType::Minus && is_unary(e))) {
+                    p.str(" ")?;
+                }
+                p.operand(e, parens)
+            }
+            Expression::Binary(lhs, op, rhs) => {
+                let (left, right) = expr_priority(op);
+                let l_parens = match lhs.as_ref() {
+                    Expression::Binary(_, l_op, _) => left > expr_priority(l_op).1,
+                    e => left > UNARY_PRIORITY && is_unary(e),
+                };
+                let r_parens = matches!(rhs.as_ref(), Expression::Binary(_, r_op, _) if expr_priority(r_op).0 <= right);
+                p.operand(lhs, l_parens)?;
+                p.str(&format!(" {} ", op))?;
+                p.operand(rhs, r_parens)
+            }
+            Expression::Error(_) => p.str("<error>"),
+        }
+    }
+}
+
+impl Print for Field<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            Field::Pair(FieldKey::Id(id), v) => {
+                p.str(&format!("{} = ", id))?;
+                p.node(v)
+            }
+            Field::Pair(FieldKey::Expr(k), v) => {
+                p.str("[")?;
+                p.node(k)?;
+                p.str("] = ")?;
+                p.node(v)
+            }
+            Field::Value(v) => p.node(v),
+        }
+    }
+}
+
+impl Print for FnParams<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            FnParams::Args(args) => p.str(&format!("({})", names(args))),
+            FnParams::VarArgs => p.str("(...)"),
+            FnParams::WithVarArgs(args) if args.is_empty() => p.str("(...)"),
+            FnParams::WithVarArgs(args) => p.str(&format!("({}, ...)", names(args))),
+        }
+    }
+}
+
+fn names(ids: &[Id]) -> String {
+    ids.iter().map(|id| id.v.as_str()).collect::<Vec<_>>().join(", ")
+}
+
+impl Print for TableConst<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.table(self)
+    }
+}
+
+impl Print for Args<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.call_args(self)
+    }
+}
+
+impl Print for NameArgs<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            NameArgs::Args(args) => p.node(args),
+            NameArgs::NameArgs(name, args) => {
+                p.str(&format!(":{}", name))?;
+                p.node(args)
+            }
+        }
+    }
+}
+
+impl Print for VarSuffix<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        for args in self.var.iter() {
+            p.node(args)?;
+        }
+        match &self.suffix {
+            Suffix::Expr(e) => {
+                p.str("[")?;
+                p.node(e)?;
+                p.str("]")
+            }
+            Suffix::Id(id) => p.str(&format!(".{}", id)),
+        }
+    }
+}
+
+impl Print for Var<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match &self.head {
+            VarHead::Expr(e, suffix) => {
+                p.operand(e, true)?;
+                p.node(suffix)?;
+            }
+            VarHead::Id(id) => p.str(&id.v)?,
+        }
+        for suffix in self.tail.iter() {
+            p.node(suffix)?;
+        }
+        Ok(())
+    }
+}
+
+impl Print for VarOrExpr<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            VarOrExpr::Expr(e) => p.operand(e, true),
+            VarOrExpr::Var(v) => p.node(v),
+        }
+    }
+}
+
+impl Print for FnCall<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.node(&self.head)?;
+        for args in self.args.iter() {
+            p.node(args)?;
+        }
+        Ok(())
+    }
+}
+
+impl Print for FnName<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        let names: Vec<&str> = self.names.iter().map(|id| id.v.as_str()).collect();
+        p.str(&names.join("."))?;
+        match &self.last {
+            None => Ok(()),
+            Some(last) => p.str(&format!(":{}", last)),
+        }
+    }
+}
+
+impl Print for AttrName<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            AttrName::Name(name) => p.str(&name.v),
+            AttrName::AttrName(name, attr) => p.str(&format!("{} <{}>", name, attr)),
+        }
+    }
+}
+
+impl Print for Block<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.statements(self, self.span().end)
+    }
+}
+
+impl Print for While<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.str("while ")?;
+        p.node(&self.cond)?;
+        p.str(" do")?;
+        p.body(&self.body, "end", self.span.end)
+    }
+}
+
+impl Print for Repeat<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.str("repeat")?;
+        p.body(&self.body, "until ", self.until.span().start)?;
+        p.node(&self.until)
+    }
+}
+
+impl Print for If<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        let (first, others, otherwise) = match self {
+            If::If(first, others, _) => (first, others, None),
+            If::IfElse(first, others, otherwise, _) => (first, others, Some(otherwise)),
+        };
+        let end = self.span().end;
+        p.str("if ")?;
+        for (i, branch) in std::iter::once(first).chain(others.iter()).enumerate() {
+            p.node(&branch.cond)?;
+            p.str(" then")?;
+            let (keyword, limit) = match (others.get(i), otherwise) {
+                (Some(next), _) => ("elseif ", next.span.start),
+                (None, Some(block)) => ("else", block.span().start),
+                (None, None) => ("end", end),
+            };
+            p.body(&branch.body, keyword, limit)?;
+        }
+        match otherwise {
+            Some(block) => p.body(block, "end", end),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Print for For<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            For::Plain(plain) => {
+                p.str(&format!("for {} = ", plain.init.0))?;
+                p.node(&plain.init.1)?;
+                p.str(", ")?;
+                p.node(&plain.border)?;
+                if let Some(step) = &plain.step {
+                    p.str(", ")?;
+                    p.node(step)?;
+                }
+                p.str(" do")?;
+                p.body(&plain.body, "end", plain.span.end)
+            }
+            For::ForCol(expr_for) => {
+                p.str(&format!("for {} in ", names(&expr_for.names)))?;
+                p.list(&expr_for.expressions)?;
+                p.str(" do")?;
+                p.body(&expr_for.body, "end", expr_for.span.end)
+            }
+        }
+    }
+}
+
+impl Print for FnDef<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        p.str("function ")?;
+        p.node(&self.name)?;
+        p.function(&self.params, &self.body, self.span)
+    }
+}
+
+impl Print for Statement<'_> {
+    fn print<W: Write>(&self, p: &mut Printer<W>) -> Result {
+        match self {
+            Statement::Empty(_) => p.str(";"),
+            Statement::Assignment(vars, exprs) => {
+                p.list(vars)?;
+                p.str(" = ")?;
+                p.list(exprs)
+            }
+            Statement::FnCall(call) => p.node(call),
+            Statement::Label(id, _) => p.str(&format!("::{}::", id)),
+            Statement::Break(_) => p.str("break"),
+            Statement::Goto(id, _) => p.str(&format!("goto {}", id)),
+            Statement::Do(block, span) => {
+                p.str("do")?;
+                p.body(block, "end", span.end)
+            }
+            Statement::While(w) => p.node(w),
+            Statement::Repeat(r) => p.node(r),
+            Statement::If(i) => p.node(i),
+            Statement::For(f) => p.node(f),
+            Statement::FnDef(def) => p.node(def),
+            Statement::LocalFnDef(def) => {
+                p.str("local ")?;
+                p.node(def)
+            }
+            Statement::LocalAttrNames(names, exprs, _) => {
+                p.str("local ")?;
+                p.list(names)?;
+                if !exprs.is_empty() {
+                    p.str(" = ")?;
+                    p.list(exprs)?;
+                }
+                Ok(())
+            }
+            Statement::Error(_) => p.str("<error>"),
+        }
+    }
+}
+
+macro_rules! display {
+    ($($tp:ident),*) => {
+        $(
+        impl Display for $tp<'_> {
+            fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+                self.print(&mut Printer::new(f))
+            }
+        }
+        )*
+    };
+}
+
+display!(
+    Expression, Field, FnParams, TableConst, Args, NameArgs, VarSuffix, Var, VarOrExpr,
+    FnCall, FnName, AttrName, Block, While, Repeat, If, For, FnDef, Statement
+);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+    use proptest::prelude::*;
+    use crate::parser::ast::*;
+    use crate::parser::{parse_chunk, parse_expr};
+    use crate::parser::fold::fold_negative_literals;
+    use crate::parser::visit::{Visitor, walk_expr, walk_statement};
+
+    const SCRIPTS: &[&str] = &[
+        include_str!("scripts/cassandra.lua"),
+        include_str!("scripts/lazy.lua"),
+        include_str!("scripts/server.lua"),
+        include_str!("scripts/treesetter.lua"),
+    ];
+
+    fn round_trip(src: &str) -> String {
+        let ast = parse_chunk(src).unwrap_or_else(|e| panic!("{}: {}", src, e));
+        let printed = ast.to_string();
+        let reparsed = parse_chunk(&printed).unwrap_or_else(|e| panic!("{}\n{}", printed, e));
+        assert!(ast.same_shape(&reparsed), "{}", printed);
+        assert_eq!(printed, reparsed.to_string());
+        printed
+    }
+
+    /// The statements and the expressions of the scripts, the samples for the properties.
+    #[derive(Default)]
+    struct Samples {
+        statements: Vec<Statement<'static>>,
+        exprs: Vec<Expression<'static>>,
+    }
+
+    impl Visitor<'static> for Samples {
+        fn visit_statement(&mut self, st: &Statement<'static>) {
+            self.statements.push(st.clone());
+            walk_statement(self, st)
+        }
+        fn visit_expr(&mut self, e: &Expression<'static>) {
+            self.exprs.push(e.clone());
+            walk_expr(self, e)
+        }
+    }
+
+    fn samples() -> &'static Samples {
+        static SAMPLES: OnceLock<Samples> = OnceLock::new();
+        SAMPLES.get_or_init(|| {
+            let mut samples = Samples::default();
+            for src in SCRIPTS {
+                samples.visit_block(&parse_chunk(src).unwrap());
+            }
+            samples
+        })
+    }
+
+    fn expression() -> impl Strategy<Value=String> {
+        let leaf = prop_oneof![
+            Just("nil".to_string()),
+            Just("true".to_string()),
+            Just("...".to_string()),
+            any::<u32>().prop_map(|v| v.to_string()),
+            any::<f64>().prop_filter("finite", |v| v.is_finite() && *v >= 0.0).prop_map(|v| format!("{:?}", v)),
+            "[a-z][a-z0-9_]{0,3}".prop_filter("not a keyword", |v| !matches!(v.as_str(),
+                "and" | "do" | "end" | "for" | "if" | "in" | "nil" | "not" | "or")),
+            "\"[ -~]{0,4}\"".prop_filter("no escapes", |v| !v[1..v.len() - 1].contains(['"', '\\'])),
+        ];
+        let binary = [
+            "or", "and", "<", ">", "<=", ">=", "~=", "==", "|", "~", "&", "<<", ">>",
+            "..", "+", "-", "*", "/", "//", "%", "^",
+        ];
+        let unary = ["not", "-", "#", "~"];
+        leaf.prop_recursive(6, 48, 4, move |inner| prop_oneof![
+            (inner.clone(), 0..binary.len(), inner.clone())
+                .prop_map(move |(l, op, r)| format!("{} {} {}", l, binary[op], r)),
+            (0..unary.len(), inner.clone()).prop_map(move |(op, e)| format!("{} {}", unary[op], e)),
+            inner.clone().prop_map(|e| format!("({})", e)),
+            inner.clone().prop_map(|e| format!("f({})", e)),
+            (inner.clone(), inner).prop_map(|(k, v)| format!("{{[{}] = {}, x = 1}}", k, v)),
+        ])
+    }
+
+    #[test]
+    fn scripts_test() {
+        for src in SCRIPTS {
+            round_trip(src);
+        }
+    }
+
+    #[test]
+    fn print_test() {
+        assert_eq!(round_trip("local a <const>, b = not x, 0xff"), "local a <const>, b = not x, 0xff");
+        assert_eq!(round_trip("repeat x = x + 1 until x > 10"), "repeat\n    x = x + 1\nuntil x > 10");
+        assert_eq!(round_trip("function a.b:c(x, ...) end"), "function a.b:c(x, ...) end");
+        assert_eq!(round_trip("local function f(x) if x then return 1 elseif y then else return end end"),
+                   "local function f(x)\n    if x then\n        return 1\n    elseif y then else\n        return\n    end\nend");
+        assert_eq!(round_trip("for i = 1, 10, 2 do end for k, v in pairs(t) do print(k) end"),
+                   "for i = 1, 10, 2 do end\nfor k, v in pairs(t) do\n    print(k)\nend");
+        assert_eq!(round_trip("x = - -1 - (a - b) .. (a .. b) ^ 2 - 2 ^ 3 ^ 2"),
+                   "x = - -1 - (a - b) .. (a .. b) ^ 2 - 2 ^ 3 ^ 2");
+        assert_eq!(round_trip("x = 1.0 + 1e300 + .5 + 1E-7 x = true or false"),
+                   "x = 1.0 + 1e300 + 0.5 + 1e-7\nx = true or false");
+        assert_eq!(round_trip("f{1, 2} o:m 'a\\n\"' ::l:: goto l a.b[1].c = (f)().x while true do break end"),
+                   "f {1, 2}\no:m \"a\\n\\\"\"\n::l::\ngoto l\na.b[1].c = (f)().x\nwhile true do\n    break\nend");
+        assert_eq!(round_trip("a = b;(f)()"), "a = b\n;\n(f)()");
+    }
+
+    #[test]
+    fn precedence_test() {
+        let bin = |l, op, r| Expression::Binary(Box::new(l), op, Box::new(r));
+        let id = |v| parse_expr(v).unwrap();
+        let unary = |op, e| Expression::Unary(op, Box::new(e), Default::default());
+
+        assert_eq!(bin(bin(id("a"), BinaryType::Add, id("b")), BinaryType::Mult, id("c")).to_string(), "(a + b) * c");
+        assert_eq!(bin(id("a"), BinaryType::Sub, bin(id("b"), BinaryType::Sub, id("c"))).to_string(), "a - (b - c)");
+        assert_eq!(bin(bin(id("a"), BinaryType::Concat, id("b")), BinaryType::Concat, id("c")).to_string(), "(a .. b) .. c");
+        assert_eq!(unary(UnaryType::Minus, bin(id("a"), BinaryType::Add, id("b"))).to_string(), "-(a + b)");
+        assert_eq!(unary(UnaryType::Minus, bin(id("a"), BinaryType::Pov, id("b"))).to_string(), "-a ^ b");
+        assert_eq!(bin(unary(UnaryType::Minus, id("a")), BinaryType::Pov, id("b")).to_string(), "(-a) ^ b");
+
+        let mut folded = parse_chunk("x = -2 ^ 2, -3").unwrap();
+        fold_negative_literals(&mut folded);
+        assert_eq!(folded.to_string(), "x = -2 ^ 2, -3");
+        let neg = |n| Expression::Number(n, Default::default());
+        assert_eq!(bin(neg(Number::Int(-2)), BinaryType::Pov, neg(Number::Float(-0.5))).to_string(), "(-2) ^ -0.5");
+        assert_eq!(unary(UnaryType::Minus, neg(Number::Int(-2))).to_string(), "- -2");
+    }
+
+    #[test]
+    fn display_fix_test() {
+        assert_eq!(Bool::True.to_string(), "true");
+        assert_eq!(Number::Hex(255).to_string(), "0xff");
+        assert_eq!(Number::Float(f64::INFINITY).to_string(), "1e9999");
+        assert_eq!(Number::Float(2.0).to_string(), "2.0");
+        assert_eq!(FnParams::Args(vec![Id::new("a")]).to_string(), "(a)");
+        assert_eq!(parse_chunk("do end").unwrap().to_string(), "do end");
+    }
+
+    proptest! {
+        #[test]
+        fn statements_round_trip(indexes in prop::collection::vec(any::<prop::sample::Index>(), 1..8)) {
+            let src: Vec<String> = indexes.iter().map(|i| i.get(&samples().statements).to_string()).collect();
+            round_trip(&src.join("\n"));
+        }
+
+        #[test]
+        fn script_exprs_round_trip(index in any::<prop::sample::Index>()) {
+            let expr = index.get(&samples().exprs);
+            let printed = expr.to_string();
+            prop_assert!(parse_expr(&printed).unwrap().same_shape(expr), "{}", printed);
+        }
+
+        #[test]
+        fn generated_exprs_round_trip(src in expression()) {
+            let expr = parse_expr(&src).unwrap();
+            let printed = expr.to_string();
+            prop_assert!(parse_expr(&printed).unwrap().same_shape(&expr), "{}", printed);
+        }
+    }
+}
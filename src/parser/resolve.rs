@@ -0,0 +1,556 @@
+use std::ops::Index;
+use crate::parser::arena::{ids, Args, Ast, BlockId, Expr, ExprId, Field, FuncId, IdMap, Idx, List, Stmt, StmtId};
+use crate::parser::ast::{Id, Name};
+use crate::parser::span::Span;
+
+ids!(
+    /// The declared name in `Scopes::bindings`.
+    BindingId
+);
+
+/// What declares the name.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BindingKind {
+    /// The environment of the chunk, `_ENV` that is not declared as a local.
+    Env,
+    Local,
+    LocalFunction,
+    Param,
+    /// The implicit `self` of the methods (`function t:m() end`).
+    SelfParam,
+    ForVar,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding<'a> {
+    pub name: Name<'a>,
+    pub span: Span,
+    pub kind: BindingKind,
+    /// The attribute of the local (`const`, `close`).
+    pub attr: Option<Id<'a>>,
+    /// The function the binding belongs to, `None` is the chunk.
+    pub func: Option<FuncId>,
+    /// The stack slot in the frame of the function, the environment of the chunk has none.
+    pub slot: Option<usize>,
+    /// The expression the local is initialized with, `None` when it starts as `nil`
+    /// or takes one of the extra values of the last expression (`local a, b = f()`).
+    pub init: Option<ExprId>,
+}
+
+impl<'a> Binding<'a> {
+    /// `<const>` and `<close>` locals can not be assigned.
+    pub fn is_const(&self) -> bool {
+        self.attr.as_ref().is_some_and(|a| matches!(a.v.as_str(), "const" | "close"))
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.attr.as_ref().is_some_and(|a| a.v.as_str() == "close")
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    /// The local of the function where it is used.
+    Local,
+    /// The local of an enclosing function.
+    Upvalue,
+    /// The field of `_ENV`, the binding is the `_ENV` in scope.
+    Global,
+}
+
+/// The binding of a name reference.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    pub binding: BindingId,
+    pub kind: Kind,
+    /// The stack slot in the frame of the function using the name,
+    /// `None` when the value lives outside of it: the upvalues and the globals of the outer `_ENV`.
+    pub slot: Option<usize>,
+}
+
+/// The bindings of the chunk and the resolution of every name in it.
+/// The names of the expressions (`Expr::Name`) and of the function statements (`function a.b() end`)
+/// are resolved, the locals are visible from the statement after the declaration
+/// so `local x = x` refers to the outer `x`.
+#[derive(Debug, Clone)]
+pub struct Scopes<'a> {
+    bindings: Vec<Binding<'a>>,
+    refs: IdMap<ExprId, Resolved>,
+    fn_names: IdMap<StmtId, Resolved>,
+    decls: IdMap<StmtId, Vec<BindingId>>,
+    params: IdMap<FuncId, Vec<BindingId>>,
+    frames: IdMap<FuncId, usize>,
+    chunk_frame: usize,
+}
+
+/// The function being resolved.
+struct Frame {
+    func: Option<FuncId>,
+    /// The next free slot.
+    free: usize,
+    /// The number of the slots the function needs.
+    size: usize,
+}
+
+struct Resolver<'s, 'a> {
+    ast: &'s Ast<'a>,
+    scopes: Scopes<'a>,
+    /// The bindings in scope, the innermost is the last.
+    visible: Vec<BindingId>,
+    frames: Vec<Frame>,
+}
+
+impl<'a> Scopes<'a> {
+    pub fn resolve(ast: &Ast<'a>) -> Self {
+        let env = Binding {
+            name: Name::Borrowed("_ENV"),
+            span: Span::default(),
+            kind: BindingKind::Env,
+            attr: None,
+            func: None,
+            slot: None,
+            init: None,
+        };
+        let scopes = Scopes {
+            bindings: vec![env],
+            refs: IdMap::new(),
+            fn_names: IdMap::new(),
+            decls: IdMap::new(),
+            params: IdMap::new(),
+            frames: IdMap::new(),
+            chunk_frame: 0,
+        };
+        let mut resolver = Resolver { ast, scopes, visible: vec![], frames: vec![Frame { func: None, free: 0, size: 0 }] };
+        resolver.block(ast.root());
+        resolver.scopes.chunk_frame = resolver.frames[0].size;
+        resolver.scopes
+    }
+
+    /// The environment of the chunk.
+    pub fn env(&self) -> BindingId {
+        BindingId(0)
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (BindingId, &Binding<'a>)> {
+        self.bindings.iter().enumerate().map(|(i, b)| (BindingId::from_index(i), b))
+    }
+
+    /// The resolution of the name expression.
+    pub fn reference(&self, expr: ExprId) -> Option<&Resolved> {
+        self.refs.get(expr)
+    }
+
+    pub fn references(&self) -> impl Iterator<Item = (ExprId, &Resolved)> {
+        self.refs.iter()
+    }
+
+    /// The expressions referring to the binding.
+    pub fn uses(&self, binding: BindingId) -> impl Iterator<Item = ExprId> + '_ {
+        self.refs.iter().filter(move |(_, r)| r.binding == binding).map(|(e, _)| e)
+    }
+
+    /// The resolution of the first name of `function a.b:c() end`.
+    pub fn function_name(&self, stmt: StmtId) -> Option<&Resolved> {
+        self.fn_names.get(stmt)
+    }
+
+    /// The bindings declared by the statement: the locals, the local function and the variables of the loops.
+    pub fn declared(&self, stmt: StmtId) -> &[BindingId] {
+        self.decls.get(stmt).map_or(&[], Vec::as_slice)
+    }
+
+    /// The parameters of the function, `self` comes first for the methods.
+    pub fn params(&self, func: FuncId) -> &[BindingId] {
+        self.params.get(func).map_or(&[], Vec::as_slice)
+    }
+
+    /// The number of the stack slots the function (`None` is the chunk) takes for its locals.
+    pub fn frame_size(&self, func: Option<FuncId>) -> usize {
+        match func {
+            Some(func) => self.frames.get(func).copied().unwrap_or_default(),
+            None => self.chunk_frame,
+        }
+    }
+}
+
+impl<'a> Index<BindingId> for Scopes<'a> {
+    type Output = Binding<'a>;
+
+    fn index(&self, id: BindingId) -> &Self::Output {
+        &self.bindings[id.index()]
+    }
+}
+
+impl<'s, 'a> Resolver<'s, 'a> {
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("the chunk frame")
+    }
+
+    /// Declares the name in the current scope taking the next free slot.
+    fn declare(&mut self, name: &Id<'a>, kind: BindingKind, attr: Option<Id<'a>>) -> BindingId {
+        let frame = self.frame();
+        let slot = frame.free;
+        frame.free += 1;
+        frame.size = frame.size.max(frame.free);
+        let func = frame.func;
+        let id = BindingId::from_index(self.scopes.bindings.len());
+        self.scopes.bindings.push(Binding {
+            name: name.v.clone(),
+            span: name.span,
+            kind,
+            attr,
+            func,
+            slot: Some(slot),
+            init: None,
+        });
+        self.visible.push(id);
+        id
+    }
+
+    fn lookup(&self, name: &str) -> Option<BindingId> {
+        self.visible.iter().rev().copied().find(|b| self.scopes[*b].name.as_str() == name)
+    }
+
+    fn resolve(&self, name: &str) -> Resolved {
+        let func = self.frames.last().expect("the chunk frame").func;
+        let local = |binding: BindingId| {
+            let b = &self.scopes[binding];
+            if b.kind != BindingKind::Env && b.func == func {
+                (Kind::Local, b.slot)
+            } else {
+                (Kind::Upvalue, None)
+            }
+        };
+        match self.lookup(name) {
+            Some(binding) => {
+                let (kind, slot) = local(binding);
+                Resolved { binding, kind, slot }
+            }
+            None if name == "_ENV" => Resolved { binding: self.scopes.env(), kind: Kind::Upvalue, slot: None },
+            None => {
+                let binding = self.lookup("_ENV").unwrap_or(self.scopes.env());
+                Resolved { binding, kind: Kind::Global, slot: local(binding).1 }
+            }
+        }
+    }
+
+    /// Runs the rule in a nested scope, its locals go out of scope and free their slots afterwards.
+    fn scoped(&mut self, rule: impl FnOnce(&mut Self)) {
+        let visible = self.visible.len();
+        let free = self.frame().free;
+        rule(self);
+        self.visible.truncate(visible);
+        self.frame().free = free;
+    }
+
+    fn block(&mut self, block: BlockId) {
+        self.scoped(|r| r.block_body(block));
+    }
+
+    /// The statements of the block in the current scope.
+    fn block_body(&mut self, block: BlockId) {
+        let block = &self.ast[block].kind;
+        for st in &self.ast[block.stmts] {
+            self.stmt(*st);
+        }
+        if let Some(exprs) = block.ret {
+            self.exprs(exprs);
+        }
+    }
+
+    fn exprs(&mut self, exprs: List<ExprId>) {
+        for e in &self.ast[exprs] {
+            self.expr(*e);
+        }
+    }
+
+    fn stmt(&mut self, id: StmtId) {
+        let ast = self.ast;
+        match &ast[id].kind {
+            Stmt::Empty | Stmt::Label(_) | Stmt::Break | Stmt::Goto(_) | Stmt::Error => {}
+            Stmt::Assign { targets, values } => {
+                self.exprs(*values);
+                self.exprs(*targets);
+            }
+            Stmt::Call(call) => self.expr(*call),
+            Stmt::Do(block) => self.block(*block),
+            Stmt::While { cond, body } => {
+                self.expr(*cond);
+                self.block(*body);
+            }
+            // the condition sees the locals of the body
+            Stmt::Repeat { body, until } => self.scoped(|r| {
+                r.block_body(*body);
+                r.expr(*until);
+            }),
+            Stmt::If { branches, otherwise } => {
+                for branch in &ast[*branches] {
+                    self.expr(branch.cond);
+                    self.block(branch.body);
+                }
+                if let Some(block) = otherwise {
+                    self.block(*block);
+                }
+            }
+            Stmt::NumericFor { var, start, limit, step, body } => {
+                self.expr(*start);
+                self.expr(*limit);
+                if let Some(step) = step {
+                    self.expr(*step);
+                }
+                self.scoped(|r| {
+                    let var = r.declare(var, BindingKind::ForVar, None);
+                    r.scopes.decls.insert(id, vec![var]);
+                    r.block(*body);
+                });
+            }
+            Stmt::GenericFor { names, exprs, body } => {
+                self.exprs(*exprs);
+                self.scoped(|r| {
+                    let vars = ast[*names].iter().map(|name| r.declare(name, BindingKind::ForVar, None)).collect();
+                    r.scopes.decls.insert(id, vars);
+                    r.block(*body);
+                });
+            }
+            Stmt::Function { name, func } => {
+                let first = &ast[name.path][0];
+                let resolved = self.resolve(first.v.as_str());
+                self.scopes.fn_names.insert(id, resolved);
+                self.func(*func, name.method.is_some());
+            }
+            // the function is visible in its own body
+            Stmt::LocalFunction { name, func } => {
+                let binding = self.declare(name, BindingKind::LocalFunction, None);
+                self.scopes.decls.insert(id, vec![binding]);
+                self.func(*func, false);
+            }
+            // the locals are not visible in their own initializers
+            Stmt::Local { names, values } => {
+                self.exprs(*values);
+                let values = &ast[*values];
+                let bindings = ast[*names]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, local)| {
+                        let binding = self.declare(&local.name, BindingKind::Local, local.attr.clone());
+                        self.scopes.bindings[binding.index()].init = values.get(i).copied();
+                        binding
+                    })
+                    .collect();
+                self.scopes.decls.insert(id, bindings);
+            }
+        }
+    }
+
+    fn func(&mut self, id: FuncId, method: bool) {
+        let ast = self.ast;
+        let func = &ast[id].kind;
+        let visible = self.visible.len();
+        self.frames.push(Frame { func: Some(id), free: 0, size: 0 });
+
+        let mut params = vec![];
+        if method {
+            let this = Id { v: Name::Borrowed("self"), span: Span::default() };
+            params.push(self.declare(&this, BindingKind::SelfParam, None));
+        }
+        for param in &ast[func.params] {
+            params.push(self.declare(param, BindingKind::Param, None));
+        }
+        self.scopes.params.insert(id, params);
+        self.block(func.body);
+
+        let frame = self.frames.pop().expect("the frame of the function");
+        self.scopes.frames.insert(id, frame.size);
+        self.visible.truncate(visible);
+    }
+
+    fn expr(&mut self, id: ExprId) {
+        let ast = self.ast;
+        match &ast[id].kind {
+            Expr::Nil | Expr::False | Expr::True | Expr::Number(_) | Expr::Text(_) | Expr::VarArgs | Expr::Error => {}
+            Expr::Name(name) => {
+                let resolved = self.resolve(name.v.as_str());
+                self.scopes.refs.insert(id, resolved);
+            }
+            Expr::Function(func) => self.func(*func, false),
+            Expr::Table(fields) => {
+                for field in &ast[*fields] {
+                    match field {
+                        Field::Named(_, v) | Field::Positional(v) => self.expr(*v),
+                        Field::Keyed(k, v) => {
+                            self.expr(*k);
+                            self.expr(*v);
+                        }
+                    }
+                }
+            }
+            Expr::Paren(e) | Expr::Field { obj: e, .. } | Expr::Unary(_, e) => self.expr(*e),
+            Expr::Index { obj, key, .. } => {
+                self.expr(*obj);
+                self.expr(*key);
+            }
+            Expr::Call { callee: obj, args, .. } | Expr::Method { obj, args, .. } => {
+                self.expr(*obj);
+                match args {
+                    Args::List(exprs, _) => self.exprs(*exprs),
+                    Args::Table(e) | Args::String(e) => self.expr(*e),
+                }
+            }
+            Expr::Binary(lhs, _, rhs) => {
+                self.expr(*lhs);
+                self.expr(*rhs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::arena::{Ast, Expr, ExprId};
+    use crate::parser::parse_chunk;
+    use crate::parser::resolve::{BindingKind, Kind, Resolved, Scopes};
+
+    /// The resolutions of the names in the order of the source.
+    fn resolve(src: &str) -> (Ast<'_>, Scopes<'_>, Vec<(String, ExprId, Resolved)>) {
+        let ast = Ast::new(&parse_chunk(src).unwrap());
+        let scopes = Scopes::resolve(&ast);
+        let mut names: Vec<_> = ast
+            .exprs()
+            .filter_map(|(id, e)| match &e.kind {
+                Expr::Name(name) => Some((name.v.to_string(), id, *scopes.reference(id).unwrap())),
+                _ => None,
+            })
+            .collect();
+        names.sort_by_key(|(_, id, _)| ast[*id].span.start);
+        (ast, scopes, names)
+    }
+
+    fn kinds(names: &[(String, ExprId, Resolved)]) -> Vec<(&str, Kind, Option<usize>)> {
+        names.iter().map(|(n, _, r)| (n.as_str(), r.kind, r.slot)).collect()
+    }
+
+    #[test]
+    fn shadowing_test() {
+        let (_, scopes, names) = resolve("local x = 1 local x = x + 1 print(x)");
+        assert_eq!(kinds(&names), [
+            ("x", Kind::Local, Some(0)),
+            ("print", Kind::Global, None),
+            ("x", Kind::Local, Some(1)),
+        ]);
+        let first = names[0].2.binding;
+        assert_eq!(scopes[first].span.start, 6);
+        assert_eq!(scopes[names[2].2.binding].span.start, 18);
+        assert_eq!(names[1].2.binding, scopes.env());
+        assert_eq!(scopes.uses(first).count(), 1);
+    }
+
+    #[test]
+    fn own_initializer_test() {
+        let (_, _, names) = resolve("local f = function() return f end local function g() return g end for i = i, 2 do end");
+        assert_eq!(kinds(&names), [
+            ("f", Kind::Global, None),
+            ("g", Kind::Upvalue, None),
+            ("i", Kind::Global, None),
+        ]);
+        let (_, _, names) = resolve("for k, v in pairs(k) do print(k, v) end");
+        assert_eq!(kinds(&names), [
+            ("pairs", Kind::Global, None),
+            ("k", Kind::Global, None),
+            ("print", Kind::Global, None),
+            ("k", Kind::Local, Some(0)),
+            ("v", Kind::Local, Some(1)),
+        ]);
+    }
+
+    #[test]
+    fn slots_test() {
+        let src = "local a do local b end local c \
+                   function t:m(x, y) local z = x return self, z end \
+                   repeat local r until r";
+        let (ast, scopes, names) = resolve(src);
+        let slots: Vec<_> = scopes.bindings().skip(1).map(|(_, b)| (b.name.to_string(), b.kind, b.slot.unwrap())).collect();
+        assert_eq!(slots, [
+            ("a".to_string(), BindingKind::Local, 0),
+            ("b".to_string(), BindingKind::Local, 1),
+            ("c".to_string(), BindingKind::Local, 1),
+            ("self".to_string(), BindingKind::SelfParam, 0),
+            ("x".to_string(), BindingKind::Param, 1),
+            ("y".to_string(), BindingKind::Param, 2),
+            ("z".to_string(), BindingKind::Local, 3),
+            ("r".to_string(), BindingKind::Local, 2),
+        ]);
+        let (func, _) = ast.funcs().next().unwrap();
+        assert_eq!(scopes.params(func).len(), 3);
+        assert_eq!(scopes.frame_size(Some(func)), 4);
+        assert_eq!(scopes.frame_size(None), 3);
+        assert_eq!(kinds(&names), [
+            ("x", Kind::Local, Some(1)),
+            ("self", Kind::Local, Some(0)),
+            ("z", Kind::Local, Some(3)),
+            ("r", Kind::Local, Some(2)),
+        ]);
+        let (stmt, _) = ast.stmts().find(|(id, _)| scopes.function_name(*id).is_some()).unwrap();
+        assert_eq!(scopes.function_name(stmt).unwrap().kind, Kind::Global);
+    }
+
+    #[test]
+    fn upvalue_test() {
+        let src = "local a, b function f(p) local c = a return function() return a + b + c + p end end";
+        let (_, scopes, names) = resolve(src);
+        assert_eq!(kinds(&names), [
+            ("a", Kind::Upvalue, None),
+            ("a", Kind::Upvalue, None),
+            ("b", Kind::Upvalue, None),
+            ("c", Kind::Upvalue, None),
+            ("p", Kind::Upvalue, None),
+        ]);
+        assert_eq!(names[0].2.binding, names[1].2.binding);
+        let c = &scopes[names[3].2.binding];
+        assert!(c.func.is_some());
+        assert_eq!(c.slot, Some(1));
+    }
+
+    #[test]
+    fn env_test() {
+        let src = "x = 1 local _ENV = {y = x} z = 2 function f() return w, _ENV end";
+        let (ast, scopes, names) = resolve(src);
+        let env = names.iter().find(|(n, _, r)| n == "_ENV" && r.kind == Kind::Upvalue).unwrap().2.binding;
+        assert_eq!(scopes[env].kind, BindingKind::Local);
+        assert_eq!(kinds(&names), [
+            ("x", Kind::Global, None),
+            ("x", Kind::Global, None),
+            ("z", Kind::Global, Some(0)),
+            ("w", Kind::Global, None),
+            ("_ENV", Kind::Upvalue, None),
+        ]);
+        assert_eq!(names[0].2.binding, scopes.env());
+        assert_eq!(names[2].2.binding, env);
+        assert_eq!(names[3].2.binding, env);
+
+        let (stmt, _) = ast.stmts().find(|(id, _)| scopes.function_name(*id).is_some()).unwrap();
+        assert_eq!(scopes.function_name(stmt).unwrap(), &Resolved { binding: env, kind: Kind::Global, slot: Some(0) });
+        let (_, _, names) = resolve("return _ENV");
+        assert_eq!(kinds(&names), [("_ENV", Kind::Upvalue, None)]);
+    }
+
+    #[test]
+    fn scripts_test() {
+        for src in [
+            include_str!("scripts/lazy.lua"),
+            include_str!("scripts/cassandra.lua"),
+            include_str!("scripts/treesetter.lua"),
+            include_str!("scripts/server.lua"),
+        ] {
+            let (ast, scopes, names) = resolve(src);
+            assert_eq!(names.len(), ast.exprs().filter(|(_, e)| matches!(e.kind, Expr::Name(_))).count());
+            for (_, _, r) in names {
+                let binding = &scopes[r.binding];
+                match r.kind {
+                    Kind::Local => assert_eq!(r.slot, binding.slot),
+                    Kind::Upvalue => assert!(r.slot.is_none()),
+                    Kind::Global => assert!(binding.kind == BindingKind::Env || binding.name.as_str() == "_ENV"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! The compact S-expression form of the ast for the golden tests and the tools.
+//! Every statement of a block goes on its own line, the spans are omitted.
+//!
+//! `a.b:c(1)[2] = {x = 1}` reads as `(assign ((index (method (. a b) c 1) 2)) ((table (field x 1))))`.
+
+use std::fmt::Write;
+use crate::parser::ast::*;
+
+pub trait ToSexp {
+    fn to_sexp(&self) -> String;
+}
+
+impl ToSexp for Block<'_> {
+    fn to_sexp(&self) -> String {
+        let mut w = Writer { out: String::new(), indent: 0 };
+        w.block(self);
+        w.out
+    }
+}
+
+impl ToSexp for Statement<'_> {
+    fn to_sexp(&self) -> String {
+        let mut w = Writer { out: String::new(), indent: 0 };
+        w.statement(self);
+        w.out
+    }
+}
+
+impl ToSexp for Expression<'_> {
+    fn to_sexp(&self) -> String {
+        let mut w = Writer { out: String::new(), indent: 0 };
+        w.expr(self);
+        w.out
+    }
+}
+
+struct Writer {
+    out: String,
+    indent: usize,
+}
+
+impl Writer {
+    fn str(&mut self, s: &str) {
+        self.out.push_str(s)
+    }
+
+    fn open(&mut self, head: &str) {
+        self.out.push('(');
+        self.out.push_str(head);
+    }
+
+    fn close(&mut self) {
+        self.out.push(')')
+    }
+
+    fn space(&mut self) {
+        self.out.push(' ')
+    }
+
+    /// `(head item...)`, the empty head gives the plain list `(item...)`.
+    fn list<T>(&mut self, head: &str, items: &[T], item: impl Fn(&mut Self, &T)) {
+        self.open(head);
+        for (idx, i) in items.iter().enumerate() {
+            if idx > 0 || !head.is_empty() {
+                self.space();
+            }
+            item(self, i);
+        }
+        self.close()
+    }
+
+    fn block(&mut self, block: &Block) {
+        let (sts, ret) = match block {
+            Block::Void(sts, _) => (sts, None),
+            Block::Return(sts, exprs, _) => (sts, Some(exprs)),
+        };
+        self.open("block");
+        self.indent += 1;
+        for st in sts.iter() {
+            self.new_line();
+            self.statement(st);
+        }
+        if let Some(exprs) = ret {
+            self.new_line();
+            self.list("return", exprs, Self::expr);
+        }
+        self.indent -= 1;
+        self.close()
+    }
+
+    fn new_line(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+    }
+
+    fn id(&mut self, id: &Id) {
+        self.str(&id.v)
+    }
+
+    fn exprs(&mut self, exprs: &[Expression]) {
+        self.list("", exprs, Self::expr)
+    }
+
+    fn statement(&mut self, st: &Statement) {
+        match st {
+            Statement::Empty(_) => self.str("(empty)"),
+            Statement::Assignment(vars, exprs) => {
+                self.open("assign ");
+                self.list("", vars, Self::var);
+                self.space();
+                self.exprs(exprs);
+                self.close()
+            }
+            Statement::FnCall(call) => self.fn_call(call),
+            Statement::Label(id, _) => {
+                self.open("label ");
+                self.id(id);
+                self.close()
+            }
+            Statement::Break(_) => self.str("(break)"),
+            Statement::Goto(id, _) => {
+                self.open("goto ");
+                self.id(id);
+                self.close()
+            }
+            Statement::Do(block, _) => {
+                self.open("do ");
+                self.block(block);
+                self.close()
+            }
+            Statement::While(w) => {
+                self.open("while ");
+                self.expr(&w.cond);
+                self.space();
+                self.block(&w.body);
+                self.close()
+            }
+            Statement::Repeat(r) => {
+                self.open("repeat ");
+                self.block(&r.body);
+                self.space();
+                self.expr(&r.until);
+                self.close()
+            }
+            Statement::If(i) => {
+                let (first, others, otherwise) = match i {
+                    If::If(first, others, _) => (first, others, None),
+                    If::IfElse(first, others, otherwise, _) => (first, others, Some(otherwise)),
+                };
+                self.open("if");
+                for branch in std::iter::once(first).chain(others.iter()) {
+                    self.str(" (branch ");
+                    self.expr(&branch.cond);
+                    self.space();
+                    self.block(&branch.body);
+                    self.close();
+                }
+                if let Some(block) = otherwise {
+                    self.str(" (else ");
+                    self.block(block);
+                    self.close();
+                }
+                self.close()
+            }
+            Statement::For(For::Plain(f)) => {
+                self.open("for ");
+                self.id(&f.init.0);
+                self.space();
+                self.expr(&f.init.1);
+                self.space();
+                self.expr(&f.border);
+                if let Some(step) = &f.step {
+                    self.space();
+                    self.expr(step);
+                }
+                self.space();
+                self.block(&f.body);
+                self.close()
+            }
+            Statement::For(For::ForCol(f)) => {
+                self.open("for-in ");
+                self.list("", &f.names, Self::id);
+                self.space();
+                self.exprs(&f.expressions);
+                self.space();
+                self.block(&f.body);
+                self.close()
+            }
+            Statement::FnDef(def) => self.fn_def("function", def),
+            Statement::LocalFnDef(def) => self.fn_def("local-function", def),
+            Statement::LocalAttrNames(names, exprs, _) => {
+                self.open("local ");
+                self.list("", names, |w, name| match name {
+                    AttrName::Name(name) => w.id(name),
+                    AttrName::AttrName(name, attr) => {
+                        w.str("(");
+                        w.id(name);
+                        w.space();
+                        w.id(attr);
+                        w.close()
+                    }
+                });
+                self.space();
+                self.exprs(exprs);
+                self.close()
+            }
+            Statement::Error(_) => self.str("(error)"),
+        }
+    }
+
+    fn fn_def(&mut self, head: &str, def: &FnDef) {
+        self.open(head);
+        self.space();
+        let mut name = def.name.names.iter().map(|id| id.v.as_str()).collect::<Vec<_>>().join(".");
+        if let Some(last) = &def.name.last {
+            write!(name, ":{}", last).unwrap();
+        }
+        self.str(&name);
+        self.space();
+        self.params(&def.params);
+        self.space();
+        self.block(&def.body);
+        self.close()
+    }
+
+    fn params(&mut self, params: &FnParams) {
+        let (ids, var_args) = match params {
+            FnParams::Args(ids) => (ids.as_slice(), false),
+            FnParams::VarArgs => (&[][..], true),
+            FnParams::WithVarArgs(ids) => (ids.as_slice(), true),
+        };
+        self.open("params");
+        for id in ids {
+            self.space();
+            self.id(id);
+        }
+        if var_args {
+            self.str(" ...");
+        }
+        self.close()
+    }
+
+    fn expr(&mut self, e: &Expression) {
+        match e {
+            Expression::Nil(_) => self.str("nil"),
+            Expression::False(_) => self.str("false"),
+            Expression::True(_) => self.str("true"),
+            Expression::Number(n, _) => self.str(&n.to_string()),
+            Expression::Text(t) => self.str(&t.to_string()),
+            Expression::VarArgs(_) => self.str("..."),
+            Expression::FnDef(params, body, _) => {
+                self.open("function ");
+                self.params(params);
+                self.space();
+                self.block(body);
+                self.close()
+            }
+            Expression::PrefixExpr(call) => self.fn_call(call),
+            Expression::TableConstructor(table) => self.table(table),
+            Expression::Unary(op, e, _) => {
+                self.open(&op.to_string());
+                self.space();
+                self.expr(e);
+                self.close()
+            }
+            Expression::Binary(lhs, op, rhs) => {
+                self.open(&op.to_string());
+                self.space();
+                self.expr(lhs);
+                self.space();
+                self.expr(rhs);
+                self.close()
+            }
+            Expression::Error(_) => self.str("(error)"),
+        }
+    }
+
+    fn table(&mut self, table: &TableConst) {
+        self.list("table", &table.fields, |w, field| match field {
+            Field::Pair(FieldKey::Id(id), v) => {
+                w.open("field ");
+                w.id(id);
+                w.space();
+                w.expr(v);
+                w.close()
+            }
+            Field::Pair(FieldKey::Expr(k), v) => {
+                w.open("pair ");
+                w.expr(k);
+                w.space();
+                w.expr(v);
+                w.close()
+            }
+            Field::Value(v) => w.expr(v),
+        })
+    }
+
+    /// The chain of suffixes is nested from the inside out, the head is the innermost.
+    fn chain(&mut self, head: impl FnOnce(&mut Self), suffixes: &[Suffixed]) {
+        for s in suffixes.iter().rev() {
+            match s {
+                Suffixed::Args(NameArgs::Args(_)) => self.open("call "),
+                Suffixed::Args(NameArgs::NameArgs(..)) => self.open("method "),
+                Suffixed::Suffix(Suffix::Id(_)) => self.open(". "),
+                Suffixed::Suffix(Suffix::Expr(_)) => self.open("index "),
+            }
+        }
+        head(self);
+        for s in suffixes.iter() {
+            match s {
+                Suffixed::Args(NameArgs::Args(args)) => self.args(args),
+                Suffixed::Args(NameArgs::NameArgs(name, args)) => {
+                    self.space();
+                    self.id(name);
+                    self.args(args)
+                }
+                Suffixed::Suffix(Suffix::Id(id)) => {
+                    self.space();
+                    self.id(id)
+                }
+                Suffixed::Suffix(Suffix::Expr(e)) => {
+                    self.space();
+                    self.expr(e)
+                }
+            }
+            self.close()
+        }
+    }
+
+    fn args(&mut self, args: &Args) {
+        match args {
+            Args::Expressions(exprs, _) => exprs.iter().for_each(|e| {
+                self.space();
+                self.expr(e)
+            }),
+            Args::Constructor(table) => {
+                self.space();
+                self.table(table)
+            }
+            Args::String(t) => {
+                self.space();
+                self.str(&t.to_string())
+            }
+        }
+    }
+
+    fn var(&mut self, var: &Var) {
+        let mut suffixes = vec![];
+        let head: Box<dyn FnOnce(&mut Self)> = match &var.head {
+            VarHead::Id(id) => Box::new(move |w: &mut Self| w.id(id)),
+            VarHead::Expr(e, suffix) => {
+                push_suffix(&mut suffixes, suffix);
+                Box::new(move |w: &mut Self| w.paren(e))
+            }
+        };
+        var.tail.iter().for_each(|s| push_suffix(&mut suffixes, s));
+        self.chain(head, &suffixes)
+    }
+
+    fn paren(&mut self, e: &Expression) {
+        self.open("paren ");
+        self.expr(e);
+        self.close()
+    }
+
+    fn fn_call(&mut self, call: &FnCall) {
+        match &call.head {
+            VarOrExpr::Expr(e) => {
+                let suffixes: Vec<Suffixed> = call.args.iter().map(Suffixed::Args).collect();
+                self.chain(|w| w.paren(e), &suffixes)
+            }
+            VarOrExpr::Var(var) => {
+                let suffixes: Vec<Suffixed> = call.args.iter().map(Suffixed::Args).collect();
+                self.chain(|w| w.var(var), &suffixes)
+            }
+        }
+    }
+}
+
+enum Suffixed<'n, 'a> {
+    Args(&'n NameArgs<'a>),
+    Suffix(&'n Suffix<'a>),
+}
+
+fn push_suffix<'n, 'a>(suffixes: &mut Vec<Suffixed<'n, 'a>>, suffix: &'n VarSuffix<'a>) {
+    suffixes.extend(suffix.var.iter().map(Suffixed::Args));
+    suffixes.push(Suffixed::Suffix(&suffix.suffix));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse_chunk, parse_expr};
+    use crate::parser::sexp::ToSexp;
+
+    fn sexp(src: &str) -> String {
+        parse_chunk(src).unwrap().to_sexp()
+    }
+
+    /// Compares with the file next to the script, `UPDATE_GOLDEN=1` rewrites the files.
+    fn golden(script: &str, src: &str, expected: &str) {
+        let actual = sexp(src);
+        if std::env::var("UPDATE_GOLDEN").is_ok() {
+            let path = format!("{}/src/parser/scripts/{}.sexp", env!("CARGO_MANIFEST_DIR"), script);
+            std::fs::write(path, &actual).unwrap();
+            return;
+        }
+        assert_eq!(actual, expected, "{}.sexp is outdated, rerun with UPDATE_GOLDEN=1", script);
+    }
+
+    #[test]
+    fn expr_test() {
+        assert_eq!(parse_expr("-x ^ 2 + #t").unwrap().to_sexp(), "(+ (- (^ x 2)) (# t))");
+        assert_eq!(parse_expr("not a and 'b' or 0x10").unwrap().to_sexp(), "(or (and (not a) \"b\") 0x10)");
+        assert_eq!(parse_expr("a.b:c(1)[2]").unwrap().to_sexp(), "(index (method (. a b) c 1) 2)");
+        assert_eq!(parse_expr("(f)(x){y = 1, [2] = 3, 4}").unwrap().to_sexp(),
+                   "(call (call (paren f) x) (table (field y 1) (pair 2 3) 4))");
+        assert_eq!(parse_expr("function(a, ...) return a end").unwrap().to_sexp(),
+                   "(function (params a ...) (block\n  (return a)))");
+    }
+
+    #[test]
+    fn statement_test() {
+        assert_eq!(sexp("local a <const>, b = 1 a.b[c], d = f 'x', {} ::l:: goto l"),
+                   "(block\n  (local ((a const) b) (1))\n  (assign ((index (. a b) c) d) ((call f \"x\") (table)))\n  (label l)\n  (goto l))");
+        assert_eq!(sexp("for i = 1, 2 do end for k, v in pairs(t) do break end"),
+                   "(block\n  (for i 1 2 (block))\n  (for-in (k v) ((call pairs t)) (block\n    (break))))");
+        assert_eq!(sexp("if a then elseif b then else end while x do end repeat until y"),
+                   "(block\n  (if (branch a (block)) (branch b (block)) (else (block)))\n  (while x (block))\n  (repeat (block) y))");
+        assert_eq!(sexp("function a.b:c() end local function f(...) end do ; end"),
+                   "(block\n  (function a.b:c (params) (block))\n  (local-function f (params ...) (block))\n  (do (block\n    (empty))))");
+    }
+
+    #[test]
+    fn golden_test() {
+        golden("treesetter", include_str!("scripts/treesetter.lua"), include_str!("scripts/treesetter.sexp"));
+        golden("lazy", include_str!("scripts/lazy.lua"), include_str!("scripts/lazy.sexp"));
+    }
+}
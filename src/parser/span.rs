@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+
+/// The location of a token or a node in the source:
+/// the byte range and the line and the column of the first byte (both start from 1).
+///
+/// Spans take part in the comparison of nodes, `ast::SameShape` compares the trees ignoring them.
+/// `Span::default()` denotes a node that does not come from a source (line 0).
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, col: usize) -> Self {
+        Self { start, end, line, col }
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The span is not attached to any source.
+    pub fn is_unknown(&self) -> bool {
+        self.line == 0
+    }
+
+    /// The span covering both spans. The unknown spans are ignored.
+    pub fn merge(self, other: Span) -> Span {
+        if self.is_unknown() {
+            return other;
+        }
+        if other.is_unknown() {
+            return self;
+        }
+        let (first, last) = if self.start <= other.start { (self, other) } else { (other, self) };
+        Span { end: first.end.max(last.end), ..first }
+    }
+
+    /// The empty span that points right after the span.
+    pub fn after(self) -> Span {
+        Span { start: self.end, col: self.col + self.len(), ..self }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Any node or token that knows its location in the source.
+pub trait Spanned {
+    fn span(&self) -> Span;
+}
+
+impl<T: Spanned> Spanned for Box<T> {
+    fn span(&self) -> Span {
+        self.as_ref().span()
+    }
+}
+
+impl<T: Spanned> Spanned for Vec<T> {
+    fn span(&self) -> Span {
+        self.iter().fold(Span::default(), |acc, el| acc.merge(el.span()))
+    }
+}
+
+/// Translates byte offsets of the source into lines and columns.
+pub struct LineIndex<'a> {
+    src: &'a str,
+    lines: Vec<usize>,
+    /// The last translated offset with its line and column, the columns of the offsets after it
+    /// on the same line are counted from there, so a long line is walked once by the lexer.
+    last: Cell<(usize, usize, usize)>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(src: &'a str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { src, lines, last: Cell::new((0, 1, 1)) }
+    }
+
+    /// The line and the column (in chars) of the byte offset, both start from 1.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.lines.partition_point(|&start| start <= offset).max(1);
+        let (last, last_line, last_col) = self.last.get();
+        let (start, col) = if last_line == line && last <= offset { (last, last_col - 1) } else { (self.lines[line - 1], 0) };
+        let col = match self.src.get(start..offset) {
+            Some(s) => col + s.chars().count(),
+            None => col + offset - start,
+        };
+        self.last.set((offset, line, col + 1));
+        (line, col + 1)
+    }
+
+    pub fn span(&self, range: Range<usize>) -> Span {
+        let (line, col) = self.line_col(range.start);
+        Span::new(range.start, range.end, line, col)
+    }
+
+    /// The text of the line (without the line break) that starts from 1.
+    pub fn line(&self, line: usize) -> &'a str {
+        let start = self.lines.get(line.saturating_sub(1)).copied().unwrap_or(self.src.len());
+        let end = self.lines.get(line).copied().unwrap_or(self.src.len());
+        self.src[start..end].trim_end_matches(['\n', '\r'])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::span::{LineIndex, Span};
+
+    #[test]
+    fn line_col_test() {
+        let idx = LineIndex::new("a = 1\nlocal b\n\nc");
+        assert_eq!(idx.line_col(0), (1, 1));
+        assert_eq!(idx.line_col(4), (1, 5));
+        assert_eq!(idx.line_col(6), (2, 1));
+        assert_eq!(idx.line_col(12), (2, 7));
+        assert_eq!(idx.line_col(14), (3, 1));
+        assert_eq!(idx.line_col(15), (4, 1));
+        assert_eq!(idx.line(2), "local b");
+        assert_eq!(idx.line(3), "");
+    }
+
+    #[test]
+    fn utf8_col_test() {
+        let idx = LineIndex::new("s = 'жж' x");
+        assert_eq!(idx.line_col(11), (1, 10));
+        assert_eq!(idx.line_col(5), (1, 6));
+        assert_eq!(idx.line_col(9), (1, 8));
+        assert_eq!(idx.line_col(11), (1, 10));
+    }
+
+    #[test]
+    fn merge_test() {
+        let l = Span::new(2, 4, 1, 3);
+        let r = Span::new(6, 9, 2, 1);
+        assert_eq!(l.merge(r).range(), 2..9);
+        assert_eq!(r.merge(l).range(), 2..9);
+        assert_eq!(r.merge(l).line, 1);
+        assert_eq!(Span::default().merge(r).range(), 6..9);
+        assert_eq!(l.merge(Span::default()).range(), 2..4);
+    }
+}
@@ -1,26 +1,38 @@
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
 use logos::{FilterResult, Lexer, Logos};
 use logos::skip;
 use crate::parser::ast::Number;
+use crate::parser::span::{LineIndex, Span};
 
 
-#[derive(Logos, Clone, Copy, Debug, PartialEq)]
-#[logos(subpattern digit = r"[0-9]([0-9_]*[0-9])?")]
+#[derive(Logos, Clone, Debug, PartialEq)]
+#[logos(subpattern digit = r"[0-9]+")]
+#[logos(subpattern hex = r"[0-9a-fA-F]+")]
 #[logos(subpattern letter = r"[a-zA-Z_]")]
 #[logos(subpattern exp = r"[eE][+-]?[0-9]+")]
+#[logos(subpattern hex_exp = r"[pP][+-]?[0-9]+")]
+#[logos(error = LexError)]
 pub enum Token<'a> {
     #[regex(r"(?&letter)((?&letter)|(?&digit))*")]
     Id(&'a str),
 
-    #[regex(r#""(?:[^"\\]|\\.)*""#,parse_qt_lit)]
-    #[regex(r#"'(?:[^'\\]|\\.)*'"#,parse_qt_lit)]
+    #[regex(r#""(?:[^"\\\r\n]|\\(?:z\s*|\r\n|\n\r|[\s\S]))*""#, parse_qt_lit)]
+    #[regex(r#"'(?:[^'\\\r\n]|\\(?:z\s*|\r\n|\n\r|[\s\S]))*'"#, parse_qt_lit)]
+    #[regex(r#""(?:[^"\\\r\n]|\\(?:z\s*|\r\n|\n\r|[\s\S]))*"#, unfinished_string)]
+    #[regex(r#"'(?:[^'\\\r\n]|\\(?:z\s*|\r\n|\n\r|[\s\S]))*"#, unfinished_string)]
     #[regex(r"\[=*\[", parse_block_text)]
-    StringLit(&'a str),
-
-    #[regex(r"-?(?&digit)", number)]
-    #[regex(r"-?(?&digit)(?&exp)", number)]
-    #[regex(r"-?(?&digit)?\.(?&digit)(?&exp)?[fFdD]?", float)]
-    #[regex(r"0[bB][01][01]*", binary)]
-    #[regex(r"-?0x[0-9a-f](([0-9a-f]|[_])*[0-9a-f])?", hex)]
+    StringLit(Cow<'a, [u8]>),
+
+    #[regex(r"(?&digit)", number)]
+    #[regex(r"((?&digit)\.(?&digit)?|\.(?&digit))(?&exp)?", float)]
+    #[regex(r"(?&digit)(?&exp)", float)]
+    #[regex(r"0[xX](?&hex)", hex)]
+    #[regex(r"0[xX]((?&hex)\.(?&hex)?|\.(?&hex))(?&hex_exp)?", hex_float)]
+    #[regex(r"0[xX](?&hex)(?&hex_exp)", hex_float)]
+    #[regex(r"0[bB][01]+", binary)]
+    #[regex(r"\.?[0-9][0-9a-zA-Z_.]*", malformed, priority = 0)]
     Digit(Number),
 
     #[token("and")]
@@ -142,34 +154,183 @@ pub enum Token<'a> {
     EllipsisOut,
 
     #[regex(r"#![^\r\n]*", skip)]
-    #[regex(r"--[^\[\r\n]*[\r\n]?", skip)]
-    #[regex(r"--\[=*[^\[\r\n]*[\r\n]?", skip)]
+    #[token("--", parse_comment)]
     Comment,
 
-    #[regex(r"--\[=*\[", parse_line_comment)]
-    LineComment,
-
     #[regex(r"[ \t\u000C\r\n]+", skip)]
     WS,
 
-    #[error]
+    /// The input the lexer rejected, the parser reports it with the reason of the lexer.
     Error,
 }
 
-fn parse_line_comment<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> FilterResult<()> {
-    let prefix: &str = lexer.slice();
-    let suffix = prefix.replace("[", "]");
-    let suffix = suffix.strip_prefix("--");
-    let suffix: &str = suffix.expect("unreachable in parsing line comments");
+impl<'a> Display for Token<'a> {
+    /// Prints the token as it is expected by the parser,
+    /// namely the lexeme for keywords and symbols and the kind for names, strings and numbers.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let lexeme = match self {
+            Token::Id(_) => return f.write_str("<name>"),
+            Token::StringLit(_) => return f.write_str("<string>"),
+            Token::Digit(_) => return f.write_str("<number>"),
+            Token::Comment => return f.write_str("<comment>"),
+            Token::WS => return f.write_str("<whitespace>"),
+            Token::Error => return f.write_str("<error>"),
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Mult => "*",
+            Token::Div => "/",
+            Token::FDiv => "//",
+            Token::Mod => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Ampersand => "&",
+            Token::Tilde => "~",
+            Token::Stick => "|",
+            Token::RShift => ">>",
+            Token::LShift => "<<",
+            Token::Eq => "==",
+            Token::TEq => "~=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBrack => "[",
+            Token::RBrack => "]",
+            Token::DColon => "::",
+            Token::Colon => ":",
+            Token::Semi => ";",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::EllipsisIn => "..",
+            Token::EllipsisOut => "...",
+        };
+        write!(f, "'{}'", lexeme)
+    }
+}
 
-    lexer
-        .remainder()
-        .find(suffix)
-        .map(|i| lexer.bump(i + suffix.len()))
-        .map(|_| FilterResult::Skip)
-        .unwrap_or(FilterResult::Error)
+/// The reason the lexer rejects the input.
+/// The `range` points at the broken part when it is narrower than the token, like an escape sequence.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LexError {
+    pub reason: String,
+    pub range: Option<Range<usize>>,
+}
+
+impl LexError {
+    pub fn new(reason: &str, range: Range<usize>) -> Self {
+        LexError { reason: reason.to_string(), range: Some(range) }
+    }
+}
+
+impl From<String> for LexError {
+    fn from(reason: String) -> Self {
+        LexError { reason, range: None }
+    }
+}
+
+/// The lexer that yields every token along with its location in the source.
+pub struct SpannedLexer<'a> {
+    delegate: Lexer<'a, Token<'a>>,
+    lines: LineIndex<'a>,
+}
+
+impl<'a> SpannedLexer<'a> {
+    pub fn new(src: &'a str) -> Self {
+        SpannedLexer {
+            delegate: Token::lexer(src),
+            lines: LineIndex::new(src),
+        }
+    }
+
+    /// The span of the whole last token, the one of an error may point at its broken part only.
+    pub(crate) fn token_span(&self) -> Span {
+        self.lines.span(self.delegate.span())
+    }
 }
-fn parse_block_text<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> FilterResult<&'a str> {
+
+impl<'a> Iterator for SpannedLexer<'a> {
+    type Item = (Result<Token<'a>, LexError>, Span);
+
+    /// The span of the error is the broken part of the token if it is known.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.delegate.next().map(|t| {
+            let range = match &t {
+                Err(LexError { range: Some(range), .. }) => range.clone(),
+                _ => self.delegate.span(),
+            };
+            (t, self.lines.span(range))
+        })
+    }
+}
+
+fn parse_comment<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> FilterResult<(), LexError> {
+    let start = lexer.span().start;
+    match comment_len(&lexer.source()[start..]) {
+        Ok(len) => {
+            lexer.bump(len - 2);
+            FilterResult::Skip
+        }
+        Err(LexError { reason, range }) => {
+            // the unclosed comment runs up to the end of the source
+            lexer.bump(lexer.remainder().len());
+            FilterResult::Error(LexError {
+                reason,
+                range: range.map(|r| r.start + start..r.end + start),
+            })
+        }
+    }
+}
+
+/// The length of the comment the source starts with:
+/// the long one `--[==[ .. ]==]` or the one running up to the end of the line.
+pub(crate) fn comment_len(src: &str) -> Result<usize, LexError> {
+    let body = &src[2..];
+    match long_bracket(body) {
+        Some(open) => {
+            let close = body[..open].replace('[', "]");
+            body[open..]
+                .find(&close)
+                .map(|i| 2 + open + i + close.len())
+                .ok_or_else(|| LexError::new(&format!("unclosed --{}", &body[..open]), 0..2 + open))
+        }
+        None => Ok(2 + body.find(['\r', '\n']).unwrap_or(body.len())),
+    }
+}
+
+/// The length of the opening long bracket `[==[` the text starts with.
+fn long_bracket(text: &str) -> Option<usize> {
+    let level = text.strip_prefix('[')?.bytes().take_while(|b| *b == b'=').count();
+    (text.as_bytes().get(level + 1) == Some(&b'[')).then_some(level + 2)
+}
+
+fn parse_block_text<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> FilterResult<Cow<'a, [u8]>, LexError> {
     let prefix: &str = lexer.slice();
     let suffix = &prefix.replace("[", "]");
 
@@ -179,50 +340,267 @@ fn parse_block_text<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> FilterResult<&'a st
         .map(|i| {
             let text = &lexer.remainder()[0..i];
             lexer.bump(i + suffix.len());
-            text
+            long_text(text)
         })
-        .map(|s| FilterResult::Emit(s))
-        .unwrap_or(FilterResult::Error)
+        .map(FilterResult::Emit)
+        .unwrap_or_else(|| {
+            // the unclosed text runs up to the end of the source
+            let range = lexer.span();
+            lexer.bump(lexer.remainder().len());
+            FilterResult::Error(LexError::new(&format!("unclosed {}", prefix), range))
+        })
+}
+
+/// The content of the long bracket without the first line break,
+/// every line break sequence (`\n`, `\r`, `\r\n` or `\n\r`) turns into `\n`.
+fn long_text(text: &str) -> Cow<'_, [u8]> {
+    let bytes = text.as_bytes();
+    let bytes = &bytes[line_break(bytes)..];
+    if !bytes.contains(&b'\r') {
+        return Cow::Borrowed(bytes);
+    }
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match line_break(&bytes[i..]) {
+            0 => {
+                res.push(bytes[i]);
+                i += 1;
+            }
+            len => {
+                res.push(b'\n');
+                i += len;
+            }
+        }
+    }
+    Cow::Owned(res)
+}
+
+/// The length of the line break sequence the bytes start with.
+fn line_break(bytes: &[u8]) -> usize {
+    match bytes {
+        [b'\r', b'\n', ..] | [b'\n', b'\r', ..] => 2,
+        [b'\r' | b'\n', ..] => 1,
+        _ => 0,
+    }
 }
-fn parse_qt_lit<'a>(lexer: &mut Lexer<'a, Token<'a>>) ->  &'a str {
+
+fn parse_qt_lit<'a>(lexer: &mut Lexer<'a, Token<'a>>) -> Result<Cow<'a, [u8]>, LexError> {
     let qt_lit: &str = lexer.slice();
-    &qt_lit[1..qt_lit.len() - 1]
+    unescape(&qt_lit[1..qt_lit.len() - 1], lexer.span().start + 1)
+}
+
+fn unfinished_string<'a>(_lexer: &mut Lexer<'a, Token<'a>>) -> Result<Cow<'a, [u8]>, LexError> {
+    Err("unfinished string".to_string().into())
 }
 
+/// Decodes the escape sequences of the short string.
+/// The `offset` of the string in the source is used to point at the broken escape sequence.
+fn unescape(raw: &str, offset: usize) -> Result<Cow<'_, [u8]>, LexError> {
+    if !raw.contains('\\') {
+        return Ok(Cow::Borrowed(raw.as_bytes()));
+    }
+    let bytes = raw.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            res.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let error = |end: usize, reason: &str| LexError::new(reason, offset + start..offset + end.min(bytes.len()));
+        let hex_digit = |i: usize| bytes.get(i).and_then(|b| (*b as char).to_digit(16));
+        i += 2;
+        match bytes[i - 1] {
+            b'a' => res.push(0x07),
+            b'b' => res.push(0x08),
+            b'f' => res.push(0x0C),
+            b'n' => res.push(b'\n'),
+            b'r' => res.push(b'\r'),
+            b't' => res.push(b'\t'),
+            b'v' => res.push(0x0B),
+            c @ (b'\\' | b'"' | b'\'') => res.push(c),
+            b'\r' | b'\n' => {
+                res.push(b'\n');
+                i = start + 1 + line_break(&bytes[start + 1..]);
+            }
+            b'z' => {
+                while i < bytes.len() && matches!(bytes[i], b' ' | b'\t' | b'\n' | b'\r' | 0x0B | 0x0C) {
+                    i += 1;
+                }
+            }
+            b'x' => {
+                let mut v = 0;
+                for _ in 0..2 {
+                    v = v * 16 + hex_digit(i).ok_or_else(|| error(i + 1, "hexadecimal digit expected"))?;
+                    i += 1;
+                }
+                res.push(v as u8);
+            }
+            c @ b'0'..=b'9' => {
+                let mut v = (c - b'0') as u32;
+                while i < bytes.len() && i < start + 4 && bytes[i].is_ascii_digit() {
+                    v = v * 10 + (bytes[i] - b'0') as u32;
+                    i += 1;
+                }
+                if v > 0xFF {
+                    return Err(error(i, "decimal escape too large"));
+                }
+                res.push(v as u8);
+            }
+            b'u' => {
+                if bytes.get(i) != Some(&b'{') {
+                    return Err(error(i + 1, "missing '{' in \\u{xxxx}"));
+                }
+                i += 1;
+                let mut v = hex_digit(i).ok_or_else(|| error(i + 1, "hexadecimal digit expected"))?;
+                i += 1;
+                while let Some(d) = hex_digit(i) {
+                    if v > 0x7FF_FFFF {
+                        return Err(error(i + 1, "UTF-8 value too large"));
+                    }
+                    v = v * 16 + d;
+                    i += 1;
+                }
+                if bytes.get(i) != Some(&b'}') {
+                    return Err(error(i + 1, "missing '}' in \\u{xxxx}"));
+                }
+                i += 1;
+                utf8_esc(v, &mut res);
+            }
+            _ => {
+                let len = raw[start + 1..].chars().next().map(char::len_utf8).unwrap_or(1);
+                return Err(error(start + 1 + len, "invalid escape sequence"));
+            }
+        }
+    }
+    Ok(Cow::Owned(res))
+}
+
+/// Encodes the code point the way lua does,
+/// namely the values up to 2^31 are encoded with up to 6 bytes.
+fn utf8_esc(mut x: u32, res: &mut Vec<u8>) {
+    if x < 0x80 {
+        res.push(x as u8);
+        return;
+    }
+    let mut buf = [0u8; 8];
+    let mut n = 1;
+    let mut mfb = 0x3F;
+    loop {
+        buf[8 - n] = 0x80 | (x & 0x3F) as u8;
+        n += 1;
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    buf[8 - n] = ((!mfb << 1) | x) as u8;
+    res.extend_from_slice(&buf[8 - n..]);
+}
 
+/// The decimal integer that does not fit into i64 turns into a float.
 fn number<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Result<Number, String> {
     lex.slice()
         .parse::<i64>()
-        .map(|r| Number::Int(r))
+        .map(Number::Int)
+        .or_else(|_| lex.slice().parse::<f64>().map(Number::Float))
         .map_err(|s| s.to_string())
 }
 
 fn float<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Result<Number, String> {
     lex.slice()
         .parse::<f64>()
-        .map(|r| Number::Float(r))
+        .map(Number::Float)
         .map_err(|s| s.to_string())
 }
 
-fn binary<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Result<Number, String> {
-    isize::from_str_radix(&lex.slice()[2..], 2)
-        .map(Number::Binary)
-        .map_err(|s| s.to_string())
+/// The binary integers are an extension, they wrap around modulo 2^64 like the hexadecimal ones.
+fn binary<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Number {
+    let v = lex.slice()[2..]
+        .bytes()
+        .fold(0u64, |acc, b| acc.wrapping_mul(2).wrapping_add((b - b'0') as u64));
+    Number::Binary(v as i64 as isize)
 }
 
-fn hex<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Result<Number, String> {
-    i64::from_str_radix(lex.slice().trim_start_matches("0x"), 16)
-        .map(|r| Number::Hex(r))
-        .map_err(|s| s.to_string())
+/// The hexadecimal integers wrap around modulo 2^64.
+fn hex<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Number {
+    let v = lex.slice()[2..]
+        .chars()
+        .filter_map(|c| c.to_digit(16))
+        .fold(0u64, |acc, d| acc.wrapping_mul(16).wrapping_add(d as u64));
+    Number::Hex(v as i64)
+}
+
+/// The hexadecimal float `0x<int>.<fraction>p<exp>` where the exponent is binary.
+/// Follows `lua_strx2number`: only the first 30 significant digits are taken into account.
+fn hex_float<'a>(lex: &mut Lexer<'a, Token<'a>>) -> Number {
+    const MAX_SIG_DIGITS: usize = 30;
+    let digits = &lex.slice()[2..];
+    let (mantissa, bin_exp) = match digits.split_once(['p', 'P']) {
+        // the exponents beyond i64 saturate like the ones beyond the clamp
+        Some((m, e)) => {
+            let exp = e.parse::<i64>().unwrap_or(if e.starts_with('-') { i64::MIN } else { i64::MAX });
+            (m, exp.clamp(-100_000, 100_000))
+        }
+        None => (digits, 0),
+    };
+
+    let mut v = 0.0;
+    let mut exp: i64 = 0;
+    let mut sig_digits = 0;
+    let mut fraction = false;
+    for c in mantissa.chars() {
+        let Some(d) = c.to_digit(16) else {
+            fraction = true;
+            continue;
+        };
+        if sig_digits == 0 && d == 0 {
+            // leading zeros are not significant
+        } else if sig_digits < MAX_SIG_DIGITS {
+            sig_digits += 1;
+            v = v * 16.0 + d as f64;
+        } else {
+            exp += 1;
+        }
+        if fraction {
+            exp -= 1;
+        }
+    }
+    Number::Float(ldexp(v, exp * 4 + bin_exp))
 }
 
+/// `x * 2^exp` that does not overflow on the way.
+fn ldexp(mut x: f64, mut exp: i64) -> f64 {
+    while exp > 1000 && x.is_finite() && x != 0.0 {
+        x *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 && x != 0.0 {
+        x *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    x * 2f64.powi(exp as i32)
+}
 
+/// A numeral glued to letters or dots, like `3x`, `1..2` or `0x`.
+fn malformed<'a>(_lex: &mut Lexer<'a, Token<'a>>) -> Result<Number, String> {
+    Err("malformed number".to_string())
+}
 
 #[cfg(test)]
 mod tests {
     use parsit::test::lexer_test as lt;
     use crate::parser::ast::Number;
-    use crate::parser::tokens::Token;
+    use std::borrow::Cow;
+    use crate::parser::tokens::{SpannedLexer, Token};
+
+    fn str(v: &str) -> Token<'_> {
+        Token::StringLit(Cow::Borrowed(v.as_bytes()))
+    }
 
     #[test]
     fn comments() {
@@ -252,35 +630,132 @@ mod tests {
             r#"--[==[hjasgdkjasd
             askldhfklsdf
             ]==]
-            a"#, vec![Token::Id("a")])
+            a"#, vec![Token::Id("a")]);
+
+        lt::expect::<Token>("-- see t[1] and [[x]]\na", vec![Token::Id("a")]);
+        lt::expect::<Token>("--[=[ ]] ]=] a --[ [[", vec![Token::Id("a")]);
+        let error = SpannedLexer::new("a --[==[ x ]]")
+            .find_map(|(t, span)| t.err().map(|e| (e.reason, span.range())));
+        assert_eq!(error, Some(("unclosed --[==[".to_string(), 2..8)));
+        assert_eq!(SpannedLexer::new("a --[[ x = 1").count(), 2);
+        let tokens: Vec<_> = SpannedLexer::new("a = [==[ x = 1").map(|(t, span)| (t.is_ok(), span.range())).collect();
+        assert_eq!(tokens, [(true, 0..1), (true, 2..3), (false, 4..8)]);
     }
     #[test]
     fn text() {
 
-        lt::expect::<Token>(r#""[/\\.-]""#,vec![Token::StringLit(r#"[/\\.-]"#)]);
+        lt::expect::<Token>(r#""[/\\.-]""#,vec![str(r#"[/\.-]"#)]);
 
         lt::expect::<Token>(r#"
         #! some
-        "text""#, vec![Token::StringLit("text")]);
+        "text""#, vec![str("text")]);
 
-        lt::expect::<Token>("\"te\\\"xt\"", vec![Token::StringLit("te\\\"xt")]);
-        lt::expect::<Token>("'te\\'xt'", vec![Token::StringLit("te\\'xt")]);
+        lt::expect::<Token>("\"te\\\"xt\"", vec![str("te\"xt")]);
+        lt::expect::<Token>("'te\\'xt'", vec![str("te'xt")]);
 
         lt::expect::<Token>(
             r#"[==[hjasgdkjasd
             askldhfklsdf
-            ]==]"#, vec![Token::StringLit("hjasgdkjasd\n            askldhfklsdf\n            ")])
+            ]==]"#, vec![str("hjasgdkjasd\n            askldhfklsdf\n            ")]);
+        lt::expect::<Token>("[[\nfirst\r\nsecond]]", vec![str("first\nsecond")]);
+        lt::expect::<Token>("[==[\r\n]]]==]", vec![str("]]")]);
+    }
+
+    #[test]
+    fn escapes() {
+        lt::expect::<Token>(r#""\a\b\f\n\r\t\v\\\"\'""#, vec![str("\x07\x08\x0C\n\r\t\x0B\\\"'")]);
+        lt::expect::<Token>("'a\\\nb\\\r\nc'", vec![str("a\nb\nc")]);
+        lt::expect::<Token>("'a\\z  \n\t b'", vec![str("ab")]);
+        lt::expect::<Token>(r#"'\x41\x7a\65\0659\0'"#, vec![str("AzAA9\0")]);
+        lt::expect::<Token>(r#"'\xff\255'"#, vec![Token::StringLit(Cow::Owned(vec![0xFF, 0xFF]))]);
+        lt::expect::<Token>(r#"'\u{48}\u{416}\u{20AC}\u{1F600}'"#, vec![str("HЖ€😀")]);
+        lt::expect::<Token>(r#"'\u{7FFFFFFF}'"#, vec![Token::StringLit(Cow::Owned(vec![0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]))]);
     }
+
+    #[test]
+    fn escape_errors() {
+        fn error(src: &str) -> (String, &str) {
+            let (t, span) = SpannedLexer::new(src).find(|(t, _)| t.is_err()).unwrap();
+            (t.unwrap_err().reason, &src[span.range()])
+        }
+        assert_eq!(error(r#"a = "x\qy""#), ("invalid escape sequence".to_string(), r"\q"));
+        assert_eq!(error(r#"a = "x\жy""#), ("invalid escape sequence".to_string(), r"\ж"));
+        assert_eq!(error(r#"a = "\x4g""#), ("hexadecimal digit expected".to_string(), r"\x4g"));
+        assert_eq!(error(r#"a = "\256""#), ("decimal escape too large".to_string(), r"\256"));
+        assert_eq!(error(r#"a = "\u{80000000}""#), ("UTF-8 value too large".to_string(), r"\u{80000000"));
+        assert_eq!(error(r#"a = "\u{12""#), ("missing '}' in \\u{xxxx}".to_string(), r"\u{12"));
+        assert_eq!(error("a = \"abc\nb = 1"), ("unfinished string".to_string(), "\"abc"));
+    }
+
     #[test]
     fn number() {
         lt::expect::<Token>(r#"1"#, vec![Token::Digit(Number::Int(1))]);
         lt::expect::<Token>(r#"1.1"#, vec![Token::Digit(Number::Float(1.1))]);
         lt::expect::<Token>(r#"1000000.000001"#, vec![Token::Digit(Number::Float(1000000.000001))]);
-        lt::expect::<Token>(r#"1e-1"#, vec![Token::Digit(Number::Float(1000000.000001))]);
+        lt::expect::<Token>(r#"1e-1"#, vec![Token::Digit(Number::Float(0.1))]);
+        lt::expect::<Token>(r#"5."#, vec![Token::Digit(Number::Float(5.0))]);
+        lt::expect::<Token>(r#".5E2"#, vec![Token::Digit(Number::Float(50.0))]);
+        lt::expect::<Token>(r#"3.e1"#, vec![Token::Digit(Number::Float(30.0))]);
+        lt::expect::<Token>(r#"9223372036854775807"#, vec![Token::Digit(Number::Int(i64::MAX))]);
+        lt::expect::<Token>(r#"9223372036854775808"#, vec![Token::Digit(Number::Float(9223372036854775808.0))]);
+    }
 
+    #[test]
+    fn hex_number() {
+        lt::expect::<Token>(r#"0xff"#, vec![Token::Digit(Number::Hex(255))]);
+        lt::expect::<Token>(r#"0XFF"#, vec![Token::Digit(Number::Hex(255))]);
+        lt::expect::<Token>(r#"0xAb"#, vec![Token::Digit(Number::Hex(171))]);
+        lt::expect::<Token>(r#"0x7fffffffffffffff"#, vec![Token::Digit(Number::Hex(i64::MAX))]);
+        lt::expect::<Token>(r#"0xffffffffffffffff"#, vec![Token::Digit(Number::Hex(-1))]);
+        lt::expect::<Token>(r#"0x1ffffffffffffffff"#, vec![Token::Digit(Number::Hex(-1))]);
+        lt::expect::<Token>(r#"0x10000000000000000"#, vec![Token::Digit(Number::Hex(0))]);
+
+        lt::expect::<Token>(r#"0x1p4"#, vec![Token::Digit(Number::Float(16.0))]);
+        lt::expect::<Token>(r#"0x.8"#, vec![Token::Digit(Number::Float(0.5))]);
+        lt::expect::<Token>(r#"0xA."#, vec![Token::Digit(Number::Float(10.0))]);
+        lt::expect::<Token>(r#"0x1.8P-1"#, vec![Token::Digit(Number::Float(0.75))]);
+        lt::expect::<Token>(r#"0x.1p+4"#, vec![Token::Digit(Number::Float(1.0))]);
+        lt::expect::<Token>(r#"0x1p-1074"#, vec![Token::Digit(Number::Float(f64::from_bits(1)))]);
+        lt::expect::<Token>(r#"0x1p1024"#, vec![Token::Digit(Number::Float(f64::INFINITY))]);
+        lt::expect::<Token>(r#"0x1p99999999999999999999"#, vec![Token::Digit(Number::Float(f64::INFINITY))]);
+        lt::expect::<Token>(r#"0x1p+99999999999999999999"#, vec![Token::Digit(Number::Float(f64::INFINITY))]);
+        lt::expect::<Token>(r#"0x1p-99999999999999999999"#, vec![Token::Digit(Number::Float(0.0))]);
+        lt::expect::<Token>(
+            r#"0x123456789abcdef0123456789abcdef0p0"#,
+            vec![Token::Digit(Number::Float(2.4197857203266735e37))],
+        );
     }
 
+    #[test]
+    fn binary_number() {
+        lt::expect::<Token>(r#"0b101"#, vec![Token::Digit(Number::Binary(5))]);
+        lt::expect::<Token>(r#"0B11"#, vec![Token::Digit(Number::Binary(3))]);
+    }
 
+    #[test]
+    fn malformed_number() {
+        for src in ["3x", "1_000", "1..2", "0x", "1e", "0xp1", "1.5.3", "2f"] {
+            let (t, span) = SpannedLexer::new(src).next().unwrap();
+            assert_eq!(t.unwrap_err().reason, "malformed number", "{}", src);
+            assert_eq!(span.range(), 0..src.len(), "{}", src);
+        }
+    }
 
+    #[test]
+    fn spans() {
+        let spans: Vec<_> = SpannedLexer::new("local a = [[x]]\n  -- c\n  f(a)")
+            .map(|(t, s)| (t.unwrap(), s.range(), s.line, s.col))
+            .collect();
+
+        assert_eq!(spans, vec![
+            (Token::Local, 0..5, 1, 1),
+            (Token::Id("a"), 6..7, 1, 7),
+            (Token::Assign, 8..9, 1, 9),
+            (str("x"), 10..15, 1, 11),
+            (Token::Id("f"), 25..26, 3, 3),
+            (Token::LParen, 26..27, 3, 4),
+            (Token::Id("a"), 27..28, 3, 5),
+            (Token::RParen, 28..29, 3, 6),
+        ]);
+    }
 }
-
@@ -0,0 +1,891 @@
+use crate::parser::ast::*;
+
+/// The traversal of the ast by reference.
+/// Every method walks the children of the node by default,
+/// an override can do its work and call the `walk_` function to keep going deeper.
+pub trait Visitor<'a> {
+    fn visit_block(&mut self, block: &Block<'a>) {
+        walk_block(self, block)
+    }
+    fn visit_statement(&mut self, st: &Statement<'a>) {
+        walk_statement(self, st)
+    }
+    fn visit_expr(&mut self, expr: &Expression<'a>) {
+        walk_expr(self, expr)
+    }
+    fn visit_id(&mut self, _id: &Id<'a>) {}
+    fn visit_text(&mut self, _text: &Text<'a>) {}
+    fn visit_fn_call(&mut self, call: &FnCall<'a>) {
+        walk_fn_call(self, call)
+    }
+    fn visit_var(&mut self, var: &Var<'a>) {
+        walk_var(self, var)
+    }
+    fn visit_var_suffix(&mut self, suffix: &VarSuffix<'a>) {
+        walk_var_suffix(self, suffix)
+    }
+    fn visit_name_args(&mut self, name_args: &NameArgs<'a>) {
+        walk_name_args(self, name_args)
+    }
+    fn visit_args(&mut self, args: &Args<'a>) {
+        walk_args(self, args)
+    }
+    fn visit_table(&mut self, table: &TableConst<'a>) {
+        walk_table(self, table)
+    }
+    fn visit_field(&mut self, field: &Field<'a>) {
+        walk_field(self, field)
+    }
+    fn visit_fn_params(&mut self, params: &FnParams<'a>) {
+        walk_fn_params(self, params)
+    }
+    fn visit_fn_name(&mut self, name: &FnName<'a>) {
+        walk_fn_name(self, name)
+    }
+    /// Both the global and the local function statements.
+    fn visit_fn_def(&mut self, def: &FnDef<'a>) {
+        walk_fn_def(self, def)
+    }
+    fn visit_attr_name(&mut self, attr: &AttrName<'a>) {
+        walk_attr_name(self, attr)
+    }
+    fn visit_while(&mut self, w: &While<'a>) {
+        walk_while(self, w)
+    }
+    fn visit_repeat(&mut self, r: &Repeat<'a>) {
+        walk_repeat(self, r)
+    }
+    fn visit_if(&mut self, i: &If<'a>) {
+        walk_if(self, i)
+    }
+    fn visit_if_branch(&mut self, branch: &IfBranch<'a>) {
+        walk_if_branch(self, branch)
+    }
+    fn visit_for(&mut self, f: &For<'a>) {
+        walk_for(self, f)
+    }
+    fn visit_plain_for(&mut self, f: &PlainFor<'a>) {
+        walk_plain_for(self, f)
+    }
+    fn visit_expr_for(&mut self, f: &ExprFor<'a>) {
+        walk_expr_for(self, f)
+    }
+}
+
+pub fn walk_block<'a, V: Visitor<'a> + ?Sized>(v: &mut V, block: &Block<'a>) {
+    match block {
+        Block::Void(sts, _) => sts.iter().for_each(|st| v.visit_statement(st)),
+        Block::Return(sts, exprs, _) => {
+            sts.iter().for_each(|st| v.visit_statement(st));
+            exprs.iter().for_each(|e| v.visit_expr(e));
+        }
+    }
+}
+
+pub fn walk_statement<'a, V: Visitor<'a> + ?Sized>(v: &mut V, st: &Statement<'a>) {
+    match st {
+        Statement::Assignment(vars, exprs) => {
+            vars.iter().for_each(|var| v.visit_var(var));
+            exprs.iter().for_each(|e| v.visit_expr(e));
+        }
+        Statement::FnCall(call) => v.visit_fn_call(call),
+        Statement::Label(id, _) | Statement::Goto(id, _) => v.visit_id(id),
+        Statement::Do(body, _) => v.visit_block(body),
+        Statement::While(w) => v.visit_while(w),
+        Statement::Repeat(r) => v.visit_repeat(r),
+        Statement::If(i) => v.visit_if(i),
+        Statement::For(f) => v.visit_for(f),
+        Statement::FnDef(def) | Statement::LocalFnDef(def) => v.visit_fn_def(def),
+        Statement::LocalAttrNames(attrs, exprs, _) => {
+            attrs.iter().for_each(|a| v.visit_attr_name(a));
+            exprs.iter().for_each(|e| v.visit_expr(e));
+        }
+        Statement::Empty(_) | Statement::Break(_) | Statement::Error(_) => {}
+    }
+}
+
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(v: &mut V, expr: &Expression<'a>) {
+    match expr {
+        Expression::Text(text) => v.visit_text(text),
+        Expression::FnDef(params, body, _) => {
+            v.visit_fn_params(params);
+            v.visit_block(body);
+        }
+        Expression::PrefixExpr(call) => v.visit_fn_call(call),
+        Expression::TableConstructor(table) => v.visit_table(table),
+        Expression::Unary(_, e, _) => v.visit_expr(e),
+        Expression::Binary(lhs, _, rhs) => {
+            v.visit_expr(lhs);
+            v.visit_expr(rhs);
+        }
+        Expression::Nil(_)
+        | Expression::False(_)
+        | Expression::True(_)
+        | Expression::Number(..)
+        | Expression::VarArgs(_)
+        | Expression::Error(_) => {}
+    }
+}
+
+pub fn walk_fn_call<'a, V: Visitor<'a> + ?Sized>(v: &mut V, call: &FnCall<'a>) {
+    match &call.head {
+        VarOrExpr::Expr(e) => v.visit_expr(e),
+        VarOrExpr::Var(var) => v.visit_var(var),
+    }
+    call.args.iter().for_each(|a| v.visit_name_args(a));
+}
+
+pub fn walk_var<'a, V: Visitor<'a> + ?Sized>(v: &mut V, var: &Var<'a>) {
+    match &var.head {
+        VarHead::Id(id) => v.visit_id(id),
+        VarHead::Expr(e, suffix) => {
+            v.visit_expr(e);
+            v.visit_var_suffix(suffix);
+        }
+    }
+    var.tail.iter().for_each(|s| v.visit_var_suffix(s));
+}
+
+pub fn walk_var_suffix<'a, V: Visitor<'a> + ?Sized>(v: &mut V, suffix: &VarSuffix<'a>) {
+    suffix.var.iter().for_each(|a| v.visit_name_args(a));
+    match &suffix.suffix {
+        Suffix::Expr(e) => v.visit_expr(e),
+        Suffix::Id(id) => v.visit_id(id),
+    }
+}
+
+pub fn walk_name_args<'a, V: Visitor<'a> + ?Sized>(v: &mut V, name_args: &NameArgs<'a>) {
+    match name_args {
+        NameArgs::Args(args) => v.visit_args(args),
+        NameArgs::NameArgs(name, args) => {
+            v.visit_id(name);
+            v.visit_args(args);
+        }
+    }
+}
+
+pub fn walk_args<'a, V: Visitor<'a> + ?Sized>(v: &mut V, args: &Args<'a>) {
+    match args {
+        Args::Expressions(exprs, _) => exprs.iter().for_each(|e| v.visit_expr(e)),
+        Args::Constructor(table) => v.visit_table(table),
+        Args::String(text) => v.visit_text(text),
+    }
+}
+
+pub fn walk_table<'a, V: Visitor<'a> + ?Sized>(v: &mut V, table: &TableConst<'a>) {
+    table.fields.iter().for_each(|f| v.visit_field(f));
+}
+
+pub fn walk_field<'a, V: Visitor<'a> + ?Sized>(v: &mut V, field: &Field<'a>) {
+    match field {
+        Field::Pair(FieldKey::Expr(key), value) => {
+            v.visit_expr(key);
+            v.visit_expr(value);
+        }
+        Field::Pair(FieldKey::Id(key), value) => {
+            v.visit_id(key);
+            v.visit_expr(value);
+        }
+        Field::Value(value) => v.visit_expr(value),
+    }
+}
+
+pub fn walk_fn_params<'a, V: Visitor<'a> + ?Sized>(v: &mut V, params: &FnParams<'a>) {
+    match params {
+        FnParams::Args(ids) | FnParams::WithVarArgs(ids) => ids.iter().for_each(|id| v.visit_id(id)),
+        FnParams::VarArgs => {}
+    }
+}
+
+pub fn walk_fn_name<'a, V: Visitor<'a> + ?Sized>(v: &mut V, name: &FnName<'a>) {
+    name.names.iter().chain(name.last.iter()).for_each(|id| v.visit_id(id));
+}
+
+pub fn walk_fn_def<'a, V: Visitor<'a> + ?Sized>(v: &mut V, def: &FnDef<'a>) {
+    v.visit_fn_name(&def.name);
+    v.visit_fn_params(&def.params);
+    v.visit_block(&def.body);
+}
+
+pub fn walk_attr_name<'a, V: Visitor<'a> + ?Sized>(v: &mut V, attr: &AttrName<'a>) {
+    match attr {
+        AttrName::Name(name) => v.visit_id(name),
+        AttrName::AttrName(name, attr) => {
+            v.visit_id(name);
+            v.visit_id(attr);
+        }
+    }
+}
+
+pub fn walk_while<'a, V: Visitor<'a> + ?Sized>(v: &mut V, w: &While<'a>) {
+    v.visit_expr(&w.cond);
+    v.visit_block(&w.body);
+}
+
+pub fn walk_repeat<'a, V: Visitor<'a> + ?Sized>(v: &mut V, r: &Repeat<'a>) {
+    v.visit_block(&r.body);
+    v.visit_expr(&r.until);
+}
+
+pub fn walk_if<'a, V: Visitor<'a> + ?Sized>(v: &mut V, i: &If<'a>) {
+    match i {
+        If::If(main, others, _) => {
+            v.visit_if_branch(main);
+            others.iter().for_each(|b| v.visit_if_branch(b));
+        }
+        If::IfElse(main, others, els, _) => {
+            v.visit_if_branch(main);
+            others.iter().for_each(|b| v.visit_if_branch(b));
+            v.visit_block(els);
+        }
+    }
+}
+
+pub fn walk_if_branch<'a, V: Visitor<'a> + ?Sized>(v: &mut V, branch: &IfBranch<'a>) {
+    v.visit_expr(&branch.cond);
+    v.visit_block(&branch.body);
+}
+
+pub fn walk_for<'a, V: Visitor<'a> + ?Sized>(v: &mut V, f: &For<'a>) {
+    match f {
+        For::Plain(f) => v.visit_plain_for(f),
+        For::ForCol(f) => v.visit_expr_for(f),
+    }
+}
+
+pub fn walk_plain_for<'a, V: Visitor<'a> + ?Sized>(v: &mut V, f: &PlainFor<'a>) {
+    v.visit_id(&f.init.0);
+    v.visit_expr(&f.init.1);
+    v.visit_expr(&f.border);
+    f.step.iter().for_each(|e| v.visit_expr(e));
+    v.visit_block(&f.body);
+}
+
+pub fn walk_expr_for<'a, V: Visitor<'a> + ?Sized>(v: &mut V, f: &ExprFor<'a>) {
+    f.names.iter().for_each(|id| v.visit_id(id));
+    f.expressions.iter().for_each(|e| v.visit_expr(e));
+    v.visit_block(&f.body);
+}
+
+/// The traversal of the ast that can change the nodes in place.
+/// It mirrors `Visitor` method by method.
+pub trait VisitorMut<'a> {
+    fn visit_block_mut(&mut self, block: &mut Block<'a>) {
+        walk_block_mut(self, block)
+    }
+    fn visit_statement_mut(&mut self, st: &mut Statement<'a>) {
+        walk_statement_mut(self, st)
+    }
+    fn visit_expr_mut(&mut self, expr: &mut Expression<'a>) {
+        walk_expr_mut(self, expr)
+    }
+    fn visit_id_mut(&mut self, _id: &mut Id<'a>) {}
+    fn visit_text_mut(&mut self, _text: &mut Text<'a>) {}
+    fn visit_fn_call_mut(&mut self, call: &mut FnCall<'a>) {
+        walk_fn_call_mut(self, call)
+    }
+    fn visit_var_mut(&mut self, var: &mut Var<'a>) {
+        walk_var_mut(self, var)
+    }
+    fn visit_var_suffix_mut(&mut self, suffix: &mut VarSuffix<'a>) {
+        walk_var_suffix_mut(self, suffix)
+    }
+    fn visit_name_args_mut(&mut self, name_args: &mut NameArgs<'a>) {
+        walk_name_args_mut(self, name_args)
+    }
+    fn visit_args_mut(&mut self, args: &mut Args<'a>) {
+        walk_args_mut(self, args)
+    }
+    fn visit_table_mut(&mut self, table: &mut TableConst<'a>) {
+        walk_table_mut(self, table)
+    }
+    fn visit_field_mut(&mut self, field: &mut Field<'a>) {
+        walk_field_mut(self, field)
+    }
+    fn visit_fn_params_mut(&mut self, params: &mut FnParams<'a>) {
+        walk_fn_params_mut(self, params)
+    }
+    fn visit_fn_name_mut(&mut self, name: &mut FnName<'a>) {
+        walk_fn_name_mut(self, name)
+    }
+    fn visit_fn_def_mut(&mut self, def: &mut FnDef<'a>) {
+        walk_fn_def_mut(self, def)
+    }
+    fn visit_attr_name_mut(&mut self, attr: &mut AttrName<'a>) {
+        walk_attr_name_mut(self, attr)
+    }
+    fn visit_while_mut(&mut self, w: &mut While<'a>) {
+        walk_while_mut(self, w)
+    }
+    fn visit_repeat_mut(&mut self, r: &mut Repeat<'a>) {
+        walk_repeat_mut(self, r)
+    }
+    fn visit_if_mut(&mut self, i: &mut If<'a>) {
+        walk_if_mut(self, i)
+    }
+    fn visit_if_branch_mut(&mut self, branch: &mut IfBranch<'a>) {
+        walk_if_branch_mut(self, branch)
+    }
+    fn visit_for_mut(&mut self, f: &mut For<'a>) {
+        walk_for_mut(self, f)
+    }
+    fn visit_plain_for_mut(&mut self, f: &mut PlainFor<'a>) {
+        walk_plain_for_mut(self, f)
+    }
+    fn visit_expr_for_mut(&mut self, f: &mut ExprFor<'a>) {
+        walk_expr_for_mut(self, f)
+    }
+}
+
+pub fn walk_block_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, block: &mut Block<'a>) {
+    match block {
+        Block::Void(sts, _) => sts.iter_mut().for_each(|st| v.visit_statement_mut(st)),
+        Block::Return(sts, exprs, _) => {
+            sts.iter_mut().for_each(|st| v.visit_statement_mut(st));
+            exprs.iter_mut().for_each(|e| v.visit_expr_mut(e));
+        }
+    }
+}
+
+pub fn walk_statement_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, st: &mut Statement<'a>) {
+    match st {
+        Statement::Assignment(vars, exprs) => {
+            vars.iter_mut().for_each(|var| v.visit_var_mut(var));
+            exprs.iter_mut().for_each(|e| v.visit_expr_mut(e));
+        }
+        Statement::FnCall(call) => v.visit_fn_call_mut(call),
+        Statement::Label(id, _) | Statement::Goto(id, _) => v.visit_id_mut(id),
+        Statement::Do(body, _) => v.visit_block_mut(body),
+        Statement::While(w) => v.visit_while_mut(w),
+        Statement::Repeat(r) => v.visit_repeat_mut(r),
+        Statement::If(i) => v.visit_if_mut(i),
+        Statement::For(f) => v.visit_for_mut(f),
+        Statement::FnDef(def) | Statement::LocalFnDef(def) => v.visit_fn_def_mut(def),
+        Statement::LocalAttrNames(attrs, exprs, _) => {
+            attrs.iter_mut().for_each(|a| v.visit_attr_name_mut(a));
+            exprs.iter_mut().for_each(|e| v.visit_expr_mut(e));
+        }
+        Statement::Empty(_) | Statement::Break(_) | Statement::Error(_) => {}
+    }
+}
+
+pub fn walk_expr_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, expr: &mut Expression<'a>) {
+    match expr {
+        Expression::Text(text) => v.visit_text_mut(text),
+        Expression::FnDef(params, body, _) => {
+            v.visit_fn_params_mut(params);
+            v.visit_block_mut(body);
+        }
+        Expression::PrefixExpr(call) => v.visit_fn_call_mut(call),
+        Expression::TableConstructor(table) => v.visit_table_mut(table),
+        Expression::Unary(_, e, _) => v.visit_expr_mut(e),
+        Expression::Binary(lhs, _, rhs) => {
+            v.visit_expr_mut(lhs);
+            v.visit_expr_mut(rhs);
+        }
+        Expression::Nil(_)
+        | Expression::False(_)
+        | Expression::True(_)
+        | Expression::Number(..)
+        | Expression::VarArgs(_)
+        | Expression::Error(_) => {}
+    }
+}
+
+pub fn walk_fn_call_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, call: &mut FnCall<'a>) {
+    match &mut call.head {
+        VarOrExpr::Expr(e) => v.visit_expr_mut(e),
+        VarOrExpr::Var(var) => v.visit_var_mut(var),
+    }
+    call.args.iter_mut().for_each(|a| v.visit_name_args_mut(a));
+}
+
+pub fn walk_var_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, var: &mut Var<'a>) {
+    match &mut var.head {
+        VarHead::Id(id) => v.visit_id_mut(id),
+        VarHead::Expr(e, suffix) => {
+            v.visit_expr_mut(e);
+            v.visit_var_suffix_mut(suffix);
+        }
+    }
+    var.tail.iter_mut().for_each(|s| v.visit_var_suffix_mut(s));
+}
+
+pub fn walk_var_suffix_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, suffix: &mut VarSuffix<'a>) {
+    suffix.var.iter_mut().for_each(|a| v.visit_name_args_mut(a));
+    match &mut suffix.suffix {
+        Suffix::Expr(e) => v.visit_expr_mut(e),
+        Suffix::Id(id) => v.visit_id_mut(id),
+    }
+}
+
+pub fn walk_name_args_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, name_args: &mut NameArgs<'a>) {
+    match name_args {
+        NameArgs::Args(args) => v.visit_args_mut(args),
+        NameArgs::NameArgs(name, args) => {
+            v.visit_id_mut(name);
+            v.visit_args_mut(args);
+        }
+    }
+}
+
+pub fn walk_args_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, args: &mut Args<'a>) {
+    match args {
+        Args::Expressions(exprs, _) => exprs.iter_mut().for_each(|e| v.visit_expr_mut(e)),
+        Args::Constructor(table) => v.visit_table_mut(table),
+        Args::String(text) => v.visit_text_mut(text),
+    }
+}
+
+pub fn walk_table_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, table: &mut TableConst<'a>) {
+    table.fields.iter_mut().for_each(|f| v.visit_field_mut(f));
+}
+
+pub fn walk_field_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, field: &mut Field<'a>) {
+    match field {
+        Field::Pair(FieldKey::Expr(key), value) => {
+            v.visit_expr_mut(key);
+            v.visit_expr_mut(value);
+        }
+        Field::Pair(FieldKey::Id(key), value) => {
+            v.visit_id_mut(key);
+            v.visit_expr_mut(value);
+        }
+        Field::Value(value) => v.visit_expr_mut(value),
+    }
+}
+
+pub fn walk_fn_params_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, params: &mut FnParams<'a>) {
+    match params {
+        FnParams::Args(ids) | FnParams::WithVarArgs(ids) => ids.iter_mut().for_each(|id| v.visit_id_mut(id)),
+        FnParams::VarArgs => {}
+    }
+}
+
+pub fn walk_fn_name_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, name: &mut FnName<'a>) {
+    name.names.iter_mut().chain(name.last.iter_mut()).for_each(|id| v.visit_id_mut(id));
+}
+
+pub fn walk_fn_def_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, def: &mut FnDef<'a>) {
+    v.visit_fn_name_mut(&mut def.name);
+    v.visit_fn_params_mut(&mut def.params);
+    v.visit_block_mut(&mut def.body);
+}
+
+pub fn walk_attr_name_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, attr: &mut AttrName<'a>) {
+    match attr {
+        AttrName::Name(name) => v.visit_id_mut(name),
+        AttrName::AttrName(name, attr) => {
+            v.visit_id_mut(name);
+            v.visit_id_mut(attr);
+        }
+    }
+}
+
+pub fn walk_while_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, w: &mut While<'a>) {
+    v.visit_expr_mut(&mut w.cond);
+    v.visit_block_mut(&mut w.body);
+}
+
+pub fn walk_repeat_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, r: &mut Repeat<'a>) {
+    v.visit_block_mut(&mut r.body);
+    v.visit_expr_mut(&mut r.until);
+}
+
+pub fn walk_if_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, i: &mut If<'a>) {
+    match i {
+        If::If(main, others, _) => {
+            v.visit_if_branch_mut(main);
+            others.iter_mut().for_each(|b| v.visit_if_branch_mut(b));
+        }
+        If::IfElse(main, others, els, _) => {
+            v.visit_if_branch_mut(main);
+            others.iter_mut().for_each(|b| v.visit_if_branch_mut(b));
+            v.visit_block_mut(els);
+        }
+    }
+}
+
+pub fn walk_if_branch_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, branch: &mut IfBranch<'a>) {
+    v.visit_expr_mut(&mut branch.cond);
+    v.visit_block_mut(&mut branch.body);
+}
+
+pub fn walk_for_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, f: &mut For<'a>) {
+    match f {
+        For::Plain(f) => v.visit_plain_for_mut(f),
+        For::ForCol(f) => v.visit_expr_for_mut(f),
+    }
+}
+
+pub fn walk_plain_for_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, f: &mut PlainFor<'a>) {
+    v.visit_id_mut(&mut f.init.0);
+    v.visit_expr_mut(&mut f.init.1);
+    v.visit_expr_mut(&mut f.border);
+    f.step.iter_mut().for_each(|e| v.visit_expr_mut(e));
+    v.visit_block_mut(&mut f.body);
+}
+
+pub fn walk_expr_for_mut<'a, V: VisitorMut<'a> + ?Sized>(v: &mut V, f: &mut ExprFor<'a>) {
+    f.names.iter_mut().for_each(|id| v.visit_id_mut(id));
+    f.expressions.iter_mut().for_each(|e| v.visit_expr_mut(e));
+    v.visit_block_mut(&mut f.body);
+}
+
+/// The rewriting of the ast into a new one.
+/// Every method rebuilds the node out of the folded children by default.
+pub trait Fold<'a> {
+    fn fold_block(&mut self, block: Block<'a>) -> Block<'a> {
+        fold_block(self, block)
+    }
+    fn fold_statement(&mut self, st: Statement<'a>) -> Statement<'a> {
+        fold_statement(self, st)
+    }
+    fn fold_expr(&mut self, expr: Expression<'a>) -> Expression<'a> {
+        fold_expr(self, expr)
+    }
+    fn fold_id(&mut self, id: Id<'a>) -> Id<'a> {
+        id
+    }
+    fn fold_text(&mut self, text: Text<'a>) -> Text<'a> {
+        text
+    }
+    fn fold_fn_call(&mut self, call: FnCall<'a>) -> FnCall<'a> {
+        fold_fn_call(self, call)
+    }
+    fn fold_var(&mut self, var: Var<'a>) -> Var<'a> {
+        fold_var(self, var)
+    }
+    fn fold_var_suffix(&mut self, suffix: VarSuffix<'a>) -> VarSuffix<'a> {
+        fold_var_suffix(self, suffix)
+    }
+    fn fold_name_args(&mut self, name_args: NameArgs<'a>) -> NameArgs<'a> {
+        fold_name_args(self, name_args)
+    }
+    fn fold_args(&mut self, args: Args<'a>) -> Args<'a> {
+        fold_args(self, args)
+    }
+    fn fold_table(&mut self, table: TableConst<'a>) -> TableConst<'a> {
+        fold_table(self, table)
+    }
+    fn fold_field(&mut self, field: Field<'a>) -> Field<'a> {
+        fold_field(self, field)
+    }
+    fn fold_fn_params(&mut self, params: FnParams<'a>) -> FnParams<'a> {
+        fold_fn_params(self, params)
+    }
+    fn fold_fn_name(&mut self, name: FnName<'a>) -> FnName<'a> {
+        fold_fn_name(self, name)
+    }
+    fn fold_fn_def(&mut self, def: FnDef<'a>) -> FnDef<'a> {
+        fold_fn_def(self, def)
+    }
+    fn fold_attr_name(&mut self, attr: AttrName<'a>) -> AttrName<'a> {
+        fold_attr_name(self, attr)
+    }
+    fn fold_while(&mut self, w: While<'a>) -> While<'a> {
+        fold_while(self, w)
+    }
+    fn fold_repeat(&mut self, r: Repeat<'a>) -> Repeat<'a> {
+        fold_repeat(self, r)
+    }
+    fn fold_if(&mut self, i: If<'a>) -> If<'a> {
+        fold_if(self, i)
+    }
+    fn fold_if_branch(&mut self, branch: IfBranch<'a>) -> IfBranch<'a> {
+        fold_if_branch(self, branch)
+    }
+    fn fold_for(&mut self, f: For<'a>) -> For<'a> {
+        fold_for(self, f)
+    }
+    fn fold_plain_for(&mut self, f: PlainFor<'a>) -> PlainFor<'a> {
+        fold_plain_for(self, f)
+    }
+    fn fold_expr_for(&mut self, f: ExprFor<'a>) -> ExprFor<'a> {
+        fold_expr_for(self, f)
+    }
+}
+
+fn fold_all<'a, T, F: Fold<'a> + ?Sized>(f: &mut F, items: Vec<T>, fold: fn(&mut F, T) -> T) -> Vec<T> {
+    items.into_iter().map(|item| fold(f, item)).collect()
+}
+
+pub fn fold_block<'a, F: Fold<'a> + ?Sized>(f: &mut F, block: Block<'a>) -> Block<'a> {
+    match block {
+        Block::Void(sts, span) => Block::Void(fold_all(f, sts, F::fold_statement), span),
+        Block::Return(sts, exprs, span) => {
+            let sts = fold_all(f, sts, F::fold_statement);
+            Block::Return(sts, fold_all(f, exprs, F::fold_expr), span)
+        }
+    }
+}
+
+pub fn fold_statement<'a, F: Fold<'a> + ?Sized>(f: &mut F, st: Statement<'a>) -> Statement<'a> {
+    match st {
+        Statement::Assignment(vars, exprs) => {
+            let vars = fold_all(f, vars, F::fold_var);
+            Statement::Assignment(vars, fold_all(f, exprs, F::fold_expr))
+        }
+        Statement::FnCall(call) => Statement::FnCall(f.fold_fn_call(call)),
+        Statement::Label(id, span) => Statement::Label(f.fold_id(id), span),
+        Statement::Goto(id, span) => Statement::Goto(f.fold_id(id), span),
+        Statement::Do(body, span) => Statement::Do(f.fold_block(body), span),
+        Statement::While(w) => Statement::While(f.fold_while(w)),
+        Statement::Repeat(r) => Statement::Repeat(f.fold_repeat(r)),
+        Statement::If(i) => Statement::If(f.fold_if(i)),
+        Statement::For(fr) => Statement::For(f.fold_for(fr)),
+        Statement::FnDef(def) => Statement::FnDef(f.fold_fn_def(def)),
+        Statement::LocalFnDef(def) => Statement::LocalFnDef(f.fold_fn_def(def)),
+        Statement::LocalAttrNames(attrs, exprs, span) => {
+            let attrs = fold_all(f, attrs, F::fold_attr_name);
+            Statement::LocalAttrNames(attrs, fold_all(f, exprs, F::fold_expr), span)
+        }
+        st @ (Statement::Empty(_) | Statement::Break(_) | Statement::Error(_)) => st,
+    }
+}
+
+pub fn fold_expr<'a, F: Fold<'a> + ?Sized>(f: &mut F, expr: Expression<'a>) -> Expression<'a> {
+    match expr {
+        Expression::Text(text) => Expression::Text(f.fold_text(text)),
+        Expression::FnDef(params, body, span) => {
+            let params = f.fold_fn_params(params);
+            Expression::FnDef(params, f.fold_block(body), span)
+        }
+        Expression::PrefixExpr(call) => Expression::PrefixExpr(Box::new(f.fold_fn_call(*call))),
+        Expression::TableConstructor(table) => Expression::TableConstructor(f.fold_table(table)),
+        Expression::Unary(tp, e, span) => Expression::Unary(tp, Box::new(f.fold_expr(*e)), span),
+        Expression::Binary(lhs, tp, rhs) => {
+            let lhs = f.fold_expr(*lhs);
+            Expression::Binary(Box::new(lhs), tp, Box::new(f.fold_expr(*rhs)))
+        }
+        e @ (Expression::Nil(_)
+        | Expression::False(_)
+        | Expression::True(_)
+        | Expression::Number(..)
+        | Expression::VarArgs(_)
+        | Expression::Error(_)) => e,
+    }
+}
+
+pub fn fold_fn_call<'a, F: Fold<'a> + ?Sized>(f: &mut F, call: FnCall<'a>) -> FnCall<'a> {
+    let head = match call.head {
+        VarOrExpr::Expr(e) => VarOrExpr::Expr(f.fold_expr(e)),
+        VarOrExpr::Var(var) => VarOrExpr::Var(f.fold_var(var)),
+    };
+    FnCall { head, args: fold_all(f, call.args, F::fold_name_args), span: call.span }
+}
+
+pub fn fold_var<'a, F: Fold<'a> + ?Sized>(f: &mut F, var: Var<'a>) -> Var<'a> {
+    let head = match var.head {
+        VarHead::Id(id) => VarHead::Id(f.fold_id(id)),
+        VarHead::Expr(e, suffix) => {
+            let e = f.fold_expr(e);
+            VarHead::Expr(e, f.fold_var_suffix(suffix))
+        }
+    };
+    Var { head, tail: fold_all(f, var.tail, F::fold_var_suffix), span: var.span }
+}
+
+pub fn fold_var_suffix<'a, F: Fold<'a> + ?Sized>(f: &mut F, suffix: VarSuffix<'a>) -> VarSuffix<'a> {
+    let var = fold_all(f, suffix.var, F::fold_name_args);
+    let s = match suffix.suffix {
+        Suffix::Expr(e) => Suffix::Expr(f.fold_expr(e)),
+        Suffix::Id(id) => Suffix::Id(f.fold_id(id)),
+    };
+    VarSuffix { var, suffix: s, span: suffix.span }
+}
+
+pub fn fold_name_args<'a, F: Fold<'a> + ?Sized>(f: &mut F, name_args: NameArgs<'a>) -> NameArgs<'a> {
+    match name_args {
+        NameArgs::Args(args) => NameArgs::Args(f.fold_args(args)),
+        NameArgs::NameArgs(name, args) => {
+            let name = f.fold_id(name);
+            NameArgs::NameArgs(name, f.fold_args(args))
+        }
+    }
+}
+
+pub fn fold_args<'a, F: Fold<'a> + ?Sized>(f: &mut F, args: Args<'a>) -> Args<'a> {
+    match args {
+        Args::Expressions(exprs, span) => Args::Expressions(fold_all(f, exprs, F::fold_expr), span),
+        Args::Constructor(table) => Args::Constructor(f.fold_table(table)),
+        Args::String(text) => Args::String(f.fold_text(text)),
+    }
+}
+
+pub fn fold_table<'a, F: Fold<'a> + ?Sized>(f: &mut F, table: TableConst<'a>) -> TableConst<'a> {
+    TableConst { fields: fold_all(f, table.fields, F::fold_field), span: table.span }
+}
+
+pub fn fold_field<'a, F: Fold<'a> + ?Sized>(f: &mut F, field: Field<'a>) -> Field<'a> {
+    match field {
+        Field::Pair(FieldKey::Expr(key), value) => {
+            let key = f.fold_expr(key);
+            Field::Pair(FieldKey::Expr(key), f.fold_expr(value))
+        }
+        Field::Pair(FieldKey::Id(key), value) => {
+            let key = f.fold_id(key);
+            Field::Pair(FieldKey::Id(key), f.fold_expr(value))
+        }
+        Field::Value(value) => Field::Value(f.fold_expr(value)),
+    }
+}
+
+pub fn fold_fn_params<'a, F: Fold<'a> + ?Sized>(f: &mut F, params: FnParams<'a>) -> FnParams<'a> {
+    match params {
+        FnParams::Args(ids) => FnParams::Args(fold_all(f, ids, F::fold_id)),
+        FnParams::WithVarArgs(ids) => FnParams::WithVarArgs(fold_all(f, ids, F::fold_id)),
+        FnParams::VarArgs => FnParams::VarArgs,
+    }
+}
+
+pub fn fold_fn_name<'a, F: Fold<'a> + ?Sized>(f: &mut F, name: FnName<'a>) -> FnName<'a> {
+    let names = fold_all(f, name.names, F::fold_id);
+    FnName { names, last: name.last.map(|id| f.fold_id(id)), span: name.span }
+}
+
+pub fn fold_fn_def<'a, F: Fold<'a> + ?Sized>(f: &mut F, def: FnDef<'a>) -> FnDef<'a> {
+    let name = f.fold_fn_name(def.name);
+    let params = f.fold_fn_params(def.params);
+    FnDef { name, params, body: f.fold_block(def.body), span: def.span }
+}
+
+pub fn fold_attr_name<'a, F: Fold<'a> + ?Sized>(f: &mut F, attr: AttrName<'a>) -> AttrName<'a> {
+    match attr {
+        AttrName::Name(name) => AttrName::Name(f.fold_id(name)),
+        AttrName::AttrName(name, attr) => {
+            let name = f.fold_id(name);
+            AttrName::AttrName(name, f.fold_id(attr))
+        }
+    }
+}
+
+pub fn fold_while<'a, F: Fold<'a> + ?Sized>(f: &mut F, w: While<'a>) -> While<'a> {
+    let cond = f.fold_expr(w.cond);
+    While { cond, body: f.fold_block(w.body), span: w.span }
+}
+
+pub fn fold_repeat<'a, F: Fold<'a> + ?Sized>(f: &mut F, r: Repeat<'a>) -> Repeat<'a> {
+    let body = f.fold_block(r.body);
+    Repeat { until: f.fold_expr(r.until), body, span: r.span }
+}
+
+pub fn fold_if<'a, F: Fold<'a> + ?Sized>(f: &mut F, i: If<'a>) -> If<'a> {
+    match i {
+        If::If(main, others, span) => {
+            let main = f.fold_if_branch(main);
+            If::If(main, fold_all(f, others, F::fold_if_branch), span)
+        }
+        If::IfElse(main, others, els, span) => {
+            let main = f.fold_if_branch(main);
+            let others = fold_all(f, others, F::fold_if_branch);
+            If::IfElse(main, others, f.fold_block(els), span)
+        }
+    }
+}
+
+pub fn fold_if_branch<'a, F: Fold<'a> + ?Sized>(f: &mut F, branch: IfBranch<'a>) -> IfBranch<'a> {
+    let cond = f.fold_expr(branch.cond);
+    IfBranch { cond, body: f.fold_block(branch.body), span: branch.span }
+}
+
+pub fn fold_for<'a, F: Fold<'a> + ?Sized>(f: &mut F, fr: For<'a>) -> For<'a> {
+    match fr {
+        For::Plain(p) => For::Plain(f.fold_plain_for(p)),
+        For::ForCol(c) => For::ForCol(f.fold_expr_for(c)),
+    }
+}
+
+pub fn fold_plain_for<'a, F: Fold<'a> + ?Sized>(f: &mut F, fr: PlainFor<'a>) -> PlainFor<'a> {
+    let init = (f.fold_id(fr.init.0), f.fold_expr(fr.init.1));
+    let border = f.fold_expr(fr.border);
+    let step = fr.step.map(|e| f.fold_expr(e));
+    PlainFor { init, border, step, body: f.fold_block(fr.body), span: fr.span }
+}
+
+pub fn fold_expr_for<'a, F: Fold<'a> + ?Sized>(f: &mut F, fr: ExprFor<'a>) -> ExprFor<'a> {
+    let names = fold_all(f, fr.names, F::fold_id);
+    let expressions = fold_all(f, fr.expressions, F::fold_expr);
+    ExprFor { names, expressions, body: f.fold_block(fr.body), span: fr.span }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::ast::*;
+    use crate::parser::parse_chunk;
+    use crate::parser::visit::{Fold, Visitor, VisitorMut, walk_expr};
+
+    const SRC: &str = r#"
+        local a <const>, b = 1, {x = 2, [y] = 3, 4}
+        function m.n:o(p, ...) return p end
+        local function q() end
+        for i = 1, 2, 3 do goto l end
+        for k, v in pairs(t) do ::l:: end
+        while c do break end
+        repeat d() until e
+        if f then elseif g then else end
+        h.i[j]:k "s" (function(...) end)
+        do r = -s .. #u end
+    "#;
+
+    #[derive(Default)]
+    struct Names(Vec<String>, usize);
+
+    impl<'a> Visitor<'a> for Names {
+        fn visit_id(&mut self, id: &Id<'a>) {
+            self.0.push(id.v.to_string())
+        }
+        fn visit_expr(&mut self, expr: &Expression<'a>) {
+            self.1 += 1;
+            walk_expr(self, expr)
+        }
+    }
+
+    #[test]
+    fn visitor_test() {
+        let block = parse_chunk(SRC).unwrap();
+        let mut names = Names::default();
+        names.visit_block(&block);
+        assert_eq!(names.0.join(" "), "a const b x y m n o p p q i l k v pairs t l c d e f g h i j k r s u");
+        assert_eq!(names.1, 23);
+    }
+
+    struct Rename;
+
+    impl<'a> VisitorMut<'a> for Rename {
+        fn visit_id_mut(&mut self, id: &mut Id<'a>) {
+            if id.v.as_str() == "p" {
+                id.v = Name::Borrowed("z");
+            }
+        }
+    }
+
+    #[test]
+    fn visitor_mut_test() {
+        let mut block = parse_chunk("function f(p) return p + g(p) end").unwrap();
+        Rename.visit_block_mut(&mut block);
+        assert_eq!(block, parse_chunk("function f(z) return z + g(z) end").unwrap());
+    }
+
+    /// Swaps the operands of every binary expression.
+    struct Swap;
+
+    impl<'a> Fold<'a> for Swap {
+        fn fold_expr(&mut self, expr: Expression<'a>) -> Expression<'a> {
+            match crate::parser::visit::fold_expr(self, expr) {
+                Expression::Binary(lhs, tp, rhs) => Expression::Binary(rhs, tp, lhs),
+                e => e,
+            }
+        }
+    }
+
+    #[test]
+    fn fold_test() {
+        let block = parse_chunk("x = f(a - (b .. c)) t = {[1 + 2] = 3 * 4}").unwrap();
+        let swapped = Swap.fold_block(block);
+        assert!(swapped.same_shape(&parse_chunk("x = f((c .. b) - a) t = {[2 + 1] = 4 * 3}").unwrap()));
+
+        let block = parse_chunk(SRC).unwrap();
+        assert_eq!(Swap.fold_block(Swap.fold_block(block.clone())), block);
+    }
+}